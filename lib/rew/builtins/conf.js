@@ -1,4 +1,39 @@
 "no-compile"
+
+function createKv(app) {
+  const pkg = () => app.config.manifest.package;
+
+  return {
+    get(key) {
+      return rew.ops.op_kv_get(pkg(), key);
+    },
+
+    async set(key, value, options = {}) {
+      return await rew.ops.op_kv_set(pkg(), key, value, options.ttl ?? null);
+    },
+
+    async delete(key) {
+      return await rew.ops.op_kv_delete(pkg(), key);
+    },
+
+    // Entries whose key starts with prefix, in key order. `start` is the first
+    // key to include and `end` the first key after the ones to include.
+    scan(prefix = '', options = {}) {
+      return rew.ops.op_kv_scan(pkg(), {
+        prefix,
+        start: options.start ?? null,
+        end: options.end ?? null,
+        limit: options.limit ?? null,
+        reverse: !!options.reverse,
+      });
+    },
+
+    async increment(key, by = 1) {
+      return await rew.ops.op_kv_increment(pkg(), key, by);
+    },
+  };
+}
+
 if(!rew.extensions.has('conf')) rew.extensions.add('conf', (Deno, module) => rew.extensions.createClass({
   _namespace(){
     return "conf";
@@ -8,6 +43,9 @@ if(!rew.extensions.has('conf')) rew.extensions.add('conf', (Deno, module) => rew
     return rew.ops.op_data_read(this.current_app.config.manifest.package, key);
   },
 
+  // The app's data directory. conf keeps its keys in `.rew-store.sqlite` there,
+  // or as one file per key with `data: { backend: files }` in app.yaml. Other
+  // files the app puts there are never touched by conf.
   path(){
     return rew.ops.op_data_get_path(this.current_app.config.manifest.package);
   },
@@ -96,10 +134,45 @@ if(!rew.extensions.has('conf')) rew.extensions.add('conf', (Deno, module) => rew
     }
   },
   
+  async transaction(fn) {
+    const changes = [];
+    const stage = (change) => {
+      const index = changes.findIndex((staged) => staged.key === change.key);
+      if (index >= 0) changes.splice(index, 1);
+      changes.push(change);
+    };
+    const tx = {
+      write(key, content) {
+        if (typeof content !== 'string') {
+          content = JSON.stringify(content);
+        }
+        stage({ op: 'write', key, content });
+      },
+      writeJSON(key, data) {
+        stage({ op: 'write', key, content: JSON.stringify(data, null, 2) });
+      },
+      writeYAML(key, value) {
+        stage({ op: 'yaml', key, value });
+      },
+      writeBinary(key, data) {
+        stage({ op: 'binary', key, data: Array.from(data) });
+      },
+      delete(key) {
+        stage({ op: 'delete', key });
+      },
+    };
+
+    const result = await fn(tx);
+    await rew.ops.op_data_transaction(this.current_app.config.manifest.package, changes);
+    return result;
+  },
+
   getInfo(key) {
     const [exists, format] = rew.ops.op_data_get_info(this.current_app.config.manifest.package, key);
     return { exists, format };
   },
 
+  kv: createKv(module.app || { config: {}, path: "" }),
+
   current_app: module.app || { config: {}, path: "" },
 }));
@@ -4,6 +4,94 @@ function trackPromise(promise){
   return Promise.resolve(promise);
 }
 
+const OPEN_MODES = {
+  'r': { read: true },
+  'r+': { read: true, write: true },
+  'w': { write: true, create: true, truncate: true },
+  'w+': { read: true, write: true, create: true, truncate: true },
+  'a': { append: true, create: true },
+  'a+': { read: true, append: true, create: true },
+  'x': { write: true, createNew: true },
+  'x+': { read: true, write: true, createNew: true },
+};
+
+const SEEK_MODES = { start: 0, current: 1, end: 2 };
+
+// Wraps a Deno FsFile so large files can be worked on a chunk at a time.
+class FileHandle {
+  #file;
+
+  constructor(file) {
+    this.#file = file;
+  }
+
+  // Resolves with the number of bytes read into buf, or null at the end of the file.
+  read(buf) {
+    return this.#file.read(buf);
+  }
+
+  async write(bytes) {
+    if (typeof bytes === 'string') {
+      bytes = new TextEncoder().encode(bytes);
+    }
+    let written = 0;
+    while (written < bytes.length) {
+      written += await this.#file.write(bytes.subarray(written));
+    }
+    return written;
+  }
+
+  seek(offset, whence = 'start') {
+    return this.#file.seek(offset, typeof whence === 'string' ? SEEK_MODES[whence] : whence);
+  }
+
+  truncate(len) {
+    return this.#file.truncate(len);
+  }
+
+  sync() {
+    return this.#file.sync();
+  }
+
+  stat() {
+    return this.#file.stat();
+  }
+
+  close() {
+    return this.#file.close();
+  }
+
+  get readable() {
+    return this.#file.readable;
+  }
+
+  get writable() {
+    return this.#file.writable;
+  }
+
+  async *lines() {
+    const decoder = new TextDecoder();
+    const buf = new Uint8Array(64 * 1024);
+    let rest = '';
+    while (true) {
+      const n = await this.#file.read(buf);
+      if (n === null) break;
+      rest += decoder.decode(buf.subarray(0, n), { stream: true });
+      const lines = rest.split('\n');
+      rest = lines.pop();
+      for (const line of lines) {
+        yield line.endsWith('\r') ? line.slice(0, -1) : line;
+      }
+    }
+    rest += decoder.decode();
+    if (rest) yield rest;
+  }
+
+  [Symbol.asyncIterator]() {
+    return this.lines();
+  }
+}
+
 if(!rew.extensions.has('fs')) rew.extensions.add('fs', (Deno, module) => rew.extensions.createClass({
   _namespace(){
     return this;
@@ -11,9 +99,13 @@ if(!rew.extensions.has('fs')) rew.extensions.add('fs', (Deno, module) => rew.ext
 
   ...Deno.fs,
 
-  open(path, options){
+  async open(path, mode = 'r'){
     const a = path.startsWith('/') ? path : rew.prototype._path.prototype.resolveFrom(module.filename, path);
-    return Deno.fs.open(a, options);
+    const options = typeof mode === 'string' ? OPEN_MODES[mode] : mode;
+    if (!options) {
+      throw new TypeError(`Unknown open mode "${mode}"`);
+    }
+    return new FileHandle(await Deno.fs.open(a, options));
   },
 
   read(path, options = { binary: false }) {
@@ -86,7 +178,62 @@ if(!rew.extensions.has('fs')) rew.extensions.add('fs', (Deno, module) => rew.ext
   async rename(src, dest) {
     return trackPromise(rew.ops.op_fs_rename(module.filename, src, dest));
   },
-  
+
+  watch(path, options = {}, callback) {
+    if (typeof options == 'function') {
+      callback = options;
+      options = {};
+    }
+    const rid = rew.ops.op_fs_watch(module.filename, path, {
+      recursive: !!options.recursive,
+      debounce: options.debounce ?? null,
+    });
+    let closed = false;
+    let pending = [];
+
+    const watcher = {
+      rid,
+      close() {
+        if (closed) return;
+        closed = true;
+        Deno.core.tryClose(rid);
+      },
+      async next() {
+        while (!pending.length) {
+          if (closed) return { done: true, value: undefined };
+          const events = await rew.ops.op_fs_watch_next(rid);
+          if (!events) {
+            watcher.close();
+            return { done: true, value: undefined };
+          }
+          pending = events;
+        }
+        return { done: false, value: pending.shift() };
+      },
+      async return() {
+        watcher.close();
+        return { done: true, value: undefined };
+      },
+      [Symbol.asyncIterator]() {
+        return watcher;
+      },
+    };
+
+    if (typeof callback == 'function') {
+      (async () => {
+        for await (const event of watcher) {
+          await callback(event);
+        }
+      })().catch((error) => {
+        // Nothing awaits the loop, so a failing callback stops the watcher
+        // instead of leaving it running unheard.
+        watcher.close();
+        console.error(`Uncaught error in fs.watch callback for ${path}:`, error);
+      });
+    }
+    return watcher;
+  },
+
   async ensureDir(path) {
     return await this.mkdir(path, { recursive: true });
   },
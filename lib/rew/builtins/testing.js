@@ -0,0 +1,261 @@
+"no-compile"
+const createSuite = (name, parent, skip = false) => ({
+  name,
+  parent,
+  skip: skip || !!parent?.skip,
+  tests: [],
+  children: [],
+  hooks: { beforeAll: [], afterAll: [], beforeEach: [], afterEach: [] },
+});
+
+const rootSuite = createSuite("", null);
+let currentSuite = rootSuite;
+
+const suitePath = (suite) => {
+  const names = [];
+  for (let s = suite; s && s.parent; s = s.parent) names.unshift(s.name);
+  return names;
+};
+
+const format = (value) => {
+  if (typeof value === "string") return JSON.stringify(value);
+  if (typeof value === "function") return `[Function ${value.name || "anonymous"}]`;
+  try {
+    return JSON.stringify(value) ?? String(value);
+  } catch {
+    return String(value);
+  }
+};
+
+const deepEqual = (a, b) => {
+  if (Object.is(a, b)) return true;
+  if (typeof a !== "object" || typeof b !== "object" || a === null || b === null) return false;
+  if (Array.isArray(a) !== Array.isArray(b)) return false;
+  if (a instanceof Date && b instanceof Date) return a.getTime() === b.getTime();
+  const keysA = Object.keys(a);
+  const keysB = Object.keys(b);
+  if (keysA.length !== keysB.length) return false;
+  return keysA.every((key) => Object.prototype.hasOwnProperty.call(b, key) && deepEqual(a[key], b[key]));
+};
+
+class AssertionError extends Error {
+  constructor(message) {
+    super(message);
+    this.name = "AssertionError";
+  }
+}
+
+const matchers = {
+  toBe: (actual, expected) => [Object.is(actual, expected), `to be ${format(expected)}`],
+  toEqual: (actual, expected) => [deepEqual(actual, expected), `to equal ${format(expected)}`],
+  toBeTruthy: (actual) => [!!actual, "to be truthy"],
+  toBeFalsy: (actual) => [!actual, "to be falsy"],
+  toBeNull: (actual) => [actual === null, "to be null"],
+  toBeUndefined: (actual) => [actual === undefined, "to be undefined"],
+  toBeDefined: (actual) => [actual !== undefined, "to be defined"],
+  toBeGreaterThan: (actual, expected) => [actual > expected, `to be greater than ${format(expected)}`],
+  toBeLessThan: (actual, expected) => [actual < expected, `to be less than ${format(expected)}`],
+  toBeInstanceOf: (actual, expected) => [actual instanceof expected, `to be an instance of ${expected?.name}`],
+  toContain: (actual, expected) => [
+    actual != null && typeof actual.includes === "function" && actual.includes(expected),
+    `to contain ${format(expected)}`,
+  ],
+  toHaveLength: (actual, expected) => [actual?.length === expected, `to have length ${expected}`],
+  toMatch: (actual, expected) => [
+    typeof actual === "string" && (expected instanceof RegExp ? expected.test(actual) : actual.includes(expected)),
+    `to match ${expected instanceof RegExp ? expected : format(expected)}`,
+  ],
+  toThrow: (actual, expected) => {
+    let thrown = null;
+    try {
+      actual();
+    } catch (e) {
+      thrown = e;
+    }
+    if (!thrown) return [false, "to throw"];
+    if (expected === undefined) return [true, "to throw"];
+    const message = thrown?.message ?? String(thrown);
+    if (expected instanceof RegExp) return [expected.test(message), `to throw ${expected}`];
+    if (typeof expected === "function") return [thrown instanceof expected, `to throw ${expected.name}`];
+    return [message.includes(expected), `to throw ${format(expected)}`];
+  },
+};
+
+const buildExpectation = (getActual, negate, asynchronous) => {
+  const expectation = {};
+  for (const [name, matcher] of Object.entries(matchers)) {
+    expectation[name] = (...args) => {
+      const check = (actual) => {
+        const [pass, description] = matcher(actual, ...args);
+        if (pass === negate) {
+          const shown = name === "toThrow" ? "function" : format(actual);
+          throw new AssertionError(`expected ${shown} ${negate ? "not " : ""}${description}`);
+        }
+      };
+      return asynchronous ? getActual().then(check) : check(getActual());
+    };
+  }
+  return expectation;
+};
+
+const expect = (actual) => {
+  const expectation = buildExpectation(() => actual, false, false);
+  expectation.not = buildExpectation(() => actual, true, false);
+  const settle = (rejects) => () =>
+    Promise.resolve(actual).then(
+      (value) => {
+        if (rejects) throw new AssertionError(`expected promise to reject, it resolved to ${format(value)}`);
+        return value;
+      },
+      (error) => {
+        if (!rejects) throw new AssertionError(`expected promise to resolve, it rejected with ${error}`);
+        return error instanceof Error ? error.message : error;
+      },
+    );
+  expectation.resolves = buildExpectation(settle(false), false, true);
+  expectation.rejects = buildExpectation(settle(true), false, true);
+  return expectation;
+};
+
+const describe = (name, fn, skip = false) => {
+  const suite = createSuite(name, currentSuite, skip);
+  currentSuite.children.push(suite);
+  const previous = currentSuite;
+  currentSuite = suite;
+  try {
+    fn();
+  } finally {
+    currentSuite = previous;
+  }
+};
+describe.skip = (name, fn) => describe(name, fn, true);
+
+const it = (name, fn, skip = false) => {
+  currentSuite.tests.push({ name, fn, skip: skip || currentSuite.skip });
+};
+it.skip = (name, fn) => it(name, fn, true);
+it.todo = (name) => it(name, null, true);
+
+const hook = (kind) => (fn) => currentSuite.hooks[kind].push(fn);
+
+const matchesFilter = (fullName, filter) => {
+  if (!filter) return true;
+  const regex = filter.match(/^\/(.*)\/([a-z]*)$/);
+  return regex ? new RegExp(regex[1], regex[2]).test(fullName) : fullName.includes(filter);
+};
+
+const errorInfo = (error) => ({
+  message: error?.message ?? String(error),
+  stack: error?.stack ?? String(error),
+});
+
+const runSuite = async (suite, options, results) => {
+  const selected = (s) =>
+    s.tests.some((test) => matchesFilter([...suitePath(s), test.name].join(" > "), options.filter)) ||
+    s.children.some(selected);
+  if (!selected(suite) || options.stopped) return;
+
+  const runHooks = async (kind, s) => {
+    for (const fn of s.hooks[kind]) await fn();
+  };
+  // Outer suites run their `beforeEach` hooks first and `afterEach` last.
+  const suiteChain = (s, reverse) => {
+    const chain = [];
+    for (let p = s; p; p = p.parent) chain.unshift(p);
+    if (reverse) chain.reverse();
+    return chain;
+  };
+
+  let setupError = null;
+  if (!suite.skip) {
+    try {
+      await runHooks("beforeAll", suite);
+    } catch (e) {
+      setupError = e;
+    }
+  }
+
+  for (const test of suite.tests) {
+    const path = suitePath(suite);
+    const fullName = [...path, test.name].join(" > ");
+    if (options.stopped || !matchesFilter(fullName, options.filter)) continue;
+
+    const result = { name: test.name, suite: path, status: "passed", duration: 0 };
+    const started = Date.now();
+    if (test.skip || !test.fn) {
+      result.status = "skipped";
+    } else if (setupError) {
+      result.status = "failed";
+      result.error = errorInfo(setupError);
+    } else {
+      try {
+        for (const s of suiteChain(suite, false)) await runHooks("beforeEach", s);
+        await test.fn();
+      } catch (e) {
+        result.status = "failed";
+        result.error = errorInfo(e);
+      }
+      try {
+        for (const s of suiteChain(suite, true)) await runHooks("afterEach", s);
+      } catch (e) {
+        if (result.status !== "failed") {
+          result.status = "failed";
+          result.error = errorInfo(e);
+        }
+      }
+    }
+    result.duration = Date.now() - started;
+    results.push(result);
+    if (result.status === "failed" && options.bail) options.stopped = true;
+  }
+
+  for (const child of suite.children) await runSuite(child, options, results);
+
+  if (!suite.skip && !setupError) {
+    try {
+      await runHooks("afterAll", suite);
+    } catch (e) {
+      results.push({
+        name: "afterAll",
+        suite: suitePath(suite),
+        status: "failed",
+        duration: 0,
+        error: errorInfo(e),
+      });
+    }
+  }
+};
+
+if (!globalThis.__rew_testing__) {
+  globalThis.__rew_testing__ = {
+    async run(options = {}) {
+      const results = [];
+      await runSuite(rootSuite, { ...options, stopped: false }, results);
+      return JSON.stringify(results);
+    },
+  };
+}
+
+if(!rew.extensions.has('testing')) rew.extensions.add('testing', (Deno, module) => rew.extensions.createClass({
+  _namespace(){
+    return {
+      describe: this.describe,
+      it: this.it,
+      test: this.it,
+      expect: this.expect,
+      beforeAll: this.beforeAll,
+      afterAll: this.afterAll,
+      beforeEach: this.beforeEach,
+      afterEach: this.afterEach,
+    };
+  },
+  describe,
+  it,
+  test: it,
+  expect,
+  AssertionError,
+  beforeAll: hook("beforeAll"),
+  afterAll: hook("afterAll"),
+  beforeEach: hook("beforeEach"),
+  afterEach: hook("afterEach"),
+}));
@@ -1,16 +1,213 @@
 "no-compile"
 const liveThreads = [];
+// A single line naming a module is spawned as a file, anything else as code.
+const WORKER_FILE = /^[^\n]+\.(coffee|civet|rew|js|ts|brew|qrew)$/;
+
+// Messages are structured clones. The ArrayBuffers listed in `transfer` are
+// moved to the other thread instead, and SharedArrayBuffers are shared.
+function encodeMessage(message, transfer) {
+  const transferred = transfer.map((b) => ArrayBuffer.isView(b) ? b.buffer : b);
+  return [rew.ops.op_serialize(message, undefined, transferred, false, undefined), transferred];
+}
+
+function decodeMessage(event) {
+  return rew.ops.op_deserialize(event.data, undefined, event.transferred, false);
+}
+// Runs tasks on a fixed number of workers, one task per worker at a time.
+// A worker's message handler gets the payload and its return value (or
+// what it throws) settles the task.
+class WorkerPool {
+  #threads;
+  #source;
+  #workers = [];
+  #queue = [];
+  #waiting = [];
+  #queueSize;
+  #timeout;
+  #nextTask = 0;
+  #closed = false;
+  #drained = null;
+  #broken = null;
+
+  constructor(threads, size, source, options) {
+    if (!Number.isInteger(size) || size < 1) {
+      throw new RangeError(`Pool size must be a positive integer, got ${size}`);
+    }
+    this.#threads = threads;
+    this.#source = typeof source === 'function' ? `onmessage(${source.toString()});` : source;
+    this.#queueSize = options.queueSize ?? size * 4;
+    this.#timeout = options.timeout ?? null;
+    for (let i = 0; i < size; i++) this.#spawn();
+  }
+
+  get size() {
+    return this.#workers.length;
+  }
+
+  // Tasks waiting for a free worker.
+  get pending() {
+    return this.#queue.length;
+  }
+
+  // Resolves with the task's result. Waits for room first while the queue is full.
+  async run(payload, options = {}) {
+    while (!this.#closed && !this.#broken && this.#queue.length >= this.#queueSize) {
+      await new Promise((resolve) => this.#waiting.push(resolve));
+    }
+    if (this.#broken) throw this.#broken;
+    if (this.#closed) throw new Error('Pool is closed');
+
+    const { signal } = options;
+    signal?.throwIfAborted();
+    return new Promise((resolve, reject) => {
+      const onAbort = () => this.#abort(task, signal.reason);
+      // However the task ends, its timer and abort listener go with it.
+      const finish = () => {
+        clearTimeout(task.timer);
+        signal?.removeEventListener('abort', onAbort);
+      };
+      const task = {
+        id: this.#nextTask++,
+        payload,
+        transfer: options.transfer ?? [],
+        resolve: (result) => (finish(), resolve(result)),
+        reject: (error) => (finish(), reject(error)),
+        timeout: options.timeout ?? this.#timeout,
+        timer: null,
+      };
+      signal?.addEventListener('abort', onAbort, { once: true });
+      this.#queue.push(task);
+      this.#schedule();
+    });
+  }
+
+  // Stops taking tasks, waits for the queued and running ones and then
+  // terminates the workers.
+  async close() {
+    this.#closed = true;
+    this.#release();
+    if (!this.#idle()) {
+      await new Promise((resolve) => (this.#drained = resolve));
+    }
+    for (const worker of this.#workers.splice(0)) {
+      worker.thread.terminate();
+    }
+  }
+
+  #spawn() {
+    const worker = { thread: this.#threads.create(this.#source), task: null, done: 0 };
+    worker.thread.onmessage(({ data }) => this.#settle(worker, data));
+    worker.thread.onerror(({ message }) => this.#crash(worker, new Error(message)));
+    this.#workers.push(worker);
+  }
+
+  #schedule() {
+    for (const worker of this.#workers) {
+      if (!this.#queue.length) break;
+      if (worker.task) continue;
+
+      const task = this.#queue.shift();
+      worker.task = task;
+      if (task.timeout != null) {
+        task.timer = setTimeout(
+          () => this.#abort(task, new Error(`Task timed out after ${task.timeout}ms`)),
+          task.timeout,
+        );
+      }
+      worker.thread.postMessage({ __task: task.id, payload: task.payload }, task.transfer);
+      this.#release();
+    }
+  }
+
+  #settle(worker, data) {
+    const task = worker.task;
+    if (!task || data?.__task !== task.id) return;
+
+    worker.task = null;
+    worker.done++;
+    if ('error' in data) task.reject(new Error(data.error));
+    else task.resolve(data.result);
+    this.#schedule();
+    this.#checkDrained();
+  }
+
+  // Fails a task that is queued or running. A running task can only be
+  // stopped with its worker, so that worker is replaced.
+  #abort(task, error) {
+    const index = this.#queue.indexOf(task);
+    if (index != -1) {
+      this.#queue.splice(index, 1);
+      this.#release();
+      task.reject(error);
+    } else {
+      const worker = this.#workers.find((worker) => worker.task === task);
+      if (!worker) return;
+      worker.task = null;
+      task.reject(error);
+      this.#replace(worker);
+    }
+    this.#checkDrained();
+  }
+
+  #crash(worker, error) {
+    if (!this.#workers.includes(worker)) return;
+    if (worker.task) {
+      worker.task.reject(error);
+      worker.task = null;
+    } else if (!worker.done) {
+      // Failing before its first task means the worker can't start at all,
+      // and neither will a new one.
+      this.#broken = error;
+      for (const task of this.#queue.splice(0)) task.reject(error);
+      this.#release();
+    }
+    this.#replace(worker);
+    this.#checkDrained();
+  }
+
+  #replace(worker) {
+    this.#workers.splice(this.#workers.indexOf(worker), 1);
+    // Whatever the worker was doing has to stop now, not once it is done.
+    worker.thread.terminate(true);
+    if (this.#broken || (this.#closed && !this.#queue.length)) return;
+    this.#spawn();
+    this.#schedule();
+  }
+
+  // Lets run() calls waiting for room in the queue check again.
+  #release() {
+    for (const resume of this.#waiting.splice(0)) resume();
+  }
+
+  #idle() {
+    return !this.#queue.length && !this.#workers.some((worker) => worker.task);
+  }
+
+  #checkDrained() {
+    if (this.#drained && this.#idle()) {
+      this.#drained();
+      this.#drained = null;
+    }
+  }
+}
+
 if(!rew.extensions.has('threads')) rew.extensions.add('threads', (Deno, module) => rew.extensions.createClass({
   _namespace(){
     return "threads";
   },
 
   spawn(code) {
+    let source;
     if (typeof code === 'function') {
-      code = `(${code.toString()})();`;
+      source = { kind: 'inline', code: `(${code.toString()})();`, parent: module.filename };
+    } else if (WORKER_FILE.test(code)) {
+      const path = code.startsWith('/') ? code : rew.prototype._path.prototype.resolveFrom(module.filename, code);
+      source = { kind: 'file', path };
+    } else {
+      source = { kind: 'inline', code, parent: module.filename };
     }
     
-    const id = rew.ops.op_thread_spawn(code);
+    const id = rew.ops.op_thread_spawn(source);
     liveThreads.push(id);
     return id;
   },
@@ -22,60 +219,75 @@ if(!rew.extensions.has('threads')) rew.extensions.add('threads', (Deno, module)
   terminate(...ids) {
     return ids.map((id) => {
       liveThreads.splice(liveThreads.indexOf(id), 1);
-      rew.ops.op_thread_terminate(id);
+      rew.ops.op_thread_terminate(id, false);
     });
   },
   
+  // Starts `size` workers running `moduleOrFn` to hand tasks to with `run`.
+  // A module receives tasks through the handler it passes to `onmessage`.
+  pool(size, moduleOrFn, options = {}) {
+    return new WorkerPool(this, size, moduleOrFn, options);
+  },
+
   create(code) {
     const threadId = this.spawn(code);
     
     return {
       id: threadId,
       
-      postMessage(message) {
-        return rew.ops.op_thread_message(this.id, JSON.stringify(message));
+      postMessage(message, transfer = []) {
+        const [data, transferred] = encodeMessage(message, transfer);
+        return rew.ops.op_thread_message(this.id, data, transferred);
       },
       
-      terminate() {
-        this._polling = false;
+      // Lets the worker finish its timers and promises first, unless `force`.
+      terminate(force = false) {
+        this._listening = false;
         liveThreads.splice(liveThreads.indexOf(this.id), 1);
-        return rew.ops.op_thread_terminate(this.id);
+        return rew.ops.op_thread_terminate(this.id, force);
       },
       
-      receiveMessage(timeout) {
+      // Resolves with the next message, or null after `timeout` ms or once the
+      // thread is gone. Throws if the worker reported an error instead.
+      async receiveMessage(timeout) {
         if(!liveThreads.includes(this.id)) return null;
-        return rew.ops.op_thread_receive(this.id, timeout);
+        const event = await rew.ops.op_thread_receive(this.id, timeout ?? 0);
+        if (!event) return null;
+        if (event.type === 'error') throw new Error(event.message);
+        return decodeMessage(event);
       },
       
-      _startPolling() {
-        if (!this._polling && this._onmessage) {
-          this._polling = true;
-          
-          const poll = () => {
-            if (!this._polling) return;
-            
-            const message = this.receiveMessage(100);
-            if (message) {
-              this._onmessage({ data: message });
-            }
-            
-            setTimeout(poll, 10);
-          };
-          
-          poll();
+      _dispatch(event) {
+        if (event.type === 'error') {
+          if (this._onerror) this._onerror({ message: event.message });
+          else console.error(`Uncaught error in thread ${this.id}: ${event.message}`);
+        } else {
+          const data = decodeMessage(event);
+          if (this._onmessage) this._onmessage({ data });
         }
       },
       
+      async _listen() {
+        if (this._listening) return;
+        this._listening = true;
+        const wanted = () => this._onmessage || this._onerror;
+        while (this._listening && wanted() && liveThreads.includes(this.id)) {
+          const event = await rew.ops.op_thread_receive(this.id, null);
+          if (!event) break;
+          this._dispatch(event);
+        }
+        this._listening = false;
+      },
+      
       onmessage(fn) {
         this._onmessage = fn;
-        if (fn) {
-          this._polling = false;
-          this._startPolling();
-        } else {
-          this._polling = false;
-        }
+        if (fn) this._listen();
+      },
+      
+      onerror(fn) {
+        this._onerror = fn;
+        if (fn) this._listen();
       }
-
     };
   }
 }));
\ No newline at end of file
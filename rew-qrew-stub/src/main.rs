@@ -1,31 +1,37 @@
-use std::{env, fs::File, io::{Read, Seek, SeekFrom}, path::PathBuf};
-use rew::runtime::{add_virtual_file, RewRuntime};
+//! A slim runner for `rew brew --exe --stub`. It only runs the brew appended
+//! to it, without the rest of the rew CLI.
 
-#[tokio::main]
-async fn main() -> anyhow::Result<(), anyhow::Error> {
-    let exe_path = env::current_exe()?;
-    let mut file = File::open(&exe_path)?;
-    let file_len = file.metadata()?.len();
+use rew::brew::{read_exe_payload, run_exe_payload};
+use rew::runtime::RuntimeArgs;
+use tokio::task::LocalSet;
 
-    file.seek(SeekFrom::End(-8))?;
-    let mut len_buf = [0u8; 8];
-    file.read_exact(&mut len_buf)?;
-    let script_len = u64::from_le_bytes(len_buf);
+fn main() -> anyhow::Result<()> {
+  let exe = std::env::current_exe()?;
+  let payload = match read_exe_payload(&exe) {
+    Ok(Some(payload)) => payload,
+    Ok(None) => {
+      eprintln!(
+        "{} carries no brew, build one with `rew brew --exe <output> --stub {}`",
+        exe.display(),
+        exe.display()
+      );
+      std::process::exit(1);
+    }
+    Err(error) => {
+      eprintln!("Error: {:#}", error);
+      std::process::exit(1);
+    }
+  };
 
-    file.seek(SeekFrom::End(-(8 + script_len as i64)))?;
-    let mut script = vec![0u8; script_len as usize];
-    file.read_exact(&mut script)?;
-	
-    let code = String::from_utf8(script)?;
-    let mut runtime = RewRuntime::new(std::env::args.collect()).unwrap();
-
-    let mut exe_vpath = exe_path.clone();
-    exe_vpath.set_extension("brew");
-    
-
-    add_virtual_file(exe_path.to_str().unwrap(), &code.clone().as_str());
-
-	runtime.run_file(exe_path).await?;
-	
-	Ok(())
+  let local = LocalSet::new();
+  tokio::runtime::Builder::new_current_thread()
+    .enable_all()
+    .build()?
+    .block_on(local.run_until(async {
+      if let Err(error) = run_exe_payload(&exe, &payload, RuntimeArgs::from_env()).await {
+        rew::diagnostics::report_error(&error);
+        std::process::exit(1);
+      }
+      Ok(())
+    }))
 }
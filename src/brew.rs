@@ -0,0 +1,660 @@
+use crate::runtime::{RewRuntime, RuntimeArgs, add_virtual_file, get_virtual_file};
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The bytes every binary brew starts with.
+pub const BREW_MAGIC: &[u8; 8] = b"REWBREW\0";
+
+/// The container version this build writes and understands.
+///
+/// Version 2 added the signature section after the manifest.
+pub const BREW_FORMAT_VERSION: u16 = 2;
+
+/// Magic, version and manifest length.
+const HEADER_LEN: usize = BREW_MAGIC.len() + 2 + 4;
+
+static LEGACY_ENTRY_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r#"//\s*entry\s*"([^"]+)""#).unwrap());
+static LEGACY_EXTERNAL_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r#"//\s*external\s*"([^"]+)""#).unwrap());
+
+/// One module stored in a brew.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrewModule {
+  /// The id the module is defined under.
+  pub id: String,
+  /// The `app://package/entry` name of the module, if it belongs to an app.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub alias: Option<String>,
+  /// Hex sha256 of the module body before compression.
+  pub sha256: String,
+  /// Where the body starts, counted from the end of the manifest.
+  pub offset: u64,
+  /// How many bytes the body takes in the file.
+  pub length: u64,
+  /// Whether the body is deflate compressed.
+  #[serde(default)]
+  pub compressed: bool,
+}
+
+/// Describes what a brew contains and what it needs from the machine it
+/// runs on.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BrewManifest {
+  /// The version of rew that built the brew.
+  #[serde(default)]
+  pub rew_version: String,
+  /// Modules to run once everything is defined, in order.
+  pub entries: Vec<String>,
+  /// Modules the brew expects to be installed, builtins or `package/entry`.
+  #[serde(default)]
+  pub externals: Vec<String>,
+  /// The modules of the brew, in the order they are defined.
+  pub modules: Vec<BrewModule>,
+}
+
+/// An ed25519 signature over the manifest bytes of a brew.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrewSignature {
+  /// Base64 public key of the signer.
+  pub key: String,
+  /// Base64 signature.
+  pub signature: String,
+}
+
+/// What checking the signature of a brew found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+  Unsigned,
+  /// Signed, and the signature matches the manifest. Holds the base64
+  /// public key of the signer.
+  Valid(String),
+  /// Signed, but the manifest was changed after signing or the signature is
+  /// malformed.
+  Invalid,
+}
+
+/// A decoded brew.
+#[derive(Debug, Clone)]
+pub struct Brew {
+  pub manifest: BrewManifest,
+  pub signature: Option<BrewSignature>,
+  /// The manifest as it was stored, which is what the signature covers.
+  manifest_bytes: Vec<u8>,
+  /// The body of every module in `manifest.modules`.
+  bodies: Vec<String>,
+  /// Built before the container format existed, the one body is the whole
+  /// script.
+  legacy: bool,
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Returns `true` if `bytes` start with the binary brew header.
+pub fn is_brew_container(bytes: &[u8]) -> bool {
+  bytes.starts_with(BREW_MAGIC)
+}
+
+impl Brew {
+  /// Creates a brew from module bodies, filling in their hashes.
+  ///
+  /// # Arguments
+  /// * `modules` - `(id, alias, body)` for every module, in definition order.
+  /// * `entries` - The modules to run after loading.
+  /// * `externals` - What the brew expects to be installed.
+  pub fn new(
+    modules: Vec<(String, Option<String>, String)>,
+    entries: Vec<String>,
+    externals: Vec<String>,
+  ) -> Self {
+    let mut manifest = BrewManifest {
+      rew_version: env!("CARGO_PKG_VERSION").to_string(),
+      entries,
+      externals,
+      modules: Vec::with_capacity(modules.len()),
+    };
+    let mut bodies = Vec::with_capacity(modules.len());
+
+    for (id, alias, body) in modules {
+      manifest.modules.push(BrewModule {
+        id,
+        alias,
+        sha256: sha256_hex(body.as_bytes()),
+        offset: 0,
+        length: 0,
+        compressed: false,
+      });
+      bodies.push(body);
+    }
+
+    Brew {
+      manifest,
+      signature: None,
+      manifest_bytes: Vec::new(),
+      bodies,
+      legacy: false,
+    }
+  }
+
+  /// Writes the brew in the binary container format.
+  ///
+  /// # Arguments
+  /// * `compress` - Deflate the module bodies that get smaller by it.
+  /// * `key` - Signs the manifest with this key when given.
+  ///
+  /// # Returns
+  /// * The bytes of the `.brew` file.
+  pub fn encode(&self, compress: bool, key: Option<&SigningKey>) -> Result<Vec<u8>> {
+    let mut manifest = self.manifest.clone();
+    let mut blob = Vec::new();
+
+    for (module, body) in manifest.modules.iter_mut().zip(&self.bodies) {
+      let mut stored = body.as_bytes().to_vec();
+      module.compressed = false;
+      if compress {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&stored)?;
+        let deflated = encoder.finish()?;
+        if deflated.len() < stored.len() {
+          stored = deflated;
+          module.compressed = true;
+        }
+      }
+      module.offset = blob.len() as u64;
+      module.length = stored.len() as u64;
+      blob.extend_from_slice(&stored);
+    }
+
+    let manifest = serde_json::to_vec(&manifest)?;
+    let signature = match key {
+      Some(key) => serde_json::to_vec(&BrewSignature {
+        key: BASE64.encode(key.verifying_key().as_bytes()),
+        signature: BASE64.encode(key.sign(&manifest).to_bytes()),
+      })?,
+      None => Vec::new(),
+    };
+
+    let mut out =
+      Vec::with_capacity(HEADER_LEN + manifest.len() + 4 + signature.len() + blob.len());
+    out.extend_from_slice(BREW_MAGIC);
+    out.extend_from_slice(&BREW_FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&(manifest.len() as u32).to_le_bytes());
+    out.extend_from_slice(&manifest);
+    out.extend_from_slice(&(signature.len() as u32).to_le_bytes());
+    out.extend_from_slice(&signature);
+    out.extend_from_slice(&blob);
+    Ok(out)
+  }
+
+  /// Reads a brew, either the binary container or the older base64 form.
+  ///
+  /// Every module body is checked against the hash in the manifest.
+  ///
+  /// # Arguments
+  /// * `bytes` - The contents of the `.brew` file.
+  pub fn decode(bytes: &[u8]) -> Result<Self> {
+    if !is_brew_container(bytes) {
+      let decoded = std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|text| BASE64.decode(text.trim()).ok())
+        .ok_or_else(|| anyhow::anyhow!("Brew file is neither binary nor base64"))?;
+      // Virtual files can only hold text, so brews registered there keep
+      // the container as base64.
+      if is_brew_container(&decoded) {
+        return Brew::decode(&decoded);
+      }
+      let script = String::from_utf8(decoded).context("Brew file is not valid UTF-8")?;
+      return Ok(Brew::decode_legacy(script));
+    }
+    if bytes.len() < HEADER_LEN {
+      anyhow::bail!("Brew file is truncated");
+    }
+
+    let mut at = BREW_MAGIC.len();
+    let version = u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    at += 2;
+    if version > BREW_FORMAT_VERSION {
+      anyhow::bail!(
+        "Brew format version {} is newer than this rew supports ({}), update rew to run it",
+        version,
+        BREW_FORMAT_VERSION
+      );
+    }
+    let manifest_len = u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+    at += 4;
+
+    let manifest_bytes = bytes
+      .get(at..at + manifest_len)
+      .ok_or_else(|| anyhow::anyhow!("Brew file is truncated"))?;
+    let manifest: BrewManifest =
+      serde_json::from_slice(manifest_bytes).context("Brew manifest is corrupted")?;
+    at += manifest_len;
+
+    let mut signature = None;
+    if version >= 2 {
+      let signature_len = bytes
+        .get(at..at + 4)
+        .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+        .ok_or_else(|| anyhow::anyhow!("Brew file is truncated"))?;
+      at += 4;
+      let signature_bytes = bytes
+        .get(at..at + signature_len)
+        .ok_or_else(|| anyhow::anyhow!("Brew file is truncated"))?;
+      if signature_len > 0 {
+        signature =
+          Some(serde_json::from_slice(signature_bytes).context("Brew signature is corrupted")?);
+      }
+      at += signature_len;
+    }
+    let blob = &bytes[at..];
+
+    let mut bodies = Vec::with_capacity(manifest.modules.len());
+    for module in &manifest.modules {
+      let start = module.offset as usize;
+      let stored = start
+        .checked_add(module.length as usize)
+        .and_then(|end| blob.get(start..end))
+        .ok_or_else(|| anyhow::anyhow!("Module {} lies outside the brew file", module.id))?;
+
+      let body = if module.compressed {
+        let mut body = Vec::new();
+        DeflateDecoder::new(stored)
+          .read_to_end(&mut body)
+          .with_context(|| format!("Failed to decompress module {}", module.id))?;
+        body
+      } else {
+        stored.to_vec()
+      };
+
+      if sha256_hex(&body) != module.sha256 {
+        anyhow::bail!(
+          "Module {} does not match its hash, the brew is corrupted",
+          module.id
+        );
+      }
+      bodies.push(
+        String::from_utf8(body)
+          .with_context(|| format!("Module {} is not valid UTF-8", module.id))?,
+      );
+    }
+
+    Ok(Brew {
+      manifest,
+      signature,
+      manifest_bytes: manifest_bytes.to_vec(),
+      bodies,
+      legacy: false,
+    })
+  }
+
+  /// Checks the signature of the brew against its manifest.
+  ///
+  /// The manifest holds the hash of every module, so a valid signature
+  /// covers the module bodies too.
+  pub fn verify_signature(&self) -> SignatureStatus {
+    let Some(signature) = &self.signature else {
+      return SignatureStatus::Unsigned;
+    };
+
+    let key = BASE64
+      .decode(&signature.key)
+      .ok()
+      .and_then(|key| <[u8; 32]>::try_from(key).ok())
+      .and_then(|key| VerifyingKey::from_bytes(&key).ok());
+    let sig = BASE64
+      .decode(&signature.signature)
+      .ok()
+      .and_then(|sig| Signature::from_slice(&sig).ok());
+
+    match (key, sig) {
+      (Some(key), Some(sig)) if key.verify(&self.manifest_bytes, &sig).is_ok() => {
+        SignatureStatus::Valid(signature.key.clone())
+      }
+      _ => SignatureStatus::Invalid,
+    }
+  }
+
+  /// Wraps the script of a base64 brew, recovering the manifest from its
+  /// `// entry` and `// external` comments.
+  fn decode_legacy(script: String) -> Self {
+    let captures = |re: &Regex| -> Vec<String> {
+      re.captures_iter(&script)
+        .map(|cap| cap[1].to_string())
+        .collect()
+    };
+    let manifest = BrewManifest {
+      rew_version: String::new(),
+      entries: captures(&LEGACY_ENTRY_RE),
+      externals: captures(&LEGACY_EXTERNAL_RE),
+      modules: vec![BrewModule {
+        id: "<legacy>".to_string(),
+        alias: None,
+        sha256: sha256_hex(script.as_bytes()),
+        offset: 0,
+        length: script.len() as u64,
+        compressed: false,
+      }],
+    };
+
+    Brew {
+      manifest,
+      signature: None,
+      manifest_bytes: Vec::new(),
+      bodies: vec![script],
+      legacy: true,
+    }
+  }
+
+  /// Assembles the script the runtime evaluates: the `// entry` and
+  /// `// external` header followed by every module body.
+  pub fn script(&self) -> String {
+    if self.legacy {
+      return self.bodies.concat();
+    }
+
+    let mut script = String::from("\n");
+    for entry in &self.manifest.entries {
+      script.push_str(&format!("// entry \"{}\" \n", entry));
+    }
+    for external in &self.manifest.externals {
+      script.push_str(&format!("// external \"{}\"\n", external));
+    }
+    script.push('\n');
+    for body in &self.bodies {
+      script.push_str(body);
+    }
+    script.push('\n');
+    script
+  }
+}
+
+/// Reads and decodes the brew at `path`, from the virtual files or disk.
+pub fn read_brew(path: &Path) -> Result<Brew> {
+  if let Some(contents) = get_virtual_file(path) {
+    return Brew::decode(contents.as_bytes());
+  }
+
+  let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+  Brew::decode(&bytes).with_context(|| format!("Failed to load brew {:?}", path))
+}
+
+/// The bytes a standalone executable ends with.
+pub const EXE_MAGIC: &[u8; 8] = b"REWEXE\0\0";
+
+/// The payload trailer version this build writes and understands.
+pub const EXE_PAYLOAD_VERSION: u16 = 1;
+
+/// Version, payload length, payload sha256 and magic.
+const EXE_TRAILER_LEN: usize = 2 + 8 + 32 + EXE_MAGIC.len();
+
+/// Appends a brew to a stub executable, followed by the trailer the stub
+/// finds it by.
+///
+/// # Arguments
+/// * `stub` - The executable that will run the brew.
+/// * `brew` - The encoded brew.
+///
+/// # Returns
+/// * The bytes of the standalone executable.
+pub fn append_exe_payload(stub: &[u8], brew: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(stub.len() + brew.len() + EXE_TRAILER_LEN);
+  out.extend_from_slice(stub);
+  out.extend_from_slice(brew);
+  out.extend_from_slice(&EXE_PAYLOAD_VERSION.to_le_bytes());
+  out.extend_from_slice(&(brew.len() as u64).to_le_bytes());
+  out.extend_from_slice(&Sha256::digest(brew));
+  out.extend_from_slice(EXE_MAGIC);
+  out
+}
+
+/// Writes a standalone executable and marks it as executable.
+pub fn write_executable(path: &Path, stub: &[u8], brew: &[u8]) -> Result<()> {
+  fs::write(path, append_exe_payload(stub, brew))
+    .with_context(|| format!("Failed to write {:?}", path))?;
+
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+  }
+
+  Ok(())
+}
+
+/// Whether `exe` ends with the payload magic.
+///
+/// Only the last bytes of the file are read, so this is cheap enough for
+/// every start. A file that can't be read carries no payload.
+pub fn has_exe_payload(exe: &Path) -> bool {
+  let mut magic = [0u8; EXE_MAGIC.len()];
+  fs::File::open(exe)
+    .and_then(|mut file| {
+      file.seek(SeekFrom::End(-(EXE_MAGIC.len() as i64)))?;
+      file.read_exact(&mut magic)
+    })
+    .is_ok_and(|()| &magic == EXE_MAGIC)
+}
+
+/// Reads the brew carried by an executable.
+///
+/// # Arguments
+/// * `exe` - The executable, usually `std::env::current_exe()`.
+///
+/// # Returns
+/// * `None` if the executable carries no brew, or an error if the payload
+///   is truncated or doesn't match its checksum.
+pub fn read_exe_payload(exe: &Path) -> Result<Option<Vec<u8>>> {
+  let mut file = fs::File::open(exe).with_context(|| format!("Failed to open {:?}", exe))?;
+  let file_len = file.metadata()?.len();
+  if file_len < EXE_TRAILER_LEN as u64 {
+    return Ok(None);
+  }
+
+  let mut trailer = [0u8; EXE_TRAILER_LEN];
+  file.seek(SeekFrom::End(-(EXE_TRAILER_LEN as i64)))?;
+  file.read_exact(&mut trailer)?;
+  if !trailer.ends_with(EXE_MAGIC) {
+    return Ok(None);
+  }
+
+  let version = u16::from_le_bytes([trailer[0], trailer[1]]);
+  if version > EXE_PAYLOAD_VERSION {
+    anyhow::bail!(
+      "The embedded brew uses payload version {}, this runtime supports up to {}",
+      version,
+      EXE_PAYLOAD_VERSION
+    );
+  }
+  let length = u64::from_le_bytes(trailer[2..10].try_into().unwrap());
+  let checksum = &trailer[10..42];
+
+  if length > file_len - EXE_TRAILER_LEN as u64 {
+    anyhow::bail!(
+      "The embedded brew is truncated: the trailer expects {} bytes, the file has {}",
+      length,
+      file_len - EXE_TRAILER_LEN as u64
+    );
+  }
+  let mut payload = vec![0u8; length as usize];
+  file.seek(SeekFrom::End(-((EXE_TRAILER_LEN as u64 + length) as i64)))?;
+  file.read_exact(&mut payload)?;
+
+  if Sha256::digest(&payload).as_slice() != checksum {
+    anyhow::bail!("The embedded brew does not match its checksum, the executable was modified");
+  }
+  Ok(Some(payload))
+}
+
+/// Runs the brew carried by a standalone executable.
+///
+/// The brew is registered as a virtual `.brew` file next to the executable,
+/// so it loads the same way as one on disk.
+///
+/// # Arguments
+/// * `exe` - The executable the payload came from.
+/// * `payload` - The brew, as `read_exe_payload` returned it.
+/// * `args` - The arguments the script sees.
+pub async fn run_exe_payload(exe: &Path, payload: &[u8], args: RuntimeArgs) -> Result<()> {
+  // Fail before starting a runtime when the brew itself is damaged.
+  Brew::decode(payload)?;
+
+  let path = exe.with_extension("brew");
+  add_virtual_file(&path.to_string_lossy(), &BASE64.encode(payload));
+
+  let mut runtime = RewRuntime::new(Some(args.0), None)?;
+  runtime.run_file(&path).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample(externals: &[&str]) -> Brew {
+    Brew::new(
+      vec![
+        ("lib".to_string(), None, "export x = 1\n".to_string()),
+        ("main".to_string(), None, "print x\n".to_string()),
+      ],
+      vec!["main".to_string()],
+      externals.iter().map(|external| external.to_string()).collect(),
+    )
+  }
+
+  #[test]
+  fn signed_brew_verifies_with_the_signer_key() {
+    let key = SigningKey::from_bytes(&[3; 32]);
+    let bytes = sample(&[]).encode(true, Some(&key)).unwrap();
+    let brew = Brew::decode(&bytes).unwrap();
+    let public = BASE64.encode(key.verifying_key().as_bytes());
+    assert_eq!(brew.verify_signature(), SignatureStatus::Valid(public));
+
+    let unsigned = Brew::decode(&sample(&[]).encode(true, None).unwrap()).unwrap();
+    assert_eq!(unsigned.verify_signature(), SignatureStatus::Unsigned);
+  }
+
+  #[test]
+  fn changed_manifest_breaks_the_signature() {
+    let key = SigningKey::from_bytes(&[3; 32]);
+    let mut bytes = sample(&["#std.fs"]).encode(false, Some(&key)).unwrap();
+    let at = bytes.windows(6).position(|w| w == b"std.fs").unwrap();
+    bytes[at..at + 6].copy_from_slice(b"std.os");
+
+    let brew = Brew::decode(&bytes).unwrap();
+    assert_eq!(brew.manifest.externals, ["#std.os"]);
+    assert_eq!(brew.verify_signature(), SignatureStatus::Invalid);
+  }
+
+  #[test]
+  fn signature_from_another_key_is_invalid() {
+    let key = SigningKey::from_bytes(&[3; 32]);
+    let other = SigningKey::from_bytes(&[4; 32]);
+    let bytes = sample(&[]).encode(false, Some(&key)).unwrap();
+    let mut brew = Brew::decode(&bytes).unwrap();
+    brew.signature.as_mut().unwrap().key = BASE64.encode(other.verifying_key().as_bytes());
+    assert_eq!(brew.verify_signature(), SignatureStatus::Invalid);
+  }
+
+  #[test]
+  fn changed_module_body_is_rejected() {
+    let key = SigningKey::from_bytes(&[3; 32]);
+    let mut bytes = sample(&[]).encode(false, Some(&key)).unwrap();
+    let at = bytes.windows(5).position(|w| w == b"print").unwrap();
+    bytes[at..at + 5].copy_from_slice(b"PRINT");
+    let err = Brew::decode(&bytes).unwrap_err();
+    assert!(err.to_string().contains("does not match its hash"));
+  }
+
+  #[test]
+  fn encoded_brew_decodes_to_the_same_modules() {
+    let mut brew = sample(&["#std.fs"]);
+    brew.bodies[0] = "x = 1\n".repeat(100);
+    brew.manifest.modules[0].sha256 = sha256_hex(brew.bodies[0].as_bytes());
+
+    for compress in [false, true] {
+      let decoded = Brew::decode(&brew.encode(compress, None).unwrap()).unwrap();
+      assert_eq!(decoded.manifest.entries, ["main"]);
+      assert_eq!(decoded.manifest.externals, ["#std.fs"]);
+      assert_eq!(decoded.bodies, brew.bodies);
+      assert_eq!(decoded.manifest.modules[0].compressed, compress);
+      // Deflating the short body would not make it smaller.
+      assert!(!decoded.manifest.modules[1].compressed);
+      assert_eq!(decoded.script(), brew.script());
+    }
+  }
+
+  #[test]
+  fn container_stored_as_base64_decodes() {
+    let bytes = sample(&[]).encode(true, None).unwrap();
+    let brew = Brew::decode(BASE64.encode(&bytes).as_bytes()).unwrap();
+    assert_eq!(brew.bodies, sample(&[]).bodies);
+  }
+
+  #[test]
+  fn version_1_container_without_signature_section_decodes() {
+    let mut bytes = sample(&[]).encode(false, None).unwrap();
+    let manifest_len = u32::from_le_bytes(bytes[10..14].try_into().unwrap()) as usize;
+    bytes[8..10].copy_from_slice(&1u16.to_le_bytes());
+    bytes.drain(HEADER_LEN + manifest_len..HEADER_LEN + manifest_len + 4);
+
+    let brew = Brew::decode(&bytes).unwrap();
+    assert_eq!(brew.bodies, sample(&[]).bodies);
+    assert_eq!(brew.verify_signature(), SignatureStatus::Unsigned);
+  }
+
+  #[test]
+  fn newer_or_truncated_containers_are_refused() {
+    let bytes = sample(&[]).encode(false, None).unwrap();
+
+    let mut newer = bytes.clone();
+    newer[8..10].copy_from_slice(&(BREW_FORMAT_VERSION + 1).to_le_bytes());
+    let err = Brew::decode(&newer).unwrap_err();
+    assert!(err.to_string().contains("update rew"));
+
+    assert!(Brew::decode(&bytes[..HEADER_LEN + 4]).is_err());
+    assert!(Brew::decode(&bytes[..bytes.len() - 1]).is_err());
+    assert!(Brew::decode(b"not a brew at all").is_err());
+  }
+
+  #[test]
+  fn legacy_base64_script_keeps_its_entries_and_externals() {
+    let script = "\n// entry \"main\" \n// external \"#std.fs\"\n\nprint 1\n";
+    let brew = Brew::decode(BASE64.encode(script).as_bytes()).unwrap();
+    assert_eq!(brew.manifest.entries, ["main"]);
+    assert_eq!(brew.manifest.externals, ["#std.fs"]);
+    assert_eq!(brew.script(), script);
+    assert_eq!(brew.verify_signature(), SignatureStatus::Unsigned);
+  }
+
+  #[test]
+  fn executable_payload_is_found_by_its_trailer() {
+    let dir = tempfile::tempdir().unwrap();
+    let brew = sample(&[]).encode(true, None).unwrap();
+    let exe = dir.path().join("app");
+    write_executable(&exe, b"stub binary", &brew).unwrap();
+    assert!(has_exe_payload(&exe));
+    assert_eq!(read_exe_payload(&exe).unwrap(), Some(brew));
+
+    let plain = dir.path().join("rew");
+    fs::write(&plain, b"just the runtime").unwrap();
+    assert!(!has_exe_payload(&plain));
+    assert_eq!(read_exe_payload(&plain).unwrap(), None);
+
+    let tiny = dir.path().join("tiny");
+    fs::write(&tiny, b"x").unwrap();
+    assert!(!has_exe_payload(&tiny));
+    assert!(!has_exe_payload(&dir.path().join("missing")));
+  }
+}
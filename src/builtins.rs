@@ -15,6 +15,7 @@ const HTTP_MODULE: &str = include_str!("../lib/rew/builtins/http.js");
 const NET_MODULE: &str = include_str!("../lib/rew/builtins/net.js");
 const TYPES_MODULE: &str = include_str!("../lib/rew/builtins/types.js");
 const YAML_MODULE: &str = include_str!("../lib/rew/builtins/yaml.js");
+const TESTING_MODULE: &str = include_str!("../lib/rew/builtins/testing.js");
 
 pub static BUILTIN_MODULES: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
   let mut m = HashMap::new();
@@ -31,7 +32,146 @@ pub static BUILTIN_MODULES: Lazy<HashMap<&'static str, &'static str>> = Lazy::ne
   m.insert("#std.net", NET_MODULE);
   m.insert("#std.types", TYPES_MODULE);
   m.insert("#std.yaml", YAML_MODULE);
+  m.insert("#std.testing", TESTING_MODULE);
   m.insert("#std", ALL_MODULE);
 
   m
 });
+
+#[cfg(test)]
+mod tests {
+  use crate::runtime::RewRuntime;
+  use anyhow::Result;
+
+  /// Lets a test script throw when something isn't as expected, which fails
+  /// the run.
+  const CHECK: &str = r##"import "#std.fs"
+using namespace rew::ns
+
+check = (ok, message) ->
+  if not ok then throw new Error message
+
+contents = (path) -> rew::fs::read path
+
+rejects = (promise) ->
+  try
+    await promise
+    false
+  catch
+    true
+
+"##;
+
+  /// Runs `script` as a Rew file in a directory of its own.
+  fn run_script(script: &str) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("main.coffee");
+    std::fs::write(&path, format!("{CHECK}{script}"))?;
+    tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()?
+      .block_on(async { RewRuntime::new(None, None)?.run_file(&path).await })
+  }
+
+  #[test]
+  fn file_handles_read_write_seek_and_truncate() {
+    run_script(
+      r##"
+main = ->
+  file = await rew::fs::open "./data.txt", "w+"
+  written = await file.write "hello world"
+  check written == 11, "wrote #{written} bytes, not 11"
+
+  await file.seek 0
+  buf = new Uint8Array 5
+  read = await file.read buf
+  text = new TextDecoder().decode buf.subarray(0, read)
+  check text == "hello", "read #{JSON.stringify text}"
+
+  position = await file.seek(-5, "end")
+  check position == 6, "seeked to #{position}, not 6"
+  await file.write "there"
+
+  await file.truncate 8
+  size = (await file.stat()).size
+  check size == 8, "the file has #{size} bytes, not 8"
+  await file.close()
+  check contents("./data.txt") == "hello th", "the file reads #{contents "./data.txt"}"
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn file_handles_read_lines() {
+    run_script(
+      r##"
+main = ->
+  await rew::fs::write "./lines.txt", "one\r\ntwo\n\nthree"
+  file = await rew::fs::open "./lines.txt"
+  lines = []
+  iterator = file.lines()
+  while not (step = await iterator.next()).done
+    lines.push step.value
+  await file.close()
+  check lines.join("|") == "one|two||three", "read #{JSON.stringify lines}"
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn open_modes_follow_the_mode_table() {
+    run_script(
+      r##"
+main = ->
+  check (await rejects rew::fs::open "./missing.txt", "r"), "r opened a missing file"
+
+  await rew::fs::write "./data.txt", "abc"
+  file = await rew::fs::open "./data.txt", "r+"
+  await file.write "x"
+  await file.close()
+  check contents("./data.txt") == "xbc", "r+ left #{contents "./data.txt"}"
+
+  file = await rew::fs::open "./data.txt", "a"
+  await file.write "d"
+  await file.close()
+  check contents("./data.txt") == "xbcd", "a left #{contents "./data.txt"}"
+
+  file = await rew::fs::open "./data.txt", "w"
+  await file.write "w"
+  await file.close()
+  check contents("./data.txt") == "w", "w left #{contents "./data.txt"}"
+
+  check (await rejects rew::fs::open "./data.txt", "x"), "x opened an existing file"
+  file = await rew::fs::open "./new.txt", "x+"
+  await file.close()
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn unknown_open_modes_are_rejected() {
+    run_script(
+      r##"
+main = ->
+  try
+    await rew::fs::open "./data.txt", "rw"
+  catch e
+    error = e
+  check error instanceof TypeError, "opening with mode rw did not fail"
+  check error.message == 'Unknown open mode "rw"', "failed with #{error.message}"
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+}
@@ -3,6 +3,41 @@ use regex::Regex;
 use std::collections::HashMap;
 
 use crate::declarations::Declaration;
+use crate::diagnostics::{RewDiagnostic, Span, did_you_mean};
+
+/// Options `using compiler` accepts, as understood by Civet plus Rew's `JSX`.
+const COMPILER_OPTIONS: &[&str] = &[
+  "autoConst",
+  "autoVar",
+  "autoLet",
+  "coffeeBinaryExistential",
+  "coffeeBooleans",
+  "coffeeClasses",
+  "coffeeComment",
+  "coffeeCompat",
+  "coffeeDo",
+  "coffeeEq",
+  "coffeeForLoops",
+  "coffeeInterpolation",
+  "coffeeIsnt",
+  "coffeeJSX",
+  "coffeeLineContinuation",
+  "coffeeNot",
+  "coffeeOf",
+  "coffeePrototype",
+  "defaultElement",
+  "deno",
+  "implicitReturns",
+  "objectIs",
+  "react",
+  "solid",
+  "client",
+  "rewriteTsImports",
+  "server",
+  "tab",
+  "verbose",
+  "JSX",
+];
 
 #[derive(Debug, Clone)]
 pub struct Token {
@@ -27,12 +62,47 @@ pub struct CompilerOptions {
 
   pub local_declarations: HashMap<String, Declaration>,
   pub global_declarations: HashMap<String, Declaration>,
+
+  /// Problems with Rew directives found while compiling.
+  pub diagnostics: Vec<RewDiagnostic>,
 }
 
 pub struct CompilerResults {
   #[allow(unused)]
   pub options: CompilerOptions,
   pub code: String,
+  /// The 0-based source line each line of `code` came from.
+  pub line_map: Vec<usize>,
+}
+
+/// Keeps track of which source line every output line of `compile_rew_stuff`
+/// starts on, so source maps can point past the rewrites it does.
+#[derive(Default)]
+struct LineTracker {
+  map: Vec<usize>,
+  out_line: usize,
+  line_start: usize,
+  scanned: usize,
+}
+
+impl LineTracker {
+  /// Records that whatever is written next to `output` comes from `src_line`.
+  fn record(&mut self, output: &str, src_line: usize) {
+    for (idx, _) in output[self.scanned..].match_indices('\n') {
+      self.out_line += 1;
+      self.line_start = self.scanned + idx + 1;
+    }
+    self.scanned = output.len();
+
+    if self.map.len() <= self.out_line {
+      for line in self.map.len()..=self.out_line {
+        self.map.push(src_line.saturating_sub(self.out_line - line));
+      }
+    } else if output[self.line_start..].trim().is_empty() {
+      // Nothing but indentation so far, e.g. after a dropped shebang.
+      self.map[self.out_line] = src_line;
+    }
+  }
 }
 
 // i hate this function
@@ -54,13 +124,16 @@ pub fn tokenize_coffee_script(code: &str) -> Vec<Token> {
     let next_next_char = chars.get(i + 2).copied();
 
     if char == '#' {
-      let comment_end = code[i..].find('\n').unwrap_or(code.len() - i);
-      let comment = &code[i..i + comment_end + 1];
+      let comment_end = chars[i..]
+        .iter()
+        .position(|c| *c == '\n')
+        .map_or(chars.len(), |idx| i + idx + 1);
+      let comment: String = chars[i..comment_end].iter().collect();
       tokens.push(Token {
         token_type: "COMMENT".to_string(),
-        value: comment.to_string(),
+        value: comment,
       });
-      i += comment.len() - 1;
+      i = comment_end - 1;
     } else if char == '"' && next_char == Some('"') && next_next_char == Some('"') {
       let mut string = "\"\"\"".to_string();
       i += 3;
@@ -207,6 +280,49 @@ fn find_next_token(
   None
 }
 
+/// Checks the `ONLYIF` conditions of a declaration whose trigger is at `index`.
+///
+/// # Arguments
+/// * `decl` - The declaration to check.
+/// * `index` - The index of the trigger token.
+/// * `tokens` - A slice of all tokens in the code.
+///
+/// # Returns
+/// * `true` if the tokens around the trigger satisfy the conditions.
+pub fn declaration_conditions_met(decl: &Declaration, index: usize, tokens: &[Token]) -> bool {
+  let mut conditions_met = true;
+
+  if let Some(prev_condition) = &decl.condition_prev {
+    let mut prev_idx = index;
+    while prev_idx > 0 {
+      prev_idx -= 1;
+      if tokens[prev_idx].token_type != "WHITESPACE" {
+        break;
+      }
+    }
+
+    if prev_idx < index {
+      if tokens[prev_idx].value != *prev_condition {
+        conditions_met = false;
+      }
+    } else {
+      conditions_met = false;
+    }
+  }
+
+  if let Some(next_condition) = &decl.condition_next {
+    if let Some((next_token, _, _)) = get_next_token(index, 1, tokens) {
+      if next_token.value != *next_condition {
+        conditions_met = false;
+      }
+    } else {
+      conditions_met = false;
+    }
+  }
+
+  conditions_met
+}
+
 /// Applies declarations to transform tokens based on predefined rules.
 /// 
 /// # Arguments
@@ -239,37 +355,7 @@ fn apply_declarations(
       // println!("==> Token value: {}, needed: {}", token.value, decl.trigger.clone());
 
       if token.value == trigger {
-        let mut conditions_met = true;
-
-        if let Some(prev_condition) = &decl.condition_prev {
-          let mut prev_idx = index;
-          while prev_idx > 0 {
-            prev_idx -= 1;
-            if tokens[prev_idx].token_type != "WHITESPACE" {
-              break;
-            }
-          }
-
-          // println!("==> Prev value: {}", tokens[prev_idx].value);
-
-          if prev_idx < index {
-            if tokens[prev_idx].value != *prev_condition {
-              conditions_met = false;
-            }
-          } else {
-            conditions_met = false;
-          }
-        }
-
-        if let Some(next_condition) = &decl.condition_next {
-          if let Some((next_token, _, _)) = get_next_token(index, 1, tokens) {
-            if next_token.value != *next_condition {
-              conditions_met = false;
-            }
-          } else {
-            conditions_met = false;
-          }
-        }
+        let conditions_met = declaration_conditions_met(decl, index, tokens);
 
         if conditions_met {
           if is_declaration {
@@ -516,6 +602,56 @@ fn handle_import(tokens: &[Token], i: usize) -> (String, usize) {
   (result, current_idx)
 }
 
+/// The 1-based line and column `tokens[idx]` starts at.
+pub fn token_position(tokens: &[Token], idx: usize) -> (usize, usize) {
+  let mut line = 1;
+  let mut column = 1;
+  for token in &tokens[..idx.min(tokens.len())] {
+    for c in token.value.chars() {
+      if c == '\n' {
+        line += 1;
+        column = 1;
+      } else {
+        column += 1;
+      }
+    }
+  }
+  (line, column)
+}
+
+/// The 1-based line and column of every token.
+pub fn token_positions(tokens: &[Token]) -> Vec<(usize, usize)> {
+  let mut positions = Vec::with_capacity(tokens.len());
+  let (mut line, mut column) = (1, 1);
+  for token in tokens {
+    positions.push((line, column));
+    for c in token.value.chars() {
+      if c == '\n' {
+        line += 1;
+        column = 1;
+      } else {
+        column += 1;
+      }
+    }
+  }
+  positions
+}
+
+/// A warning for a `using compiler` option Civet does not know, or `None`
+/// when the option is fine.
+pub fn check_compiler_option(name: &str, line: usize, column: usize) -> Option<RewDiagnostic> {
+  if COMPILER_OPTIONS.contains(&name) {
+    return None;
+  }
+
+  let mut diagnostic = RewDiagnostic::warning(format!("Unknown compiler option `{}`", name))
+    .with_span(Span::new(line, column, name.chars().count()));
+  if let Some(suggestion) = did_you_mean(name, COMPILER_OPTIONS) {
+    diagnostic = diagnostic.with_hint(format!("did you mean `{}`?", suggestion));
+  }
+  Some(diagnostic)
+}
+
 fn handle_compiler_options(
   tokens: &[Token],
   options: &mut CompilerOptions,
@@ -526,6 +662,10 @@ fn handle_compiler_options(
 
   if let Some((name_token, idx)) = find_next_token(current_idx, tokens, "IDENTIFIER", None, None) {
     let mut name = name_token.value.clone();
+    let (line, column) = token_position(tokens, idx);
+    if let Some(diagnostic) = check_compiler_option(&name, line, column) {
+      options.diagnostics.push(diagnostic);
+    }
     current_idx = idx + 1;
     if let Some((_dot, _, idx)) = get_next_token(idx, 1, tokens) {
       if _dot.value == "." {
@@ -565,7 +705,17 @@ pub fn compile_rew_stuff(content: &str, options: &mut CompilerOptions) -> Result
   let local_declarations = options.local_declarations.clone();
   let global_declarations = options.global_declarations.clone();
 
+  let mut token_lines = Vec::with_capacity(tokens.len() + 1);
+  let mut line = 0;
+  for token in &tokens {
+    token_lines.push(line);
+    line += token.value.matches('\n').count();
+  }
+  token_lines.push(line);
+  let mut lines = LineTracker::default();
+
   while i < tokens.len() {
+    lines.record(&result, token_lines[i]);
     let token = &tokens[i];
     let next_token = get_next_token(i, 1, &tokens);
     let prev_token = if i > 1 {
@@ -866,6 +1016,8 @@ pub fn compile_rew_stuff(content: &str, options: &mut CompilerOptions) -> Result
     i += 1;
   }
 
+  lines.record(&result, token_lines[tokens.len()]);
+
   if options.included {
     options.local_declarations = local_declarations;
   }
@@ -882,9 +1034,11 @@ pub fn compile_rew_stuff(content: &str, options: &mut CompilerOptions) -> Result
         included: false,
         local_declarations: HashMap::new(),
         global_declarations: HashMap::new(),
+        diagnostics: vec![],
       },
     ),
     code: result,
+    line_map: lines.map,
   };
 
   // println!("{}", compiler_results.code);
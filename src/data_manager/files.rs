@@ -0,0 +1,448 @@
+use super::{INTERNAL_PREFIX, ScanOptions, StorageBackend};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const LOCK_FILE: &str = ".rew-lock";
+const JOURNAL_FILE: &str = ".rew-journal";
+const TEMP_PREFIX: &str = ".rew-tmp-";
+const INDEX_FILE: &str = ".rew-keys";
+
+/// Keeps every key as a file under the data directory.
+///
+/// Writes go through a temporary file and a rename, and take an advisory
+/// lock so processes of the same app don't interleave.
+pub struct FilesBackend {
+  data_dir: PathBuf,
+}
+
+impl FilesBackend {
+  pub fn new(data_dir: PathBuf) -> Result<Self> {
+    let backend = Self { data_dir };
+    // A transaction that was cut short is finished before anything is read.
+    if backend.data_dir.join(JOURNAL_FILE).exists() {
+      drop(backend.lock()?);
+    }
+    if !backend.data_dir.join(INDEX_FILE).exists() {
+      let _lock = backend.lock()?;
+      if !backend.data_dir.join(INDEX_FILE).exists() {
+        // Files that are already there may have been put there by the app
+        // itself, so only an index started in an empty directory is complete.
+        let index = KeyIndex {
+          complete: backend.keys("")?.is_empty(),
+          keys: BTreeSet::new(),
+        };
+        backend.write_index(&index)?;
+      }
+    }
+    Ok(backend)
+  }
+
+  fn get_path(&self, key: &str) -> PathBuf {
+    self.data_dir.join(key)
+  }
+
+  /// The keys written through this backend, as opposed to files the app put
+  /// into the data directory some other way.
+  pub fn index(&self) -> Result<KeyIndex> {
+    let path = self.data_dir.join(INDEX_FILE);
+    match fs::read(&path) {
+      Ok(data) => serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse key index: {:?}", path)),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(KeyIndex::default()),
+      Err(e) => Err(e).with_context(|| format!("Failed to read key index: {:?}", path)),
+    }
+  }
+
+  fn write_index(&self, index: &KeyIndex) -> Result<()> {
+    let temp = self.data_dir.join(self.write_temp(&serde_json::to_vec(index)?)?);
+    self.move_into_place(&temp, &self.data_dir.join(INDEX_FILE))
+  }
+
+  /// Adds the keys a change wrote to the index and drops the ones it deleted.
+  /// Only called with the lock held.
+  fn record<'a>(&self, changes: impl IntoIterator<Item = (&'a str, bool)>) -> Result<()> {
+    let mut index = self.index()?;
+    for (key, written) in changes {
+      if written {
+        index.keys.insert(key.to_string());
+      } else {
+        index.keys.remove(key);
+      }
+    }
+    self.write_index(&index)
+  }
+
+  /// Takes the advisory lock on the data directory, waiting for other
+  /// processes that hold it.
+  ///
+  /// Taking it again from the same process while it is held blocks forever.
+  ///
+  /// # Returns
+  /// * A guard that releases the lock when dropped.
+  pub fn lock(&self) -> Result<DataLock> {
+    let path = self.data_dir.join(LOCK_FILE);
+    let file = fs::OpenOptions::new()
+      .create(true)
+      .truncate(false)
+      .write(true)
+      .open(&path)
+      .with_context(|| format!("Failed to open lock file: {:?}", path))?;
+    file
+      .lock()
+      .with_context(|| format!("Failed to lock data directory: {:?}", self.data_dir))?;
+
+    let lock = DataLock { file };
+    self.recover()?;
+    Ok(lock)
+  }
+
+  /// Applies a journal. Renames whose temporary file is gone were already done.
+  fn replay(&self, journal: &Journal) -> Result<()> {
+    for (temp, key) in &journal.renames {
+      let temp = self.data_dir.join(temp);
+      if temp.exists() {
+        self.move_into_place(&temp, &self.get_path(key))?;
+      }
+    }
+
+    for key in &journal.deletes {
+      let path = self.get_path(key);
+      if path.exists() {
+        fs::remove_file(&path)
+          .with_context(|| format!("Failed to delete data file: {:?}", path))?;
+      }
+    }
+
+    let written = journal.renames.iter().map(|(_, key)| (key.as_str(), true));
+    let deleted = journal.deletes.iter().map(|key| (key.as_str(), false));
+    self.record(written.chain(deleted))
+  }
+
+  /// Finishes a transaction a crash interrupted and removes temporary files
+  /// left by writes that never completed. Only called with the lock held.
+  fn recover(&self) -> Result<()> {
+    let journal_path = self.data_dir.join(JOURNAL_FILE);
+    if journal_path.exists() {
+      let journal: Journal = serde_json::from_slice(&fs::read(&journal_path)?)
+        .with_context(|| format!("Failed to parse journal: {:?}", journal_path))?;
+      self.replay(&journal)?;
+      fs::remove_file(&journal_path)?;
+    }
+
+    for entry in fs::read_dir(&self.data_dir)? {
+      let entry = entry?;
+      if entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+        fs::remove_file(entry.path())?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Writes `data` to a new temporary file in the data directory and flushes
+  /// it to disk.
+  ///
+  /// # Returns
+  /// * The name of the temporary file.
+  fn write_temp(&self, data: &[u8]) -> Result<String> {
+    let name = format!("{}{}", TEMP_PREFIX, uuid::Uuid::new_v4());
+    let path = self.data_dir.join(&name);
+
+    let mut file = fs::File::create(&path)
+      .with_context(|| format!("Failed to create temporary file: {:?}", path))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(name)
+  }
+
+  fn move_into_place(&self, temp: &Path, path: &Path) -> Result<()> {
+    let parent = path.parent().unwrap_or(&self.data_dir);
+    fs::create_dir_all(parent)
+      .with_context(|| format!("Failed to create parent directory: {:?}", parent))?;
+    fs::rename(temp, path).with_context(|| format!("Failed to write data file: {:?}", path))?;
+    sync_dir(parent)?;
+    Ok(())
+  }
+
+  /// Replaces the file of `key` with `data` so readers see either the old or
+  /// the new contents, never a mix, even if rew dies halfway. Only called
+  /// with the lock held.
+  fn write_locked(&self, key: &str, data: &[u8]) -> Result<()> {
+    let temp = self.data_dir.join(self.write_temp(data)?);
+    self.move_into_place(&temp, &self.get_path(key))?;
+    self.record([(key, true)])
+  }
+
+  /// Deletes the file of `key`. Only called with the lock held.
+  pub(super) fn delete_locked(&self, key: &str) -> Result<()> {
+    let path = self.get_path(key);
+    if path.exists() {
+      fs::remove_file(&path).with_context(|| format!("Failed to delete data file: {:?}", path))?;
+    }
+    self.record([(key, false)])
+  }
+
+  fn list_recursive(&self, dir: &Path, result: &mut Vec<String>) -> Result<()> {
+    if !dir.exists() {
+      return Ok(());
+    }
+
+    for entry in
+      fs::read_dir(dir).with_context(|| format!("Failed to read directory: {:?}", dir))?
+    {
+      let entry = entry?;
+      let path = entry.path();
+
+      if entry
+        .file_name()
+        .to_string_lossy()
+        .starts_with(INTERNAL_PREFIX)
+      {
+        continue;
+      }
+
+      if path.is_file() {
+        let rel_path = path
+          .strip_prefix(&self.data_dir)
+          .unwrap_or(&path)
+          .to_string_lossy()
+          .to_string();
+        result.push(rel_path);
+      } else if path.is_dir() {
+        self.list_recursive(&path, result)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl StorageBackend for FilesBackend {
+  fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    let path = self.get_path(key);
+    match fs::read(&path) {
+      Ok(data) => Ok(Some(data)),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e).with_context(|| format!("Failed to read data file: {:?}", path)),
+    }
+  }
+
+  fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+    let _lock = self.lock()?;
+    self.write_locked(key, value)
+  }
+
+  fn delete(&self, key: &str) -> Result<()> {
+    let _lock = self.lock()?;
+    self.delete_locked(key)
+  }
+
+  fn exists(&self, key: &str) -> Result<bool> {
+    Ok(self.get_path(key).is_file())
+  }
+
+  fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+    let mut result = Vec::new();
+    self.list_recursive(&self.data_dir.join(prefix), &mut result)?;
+    Ok(result)
+  }
+
+  fn scan(&self, options: &ScanOptions) -> Result<Vec<(String, Vec<u8>)>> {
+    // The prefix may end in the middle of a file name, so its directory is
+    // walked and the keys filtered.
+    let dir = match options.prefix.rfind('/') {
+      Some(index) => &options.prefix[..index],
+      None => "",
+    };
+    let mut keys: Vec<String> = self
+      .keys(dir)?
+      .into_iter()
+      .filter(|key| options.contains(key))
+      .collect();
+    keys.sort();
+    if options.reverse {
+      keys.reverse();
+    }
+
+    let mut result = Vec::new();
+    for key in keys.into_iter().take(options.limit.unwrap_or(usize::MAX)) {
+      if let Some(value) = self.get(&key)? {
+        result.push((key, value));
+      }
+    }
+    Ok(result)
+  }
+
+  /// Writes the changes to temporary files, records them in a journal and
+  /// then moves them into place. A crash before the journal is in place
+  /// loses the transaction; one after it is finished by `recover`.
+  fn commit(&self, changes: BTreeMap<String, Option<Vec<u8>>>) -> Result<()> {
+    let _lock = self.lock()?;
+
+    let mut journal = Journal::default();
+    for (key, change) in changes {
+      match change {
+        Some(data) => {
+          let temp = self.write_temp(&data)?;
+          journal.renames.push((temp, key));
+        }
+        None => journal.deletes.push(key),
+      }
+    }
+
+    let journal_path = self.data_dir.join(JOURNAL_FILE);
+    let journal_temp = self.write_temp(&serde_json::to_vec(&journal)?)?;
+    fs::rename(self.data_dir.join(journal_temp), &journal_path)
+      .with_context(|| format!("Failed to write journal: {:?}", journal_path))?;
+    sync_dir(&self.data_dir)?;
+
+    self.replay(&journal)?;
+    fs::remove_file(&journal_path)
+      .with_context(|| format!("Failed to remove journal: {:?}", journal_path))
+  }
+
+  fn update(
+    &self,
+    key: &str,
+    f: &mut dyn FnMut(Option<Vec<u8>>) -> Result<Option<Vec<u8>>>,
+  ) -> Result<()> {
+    let _lock = self.lock()?;
+    match f(self.get(key)?)? {
+      Some(value) => self.write_locked(key, &value),
+      None => self.delete_locked(key),
+    }
+  }
+}
+
+/// An advisory lock on an app's data directory, from `FilesBackend::lock`.
+pub struct DataLock {
+  file: fs::File,
+}
+
+impl Drop for DataLock {
+  fn drop(&mut self) {
+    let _ = self.file.unlock();
+  }
+}
+
+/// The keys a `FilesBackend` wrote, kept next to them.
+#[derive(Serialize, Deserialize, Default)]
+pub struct KeyIndex {
+  /// Whether every key in the directory is listed. Not the case for data a
+  /// rew without the index wrote, which may be mixed with the app's own files.
+  pub complete: bool,
+  pub keys: BTreeSet<String>,
+}
+
+/// What a transaction still has to do once its files are written: move each
+/// temporary file onto its key, then delete keys.
+#[derive(Serialize, Deserialize, Default)]
+struct Journal {
+  renames: Vec<(String, String)>,
+  deletes: Vec<String>,
+}
+
+/// Flushes a directory so renames into it survive a crash.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+  fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn temp_files(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+      .filter(|name| name.starts_with(TEMP_PREFIX))
+      .collect()
+  }
+
+  #[test]
+  fn writes_replace_the_whole_file_and_leave_no_temporary_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = FilesBackend::new(dir.path().to_path_buf()).unwrap();
+    backend.set("notes/today", b"a long first version").unwrap();
+    backend.set("notes/today", b"short").unwrap();
+
+    assert_eq!(backend.get("notes/today").unwrap(), Some(b"short".to_vec()));
+    assert!(temp_files(dir.path()).is_empty());
+    assert!(backend.index().unwrap().keys.contains("notes/today"));
+  }
+
+  #[test]
+  fn write_cut_short_before_the_rename_keeps_the_old_value() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = FilesBackend::new(dir.path().to_path_buf()).unwrap();
+    backend.set("count", b"1").unwrap();
+    // A write that died between writing its temporary file and the rename.
+    backend.write_temp(b"2").unwrap();
+
+    drop(backend.lock().unwrap());
+    assert_eq!(backend.get("count").unwrap(), Some(b"1".to_vec()));
+    assert!(temp_files(dir.path()).is_empty());
+  }
+
+  #[test]
+  fn interrupted_commit_is_finished_on_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = FilesBackend::new(dir.path().to_path_buf()).unwrap();
+    backend.set("a", b"old a").unwrap();
+    backend.set("gone", b"old").unwrap();
+
+    // The journal is in place and the first rename already happened when
+    // the process died.
+    let done = backend.write_temp(b"new a").unwrap();
+    let pending = backend.write_temp(b"new b").unwrap();
+    backend
+      .move_into_place(&dir.path().join(&done), &dir.path().join("a"))
+      .unwrap();
+    let journal = Journal {
+      renames: vec![(done, "a".to_string()), (pending, "b/c".to_string())],
+      deletes: vec!["gone".to_string()],
+    };
+    fs::write(
+      dir.path().join(JOURNAL_FILE),
+      serde_json::to_vec(&journal).unwrap(),
+    )
+    .unwrap();
+    drop(backend);
+
+    let backend = FilesBackend::new(dir.path().to_path_buf()).unwrap();
+    assert_eq!(backend.get("a").unwrap(), Some(b"new a".to_vec()));
+    assert_eq!(backend.get("b/c").unwrap(), Some(b"new b".to_vec()));
+    assert_eq!(backend.get("gone").unwrap(), None);
+    assert!(!dir.path().join(JOURNAL_FILE).exists());
+    assert!(temp_files(dir.path()).is_empty());
+
+    let keys = backend.index().unwrap().keys;
+    assert!(keys.contains("a") && keys.contains("b/c") && !keys.contains("gone"));
+  }
+
+  #[test]
+  fn commit_applies_writes_and_deletes_together() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = FilesBackend::new(dir.path().to_path_buf()).unwrap();
+    backend.set("old", b"x").unwrap();
+
+    let mut changes = BTreeMap::new();
+    changes.insert("new".to_string(), Some(b"y".to_vec()));
+    changes.insert("old".to_string(), None);
+    backend.commit(changes).unwrap();
+
+    assert_eq!(backend.keys("").unwrap(), ["new"]);
+    assert!(!dir.path().join(JOURNAL_FILE).exists());
+    assert!(temp_files(dir.path()).is_empty());
+  }
+}
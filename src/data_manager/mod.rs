@@ -0,0 +1,709 @@
+use crate::ext::web::normalize_path;
+use crate::utils;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod files;
+mod sqlite;
+
+pub use files::{DataLock, FilesBackend, KeyIndex};
+pub use sqlite::SqliteBackend;
+
+/// Names starting with this are kept by the data manager itself and aren't
+/// listed as keys.
+const INTERNAL_PREFIX: &str = ".rew-";
+/// The single file the default backend keeps an app's data in.
+const STORE_FILE: &str = ".rew-store.sqlite";
+/// Where `conf.kv` entries live among the other keys.
+const KV_PREFIX: &str = ".rew-kv/";
+/// The store meta key set once loose files were moved into the store.
+const MIGRATED: &str = "migrated";
+
+/// The `data` section of `app.yaml`.
+///
+/// ```yaml
+/// data:
+///   backend: files
+/// ```
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct DataConfig {
+  /// Picked by `BackendKind::for_data_dir` when not set.
+  #[serde(default)]
+  pub backend: Option<BackendKind>,
+}
+
+/// Where an app's `conf` keys are kept, inside its data directory.
+///
+/// Switching from `files` to `sqlite` moves the keys into the store once;
+/// switching back doesn't move them again.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+  /// All keys in one SQLite file, `.rew-store.sqlite`.
+  Sqlite,
+  /// A file per key, the way rew used to keep them.
+  Files,
+}
+
+impl BackendKind {
+  /// The backend for a data directory whose app doesn't pick one.
+  ///
+  /// New data goes into SQLite. Files a rew without the key index wrote
+  /// can't be told apart from files the app put there through `conf.path()`,
+  /// so that data stays with the files backend until the app asks for
+  /// `sqlite`.
+  pub fn for_data_dir(data_dir: &Path) -> Result<Self> {
+    if data_dir.join(STORE_FILE).exists() {
+      return Ok(BackendKind::Sqlite);
+    }
+    let index = FilesBackend::new(data_dir.to_path_buf())?.index()?;
+    Ok(if index.complete {
+      BackendKind::Sqlite
+    } else {
+      BackendKind::Files
+    })
+  }
+}
+
+/// Where a `DataManager` keeps its keys.
+///
+/// Values are plain bytes; formats, TTLs and counters are handled by the
+/// `DataManager` on top.
+pub trait StorageBackend {
+  fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+  fn set(&self, key: &str, value: &[u8]) -> Result<()>;
+
+  fn delete(&self, key: &str) -> Result<()>;
+
+  fn exists(&self, key: &str) -> Result<bool> {
+    Ok(self.get(key)?.is_some())
+  }
+
+  /// The keys under `prefix`, including the internal ones.
+  fn keys(&self, prefix: &str) -> Result<Vec<String>>;
+
+  /// The entries `options` selects, in key order.
+  fn scan(&self, options: &ScanOptions) -> Result<Vec<(String, Vec<u8>)>>;
+
+  /// Applies every change or none of them. `None` deletes the key.
+  fn commit(&self, changes: BTreeMap<String, Option<Vec<u8>>>) -> Result<()>;
+
+  /// Replaces the value of `key` with what `f` makes of it, with other
+  /// writers shut out in between. `f` returning `None` deletes the key.
+  fn update(
+    &self,
+    key: &str,
+    f: &mut dyn FnMut(Option<Vec<u8>>) -> Result<Option<Vec<u8>>>,
+  ) -> Result<()>;
+}
+
+/// Which keys a scan returns.
+#[derive(Deserialize, Default, Debug, Clone)]
+pub struct ScanOptions {
+  #[serde(default)]
+  pub prefix: String,
+  /// The first key to include.
+  pub start: Option<String>,
+  /// The first key after the ones to include.
+  pub end: Option<String>,
+  pub limit: Option<usize>,
+  #[serde(default)]
+  pub reverse: bool,
+}
+
+impl ScanOptions {
+  pub fn contains(&self, key: &str) -> bool {
+    key.starts_with(&self.prefix)
+      && self.start.as_deref().is_none_or(|start| key >= start)
+      && self.end.as_deref().is_none_or(|end| key < end)
+  }
+}
+
+pub struct DataManager {
+  data_dir: PathBuf,
+  backend: Box<dyn StorageBackend>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DataFormat {
+  Text,
+  Json,
+  Yaml,
+  Binary,
+}
+
+impl DataManager {
+  /// Where the data of `app_package` is kept for `user_id`.
+  ///
+  /// Fails for names that would lead out of the data directory, such as
+  /// `../..` or an absolute path.
+  pub fn data_dir(user_id: &str, app_package: &str) -> Result<PathBuf> {
+    utils::check_package_name(user_id)?;
+    utils::check_package_name(app_package)?;
+    let data_root = utils::get_rew_root().join("data");
+    let data_dir = data_root.join(user_id).join(app_package);
+    if !normalize_path(&data_dir).starts_with(normalize_path(&data_root)) {
+      bail!("The data of {:?} would be kept outside {:?}", app_package, data_root);
+    }
+    Ok(data_dir)
+  }
+
+  /// Opens the data of `app_package` in `backend`, or the one
+  /// `BackendKind::for_data_dir` picks.
+  ///
+  /// The first time the `sqlite` backend is opened, keys kept as loose files
+  /// are copied into the store; see `migrate_files`.
+  pub fn new(user_id: &str, app_package: &str, backend: Option<BackendKind>) -> Result<Self> {
+    let data_dir = Self::data_dir(user_id, app_package)?;
+
+    // Ensure the directory exists
+    fs::create_dir_all(&data_dir)
+      .with_context(|| format!("Failed to create data directory: {:?}", data_dir))?;
+
+    let backend = match backend {
+      Some(backend) => backend,
+      None => BackendKind::for_data_dir(&data_dir)?,
+    };
+    let backend: Box<dyn StorageBackend> = match backend {
+      BackendKind::Files => Box::new(FilesBackend::new(data_dir.clone())?),
+      BackendKind::Sqlite => {
+        let store = SqliteBackend::open(&data_dir.join(STORE_FILE))?;
+        if store.meta(MIGRATED)?.is_none() {
+          migrate_files(&data_dir, &store)?;
+        }
+        Box::new(store)
+      }
+    };
+
+    Ok(Self::with_backend(data_dir, backend))
+  }
+
+  /// Creates a manager that keeps its data in `backend`.
+  ///
+  /// # Arguments
+  /// * `data_dir` - The directory reported by `get_path`, which permission
+  ///   checks are made against.
+  /// * `backend` - Where the keys are stored.
+  pub fn with_backend(data_dir: PathBuf, backend: Box<dyn StorageBackend>) -> Self {
+    Self { data_dir, backend }
+  }
+
+  /// Runs `f` and then commits the changes it staged all at once.
+  ///
+  /// Nothing is written if `f` fails. Reads made inside `f` aren't isolated
+  /// from other processes.
+  ///
+  /// # Arguments
+  /// * `f` - Stages writes and deletes on the transaction it is given.
+  ///
+  /// # Returns
+  /// * What `f` returned, once its changes are stored.
+  pub fn transaction<T>(&self, f: impl FnOnce(&mut Transaction) -> Result<T>) -> Result<T> {
+    let mut tx = Transaction {
+      changes: BTreeMap::new(),
+    };
+    let result = f(&mut tx)?;
+
+    self.backend.commit(tx.changes)?;
+    Ok(result)
+  }
+
+  /// The `conf.kv` store of this app.
+  pub fn kv(&self) -> Kv<'_> {
+    Kv { manager: self }
+  }
+
+  pub fn get_path(&self, key: &str) -> PathBuf {
+    self.data_dir.join(key)
+  }
+
+  fn read_bytes(&self, key: &str) -> Result<Vec<u8>> {
+    match self.backend.get(key)? {
+      Some(data) => Ok(data),
+      None => bail!("Data not found: {}", key),
+    }
+  }
+
+  pub fn read(&self, key: &str) -> Result<String> {
+    String::from_utf8(self.read_bytes(key)?)
+      .with_context(|| format!("Data is not valid UTF-8: {}", key))
+  }
+
+  pub fn read_json(&self, key: &str) -> Result<Value> {
+    let content = self.read(key)?;
+    serde_json::from_str(&content)
+      .with_context(|| format!("Failed to parse JSON from data file: {}", key))
+  }
+
+  pub fn write(&self, key: &str, content: &str) -> Result<()> {
+    self.backend.set(key, content.as_bytes())
+  }
+
+  pub fn write_json(&self, key: &str, value: &Value) -> Result<()> {
+    let content = serde_json::to_string_pretty(value)
+      .with_context(|| format!("Failed to serialize JSON for data file: {}", key))?;
+    self.write(key, &content)
+  }
+
+  pub fn delete(&self, key: &str) -> Result<()> {
+    self.backend.delete(key)
+  }
+
+  pub fn exists(&self, key: &str) -> bool {
+    self.backend.exists(key).unwrap_or(false)
+  }
+
+  pub fn list(&self, prefix: &str) -> Result<Vec<String>> {
+    let mut keys = self.backend.keys(prefix)?;
+    keys.retain(|key| !key.starts_with(INTERNAL_PREFIX));
+    Ok(keys)
+  }
+
+  // Read binary data
+  pub fn read_binary(&self, key: &str) -> Result<Vec<u8>> {
+    self.read_bytes(key)
+  }
+
+  // Write binary data
+  pub fn write_binary(&self, key: &str, data: &[u8]) -> Result<()> {
+    self.backend.set(key, data)
+  }
+
+  // Read YAML data
+  pub fn read_yaml(&self, key: &str) -> Result<Value> {
+    let content = self.read(key)?;
+    serde_yaml::from_str(&content)
+      .with_context(|| format!("Failed to parse YAML from data file: {}", key))
+  }
+
+  // Write YAML data
+  pub fn write_yaml(&self, key: &str, value: &Value) -> Result<()> {
+    let content = serde_yaml::to_string(value)
+      .with_context(|| format!("Failed to serialize YAML for data file: {}", key))?;
+    self.write(key, &content)
+  }
+
+  // Get file info including format detection
+  pub fn get_file_info(&self, key: &str) -> Result<(bool, DataFormat)> {
+    let Some(data) = self.backend.get(key)? else {
+      return Ok((false, DataFormat::Text)); // Default to text for non-existent files
+    };
+
+    // Try to detect format based on extension
+    let format = if let Some(ext) = Path::new(key).extension() {
+      match ext.to_str().unwrap_or("").to_lowercase().as_str() {
+        "json" => DataFormat::Json,
+        "yaml" | "yml" => DataFormat::Yaml,
+        "bin" | "dat" => DataFormat::Binary,
+        _ => {
+          // Try to detect based on content
+          detect_format(&data)
+        }
+      }
+    } else {
+      // No extension, try to detect based on content
+      detect_format(&data)
+    };
+
+    Ok((true, format))
+  }
+}
+
+// Detect format based on the first 512 bytes of the content
+fn detect_format(data: &[u8]) -> DataFormat {
+  let sample = &data[..data.len().min(512)];
+
+  if sample.is_empty() {
+    return DataFormat::Text; // Empty file, default to text
+  }
+
+  // Check for binary content
+  if sample
+    .iter()
+    .any(|&b| b < 9 || (b > 13 && b < 32 && b != 27))
+  {
+    return DataFormat::Binary;
+  }
+
+  // Try to parse as JSON
+  let content = String::from_utf8_lossy(sample);
+  if (content.trim_start().starts_with('{') || content.trim_start().starts_with('['))
+    && serde_json::from_str::<Value>(&content).is_ok()
+  {
+    return DataFormat::Json;
+  }
+
+  // Try to parse as YAML
+  if content.contains(':')
+    && !content.contains('{')
+    && serde_yaml::from_str::<Value>(&content).is_ok()
+  {
+    return DataFormat::Yaml;
+  }
+
+  // Default to text
+  DataFormat::Text
+}
+
+/// Copies the keys an app kept as loose files into `store`, in the same
+/// transaction that marks the store as migrated.
+///
+/// Apps can put their own files into the data directory through
+/// `conf.path()`, and those are left alone. When the files backend's index
+/// lists every key `conf` wrote, just those are copied and, once the store
+/// is marked, removed. Data from a rew that kept no index can't be told
+/// apart from the app's files, so every file is copied and all of them stay
+/// where they are.
+fn migrate_files(data_dir: &Path, store: &SqliteBackend) -> Result<()> {
+  let files = FilesBackend::new(data_dir.to_path_buf())?;
+  // Another process may be migrating the same app.
+  let _lock = files.lock()?;
+  if store.meta(MIGRATED)?.is_some() {
+    return Ok(());
+  }
+
+  let index = files.index()?;
+  let keys: Vec<String> = if index.complete {
+    index.keys.into_iter().collect()
+  } else {
+    files.keys("")?
+  };
+  let mut changes = BTreeMap::new();
+  for key in &keys {
+    if let Some(data) = files.get(key)? {
+      changes.insert(key.clone(), Some(data));
+    }
+  }
+  let source = if index.complete { "index" } else { "files" };
+  store.commit_with_meta(changes, (MIGRATED, source))?;
+
+  if index.complete {
+    for key in &keys {
+      files.delete_locked(key)?;
+    }
+  }
+  Ok(())
+}
+
+/// The changes a `DataManager::transaction` commits.
+pub struct Transaction {
+  /// The new contents of each key, or `None` to delete it.
+  changes: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+  pub fn write(&mut self, key: &str, content: &str) {
+    self.write_binary(key, content.as_bytes());
+  }
+
+  pub fn write_binary(&mut self, key: &str, data: &[u8]) {
+    self.changes.insert(key.to_string(), Some(data.to_vec()));
+  }
+
+  pub fn write_json(&mut self, key: &str, value: &Value) -> Result<()> {
+    let content = serde_json::to_string_pretty(value)
+      .with_context(|| format!("Failed to serialize JSON for data file: {}", key))?;
+    self.write(key, &content);
+    Ok(())
+  }
+
+  pub fn write_yaml(&mut self, key: &str, value: &Value) -> Result<()> {
+    let content = serde_yaml::to_string(value)
+      .with_context(|| format!("Failed to serialize YAML for data file: {}", key))?;
+    self.write(key, &content);
+    Ok(())
+  }
+
+  pub fn delete(&mut self, key: &str) {
+    self.changes.insert(key.to_string(), None);
+  }
+}
+
+/// A `conf.kv` entry as it is stored.
+#[derive(Serialize, Deserialize)]
+struct KvEntry {
+  value: Value,
+  /// Milliseconds since the epoch after which the entry is gone.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  expires_at: Option<u64>,
+}
+
+impl KvEntry {
+  fn decode(data: &[u8]) -> Result<Self> {
+    serde_json::from_slice(data).context("Failed to parse key-value entry")
+  }
+
+  fn is_expired(&self) -> bool {
+    self
+      .expires_at
+      .is_some_and(|expires_at| expires_at <= now_millis())
+  }
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+/// An app's key-value store: JSON values kept in key order, with optional
+/// expiry and atomic counters.
+pub struct Kv<'a> {
+  manager: &'a DataManager,
+}
+
+impl Kv<'_> {
+  fn backend(&self) -> &dyn StorageBackend {
+    self.manager.backend.as_ref()
+  }
+
+  /// Returns the value of `key`, or `None` if it is missing or expired.
+  pub fn get(&self, key: &str) -> Result<Option<Value>> {
+    let Some(data) = self.backend().get(&format!("{}{}", KV_PREFIX, key))? else {
+      return Ok(None);
+    };
+    let entry = KvEntry::decode(&data)?;
+    Ok((!entry.is_expired()).then_some(entry.value))
+  }
+
+  /// Stores `value` under `key`.
+  ///
+  /// # Arguments
+  /// * `key` - The key to store the value under.
+  /// * `value` - The value to store.
+  /// * `ttl` - How long the entry lives, or `None` to keep it until deleted.
+  pub fn set(&self, key: &str, value: Value, ttl: Option<Duration>) -> Result<()> {
+    let entry = KvEntry {
+      value,
+      expires_at: ttl.map(|ttl| now_millis() + ttl.as_millis() as u64),
+    };
+    self.backend().set(
+      &format!("{}{}", KV_PREFIX, key),
+      &serde_json::to_vec(&entry)?,
+    )
+  }
+
+  pub fn delete(&self, key: &str) -> Result<()> {
+    self.backend().delete(&format!("{}{}", KV_PREFIX, key))
+  }
+
+  /// Returns the live entries `options` selects, in key order.
+  pub fn scan(&self, options: &ScanOptions) -> Result<Vec<(String, Value)>> {
+    let prefixed = |key: &Option<String>| key.as_ref().map(|key| format!("{}{}", KV_PREFIX, key));
+    let options = ScanOptions {
+      prefix: format!("{}{}", KV_PREFIX, options.prefix),
+      start: prefixed(&options.start),
+      end: prefixed(&options.end),
+      ..options.clone()
+    };
+
+    // Expired entries would count against the limit, so they are removed
+    // and the scan run again.
+    loop {
+      let mut result = Vec::new();
+      let mut expired = Vec::new();
+      for (key, data) in self.backend().scan(&options)? {
+        let entry = KvEntry::decode(&data)?;
+        if entry.is_expired() {
+          expired.push(key);
+        } else {
+          result.push((key[KV_PREFIX.len()..].to_string(), entry.value));
+        }
+      }
+
+      if expired.is_empty() {
+        return Ok(result);
+      }
+      for key in expired {
+        self.remove_if_expired(&key)?;
+      }
+    }
+  }
+
+  /// Deletes the entry stored under `key` unless it was renewed meanwhile.
+  fn remove_if_expired(&self, key: &str) -> Result<()> {
+    self.backend().update(key, &mut |data| match data {
+      Some(data) if !KvEntry::decode(&data)?.is_expired() => Ok(Some(data)),
+      _ => Ok(None),
+    })
+  }
+
+  /// Adds `by` to the number stored under `key`, starting from 0 when it is
+  /// missing or expired. The entry keeps its expiry.
+  ///
+  /// # Returns
+  /// * The new value.
+  pub fn increment(&self, key: &str, by: f64) -> Result<f64> {
+    let mut result = 0.0;
+    self
+      .backend()
+      .update(&format!("{}{}", KV_PREFIX, key), &mut |data| {
+        let entry = match data {
+          Some(data) => Some(KvEntry::decode(&data)?).filter(|entry| !entry.is_expired()),
+          None => None,
+        };
+        let (current, expires_at) = match entry {
+          Some(KvEntry { value, expires_at }) => match value.as_f64() {
+            Some(current) => (current, expires_at),
+            None => bail!("Cannot increment {}: it is not a number", key),
+          },
+          None => (0.0, None),
+        };
+
+        result = current + by;
+        let entry = KvEntry {
+          value: serde_json::json!(result),
+          expires_at,
+        };
+        Ok(Some(serde_json::to_vec(&entry)?))
+      })?;
+    Ok(result)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn open_store(dir: &Path) -> SqliteBackend {
+    SqliteBackend::open(&dir.join(STORE_FILE)).unwrap()
+  }
+
+  #[test]
+  fn migration_moves_only_keys_conf_wrote() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = FilesBackend::new(dir.path().to_path_buf()).unwrap();
+    files.set("settings.json", b"{}").unwrap();
+    files.set("notes/today", b"hello").unwrap();
+    fs::write(dir.path().join("cache.bin"), b"app's own").unwrap();
+
+    let store = open_store(dir.path());
+    migrate_files(dir.path(), &store).unwrap();
+
+    assert_eq!(store.get("settings.json").unwrap(), Some(b"{}".to_vec()));
+    assert_eq!(store.get("notes/today").unwrap(), Some(b"hello".to_vec()));
+    assert_eq!(store.get("cache.bin").unwrap(), None);
+    assert_eq!(store.meta(MIGRATED).unwrap().as_deref(), Some("index"));
+
+    assert!(!dir.path().join("settings.json").exists());
+    assert!(!dir.path().join("notes/today").exists());
+    assert!(dir.path().join("cache.bin").exists());
+  }
+
+  #[test]
+  fn migration_without_index_keeps_every_file() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("settings.json"), b"{}").unwrap();
+    fs::write(dir.path().join("cache.bin"), b"app's own").unwrap();
+
+    let store = open_store(dir.path());
+    migrate_files(dir.path(), &store).unwrap();
+
+    assert_eq!(store.get("settings.json").unwrap(), Some(b"{}".to_vec()));
+    assert_eq!(store.get("cache.bin").unwrap(), Some(b"app's own".to_vec()));
+    assert_eq!(store.meta(MIGRATED).unwrap().as_deref(), Some("files"));
+    assert!(dir.path().join("settings.json").exists());
+    assert!(dir.path().join("cache.bin").exists());
+  }
+
+  #[test]
+  fn migration_runs_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = open_store(dir.path());
+    migrate_files(dir.path(), &store).unwrap();
+
+    FilesBackend::new(dir.path().to_path_buf())
+      .unwrap()
+      .set("later", b"1")
+      .unwrap();
+    migrate_files(dir.path(), &store).unwrap();
+
+    assert_eq!(store.get("later").unwrap(), None);
+    assert!(dir.path().join("later").exists());
+  }
+
+  #[test]
+  fn backend_is_read_from_app_config() {
+    let config: crate::utils::AppConfig =
+      serde_yaml::from_str("manifest:\n  package: demo\ndata:\n  backend: files\n").unwrap();
+    assert_eq!(config.data.unwrap().backend, Some(BackendKind::Files));
+
+    let config: DataConfig = serde_yaml::from_str("{}").unwrap();
+    assert_eq!(config.backend, None);
+  }
+
+  #[test]
+  fn data_without_a_key_index_stays_with_the_files_backend() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("settings.json"), b"{}").unwrap();
+    fs::write(dir.path().join("cache.bin"), b"app's own").unwrap();
+
+    assert_eq!(BackendKind::for_data_dir(dir.path()).unwrap(), BackendKind::Files);
+    assert!(!dir.path().join(STORE_FILE).exists());
+    // Asking again doesn't change the answer now that the index exists.
+    assert_eq!(BackendKind::for_data_dir(dir.path()).unwrap(), BackendKind::Files);
+  }
+
+  #[test]
+  fn new_and_indexed_data_goes_into_sqlite() {
+    let empty = tempfile::tempdir().unwrap();
+    assert_eq!(BackendKind::for_data_dir(empty.path()).unwrap(), BackendKind::Sqlite);
+
+    let indexed = tempfile::tempdir().unwrap();
+    FilesBackend::new(indexed.path().to_path_buf())
+      .unwrap()
+      .set("settings.json", b"{}")
+      .unwrap();
+    assert_eq!(BackendKind::for_data_dir(indexed.path()).unwrap(), BackendKind::Sqlite);
+
+    let migrated = tempfile::tempdir().unwrap();
+    fs::write(migrated.path().join("cache.bin"), b"app's own").unwrap();
+    open_store(migrated.path());
+    assert_eq!(BackendKind::for_data_dir(migrated.path()).unwrap(), BackendKind::Sqlite);
+  }
+
+  #[test]
+  fn both_backends_list_the_keys_under_a_directory() {
+    let files_dir = tempfile::tempdir().unwrap();
+    let sqlite_dir = tempfile::tempdir().unwrap();
+    let files = FilesBackend::new(files_dir.path().to_path_buf()).unwrap();
+    let sqlite = open_store(sqlite_dir.path());
+    for backend in [&files as &dyn StorageBackend, &sqlite] {
+      for key in ["notes/a", "notes/b/c", "notesfile", "other"] {
+        backend.set(key, b"x").unwrap();
+      }
+    }
+
+    for prefix in ["notes", "notes/", "notes/b", ""] {
+      let mut from_files = files.keys(prefix).unwrap();
+      from_files.retain(|key| !key.starts_with(INTERNAL_PREFIX));
+      from_files.sort();
+      assert_eq!(sqlite.keys(prefix).unwrap(), from_files, "prefix {:?}", prefix);
+    }
+    assert_eq!(sqlite.keys("notes").unwrap(), ["notes/a", "notes/b/c"]);
+  }
+
+  #[test]
+  fn package_names_cannot_lead_out_of_the_data_directory() {
+    utils::test_rew_root();
+    for package in ["/", "../../../../..", "a/../../b", "a/b", "..", ".", "", "C:\\x"] {
+      assert!(
+        DataManager::data_dir("default", package).is_err(),
+        "{:?} was accepted",
+        package
+      );
+    }
+    assert!(DataManager::data_dir("..", "com.example.app").is_err());
+
+    let data_dir = DataManager::data_dir("default", "com.example.app").unwrap();
+    assert!(data_dir.starts_with(utils::get_rew_root().join("data")));
+    assert!(data_dir.ends_with("default/com.example.app"));
+  }
+}
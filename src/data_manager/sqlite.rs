@@ -0,0 +1,177 @@
+use super::{ScanOptions, StorageBackend};
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long a write waits for another process to finish its own.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps every key in one SQLite file, ordered by key.
+pub struct SqliteBackend {
+  conn: Connection,
+}
+
+impl SqliteBackend {
+  /// Opens the store at `path`, creating it if needed.
+  pub fn open(path: &Path) -> Result<Self> {
+    let conn =
+      Connection::open(path).with_context(|| format!("Failed to open data store: {:?}", path))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS data (key TEXT PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID;
+       CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL) WITHOUT ROWID;",
+    )?;
+    Ok(Self { conn })
+  }
+
+  /// Reads a value the store keeps about itself, such as whether old files
+  /// were migrated into it.
+  pub fn meta(&self, key: &str) -> Result<Option<String>> {
+    Ok(
+      self
+        .conn
+        .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+          row.get(0)
+        })
+        .optional()?,
+    )
+  }
+
+  /// Commits `changes` together with a `meta` entry.
+  pub fn commit_with_meta(
+    &self,
+    changes: BTreeMap<String, Option<Vec<u8>>>,
+    meta: (&str, &str),
+  ) -> Result<()> {
+    let tx = self.conn.unchecked_transaction()?;
+    apply(&tx, changes)?;
+    tx.execute(
+      "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+      params![meta.0, meta.1],
+    )?;
+    tx.commit()?;
+    Ok(())
+  }
+}
+
+fn apply(conn: &Connection, changes: BTreeMap<String, Option<Vec<u8>>>) -> Result<()> {
+  for (key, change) in changes {
+    match change {
+      Some(value) => {
+        conn.execute(
+          "INSERT OR REPLACE INTO data (key, value) VALUES (?1, ?2)",
+          params![key, value],
+        )?;
+      }
+      None => {
+        conn.execute("DELETE FROM data WHERE key = ?1", [key])?;
+      }
+    }
+  }
+  Ok(())
+}
+
+impl StorageBackend for SqliteBackend {
+  fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    Ok(
+      self
+        .conn
+        .query_row("SELECT value FROM data WHERE key = ?1", [key], |row| {
+          row.get(0)
+        })
+        .optional()?,
+    )
+  }
+
+  fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+    self.conn.execute(
+      "INSERT OR REPLACE INTO data (key, value) VALUES (?1, ?2)",
+      params![key, value],
+    )?;
+    Ok(())
+  }
+
+  fn delete(&self, key: &str) -> Result<()> {
+    self
+      .conn
+      .execute("DELETE FROM data WHERE key = ?1", [key])?;
+    Ok(())
+  }
+
+  fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+    // `prefix` names a directory, as it does for the files backend: "notes"
+    // lists "notes/a" but not "notesfile".
+    let prefix = match prefix.trim_end_matches('/') {
+      "" => String::new(),
+      dir => format!("{}/", dir),
+    };
+    let mut statement = self
+      .conn
+      .prepare("SELECT key FROM data WHERE key >= ?1 AND key < ?2 ORDER BY key")?;
+    let keys = statement
+      .query_map(params![prefix, prefix_end(&prefix)], |row| row.get(0))?
+      .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(keys)
+  }
+
+  fn scan(&self, options: &ScanOptions) -> Result<Vec<(String, Vec<u8>)>> {
+    let prefix_end = prefix_end(&options.prefix);
+    let start = match options.start.as_deref() {
+      Some(start) if start > options.prefix.as_str() => start,
+      _ => options.prefix.as_str(),
+    };
+    let end = match options.end.as_deref() {
+      Some(end) if end < prefix_end.as_str() => end,
+      _ => prefix_end.as_str(),
+    };
+    let order = if options.reverse { "DESC" } else { "ASC" };
+    let limit = options.limit.map_or(-1, |limit| limit as i64);
+
+    let mut statement = self.conn.prepare(&format!(
+      "SELECT key, value FROM data WHERE key >= ?1 AND key < ?2 ORDER BY key {} LIMIT ?3",
+      order
+    ))?;
+    let entries = statement
+      .query_map(params![start, end, limit], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+      })?
+      .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(entries)
+  }
+
+  fn commit(&self, changes: BTreeMap<String, Option<Vec<u8>>>) -> Result<()> {
+    let tx = self.conn.unchecked_transaction()?;
+    apply(&tx, changes)?;
+    tx.commit()?;
+    Ok(())
+  }
+
+  fn update(
+    &self,
+    key: &str,
+    f: &mut dyn FnMut(Option<Vec<u8>>) -> Result<Option<Vec<u8>>>,
+  ) -> Result<()> {
+    // Immediate, so no other process can write between the read and the write.
+    let tx = rusqlite::Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+    let current = tx
+      .query_row("SELECT value FROM data WHERE key = ?1", [key], |row| {
+        row.get(0)
+      })
+      .optional()?;
+    let mut changes = BTreeMap::new();
+    changes.insert(key.to_string(), f(current)?);
+    apply(&tx, changes)?;
+    tx.commit()?;
+    Ok(())
+  }
+}
+
+/// The first key after every key that starts with `prefix`.
+///
+/// Keys compare as UTF-8 bytes, and no character sorts after `char::MAX`.
+fn prefix_end(prefix: &str) -> String {
+  format!("{}{}", prefix, char::MAX)
+}
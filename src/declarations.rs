@@ -1,6 +1,10 @@
+use crate::diagnostics::{RewDiagnostic, Span};
+use once_cell::sync::Lazy;
 use regex::Regex;
 use std::collections::HashMap;
 
+static ONLYIF_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"ONLYIF\(([^)]+)\)").unwrap());
+
 #[derive(Debug, Clone)]
 /// Represents a code declaration with a trigger and replacement logic.
 /// 
@@ -47,10 +51,27 @@ impl Declaration {
     }
   }
 
+  /// Keys of an `ONLYIF(...)` condition other than `prev` and `next`, which
+  /// `parse_onlyif` ignores.
+  ///
+  /// # Arguments
+  /// * `replacement` - The replacement text, before `ONLYIF` is stripped.
+  pub fn unknown_onlyif_keys(replacement: &str) -> Vec<String> {
+    let Some(caps) = ONLYIF_RE.captures(replacement) else {
+      return Vec::new();
+    };
+
+    caps[1]
+      .split(',')
+      .map(|condition| condition.split('=').next().unwrap_or("").trim().to_string())
+      .filter(|key| key != "prev" && key != "next")
+      .collect()
+  }
+
   // New method to parse ONLYIF conditions
   fn parse_onlyif(replacement: &str) -> (String, Option<String>, Option<String>) {
     // Check if ONLYIF is present in the replacement
-    let re = Regex::new(r"ONLYIF\(([^)]+)\)").unwrap();
+    let re = &*ONLYIF_RE;
 
     if let Some(caps) = re.captures(replacement) {
       let conditions = &caps[1];
@@ -83,6 +104,28 @@ impl Declaration {
   }
 }
 
+/// A `#declare` directive and where it was written.
+#[derive(Debug, Clone)]
+pub struct DeclarationSite {
+  /// 1-based line the directive starts on.
+  pub line: usize,
+  /// 1-based column the directive starts at.
+  pub column: usize,
+  /// Length of the directive's first line.
+  pub length: usize,
+  /// The directive as written, across all of its lines.
+  pub text: String,
+  /// The parsed declaration, `None` when the directive is malformed.
+  pub declaration: Option<Declaration>,
+  pub is_global: bool,
+}
+
+impl DeclarationSite {
+  pub fn span(&self) -> Span {
+    Span::new(self.line, self.column, self.length)
+  }
+}
+
 #[derive(Default, Clone)]
 /// Engine for managing and processing code declarations.
 pub struct DeclarationEngine {
@@ -229,4 +272,97 @@ impl DeclarationEngine {
 
     local_declarations
   }
+
+  /// Finds every `#declare` directive in a script, parsed on its own.
+  ///
+  /// # Arguments
+  /// * `script` - The source to scan.
+  ///
+  /// # Returns
+  /// * The directives in source order, with where they were written.
+  pub fn locate_declarations(script: &str) -> Vec<DeclarationSite> {
+    let mut sites = Vec::new();
+    let mut pending: Option<DeclarationSite> = None;
+
+    let finish = |mut site: DeclarationSite| {
+      let mut scratch = DeclarationEngine::default();
+      let mut local = HashMap::new();
+      if scratch.parse_declaration(&site.text, &mut local) {
+        site.is_global = !scratch.global_declarations.is_empty();
+        site.declaration = scratch
+          .global_declarations
+          .into_values()
+          .chain(local.into_values())
+          .next();
+      }
+      site
+    };
+
+    for (idx, line) in script.lines().enumerate() {
+      let trimmed = line.trim();
+
+      if let Some(site) = pending.as_mut() {
+        site.text.push_str(line);
+        site.text.push('\n');
+        if trimmed.ends_with(';') {
+          sites.push(finish(pending.take().unwrap()));
+        }
+        continue;
+      }
+
+      let directive = trimmed
+        .strip_prefix("#declare")
+        .or_else(|| trimmed.strip_prefix("//declare"));
+      let Some(rest) = directive else {
+        continue;
+      };
+      // `#declared ...` is an ordinary comment.
+      if rest
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphanumeric() || c == '_')
+      {
+        continue;
+      }
+
+      let site = DeclarationSite {
+        line: idx + 1,
+        column: line.len() - line.trim_start().len() + 1,
+        length: trimmed.chars().count(),
+        text: format!("{}\n", trimmed),
+        declaration: None,
+        is_global: false,
+      };
+      if trimmed.ends_with(';') {
+        sites.push(finish(site));
+      } else {
+        pending = Some(site);
+      }
+    }
+
+    if let Some(site) = pending {
+      sites.push(finish(site));
+    }
+
+    sites
+  }
+
+  /// Finds `#declare` directives that `process_script` would silently skip.
+  ///
+  /// # Arguments
+  /// * `script` - The source to check.
+  ///
+  /// # Returns
+  /// * A diagnostic for every declaration that can't be parsed.
+  pub fn check_script(script: &str) -> Vec<RewDiagnostic> {
+    Self::locate_declarations(script)
+      .into_iter()
+      .filter(|site| site.declaration.is_none())
+      .map(|site| {
+        RewDiagnostic::error("Malformed declaration")
+          .with_span(site.span())
+          .with_hint("declarations look like `#declare \"trigger\" = replacement;`")
+      })
+      .collect()
+  }
 }
@@ -0,0 +1,272 @@
+use colored::*;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Lines of context shown around the highlighted line in a code frame.
+const CODE_FRAME_CONTEXT: usize = 2;
+
+/// Switches between colored, human readable reports and JSON lines.
+pub fn set_json_output(enabled: bool) {
+  JSON_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn json_output() -> bool {
+  JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+  Error,
+  Warning,
+}
+
+impl Severity {
+  fn label(&self) -> ColoredString {
+    match self {
+      Severity::Error => "error".red().bold(),
+      Severity::Warning => "warning".yellow().bold(),
+    }
+  }
+}
+
+/// A range in a file. Lines and columns are 1-based, the end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Span {
+  pub line: usize,
+  pub column: usize,
+  pub end_line: usize,
+  pub end_column: usize,
+}
+
+impl Span {
+  /// A span of `length` characters on a single line.
+  pub fn new(line: usize, column: usize, length: usize) -> Self {
+    Self {
+      line,
+      column,
+      end_line: line,
+      end_column: column + length.max(1),
+    }
+  }
+}
+
+/// A problem found while compiling a Rew file.
+#[derive(Debug, Clone, Serialize)]
+pub struct RewDiagnostic {
+  pub file: Option<PathBuf>,
+  pub span: Option<Span>,
+  pub severity: Severity,
+  /// The lint rule that produced the diagnostic, if any.
+  pub code: Option<String>,
+  pub message: String,
+  pub hints: Vec<String>,
+  #[serde(skip)]
+  pub source: Option<String>,
+}
+
+impl RewDiagnostic {
+  pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+    Self {
+      file: None,
+      span: None,
+      severity,
+      code: None,
+      message: message.into(),
+      hints: Vec::new(),
+      source: None,
+    }
+  }
+
+  pub fn error(message: impl Into<String>) -> Self {
+    Self::new(Severity::Error, message)
+  }
+
+  pub fn warning(message: impl Into<String>) -> Self {
+    Self::new(Severity::Warning, message)
+  }
+
+  pub fn with_span(mut self, span: Span) -> Self {
+    self.span = Some(span);
+    self
+  }
+
+  pub fn with_code(mut self, code: impl Into<String>) -> Self {
+    self.code = Some(code.into());
+    self
+  }
+
+  pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+    self.hints.push(hint.into());
+    self
+  }
+
+  /// Attaches the file the diagnostic belongs to, and its source for the
+  /// code frame.
+  pub fn in_file(mut self, file: &Path, source: &str) -> Self {
+    self.file = Some(file.to_path_buf());
+    self.source = Some(source.to_string());
+    self
+  }
+
+  pub fn is_error(&self) -> bool {
+    self.severity == Severity::Error
+  }
+
+  /// Renders the diagnostic with a colored code frame.
+  pub fn render(&self) -> String {
+    let label = match &self.code {
+      Some(code) => format!("{}{}", self.severity.label(), format!("[{}]", code).bold()),
+      None => self.severity.label().to_string(),
+    };
+    let mut output = format!("{}: {}\n", label, self.message.bold());
+
+    if let Some(file) = &self.file {
+      let location = match self.span {
+        Some(span) => format!("{}:{}:{}", file.display(), span.line, span.column),
+        None => file.display().to_string(),
+      };
+      output.push_str(&format!("  {} {}\n", "-->".blue(), location));
+    }
+
+    if let (Some(span), Some(source)) = (self.span, &self.source) {
+      let length = if span.end_line == span.line {
+        span.end_column.saturating_sub(span.column)
+      } else {
+        1
+      };
+      output.push_str(&format_code_frame(source, span.line, span.column, length));
+    }
+
+    for hint in &self.hints {
+      output.push_str(&format!("  {} {}\n", "= hint:".cyan(), hint));
+    }
+
+    output
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self).unwrap_or_default()
+  }
+}
+
+impl fmt::Display for RewDiagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.render().trim_end())
+  }
+}
+
+impl std::error::Error for RewDiagnostic {}
+
+/// Every diagnostic a failed compilation produced.
+#[derive(Debug, Clone)]
+pub struct Diagnostics(pub Vec<RewDiagnostic>);
+
+impl fmt::Display for Diagnostics {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let rendered: Vec<String> = self.0.iter().map(|d| d.to_string()).collect();
+    write!(f, "{}", rendered.join("\n\n"))
+  }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/// Prints a diagnostic to stderr in the current output mode.
+pub fn emit(diagnostic: &RewDiagnostic) {
+  if json_output() {
+    eprintln!("{}", diagnostic.to_json());
+  } else {
+    eprintln!("{}", diagnostic.render());
+  }
+}
+
+/// Prints an error that stopped a command, turning it into diagnostics when
+/// it carries them.
+pub fn report_error(error: &anyhow::Error) {
+  if let Some(diagnostics) = error.downcast_ref::<Diagnostics>() {
+    diagnostics.0.iter().for_each(emit);
+  } else if let Some(diagnostic) = error.downcast_ref::<RewDiagnostic>() {
+    emit(diagnostic);
+  } else if json_output() {
+    emit(&RewDiagnostic::error(format!("{:#}", error)));
+  } else {
+    eprintln!("{} {:?}", "Error:".red().bold(), error);
+  }
+}
+
+/// Renders the lines around `line` and underlines `length` characters
+/// starting at `column`.
+///
+/// # Arguments
+/// * `source` - The file contents.
+/// * `line` - The 1-based line to point at.
+/// * `column` - The 1-based column to point at.
+/// * `length` - How many characters to underline.
+pub fn format_code_frame(source: &str, line: usize, column: usize, length: usize) -> String {
+  let lines: Vec<&str> = source.lines().collect();
+  if line == 0 || line > lines.len() {
+    return String::new();
+  }
+
+  let first = line.saturating_sub(CODE_FRAME_CONTEXT).max(1);
+  let last = (line + CODE_FRAME_CONTEXT).min(lines.len());
+  let width = last.to_string().len();
+  let mut frame = String::new();
+
+  for number in first..=last {
+    let gutter = format!("{:>width$} |", number, width = width);
+    if number == line {
+      frame.push_str(&format!("{} {} {}\n", ">".red(), gutter, lines[number - 1]));
+      frame.push_str(&format!(
+        "  {:>width$} | {}{}\n",
+        "",
+        " ".repeat(column.saturating_sub(1)),
+        "^".repeat(length.max(1)).red(),
+        width = width
+      ));
+    } else {
+      frame.push_str(&format!("  {} {}\n", gutter.dimmed(), lines[number - 1]));
+    }
+  }
+
+  frame
+}
+
+/// Finds the candidate closest to `name`, for "did you mean" hints.
+pub fn did_you_mean<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+  candidates
+    .iter()
+    .map(|candidate| {
+      (
+        edit_distance(&name.to_lowercase(), &candidate.to_lowercase()),
+        *candidate,
+      )
+    })
+    .filter(|(distance, _)| *distance <= 2)
+    .min_by_key(|(distance, _)| *distance)
+    .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let cost = if ca == *cb { 0 } else { 1 };
+      current.push(
+        (previous[j] + cost)
+          .min(previous[j + 1] + 1)
+          .min(current[j] + 1),
+      );
+    }
+    previous = current;
+  }
+
+  previous[b.len()]
+}
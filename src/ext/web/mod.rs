@@ -9,7 +9,7 @@ mod permissions;
 pub(crate) use permissions::PermissionsContainer;
 pub use permissions::{
   AllowlistWebPermissions, DefaultWebPermissions, PermissionDeniedError, SystemsPermissionKind,
-  WebPermissions,
+  WebPermissions, normalize_path, resolve_path,
 };
 
 extension!(
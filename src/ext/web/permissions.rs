@@ -13,6 +13,66 @@ pub fn oops<T>(msg: impl std::fmt::Display) -> Result<T, PermissionDeniedError>
   })
 }
 
+/// Denies an access that a `--allow-{name}` flag would grant
+fn denied<T>(access: String, name: &'static str) -> Result<T, PermissionDeniedError> {
+  Err(PermissionDeniedError::Retryable { access, name })
+}
+
+/// Denies an access that was explicitly denied, so that asking again can't
+/// grant it
+fn refused<T>(access: String) -> Result<T, PermissionDeniedError> {
+  Err(PermissionDeniedError::Fatal { access })
+}
+
+/// Makes `path` absolute against the current directory and removes `.` and
+/// `..` components, without touching the file system
+#[must_use]
+pub fn normalize_path(path: &Path) -> PathBuf {
+  let path = if path.is_absolute() {
+    path.to_path_buf()
+  } else {
+    std::env::current_dir()
+      .unwrap_or_else(|_| PathBuf::from("/"))
+      .join(path)
+  };
+
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      std::path::Component::CurDir => {}
+      std::path::Component::ParentDir => {
+        normalized.pop();
+      }
+      other => normalized.push(other),
+    }
+  }
+  normalized
+}
+
+/// Like [`normalize_path`], but symlinks in the part of `path` that exists
+/// are followed, so a link inside an allowed directory can't reach past it
+#[must_use]
+pub fn resolve_path(path: &Path) -> PathBuf {
+  // `..` is left to the file system while it can, `link/..` is the parent
+  // of where the link points.
+  let path = std::env::current_dir()
+    .unwrap_or_else(|_| PathBuf::from("/"))
+    .join(path);
+  let resolved = path
+    .ancestors()
+    .find_map(|existing| {
+      let rest = path.strip_prefix(existing).ok()?;
+      Some(existing.canonicalize().ok()?.join(rest))
+    })
+    .unwrap_or(path);
+  normalize_path(&resolved)
+}
+
+/// Whether `path` is one of `paths` or inside one of them
+fn contains_path(paths: &HashSet<String>, path: &Path) -> bool {
+  paths.iter().any(|allowed| path.starts_with(allowed))
+}
+
 /// The default permissions manager for the web related extensions
 ///
 /// Allows all operations
@@ -126,6 +186,9 @@ struct AllowlistWebPermissionsSet {
   pub exec: bool,
   pub read_all: bool,
   pub write_all: bool,
+  pub net_all: bool,
+  pub env_all: bool,
+  pub sys_all: bool,
   pub url: HashSet<String>,
   pub openr_paths: HashSet<String>,
   pub openw_paths: HashSet<String>,
@@ -134,6 +197,11 @@ struct AllowlistWebPermissionsSet {
   pub read_paths: HashSet<String>,
   pub write_paths: HashSet<String>,
   pub hosts: HashSet<String>,
+  pub denied_read_paths: HashSet<String>,
+  pub denied_write_paths: HashSet<String>,
+  pub denied_hosts: HashSet<String>,
+  pub denied_envs: HashSet<String>,
+  pub denied_sys: HashSet<SystemsPermissionKind>,
 }
 
 /// Permissions manager for the web related extensions
@@ -174,18 +242,41 @@ impl AllowlistWebPermissions {
 
   /// Set the `read_all` permission
   ///
-  /// If false all reads will be denied
+  /// If true every path that isn't denied may be read, otherwise only the
+  /// whitelisted paths
   pub fn set_read_all(&self, value: bool) {
     self.borrow_mut().read_all = value;
   }
 
   /// Set the `write_all` permission
   ///
-  /// If false all writes will be denied
+  /// If true every path that isn't denied may be written, otherwise only the
+  /// whitelisted paths
   pub fn set_write_all(&self, value: bool) {
     self.borrow_mut().write_all = value;
   }
 
+  /// Set the `net_all` permission
+  ///
+  /// If true every host that isn't denied may be connected to
+  pub fn set_net_all(&self, value: bool) {
+    self.borrow_mut().net_all = value;
+  }
+
+  /// Set the `env_all` permission
+  ///
+  /// If true every environment variable that isn't denied may be accessed
+  pub fn set_env_all(&self, value: bool) {
+    self.borrow_mut().env_all = value;
+  }
+
+  /// Set the `sys_all` permission
+  ///
+  /// If true every system operation may be performed
+  pub fn set_sys_all(&self, value: bool) {
+    self.borrow_mut().sys_all = value;
+  }
+
   /// Whitelist a path for opening
   ///
   /// If `read` is true, the path will be allowed to be opened for reading  
@@ -209,34 +300,44 @@ impl AllowlistWebPermissions {
     self.borrow_mut().url.remove(url);
   }
 
-  /// Whitelist a path for reading
+  /// Whitelist a path, and everything inside it, for reading
   pub fn allow_read(&self, path: &str) {
-    self.borrow_mut().read_paths.insert(path.to_string());
+    let path = resolve_path(Path::new(path)).to_string_lossy().into_owned();
+    self.borrow_mut().read_paths.insert(path);
   }
 
-  /// Blacklist a path for reading
+  /// Blacklist a path, and everything inside it, for reading
   pub fn deny_read(&self, path: &str) {
-    self.borrow_mut().read_paths.remove(path);
+    let path = resolve_path(Path::new(path)).to_string_lossy().into_owned();
+    let mut inst = self.borrow_mut();
+    inst.read_paths.remove(&path);
+    inst.denied_read_paths.insert(path);
   }
 
-  /// Whitelist a path for writing
+  /// Whitelist a path, and everything inside it, for writing
   pub fn allow_write(&self, path: &str) {
-    self.borrow_mut().write_paths.insert(path.to_string());
+    let path = resolve_path(Path::new(path)).to_string_lossy().into_owned();
+    self.borrow_mut().write_paths.insert(path);
   }
 
-  /// Blacklist a path for writing
+  /// Blacklist a path, and everything inside it, for writing
   pub fn deny_write(&self, path: &str) {
-    self.borrow_mut().write_paths.remove(path);
+    let path = resolve_path(Path::new(path)).to_string_lossy().into_owned();
+    let mut inst = self.borrow_mut();
+    inst.write_paths.remove(&path);
+    inst.denied_write_paths.insert(path);
   }
 
-  /// Whitelist a host
+  /// Whitelist a host, either `host` or `host:port`
   pub fn allow_host(&self, host: &str) {
     self.borrow_mut().hosts.insert(host.to_string());
   }
 
-  /// Blacklist a host
+  /// Blacklist a host, either `host` or `host:port`
   pub fn deny_host(&self, host: &str) {
-    self.borrow_mut().hosts.remove(host);
+    let mut inst = self.borrow_mut();
+    inst.hosts.remove(host);
+    inst.denied_hosts.insert(host.to_string());
   }
 
   /// Whitelist an environment variable
@@ -246,7 +347,9 @@ impl AllowlistWebPermissions {
 
   /// Blacklist an environment variable
   pub fn deny_env(&self, var: &str) {
-    self.borrow_mut().envs.remove(var);
+    let mut inst = self.borrow_mut();
+    inst.envs.remove(var);
+    inst.denied_envs.insert(var.to_string());
   }
 
   /// Whitelist a system operation
@@ -256,7 +359,9 @@ impl AllowlistWebPermissions {
 
   /// Blacklist a system operation
   pub fn deny_sys(&self, kind: SystemsPermissionKind) {
-    self.borrow_mut().sys.remove(&kind);
+    let mut inst = self.borrow_mut();
+    inst.sys.remove(&kind);
+    inst.denied_sys.insert(kind);
   }
 }
 impl WebPermissions for AllowlistWebPermissions {
@@ -270,10 +375,19 @@ impl WebPermissions for AllowlistWebPermissions {
     port: Option<u16>,
     api_name: &str,
   ) -> Result<(), PermissionDeniedError> {
-    if self.borrow().hosts.contains(host) {
+    let inst = self.borrow();
+    let with_port = port.map(|port| format!("{}:{}", host, port));
+    let listed = |hosts: &HashSet<String>| {
+      hosts.contains(host) || with_port.as_ref().is_some_and(|h| hosts.contains(h))
+    };
+
+    let access = format!("net access to {}", with_port.as_deref().unwrap_or(host));
+    if listed(&inst.denied_hosts) {
+      refused(access)
+    } else if inst.net_all || listed(&inst.hosts) {
       Ok(())
     } else {
-      oops(host)?
+      denied(access, "net")
     }
   }
 
@@ -283,9 +397,11 @@ impl WebPermissions for AllowlistWebPermissions {
     api_name: &str,
   ) -> Result<(), PermissionDeniedError> {
     if self.borrow().url.contains(url.as_str()) {
-      Ok(())
-    } else {
-      oops(url)?
+      return Ok(());
+    }
+    match url.host_str() {
+      Some(host) => self.check_host(host, url.port_or_known_default(), api_name),
+      None => denied(format!("net access to {}", url), "net"),
     }
   }
 
@@ -295,10 +411,14 @@ impl WebPermissions for AllowlistWebPermissions {
     api_name: Option<&str>,
   ) -> Result<Cow<'a, Path>, PermissionDeniedError> {
     let inst = self.borrow();
-    if inst.read_all && inst.read_paths.contains(p.to_str().unwrap()) {
+    let path = resolve_path(p);
+    let access = format!("read access to {}", p.display());
+    if contains_path(&inst.denied_read_paths, &path) {
+      refused(access)
+    } else if inst.read_all || contains_path(&inst.read_paths, &path) {
       Ok(Cow::Borrowed(p))
     } else {
-      oops(p.display())?
+      denied(access, "read")
     }
   }
 
@@ -308,10 +428,14 @@ impl WebPermissions for AllowlistWebPermissions {
     api_name: Option<&str>,
   ) -> Result<Cow<'a, Path>, PermissionDeniedError> {
     let inst = self.borrow();
-    if inst.write_all && inst.write_paths.contains(p.to_str().unwrap()) {
+    let path = resolve_path(p);
+    let access = format!("write access to {}", p.display());
+    if contains_path(&inst.denied_write_paths, &path) {
+      refused(access)
+    } else if inst.write_all || contains_path(&inst.write_paths, &path) {
       Ok(Cow::Borrowed(p))
     } else {
-      oops(p.display())?
+      denied(access, "write")
     }
   }
 
@@ -323,21 +447,21 @@ impl WebPermissions for AllowlistWebPermissions {
     path: &'a Path,
     api_name: &str,
   ) -> Option<std::borrow::Cow<'a, Path>> {
-    let path = path.to_str().unwrap();
-    if read && !self.borrow().openr_paths.contains(path) {
+    let listed = |paths: &HashSet<String>| path.to_str().is_some_and(|p| paths.contains(p));
+    if read && !listed(&self.borrow().openr_paths) && self.check_read(path, None).is_err() {
       return None;
     }
-    if write && !self.borrow().openw_paths.contains(path) {
+    if write && !listed(&self.borrow().openw_paths) && self.check_write(path, None).is_err() {
       return None;
     }
-    Some(Cow::Borrowed(path.as_ref()))
+    Some(Cow::Borrowed(path))
   }
 
   fn check_read_all(&self, api_name: Option<&str>) -> Result<(), PermissionDeniedError> {
     if self.borrow().read_all {
       Ok(())
     } else {
-      oops("read_all")?
+      denied("read access to every file".to_string(), "read")
     }
   }
 
@@ -358,7 +482,7 @@ impl WebPermissions for AllowlistWebPermissions {
     if self.borrow().write_all {
       Ok(())
     } else {
-      oops("write_all")?
+      denied("write access to every file".to_string(), "write")
     }
   }
 
@@ -386,18 +510,26 @@ impl WebPermissions for AllowlistWebPermissions {
     kind: SystemsPermissionKind,
     api_name: &str,
   ) -> Result<(), PermissionDeniedError> {
-    if self.borrow().sys.contains(&kind) {
+    let inst = self.borrow();
+    let access = format!("sys access to {}", kind.as_str());
+    if inst.denied_sys.contains(&kind) {
+      refused(access)
+    } else if inst.sys_all || inst.sys.contains(&kind) {
       Ok(())
     } else {
-      oops(kind.as_str())?
+      denied(access, "sys")
     }
   }
 
   fn check_env(&self, var: &str) -> Result<(), PermissionDeniedError> {
-    if self.borrow().envs.contains(var) {
+    let inst = self.borrow();
+    let access = format!("env access to {}", var);
+    if inst.denied_envs.contains(var) {
+      refused(access)
+    } else if inst.env_all || inst.envs.contains(var) {
       Ok(())
     } else {
-      oops(var)?
+      denied(access, "env")
     }
   }
 
@@ -405,7 +537,7 @@ impl WebPermissions for AllowlistWebPermissions {
     if self.borrow().exec {
       Ok(())
     } else {
-      oops("ffi")?
+      denied("ffi access".to_string(), "ffi")
     }
   }
 }
@@ -609,3 +741,40 @@ impl deno_web::TimersPermission for PermissionsContainer {
     self.0.allow_hrtime()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolve_path_removes_dots_from_missing_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    let missing = dir.path().join("a/./b/../c");
+    assert_eq!(resolve_path(&missing), root.join("a/c"));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinks_cannot_lead_out_of_an_allowed_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let allowed = dir.path().join("allowed");
+    let secret = dir.path().join("secret");
+    std::fs::create_dir_all(allowed.join("sub")).unwrap();
+    std::fs::create_dir(&secret).unwrap();
+    std::fs::write(secret.join("key"), "hidden").unwrap();
+    std::os::unix::fs::symlink(&secret, allowed.join("link")).unwrap();
+    std::os::unix::fs::symlink(secret.join("key"), allowed.join("sub/key")).unwrap();
+
+    let permissions = AllowlistWebPermissions::new();
+    permissions.allow_read(&allowed.to_string_lossy());
+
+    assert!(permissions.check_read(&allowed.join("sub"), None).is_ok());
+    assert!(permissions.check_read(&allowed.join("new-file"), None).is_ok());
+    assert!(permissions.check_read(&allowed.join("link/key"), None).is_err());
+    assert!(permissions.check_read(&allowed.join("link/new-file"), None).is_err());
+    assert!(permissions.check_read(&allowed.join("sub/key"), None).is_err());
+    // `link/..` is the directory the link points into, not `allowed`.
+    assert!(permissions.check_read(&allowed.join("link/../secret/key"), None).is_err());
+  }
+}
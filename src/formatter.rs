@@ -0,0 +1,480 @@
+use crate::compiler::{Token, tokenize_coffee_script};
+use crate::runtime::RewRuntime;
+use crate::utils::collect_files;
+use anyhow::{Context, Result};
+use colored::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Spaces per indentation level in formatted output.
+const INDENT: &str = "  ";
+
+/// What the formatter may do to a line.
+#[derive(Debug, Clone, Copy, Default)]
+struct LineInfo {
+  /// The line starts inside a string, template or block comment and is
+  /// copied as it is.
+  verbatim: bool,
+  /// The line has something the tokenizer can't see into, like a regex or a
+  /// template literal, so only its indentation is touched.
+  fragile: bool,
+  /// The line ends inside a multi-line literal, so its trailing whitespace
+  /// belongs to the literal.
+  open_end: bool,
+}
+
+/// Works out which lines can be reformatted by walking the token stream.
+fn analyze_lines(source: &str, tokens: &[Token]) -> Vec<LineInfo> {
+  let line_count = source.split('\n').count();
+  let mut info = vec![LineInfo::default(); line_count];
+  let mut line = 0;
+  let mut in_template = false;
+
+  for token in tokens {
+    let newlines = token.value.matches('\n').count();
+    // Comments carry the newline that ends them.
+    let spans_lines = newlines > 0 && token.token_type != "COMMENT";
+
+    match token.token_type.as_str() {
+      "STRING" | "TRIPLE_STRING" if spans_lines => {
+        info[line].fragile = true;
+        for l in line..line + newlines {
+          info[l].open_end = true;
+          info[l + 1].verbatim = true;
+        }
+      }
+      "WHITESPACE" if spans_lines && in_template => {
+        for l in line..line + newlines {
+          info[l].open_end = true;
+          info[l + 1].verbatim = true;
+        }
+      }
+      "OTHER" if token.value == "`" => {
+        in_template = !in_template;
+        info[line].fragile = true;
+      }
+      // Regex literals look like division to the tokenizer, and the text of
+      // `//` comments is tokenized as code.
+      "OTHER" if token.value == "/" => {
+        info[line].fragile = true;
+      }
+      _ => {
+        if in_template {
+          info[line].fragile = true;
+        }
+      }
+    }
+
+    line += newlines;
+  }
+
+  mark_directive_blocks(source, &mut info);
+  info
+}
+
+/// Keeps `###` block comments and the continuation lines of multi-line
+/// `#declare` directives as they are.
+fn mark_directive_blocks(source: &str, info: &mut [LineInfo]) {
+  let mut in_block_comment = false;
+  let mut in_declaration = false;
+
+  for (idx, line) in source.split('\n').enumerate() {
+    let trimmed = line.trim();
+    if info[idx].verbatim {
+      continue;
+    }
+
+    if in_block_comment {
+      info[idx].verbatim = true;
+      if trimmed.starts_with("###") {
+        in_block_comment = false;
+      }
+      continue;
+    }
+    if in_declaration {
+      info[idx].verbatim = true;
+      in_declaration = !trimmed.ends_with(';');
+      continue;
+    }
+
+    if trimmed.starts_with("###") && !trimmed.starts_with("####") {
+      in_block_comment = !trimmed[3..].contains("###");
+    } else if (trimmed.starts_with("#declare") || trimmed.starts_with("//declare"))
+      && !trimmed.ends_with(';')
+    {
+      in_declaration = true;
+    }
+  }
+}
+
+fn indent_width(line: &str) -> usize {
+  line.chars().take_while(|c| *c == ' ' || *c == '\t').count()
+}
+
+fn is_comment_line(trimmed: &str) -> bool {
+  trimmed.starts_with('#') || trimmed.starts_with("//")
+}
+
+/// Maps every line to an indentation depth, keeping the nesting of blocks.
+///
+/// Returns `None` when a line dedents to a width no enclosing block has, in
+/// which case the file keeps its indentation.
+fn indent_depths(lines: &[&str], info: &[LineInfo]) -> Option<Vec<usize>> {
+  let mut stack = vec![0];
+  let mut depths = vec![0; lines.len()];
+
+  for (idx, line) in lines.iter().enumerate() {
+    let trimmed = line.trim();
+    if info[idx].verbatim || trimmed.is_empty() {
+      continue;
+    }
+    let width = indent_width(line);
+
+    if is_comment_line(trimmed) {
+      // Comments follow the code around them without opening blocks.
+      let depth = stack.iter().rposition(|w| *w <= width).unwrap_or(0);
+      let deeper = width > *stack.last().unwrap();
+      depths[idx] = depth + deeper as usize;
+      continue;
+    }
+
+    if width > *stack.last().unwrap() {
+      stack.push(width);
+    } else {
+      while width < *stack.last().unwrap() {
+        stack.pop();
+      }
+      if width != *stack.last().unwrap() {
+        return None;
+      }
+    }
+    depths[idx] = stack.len() - 1;
+  }
+
+  Some(depths)
+}
+
+/// Normalises the spacing between the tokens of one line.
+///
+/// Runs of spaces become one space, commas are followed by a space, and
+/// the Rew forms `@{decorator}` and `package a.b.c` lose their inner spaces.
+fn format_line_content(content: &str) -> String {
+  let tokens = tokenize_coffee_script(content);
+  let mut output = String::new();
+  let is_package = tokens
+    .first()
+    .is_some_and(|t| t.token_type == "IDENTIFIER" && t.value == "package")
+    && tokens.get(2).is_some_and(|t| t.token_type == "IDENTIFIER");
+
+  for (idx, token) in tokens.iter().enumerate() {
+    let prev = idx.checked_sub(1).map(|i| &tokens[i]);
+    let next = tokens.get(idx + 1);
+
+    if token.token_type == "WHITESPACE" {
+      let Some(next) = next else {
+        continue;
+      };
+      let tight_decorator = prev.is_some_and(|p| p.value == "@") && next.value == "{";
+      let tight_package = is_package && idx > 1 && next.token_type != "COMMENT";
+      if !tight_decorator && !tight_package {
+        output.push(' ');
+      }
+      continue;
+    }
+
+    output.push_str(&token.value);
+
+    if token.value == ","
+      && next.is_some_and(|n| !matches!(n.value.as_str(), "," | ")" | "]" | "}" | ";"))
+      && next.is_some_and(|n| n.token_type != "WHITESPACE")
+    {
+      output.push(' ');
+    }
+  }
+
+  output
+}
+
+fn significant_tokens(source: &str) -> Vec<Token> {
+  tokenize_coffee_script(source)
+    .into_iter()
+    .filter(|t| t.token_type != "WHITESPACE")
+    .collect()
+}
+
+/// Formats Rew source: re-indents blocks by two spaces, normalises spacing,
+/// strips trailing whitespace and keeps at most one blank line in a row.
+///
+/// The token stream is left as it is, only whitespace between tokens
+/// changes.
+///
+/// # Arguments
+/// * `source` - The source to format.
+///
+/// # Returns
+/// * The formatted source, or an error if formatting would have changed a
+///   token.
+pub fn format_source(source: &str) -> Result<String> {
+  let source = source.replace("\r\n", "\n");
+  let tokens = tokenize_coffee_script(&source);
+  let info = analyze_lines(&source, &tokens);
+  let lines: Vec<&str> = source.split('\n').collect();
+  let depths = indent_depths(&lines, &info);
+
+  let mut output: Vec<String> = Vec::with_capacity(lines.len());
+  let mut blank_run = 0;
+
+  for (idx, line) in lines.iter().enumerate() {
+    let line_info = info[idx];
+    if line_info.verbatim {
+      blank_run = 0;
+      let line = if line_info.open_end {
+        line.to_string()
+      } else {
+        line.trim_end().to_string()
+      };
+      output.push(line);
+      continue;
+    }
+
+    let trimmed = line.trim_start();
+    if trimmed.trim().is_empty() {
+      blank_run += 1;
+      if blank_run == 1 && !output.is_empty() {
+        output.push(String::new());
+      }
+      continue;
+    }
+    blank_run = 0;
+
+    let indent = match &depths {
+      Some(depths) => INDENT.repeat(depths[idx]),
+      None => line[..line.len() - trimmed.len()].to_string(),
+    };
+    let content = if line_info.fragile {
+      if line_info.open_end {
+        trimmed.to_string()
+      } else {
+        trimmed.trim_end().to_string()
+      }
+    } else {
+      format_line_content(trimmed.trim_end())
+    };
+    output.push(format!("{}{}", indent, content));
+  }
+
+  while output.last().is_some_and(|line| line.is_empty()) {
+    output.pop();
+  }
+  let mut formatted = output.join("\n");
+  formatted.push('\n');
+
+  let before = significant_tokens(&source);
+  let after = significant_tokens(&formatted);
+  let same = before.len() == after.len()
+    && before
+      .iter()
+      .zip(&after)
+      .all(|(a, b)| a.token_type == b.token_type && a.value == b.value);
+  if !same {
+    anyhow::bail!("Formatting would change the code, leaving the file as it is");
+  }
+
+  Ok(formatted)
+}
+
+/// Checks that the formatted source compiles to the same JavaScript as the
+/// original, ignoring whitespace.
+///
+/// # Arguments
+/// * `path` - The file both sources belong to.
+/// * `original` - The source before formatting.
+/// * `formatted` - The source after formatting.
+pub async fn verify_round_trip(path: &Path, original: &str, formatted: &str) -> Result<()> {
+  let compile = |source: String| async move {
+    let mut runtime = RewRuntime::new(None, None)?;
+    runtime.compile_and_run(&source, path, true).await
+  };
+  let squash = |js: String| js.split_whitespace().collect::<String>();
+
+  let before = compile(original.to_string())
+    .await
+    .with_context(|| format!("{} does not compile, not formatting it", path.display()))?;
+  let after = compile(formatted.to_string()).await?;
+
+  if squash(before) != squash(after) {
+    anyhow::bail!(
+      "Formatting {} would change the compiled output, leaving it as it is",
+      path.display()
+    );
+  }
+  Ok(())
+}
+
+fn is_formattable(path: &Path) -> bool {
+  path
+    .extension()
+    .is_some_and(|ext| ext == "coffee" || ext == "civet" || ext == "rew")
+}
+
+/// Formats files in place, or only reports the ones that need formatting
+/// when `check` is set.
+///
+/// # Arguments
+/// * `paths` - Files and directories to format, the current directory when empty.
+/// * `check` - Don't write anything, only list unformatted files.
+///
+/// # Returns
+/// * `true` if every file was already formatted (with `check`) or was
+///   formatted without problems.
+pub async fn format_paths(paths: &[PathBuf], check: bool) -> Result<bool> {
+  let paths = if paths.is_empty() {
+    vec![std::env::current_dir()?]
+  } else {
+    paths.to_vec()
+  };
+
+  let mut files = Vec::new();
+  for path in paths {
+    if path.is_dir() {
+      collect_files(&path, &is_formattable, &mut files)
+        .with_context(|| format!("Failed to read directory {:?}", path))?;
+    } else {
+      files.push(path);
+    }
+  }
+
+  let mut changed = 0;
+  let mut written = 0;
+  let mut failed = 0;
+
+  for file in &files {
+    let source = fs::read_to_string(file).with_context(|| format!("Failed to read {:?}", file))?;
+    let formatted = match format_source(&source) {
+      Ok(formatted) => formatted,
+      Err(e) => {
+        eprintln!("{} {}: {}", "error".red().bold(), file.display(), e);
+        failed += 1;
+        continue;
+      }
+    };
+    if formatted == source {
+      continue;
+    }
+    changed += 1;
+
+    if check {
+      println!("{} {}", "unformatted".yellow(), file.display());
+      continue;
+    }
+
+    if let Err(e) = verify_round_trip(file, &source, &formatted).await {
+      eprintln!("{} {:#}", "error".red().bold(), e);
+      failed += 1;
+      continue;
+    }
+    fs::write(file, formatted).with_context(|| format!("Failed to write {:?}", file))?;
+    written += 1;
+    println!("{} {}", "formatted".green(), file.display());
+  }
+
+  if check {
+    println!("{} of {} file(s) need formatting", changed, files.len());
+    Ok(changed == 0 && failed == 0)
+  } else {
+    println!("Formatted {} of {} file(s)", written, files.len());
+    Ok(failed == 0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fmt(source: &str) -> String {
+    format_source(source).unwrap()
+  }
+
+  #[test]
+  fn reindents_blocks_by_two_spaces() {
+    let source = "if a\n    b()\n    if c\n        d()\n    e()\n";
+    assert_eq!(fmt(source), "if a\n  b()\n  if c\n    d()\n  e()\n");
+  }
+
+  #[test]
+  fn keeps_indentation_it_cannot_map_to_blocks() {
+    let source = "if a\n    b\n  c\n";
+    assert_eq!(fmt(source), source);
+  }
+
+  #[test]
+  fn normalises_spacing_and_blank_lines() {
+    let source = "f( 1,2 )   \r\n\n\n\nx  =   3\n\n";
+    assert_eq!(fmt(source), "f( 1, 2 )\n\nx = 3\n");
+  }
+
+  #[test]
+  fn keeps_declare_directives() {
+    let source = "#declare   \"x\"   =   y;\n#declare \"m\" =\n      foo   bar;\nx m\n";
+    assert_eq!(fmt(source), source);
+  }
+
+  #[test]
+  fn tightens_decorators_and_package_names() {
+    assert_eq!(fmt("@ {get: 1}\nclass X\n"), "@{get: 1}\nclass X\n");
+    assert_eq!(fmt("package  a . b . c\n"), "package a.b.c\n");
+    assert_eq!(fmt("package a.b # the app\n"), "package a.b # the app\n");
+  }
+
+  #[test]
+  fn leaves_fragile_and_verbatim_lines_alone() {
+    let source = [
+      "if a",
+      "    x = /a  b/",
+      "    y = \"\"\"",
+      "  keep   ",
+      "   this\"\"\"",
+      "    z = `t ${ a }  x`",
+      "###",
+      "   block   ",
+      "###",
+      "",
+    ]
+    .join("\n");
+    let expected = [
+      "if a",
+      "  x = /a  b/",
+      "  y = \"\"\"",
+      "  keep   ",
+      "   this\"\"\"",
+      "  z = `t ${ a }  x`",
+      "###",
+      "   block",
+      "###",
+      "",
+    ]
+    .join("\n");
+    assert_eq!(fmt(&source), expected);
+  }
+
+  #[test]
+  fn formatting_is_idempotent() {
+    let samples = [
+      "if a\n    b()   ,c\n    if  c\n        d( 1,2 )\n\n\n# comment\n",
+      "@ {get: 1}\nclass X\n      \npackage  a . b . c\n",
+      "x = /a  b/\ny = \"\"\"\n  keep   \n   this\"\"\"\nz = `t ${ a }  x`\n",
+      "#declare \"m\" =\n      foo   bar;\nif a\n      # note\n   m 1\n",
+    ];
+    for sample in samples {
+      let once = fmt(sample);
+      assert_eq!(fmt(&once), once, "formatting {:?} twice changed it", sample);
+    }
+  }
+
+  #[test]
+  fn refuses_changes_to_the_tokens() {
+    // Squeezing the names of a malformed package line would join them.
+    let err = format_source("package a b\n").unwrap_err();
+    assert!(err.to_string().contains("would change the code"));
+  }
+}
@@ -0,0 +1,321 @@
+use crate::runtime::check_read_permission;
+use deno_core::{
+  AsyncRefCell, CancelFuture, CancelHandle, OpState, RcRef, Resource, ResourceId, op2,
+};
+use deno_error::JsErrorBox;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+/// How long a path has to stay quiet before its events are emitted, unless
+/// the watcher was given its own `debounce`.
+const DEFAULT_DEBOUNCE_MS: u64 = 50;
+
+#[derive(Deserialize, Default)]
+pub struct WatchOptions {
+  #[serde(default)]
+  recursive: bool,
+  debounce: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum WatchEventKind {
+  Created,
+  Modified,
+  Removed,
+  Renamed,
+}
+
+/// A change as `fs.watch` reports it. Renames carry the old and the new path.
+#[derive(Serialize, Debug)]
+pub struct WatchEvent {
+  kind: WatchEventKind,
+  paths: Vec<PathBuf>,
+}
+
+impl WatchEvent {
+  /// Maps a `notify` event, or returns `None` for accesses and other events
+  /// that don't change anything.
+  fn from_notify(event: Event) -> Option<Self> {
+    let kind = match event.kind {
+      EventKind::Create(_) => WatchEventKind::Created,
+      EventKind::Remove(_) => WatchEventKind::Removed,
+      EventKind::Modify(ModifyKind::Name(RenameMode::From)) => WatchEventKind::Removed,
+      EventKind::Modify(ModifyKind::Name(RenameMode::To)) => WatchEventKind::Created,
+      EventKind::Modify(ModifyKind::Name(_)) => WatchEventKind::Renamed,
+      EventKind::Modify(_) => WatchEventKind::Modified,
+      _ => return None,
+    };
+    Some(Self {
+      kind,
+      paths: event.paths,
+    })
+  }
+}
+
+/// Folds the events of one debounce window into one event per path.
+///
+/// A file that is created and then written is reported as created, one that
+/// is replaced (removed and created again) as modified, and one that comes
+/// and goes within the window not at all.
+#[derive(Default)]
+struct EventBatch {
+  events: Vec<WatchEvent>,
+}
+
+impl EventBatch {
+  fn push(&mut self, event: WatchEvent) {
+    if event.kind == WatchEventKind::Renamed {
+      // Some backends report both halves of a rename before the rename itself.
+      self.events.retain(|pending| {
+        !(pending.kind != WatchEventKind::Renamed && event.paths.contains(&pending.paths[0]))
+      });
+      self.events.push(event);
+      return;
+    }
+
+    for path in event.paths {
+      self.push_path(event.kind, path);
+    }
+  }
+
+  fn push_path(&mut self, kind: WatchEventKind, path: PathBuf) {
+    let pending = self
+      .events
+      .iter()
+      .position(|pending| pending.kind != WatchEventKind::Renamed && pending.paths[0] == path);
+
+    let Some(index) = pending else {
+      self.events.push(WatchEvent {
+        kind,
+        paths: vec![path],
+      });
+      return;
+    };
+
+    let merged = match (self.events[index].kind, kind) {
+      (WatchEventKind::Created, WatchEventKind::Removed) => None,
+      (WatchEventKind::Created, _) => Some(WatchEventKind::Created),
+      (WatchEventKind::Removed, WatchEventKind::Created) => Some(WatchEventKind::Modified),
+      (_, kind) => Some(kind),
+    };
+
+    match merged {
+      Some(kind) => self.events[index].kind = kind,
+      None => {
+        self.events.remove(index);
+      }
+    }
+  }
+}
+
+/// A running watcher. Closing the resource stops it and ends any pending
+/// `op_fs_watch_next` with `null`.
+struct FsWatcherResource {
+  _watcher: RecommendedWatcher,
+  receiver: AsyncRefCell<UnboundedReceiver<WatchEvent>>,
+  debounce: Duration,
+  cancel: CancelHandle,
+}
+
+impl Resource for FsWatcherResource {
+  fn name(&self) -> Cow<'_, str> {
+    "fsWatcher".into()
+  }
+
+  fn close(self: Rc<Self>) {
+    self.cancel.cancel();
+  }
+}
+
+impl FsWatcherResource {
+  /// Waits for a change and then for the burst of events it causes to settle.
+  ///
+  /// # Returns
+  /// * The folded events, or `None` once the watcher has stopped.
+  async fn next(self: Rc<Self>) -> Option<Vec<WatchEvent>> {
+    let mut receiver = RcRef::map(&self, |r| &r.receiver).borrow_mut().await;
+    let cancel = RcRef::map(&self, |r| &r.cancel);
+
+    async {
+      let mut batch = EventBatch::default();
+      loop {
+        batch.push(receiver.recv().await?);
+        while let Ok(Some(event)) = tokio::time::timeout(self.debounce, receiver.recv()).await {
+          batch.push(event);
+        }
+        if !batch.events.is_empty() {
+          return Some(batch.events);
+        }
+      }
+    }
+    .or_cancel(cancel)
+    .await
+    .unwrap_or(None)
+  }
+}
+
+/// Starts watching `path` and returns the id of the watcher resource.
+#[op2]
+#[smi]
+pub fn op_fs_watch(
+  #[string] current_file: String,
+  #[string] path: String,
+  #[serde] options: Option<WatchOptions>,
+  state: Rc<RefCell<OpState>>,
+) -> Result<ResourceId, JsErrorBox> {
+  let current_file_path = Path::new(&current_file);
+  let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
+
+  let full_path = base_dir.join(path);
+  check_read_permission(&state, &full_path)?;
+
+  let options = options.unwrap_or_default();
+  let (tx, rx) = unbounded_channel();
+
+  let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+    if let Some(event) = res.ok().and_then(WatchEvent::from_notify) {
+      let _ = tx.send(event);
+    }
+  })
+  .map_err(|e| JsErrorBox::generic(e.to_string()))?;
+
+  let mode = if options.recursive {
+    RecursiveMode::Recursive
+  } else {
+    RecursiveMode::NonRecursive
+  };
+  watcher
+    .watch(&full_path, mode)
+    .map_err(|e| JsErrorBox::generic(format!("Failed to watch {:?}: {}", full_path, e)))?;
+
+  let resource = FsWatcherResource {
+    _watcher: watcher,
+    receiver: AsyncRefCell::new(rx),
+    debounce: Duration::from_millis(options.debounce.unwrap_or(DEFAULT_DEBOUNCE_MS)),
+    cancel: CancelHandle::new(),
+  };
+
+  Ok(state.borrow_mut().resource_table.add(resource))
+}
+
+/// Resolves with the next batch of changes, or `null` once the watcher is closed.
+#[op2(async)]
+#[serde]
+pub async fn op_fs_watch_next(
+  state: Rc<RefCell<OpState>>,
+  #[smi] rid: ResourceId,
+) -> Result<Option<Vec<WatchEvent>>, JsErrorBox> {
+  let resource = state
+    .borrow()
+    .resource_table
+    .get::<FsWatcherResource>(rid)
+    .map_err(JsErrorBox::from_err)?;
+
+  Ok(resource.next().await)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use deno_core::ResourceTable;
+
+  fn event(kind: WatchEventKind, paths: &[&str]) -> WatchEvent {
+    WatchEvent {
+      kind,
+      paths: paths.iter().map(PathBuf::from).collect(),
+    }
+  }
+
+  fn fold(events: Vec<WatchEvent>) -> Vec<(WatchEventKind, Vec<PathBuf>)> {
+    let mut batch = EventBatch::default();
+    for event in events {
+      batch.push(event);
+    }
+    batch
+      .events
+      .into_iter()
+      .map(|event| (event.kind, event.paths))
+      .collect()
+  }
+
+  #[test]
+  fn a_created_file_that_is_written_is_reported_as_created() {
+    let events = fold(vec![
+      event(WatchEventKind::Created, &["a.txt"]),
+      event(WatchEventKind::Modified, &["a.txt"]),
+    ]);
+    assert_eq!(events, [(WatchEventKind::Created, vec!["a.txt".into()])]);
+  }
+
+  #[test]
+  fn a_replaced_file_is_reported_as_modified() {
+    let events = fold(vec![
+      event(WatchEventKind::Removed, &["a.txt"]),
+      event(WatchEventKind::Created, &["a.txt"]),
+    ]);
+    assert_eq!(events, [(WatchEventKind::Modified, vec!["a.txt".into()])]);
+  }
+
+  #[test]
+  fn a_file_that_comes_and_goes_is_not_reported() {
+    let events = fold(vec![
+      event(WatchEventKind::Created, &["a.txt"]),
+      event(WatchEventKind::Modified, &["a.txt"]),
+      event(WatchEventKind::Removed, &["a.txt"]),
+      event(WatchEventKind::Modified, &["b.txt"]),
+    ]);
+    assert_eq!(events, [(WatchEventKind::Modified, vec!["b.txt".into()])]);
+  }
+
+  #[test]
+  fn renames_swallow_their_halves() {
+    let events = fold(vec![
+      event(WatchEventKind::Removed, &["old.txt"]),
+      event(WatchEventKind::Created, &["new.txt"]),
+      event(WatchEventKind::Renamed, &["old.txt", "new.txt"]),
+    ]);
+    assert_eq!(
+      events,
+      [(WatchEventKind::Renamed, vec!["old.txt".into(), "new.txt".into()])]
+    );
+  }
+
+  #[test]
+  fn closing_the_watcher_ends_a_pending_wait() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_tx, rx) = unbounded_channel();
+    let mut watcher = notify::recommended_watcher(|_: notify::Result<Event>| {}).unwrap();
+    watcher.watch(dir.path(), RecursiveMode::NonRecursive).unwrap();
+
+    let mut table = ResourceTable::default();
+    let rid = table.add(FsWatcherResource {
+      _watcher: watcher,
+      receiver: AsyncRefCell::new(rx),
+      debounce: Duration::from_millis(DEFAULT_DEBOUNCE_MS),
+      cancel: CancelHandle::new(),
+    });
+    let resource = table.get::<FsWatcherResource>(rid).unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .unwrap();
+    let next = runtime.block_on(async {
+      let next = resource.next();
+      let close = async {
+        tokio::task::yield_now().await;
+        table.take_any(rid).unwrap().close();
+      };
+      tokio::join!(next, close).0
+    });
+    assert!(next.is_none());
+  }
+}
@@ -1,12 +1,27 @@
+pub mod brew;
 pub mod builtins;
 mod civet;
 pub mod compiler;
 pub mod data_manager;
 mod declarations;
+pub mod diagnostics;
 pub mod ext;
+pub mod formatter;
+mod fs_watch;
 pub mod runtime;
 mod runtime_script;
+pub mod source_maps;
+pub mod test_runner;
+pub mod trust;
 // mod shell;
 mod jsx;
+pub mod linter;
+pub mod lsp;
+pub mod module_graph;
+pub mod permission_prompt;
+pub mod permissions;
+pub mod repl;
 mod utils;
+pub mod vfs;
+pub mod watch;
 mod workers;
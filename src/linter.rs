@@ -0,0 +1,560 @@
+use crate::builtins::BUILTIN_MODULES;
+use crate::compiler::{
+  Token, check_compiler_option, declaration_conditions_met, token_positions, tokenize_coffee_script,
+};
+use crate::declarations::{Declaration, DeclarationEngine};
+use crate::diagnostics::{RewDiagnostic, Severity, Span, did_you_mean};
+use crate::module_graph::{ImportStatement, resolve_import, scan_import_statements};
+use crate::utils::{collect_files, find_app_info};
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How a lint rule reports what it finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleLevel {
+  Off,
+  #[serde(alias = "warning")]
+  Warn,
+  Error,
+}
+
+/// A rule setting in `app.yaml`, either a level or `true`/`false` to keep
+/// the default level or turn the rule off.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RuleSetting {
+  Enabled(bool),
+  Level(RuleLevel),
+}
+
+/// The `lint:` section of `app.yaml`.
+///
+/// ```yaml
+/// lint:
+///   rules:
+///     unused-import: off
+///     unknown-compiler-option: error
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct LintConfig {
+  #[serde(default)]
+  pub rules: HashMap<String, RuleSetting>,
+}
+
+impl LintConfig {
+  /// The level `rule` runs at.
+  pub fn level(&self, rule: &LintRule) -> RuleLevel {
+    match self.rules.get(rule.name) {
+      Some(RuleSetting::Enabled(true)) | None => rule.default,
+      Some(RuleSetting::Enabled(false)) => RuleLevel::Off,
+      Some(RuleSetting::Level(level)) => *level,
+    }
+  }
+}
+
+pub struct LintRule {
+  pub name: &'static str,
+  pub description: &'static str,
+  pub default: RuleLevel,
+}
+
+pub const RULES: &[LintRule] = &[
+  LintRule {
+    name: "malformed-declaration",
+    description: "`#declare` directives the compiler can't parse",
+    default: RuleLevel::Error,
+  },
+  LintRule {
+    name: "unused-declaration",
+    description: "`#declare` rules whose trigger never matches in the file",
+    default: RuleLevel::Warn,
+  },
+  LintRule {
+    name: "unknown-onlyif-key",
+    description: "`ONLYIF` conditions with keys other than `prev` and `next`",
+    default: RuleLevel::Warn,
+  },
+  LintRule {
+    name: "unresolved-import",
+    description: "imports of files or apps that can't be found",
+    default: RuleLevel::Error,
+  },
+  LintRule {
+    name: "unknown-builtin",
+    description: "imports of `#std` modules that don't exist",
+    default: RuleLevel::Error,
+  },
+  LintRule {
+    name: "unused-import",
+    description: "imported names that are never used",
+    default: RuleLevel::Warn,
+  },
+  LintRule {
+    name: "unknown-compiler-option",
+    description: "`using compiler` options Civet does not recognise",
+    default: RuleLevel::Warn,
+  },
+];
+
+fn rule(name: &str) -> &'static LintRule {
+  RULES
+    .iter()
+    .find(|rule| rule.name == name)
+    .expect("lint rule is not registered")
+}
+
+/// Whether `rew lint` picks up a file when searching a directory.
+fn is_lintable(path: &Path) -> bool {
+  path
+    .extension()
+    .is_some_and(|ext| ext == "coffee" || ext == "civet" || ext == "rew")
+}
+
+/// Collects diagnostics for one file, applying the rule levels of its app.
+struct Linter<'a> {
+  path: &'a Path,
+  source: &'a str,
+  tokens: Vec<Token>,
+  positions: Vec<(usize, usize)>,
+  config: &'a LintConfig,
+  diagnostics: Vec<RewDiagnostic>,
+}
+
+impl<'a> Linter<'a> {
+  fn new(path: &'a Path, source: &'a str, config: &'a LintConfig) -> Self {
+    let tokens = tokenize_coffee_script(source);
+    let positions = token_positions(&tokens);
+    Self {
+      path,
+      source,
+      tokens,
+      positions,
+      config,
+      diagnostics: Vec::new(),
+    }
+  }
+
+  /// Records `diagnostic` under `rule`, unless the rule is turned off.
+  fn report(&mut self, rule_name: &str, diagnostic: RewDiagnostic) {
+    let severity = match self.config.level(rule(rule_name)) {
+      RuleLevel::Off => return,
+      RuleLevel::Warn => Severity::Warning,
+      RuleLevel::Error => Severity::Error,
+    };
+    let mut diagnostic = diagnostic
+      .with_code(rule_name)
+      .in_file(self.path, self.source);
+    diagnostic.severity = severity;
+    self.diagnostics.push(diagnostic);
+  }
+
+  fn check_declarations(&mut self) {
+    for site in DeclarationEngine::locate_declarations(self.source) {
+      let Some(declaration) = &site.declaration else {
+        self.report(
+          "malformed-declaration",
+          RewDiagnostic::error("Malformed declaration")
+            .with_span(site.span())
+            .with_hint("declarations look like `#declare \"trigger\" = replacement;`"),
+        );
+        continue;
+      };
+
+      for key in Declaration::unknown_onlyif_keys(&site.text) {
+        self.report(
+          "unknown-onlyif-key",
+          RewDiagnostic::warning(format!("Unknown `ONLYIF` key `{}`", key))
+            .with_span(site.span())
+            .with_hint("`ONLYIF` understands `prev` and `next`, other keys are ignored"),
+        );
+      }
+
+      // Global declarations are there for the files that import this one.
+      if site.is_global {
+        continue;
+      }
+
+      let mut seen = false;
+      let mut matched = false;
+      for (idx, token) in self.tokens.iter().enumerate() {
+        if token.token_type == "IDENTIFIER" && token.value == declaration.trigger {
+          seen = true;
+          if declaration_conditions_met(declaration, idx, &self.tokens) {
+            matched = true;
+            break;
+          }
+        }
+      }
+
+      if !matched {
+        let hint = if seen {
+          format!(
+            "`{}` is used, but never where its `ONLYIF` condition holds",
+            declaration.trigger
+          )
+        } else {
+          format!("nothing in this file is named `{}`", declaration.trigger)
+        };
+        self.report(
+          "unused-declaration",
+          RewDiagnostic::warning(format!(
+            "Declaration `{}` never matches",
+            declaration.trigger
+          ))
+          .with_span(site.span())
+          .with_hint(hint),
+        );
+      }
+    }
+  }
+
+  fn check_imports(&mut self) {
+    let imports = scan_import_statements(self.source);
+    let parent = self.path.parent().unwrap_or(Path::new(".")).to_path_buf();
+
+    for import in &imports {
+      let name = import.specifier.specifier.as_str();
+      let span = Span::new(import.line, import.column, import.length);
+
+      if name.starts_with('#') {
+        if !BUILTIN_MODULES.contains_key(name) {
+          let builtins: Vec<&str> = BUILTIN_MODULES.keys().copied().collect();
+          let mut diagnostic =
+            RewDiagnostic::error(format!("Unknown builtin module `{}`", name)).with_span(span);
+          if let Some(suggestion) = did_you_mean(name, &builtins) {
+            diagnostic = diagnostic.with_hint(format!("did you mean `{}`?", suggestion));
+          }
+          self.report("unknown-builtin", diagnostic);
+        }
+        continue;
+      }
+
+      let message = match resolve_import(&parent, &import.specifier) {
+        Ok(Some(_)) => continue,
+        Ok(None) => format!("Cannot resolve `{}`", name),
+        Err(e) => format!("{:#}", e),
+      };
+      self.report(
+        "unresolved-import",
+        RewDiagnostic::error(message).with_span(span),
+      );
+    }
+
+    self.check_unused_imports(&imports);
+  }
+
+  fn check_unused_imports(&mut self, imports: &[ImportStatement]) {
+    let declarations: Vec<String> = DeclarationEngine::locate_declarations(self.source)
+      .into_iter()
+      .filter_map(|site| site.declaration.map(|d| d.replacement))
+      .collect();
+
+    for import in imports {
+      // Preprocessed imports are there for their declarations.
+      if import.specifier.preprocess {
+        continue;
+      }
+
+      for (name, line, column) in &import.bindings {
+        let word = Regex::new(&format!(r"\b{}\b", regex::escape(name))).unwrap();
+        if !self.is_used(name, &word, imports, &declarations) {
+          self.report(
+            "unused-import",
+            RewDiagnostic::warning(format!("`{}` is imported but never used", name))
+              .with_span(Span::new(*line, *column, name.chars().count())),
+          );
+        }
+      }
+    }
+  }
+
+  /// Whether `name` is referenced anywhere outside of the import statements.
+  /// `word` matches `name` as a whole word, for text that isn't tokenized.
+  fn is_used(
+    &self,
+    name: &str,
+    word: &Regex,
+    imports: &[ImportStatement],
+    declarations: &[String],
+  ) -> bool {
+    let in_code = self.tokens.iter().enumerate().any(|(idx, token)| {
+      if imports.iter().any(|import| import.tokens.contains(&idx)) {
+        return false;
+      }
+      match token.token_type.as_str() {
+        "IDENTIFIER" => {
+          token.value == name
+            && self.tokens[..idx]
+              .iter()
+              .rev()
+              .find(|t| t.token_type != "WHITESPACE")
+              .is_none_or(|t| t.value != ".")
+        }
+        // Interpolations are part of the string token.
+        "STRING" | "TRIPLE_STRING" => token.value.contains("#{") && word.is_match(&token.value),
+        _ => false,
+      }
+    });
+
+    in_code
+      || declarations
+        .iter()
+        .any(|replacement| word.is_match(replacement))
+  }
+
+  fn check_compiler_options(&mut self) {
+    let code: Vec<usize> = (0..self.tokens.len())
+      .filter(|idx| {
+        let token_type = self.tokens[*idx].token_type.as_str();
+        token_type != "WHITESPACE" && token_type != "COMMENT"
+      })
+      .collect();
+    let value = |i: usize| code.get(i).map(|idx| self.tokens[*idx].value.as_str());
+
+    let mut found = Vec::new();
+    for i in 0..code.len() {
+      if value(i) != Some("using") || i > 0 && matches!(value(i - 1), Some("." | ":")) {
+        continue;
+      }
+      let mut next = i + 1;
+      if matches!(value(next), Some("pub" | "public")) {
+        next += 1;
+      }
+      if value(next) != Some("compiler") {
+        continue;
+      }
+      if let Some(&idx) = code.get(next + 1)
+        && self.tokens[idx].token_type == "IDENTIFIER"
+      {
+        let (line, column) = self.positions[idx];
+        found.extend(check_compiler_option(&self.tokens[idx].value, line, column));
+      }
+    }
+
+    for diagnostic in found {
+      self.report("unknown-compiler-option", diagnostic);
+    }
+  }
+}
+
+/// Lints one file.
+///
+/// # Arguments
+/// * `path` - The file the source was read from, imports resolve relative to it.
+/// * `source` - The contents of the file.
+/// * `config` - Rule levels to apply.
+///
+/// # Returns
+/// * The problems found, in the order the rules ran.
+pub fn lint_source(path: &Path, source: &str, config: &LintConfig) -> Vec<RewDiagnostic> {
+  let mut linter = Linter::new(path, source, config);
+  linter.check_declarations();
+  linter.check_imports();
+  linter.check_compiler_options();
+  linter.diagnostics
+}
+
+/// The `lint:` settings of the app `file` belongs to.
+pub fn config_for(file: &Path) -> LintConfig {
+  find_app_info(file)
+    .and_then(|app| app.config.lint)
+    .unwrap_or_default()
+}
+
+/// Warnings for rule names in `app.yaml` that `rew lint` doesn't have.
+fn check_config(app_path: &Path, config: &LintConfig) -> Vec<RewDiagnostic> {
+  let names: Vec<&str> = RULES.iter().map(|rule| rule.name).collect();
+  config
+    .rules
+    .keys()
+    .filter(|name| !names.contains(&name.as_str()))
+    .map(|name| {
+      let mut diagnostic = RewDiagnostic::warning(format!("Unknown lint rule `{}`", name));
+      diagnostic.file = Some(app_path.join("app.yaml"));
+      match did_you_mean(name, &names) {
+        Some(suggestion) => diagnostic.with_hint(format!("did you mean `{}`?", suggestion)),
+        None => diagnostic,
+      }
+    })
+    .collect()
+}
+
+/// Lints files and directories, using the `lint:` settings of the app each
+/// file belongs to.
+///
+/// # Arguments
+/// * `paths` - Files and directories to lint, the current directory when empty.
+///
+/// # Returns
+/// * Every diagnostic found, grouped by file.
+pub fn lint_paths(paths: &[PathBuf]) -> Result<Vec<RewDiagnostic>> {
+  let paths = if paths.is_empty() {
+    vec![std::env::current_dir()?]
+  } else {
+    paths.to_vec()
+  };
+
+  let mut files = Vec::new();
+  for path in paths {
+    if path.is_dir() {
+      collect_files(&path, &is_lintable, &mut files)
+        .with_context(|| format!("Failed to read directory {:?}", path))?;
+    } else {
+      files.push(path);
+    }
+  }
+
+  let mut diagnostics = Vec::new();
+  let mut configs: HashMap<PathBuf, LintConfig> = HashMap::new();
+  let default_config = LintConfig::default();
+
+  for file in files {
+    let file = file
+      .canonicalize()
+      .with_context(|| format!("File not found: {}", file.display()))?;
+    let source = fs::read_to_string(&file).with_context(|| format!("Failed to read {:?}", file))?;
+
+    let config = match find_app_info(&file) {
+      Some(app) => {
+        if !configs.contains_key(&app.path) {
+          let config = app.config.lint.unwrap_or_default();
+          diagnostics.extend(check_config(&app.path, &config));
+          configs.insert(app.path.clone(), config);
+        }
+        &configs[&app.path]
+      }
+      None => &default_config,
+    };
+
+    diagnostics.extend(lint_source(&file, &source, config));
+  }
+
+  Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lint(source: &str) -> Vec<RewDiagnostic> {
+    lint_source(Path::new("main.coffee"), source, &LintConfig::default())
+  }
+
+  /// The messages of the diagnostics `rule` produced.
+  fn messages(diagnostics: &[RewDiagnostic], rule: &str) -> Vec<String> {
+    diagnostics
+      .iter()
+      .filter(|diagnostic| diagnostic.code.as_deref() == Some(rule))
+      .map(|diagnostic| diagnostic.message.clone())
+      .collect()
+  }
+
+  #[test]
+  fn declarations_are_unused_unless_their_onlyif_condition_holds() {
+    let declare = "#declare \"greet\" = ONLYIF(prev=\"say\") console.log;\n";
+
+    let diagnostics = lint(&format!("{}say greet\n", declare));
+    assert!(messages(&diagnostics, "unused-declaration").is_empty());
+
+    let diagnostics = lint(&format!("{}greet \"hi\"\n", declare));
+    assert_eq!(
+      messages(&diagnostics, "unused-declaration"),
+      ["Declaration `greet` never matches"]
+    );
+    assert!(diagnostics[0].hints[0].contains("never where its `ONLYIF` condition holds"));
+
+    let diagnostics = lint(declare);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].hints, ["nothing in this file is named `greet`"]);
+  }
+
+  #[test]
+  fn onlyif_keys_other_than_prev_and_next_are_reported() {
+    let diagnostics = lint("#declare \"greet\" = ONLYIF(before=\"say\") console.log;\ngreet 1\n");
+    assert_eq!(
+      messages(&diagnostics, "unknown-onlyif-key"),
+      ["Unknown `ONLYIF` key `before`"]
+    );
+  }
+
+  #[test]
+  fn member_access_does_not_use_an_import() {
+    let diagnostics = lint("import { log } from \"./lib.coffee\"\nconsole.log \"hi\"\n");
+    assert_eq!(
+      messages(&diagnostics, "unused-import"),
+      ["`log` is imported but never used"]
+    );
+
+    let diagnostics = lint("import { log } from \"./lib.coffee\"\nlog \"hi\"\n");
+    assert!(messages(&diagnostics, "unused-import").is_empty());
+  }
+
+  #[test]
+  fn interpolations_use_an_import_but_plain_strings_do_not() {
+    let diagnostics = lint("import { name } from \"./lib.coffee\"\nprint \"hi #{name}\"\n");
+    assert!(messages(&diagnostics, "unused-import").is_empty());
+
+    let diagnostics = lint("import { name } from \"./lib.coffee\"\nprint \"hi name\"\n");
+    assert_eq!(
+      messages(&diagnostics, "unused-import"),
+      ["`name` is imported but never used"]
+    );
+  }
+
+  #[test]
+  fn declaration_replacements_use_an_import() {
+    let source = "import { shout } from \"./lib.coffee\"\n#declare \"yell\" = shout;\nyell 1\n";
+    assert!(messages(&lint(source), "unused-import").is_empty());
+  }
+
+  #[test]
+  fn unknown_builtins_suggest_the_closest_module() {
+    let diagnostics = lint("import \"#std.fss\"\nimport \"#std.nothing-like-it\"\n");
+    let unknown: Vec<&RewDiagnostic> = diagnostics
+      .iter()
+      .filter(|diagnostic| diagnostic.code.as_deref() == Some("unknown-builtin"))
+      .collect();
+    assert_eq!(unknown.len(), 2);
+    assert_eq!(unknown[0].message, "Unknown builtin module `#std.fss`");
+    assert_eq!(unknown[0].hints, ["did you mean `#std.fs`?"]);
+    assert!(unknown[1].hints.is_empty());
+    assert_eq!(unknown[0].severity, Severity::Error);
+  }
+
+  #[test]
+  fn rule_levels_come_from_app_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+      dir.path().join("app.yaml"),
+      "manifest:\n  package: demo\nlint:\n  rules:\n    unused-import: off\n    \
+       unused-declaration: error\n    unknown-builtin: true\n    unknown-builtn: warn\n",
+    )
+    .unwrap();
+    fs::write(
+      dir.path().join("main.coffee"),
+      "import { log } from \"./main.coffee\"\nimport \"#std.fss\"\n#declare \"greet\" = hi;\n",
+    )
+    .unwrap();
+
+    let diagnostics = lint_paths(&[dir.path().to_path_buf()]).unwrap();
+    let codes: Vec<(Option<&str>, &Severity)> = diagnostics
+      .iter()
+      .map(|diagnostic| (diagnostic.code.as_deref(), &diagnostic.severity))
+      .collect();
+    assert_eq!(
+      codes,
+      [
+        (None, &Severity::Warning),
+        (Some("unused-declaration"), &Severity::Error),
+        (Some("unknown-builtin"), &Severity::Error),
+      ]
+    );
+    assert_eq!(diagnostics[0].message, "Unknown lint rule `unknown-builtn`");
+    assert_eq!(diagnostics[0].hints, ["did you mean `unknown-builtin`?"]);
+  }
+}
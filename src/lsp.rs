@@ -0,0 +1,698 @@
+use crate::builtins::BUILTIN_MODULES;
+use crate::compiler::{Token, token_positions, tokenize_coffee_script};
+use crate::declarations::{Declaration, DeclarationEngine};
+use crate::diagnostics::{Diagnostics, RewDiagnostic, Severity};
+use crate::linter;
+use crate::module_graph::{ImportStatement, read_module, resolve_import, scan_import_statements};
+use crate::runtime::RewRuntime;
+use crate::utils::installed_apps;
+use anyhow::Result;
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+  DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+  Notification as LspNotification, PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as LspRequest};
+use lsp_types::{
+  CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+  CompletionTextEdit, Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams,
+  DidCloseTextDocumentParams, DidOpenTextDocumentParams, GotoDefinitionParams,
+  GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability, Location,
+  MarkupContent, MarkupKind, NumberOrString, OneOf, Position, PublishDiagnosticsParams, Range,
+  ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit, Url,
+};
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Namespaces the runtime defines on `rew` itself, next to one per `#std`
+/// module.
+const CORE_NAMESPACES: &[&str] = &[
+  "bootstrap",
+  "channel",
+  "env",
+  "io",
+  "mod",
+  "ns",
+  "process",
+  "ptr",
+  "vfile",
+];
+
+/// JSON-RPC error for requests the server doesn't handle.
+const METHOD_NOT_FOUND: i32 = -32601;
+
+fn server_capabilities() -> ServerCapabilities {
+  ServerCapabilities {
+    text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+    completion_provider: Some(CompletionOptions {
+      trigger_characters: Some(
+        ["#", ".", ":", "\"", "'"]
+          .iter()
+          .map(|c| c.to_string())
+          .collect(),
+      ),
+      ..Default::default()
+    }),
+    definition_provider: Some(OneOf::Left(true)),
+    hover_provider: Some(HoverProviderCapability::Simple(true)),
+    ..Default::default()
+  }
+}
+
+/// Runs the language server over stdin and stdout until the client exits.
+pub async fn run_stdio() -> Result<()> {
+  let (connection, io_threads) = Connection::stdio();
+  serve(connection).await?;
+  io_threads.join()?;
+  Ok(())
+}
+
+/// Runs the language server on `connection`, which can also be one end of
+/// `Connection::memory()` when driving the server from a test client.
+pub async fn serve(connection: Connection) -> Result<()> {
+  connection.initialize(serde_json::to_value(server_capabilities())?)?;
+
+  let mut server = LanguageServer {
+    connection: &connection,
+    documents: HashMap::new(),
+    runtime: None,
+  };
+
+  for message in &connection.receiver {
+    match message {
+      Message::Request(request) => {
+        if connection.handle_shutdown(&request)? {
+          return Ok(());
+        }
+        server.handle_request(request)?;
+      }
+      Message::Notification(notification) => server.handle_notification(notification).await?,
+      Message::Response(_) => {}
+    }
+  }
+
+  Ok(())
+}
+
+struct LanguageServer<'a> {
+  connection: &'a Connection,
+  /// Open documents and their current text.
+  documents: HashMap<Url, String>,
+  /// Compiles documents for diagnostics, created on first use.
+  runtime: Option<RewRuntime>,
+}
+
+impl LanguageServer<'_> {
+  fn send(&self, message: impl Into<Message>) -> Result<()> {
+    self.connection.sender.send(message.into())?;
+    Ok(())
+  }
+
+  fn handle_request(&mut self, request: Request) -> Result<()> {
+    let id = request.id.clone();
+    let result = match request.method.as_str() {
+      Completion::METHOD => {
+        let params: CompletionParams = serde_json::from_value(request.params)?;
+        serde_json::to_value(self.completion(params))?
+      }
+      GotoDefinition::METHOD => {
+        let params: GotoDefinitionParams = serde_json::from_value(request.params)?;
+        serde_json::to_value(self.definition(params))?
+      }
+      HoverRequest::METHOD => {
+        let params: HoverParams = serde_json::from_value(request.params)?;
+        serde_json::to_value(self.hover(params))?
+      }
+      method => {
+        return self.send(Response::new_err(
+          id,
+          METHOD_NOT_FOUND,
+          format!("Unhandled method {}", method),
+        ));
+      }
+    };
+    self.respond(id, result)
+  }
+
+  fn respond(&self, id: RequestId, result: serde_json::Value) -> Result<()> {
+    self.send(Response::new_ok(id, result))
+  }
+
+  async fn handle_notification(&mut self, notification: Notification) -> Result<()> {
+    match notification.method.as_str() {
+      DidOpenTextDocument::METHOD => {
+        let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+        let uri = params.text_document.uri;
+        self
+          .documents
+          .insert(uri.clone(), params.text_document.text);
+        self.publish_diagnostics(&uri).await?;
+      }
+      DidChangeTextDocument::METHOD => {
+        let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+        let uri = params.text_document.uri;
+        // Full sync, the last change holds the whole document.
+        if let Some(change) = params.content_changes.into_iter().last() {
+          self.documents.insert(uri.clone(), change.text);
+        }
+        self.publish_diagnostics(&uri).await?;
+      }
+      DidCloseTextDocument::METHOD => {
+        let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+        let uri = params.text_document.uri;
+        self.documents.remove(&uri);
+        self.send_diagnostics(uri, Vec::new())?;
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  fn send_diagnostics(&self, uri: Url, diagnostics: Vec<Diagnostic>) -> Result<()> {
+    let params = PublishDiagnosticsParams {
+      uri,
+      diagnostics,
+      version: None,
+    };
+    self.send(Notification::new(
+      PublishDiagnostics::METHOD.to_string(),
+      params,
+    ))
+  }
+
+  /// Lints and compiles a document and publishes what was found.
+  async fn publish_diagnostics(&mut self, uri: &Url) -> Result<()> {
+    let Some(text) = self.documents.get(uri).cloned() else {
+      return Ok(());
+    };
+    let path = document_path(uri);
+
+    let mut found = linter::lint_source(&path, &text, &linter::config_for(&path));
+    for diagnostic in self.compile_errors(&path, &text).await {
+      let duplicate = found
+        .iter()
+        .any(|d| d.span == diagnostic.span && d.message == diagnostic.message);
+      if !duplicate {
+        found.push(diagnostic);
+      }
+    }
+
+    let diagnostics = found
+      .iter()
+      .map(|diagnostic| to_lsp_diagnostic(&text, diagnostic))
+      .collect();
+    self.send_diagnostics(uri.clone(), diagnostics)
+  }
+
+  /// Errors the compile pipeline reports for a document.
+  async fn compile_errors(&mut self, path: &Path, text: &str) -> Vec<RewDiagnostic> {
+    if self.runtime.is_none() {
+      match RewRuntime::new(None, None) {
+        Ok(runtime) => self.runtime = Some(runtime),
+        Err(e) => return vec![RewDiagnostic::error(format!("{:#}", e))],
+      }
+    }
+    let runtime = self.runtime.as_mut().unwrap();
+    runtime.reset_compile_state();
+
+    match runtime.compile_and_run(text, path, true).await {
+      Ok(_) => Vec::new(),
+      Err(error) => match error.downcast::<Diagnostics>() {
+        Ok(diagnostics) => diagnostics.0,
+        Err(error) => match error.downcast::<RewDiagnostic>() {
+          Ok(diagnostic) => vec![diagnostic],
+          Err(error) => vec![RewDiagnostic::error(format!("{:#}", error))],
+        },
+      },
+    }
+  }
+
+  fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+    let position = params.text_document_position.position;
+    let text = self
+      .documents
+      .get(&params.text_document_position.text_document.uri)?;
+    let line = text.split('\n').nth(position.line as usize)?;
+    let prefix: String = char_prefix(line, position.character);
+
+    let string_re = Regex::new(r#"\b(import|imp|from)\b[^"']*["']([^"']*)$"#).unwrap();
+    let namespace_re = Regex::new(r"rew::([A-Za-z_]*)$").unwrap();
+
+    let (typed, labels, detail): (&str, Vec<String>, &str) =
+      if let Some(caps) = string_re.captures(&prefix) {
+        let typed = caps.get(2).unwrap().as_str();
+        if typed.starts_with('#') {
+          let mut builtins: Vec<String> = BUILTIN_MODULES.keys().map(|k| k.to_string()).collect();
+          builtins.sort();
+          (typed, builtins, "builtin module")
+        } else if typed.starts_with('.') || typed.starts_with('/') {
+          return None;
+        } else {
+          let mut packages: Vec<String> = installed_apps()
+            .into_iter()
+            .filter_map(|app| app.config.manifest?.package)
+            .collect();
+          packages.sort();
+          (typed, packages, "app package")
+        }
+      } else if let Some(caps) = namespace_re.captures(&prefix) {
+        let typed = caps.get(1).unwrap().as_str();
+        let mut namespaces: Vec<String> = CORE_NAMESPACES.iter().map(|n| n.to_string()).collect();
+        namespaces.extend(
+          BUILTIN_MODULES
+            .keys()
+            .filter_map(|k| k.strip_prefix("#std."))
+            .map(|n| n.to_string()),
+        );
+        namespaces.sort();
+        (typed, namespaces, "rew namespace")
+      } else {
+        return None;
+      };
+
+    let start = position.character - typed.encode_utf16().count() as u32;
+    let range = Range::new(Position::new(position.line, start), position);
+    let items = labels
+      .into_iter()
+      .filter(|label| label.starts_with(typed))
+      .map(|label| CompletionItem {
+        label: label.clone(),
+        kind: Some(CompletionItemKind::MODULE),
+        detail: Some(detail.to_string()),
+        text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(range, label))),
+        ..Default::default()
+      })
+      .collect();
+    Some(CompletionResponse::Array(items))
+  }
+
+  fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+    let uri = &params.text_document_position_params.text_document.uri;
+    let text = self.documents.get(uri)?;
+    let path = document_path(uri);
+    let (tokens, idx) = token_at(text, params.text_document_position_params.position)?;
+    let imports = scan_import_statements(text);
+
+    // The import whose path or binding is under the cursor, or that binds
+    // the identifier under the cursor.
+    let (import, name) = imports
+      .iter()
+      .find(|import| import.tokens.contains(&idx))
+      .map(|import| {
+        let name = import
+          .bindings
+          .iter()
+          .find(|(name, ..)| *name == tokens[idx].value)
+          .map(|(name, ..)| name.clone());
+        (import, name)
+      })
+      .or_else(|| {
+        let name = &tokens[idx].value;
+        imports
+          .iter()
+          .find(|import| import.bindings.iter().any(|(n, ..)| n == name))
+          .map(|import| (import, Some(name.clone())))
+      })?;
+
+    let target = resolve_target(&path, import)?;
+    let source = read_module(&target).ok()?;
+    let position = name
+      .and_then(|name| find_definition(&source, &name))
+      .unwrap_or_default();
+
+    Some(GotoDefinitionResponse::Scalar(Location::new(
+      Url::from_file_path(&target).ok()?,
+      Range::new(position, position),
+    )))
+  }
+
+  fn hover(&self, params: HoverParams) -> Option<Hover> {
+    let uri = &params.text_document_position_params.text_document.uri;
+    let text = self.documents.get(uri)?;
+    let path = document_path(uri);
+    let (tokens, idx) = token_at(text, params.text_document_position_params.position)?;
+    let token = &tokens[idx];
+    if token.token_type != "IDENTIFIER" {
+      return None;
+    }
+
+    let declaration = visible_declarations(&path, text)
+      .into_iter()
+      .find(|declaration| declaration.trigger == token.value)?;
+
+    let positions = token_positions(&tokens);
+    let (line, column) = positions[idx];
+    let start = lsp_position(text, line, column);
+    let end = lsp_position(text, line, column + token.value.chars().count());
+
+    Some(Hover {
+      contents: HoverContents::Markup(MarkupContent {
+        kind: MarkupKind::Markdown,
+        value: describe_declaration(&declaration),
+      }),
+      range: Some(Range::new(start, end)),
+    })
+  }
+}
+
+/// The path a document is compiled and resolved as.
+fn document_path(uri: &Url) -> PathBuf {
+  uri.to_file_path().unwrap_or_else(|_| {
+    std::env::current_dir()
+      .unwrap_or_default()
+      .join("untitled.coffee")
+  })
+}
+
+/// The characters of `line` before the UTF-16 offset `character`.
+fn char_prefix(line: &str, character: u32) -> String {
+  let mut units = 0;
+  line
+    .chars()
+    .take_while(|c| {
+      units += c.len_utf16() as u32;
+      units <= character
+    })
+    .collect()
+}
+
+/// Converts a 1-based line and character column into an LSP position.
+fn lsp_position(text: &str, line: usize, column: usize) -> Position {
+  let line_text = text.split('\n').nth(line.saturating_sub(1)).unwrap_or("");
+  let character: usize = line_text
+    .chars()
+    .take(column.saturating_sub(1))
+    .map(char::len_utf16)
+    .sum();
+  Position::new(line.saturating_sub(1) as u32, character as u32)
+}
+
+fn to_lsp_diagnostic(text: &str, diagnostic: &RewDiagnostic) -> Diagnostic {
+  let range = match diagnostic.span {
+    Some(span) => Range::new(
+      lsp_position(text, span.line, span.column),
+      lsp_position(text, span.end_line, span.end_column),
+    ),
+    None => Range::default(),
+  };
+  let mut message = diagnostic.message.clone();
+  for hint in &diagnostic.hints {
+    message.push_str(&format!("\nhint: {}", hint));
+  }
+
+  Diagnostic {
+    range,
+    severity: Some(match diagnostic.severity {
+      Severity::Error => DiagnosticSeverity::ERROR,
+      Severity::Warning => DiagnosticSeverity::WARNING,
+    }),
+    code: diagnostic.code.clone().map(NumberOrString::String),
+    source: Some("rew".to_string()),
+    message,
+    ..Default::default()
+  }
+}
+
+/// Tokenizes `text` and finds the token under `position`.
+fn token_at(text: &str, position: Position) -> Option<(Vec<Token>, usize)> {
+  let tokens = tokenize_coffee_script(text);
+  let positions = token_positions(&tokens);
+  let line = position.line as usize + 1;
+  let line_text = text.split('\n').nth(position.line as usize)?;
+  let column = char_prefix(line_text, position.character).chars().count() + 1;
+
+  let idx = tokens.iter().enumerate().position(|(idx, token)| {
+    let (start_line, start_column) = positions[idx];
+    let end = positions
+      .get(idx + 1)
+      .copied()
+      .unwrap_or((usize::MAX, usize::MAX));
+    (start_line, start_column) <= (line, column)
+      && (line, column) < end
+      && token.token_type != "WHITESPACE"
+  })?;
+  Some((tokens, idx))
+}
+
+/// The file an import points to, `None` for builtins and unresolved paths.
+fn resolve_target(document: &Path, import: &ImportStatement) -> Option<PathBuf> {
+  if import.specifier.specifier.starts_with('#') {
+    return None;
+  }
+  let parent = document.parent().unwrap_or(Path::new("."));
+  resolve_import(parent, &import.specifier)
+    .ok()
+    .flatten()
+    .map(|(path, _)| path)
+}
+
+/// Where `name` is exported or defined at the top level of `source`.
+fn find_definition(source: &str, name: &str) -> Option<Position> {
+  const DEFINERS: &[&str] = &["export", "function", "class", "const", "let", "var"];
+
+  let tokens = tokenize_coffee_script(source);
+  let positions = token_positions(&tokens);
+  let significant: Vec<usize> = (0..tokens.len())
+    .filter(|idx| tokens[*idx].token_type != "WHITESPACE")
+    .collect();
+
+  significant.iter().enumerate().find_map(|(i, &idx)| {
+    let token = &tokens[idx];
+    if token.token_type != "IDENTIFIER" || token.value != name {
+      return None;
+    }
+    let prev = i
+      .checked_sub(1)
+      .map(|p| tokens[significant[p]].value.as_str());
+    let next = significant.get(i + 1).map(|n| tokens[*n].value.as_str());
+    let starts_line = idx == 0 || tokens[idx - 1].value.contains('\n') || positions[idx].1 == 1;
+
+    let defined = prev.is_some_and(|p| DEFINERS.contains(&p))
+      || (starts_line && matches!(next, Some("=" | ":")));
+    defined.then(|| lsp_position(source, positions[idx].0, positions[idx].1))
+  })
+}
+
+/// Declarations in effect in a document: its own and those promoted by
+/// `import "...!"`.
+fn visible_declarations(path: &Path, text: &str) -> Vec<Declaration> {
+  let mut declarations: Vec<Declaration> = DeclarationEngine::locate_declarations(text)
+    .into_iter()
+    .filter_map(|site| site.declaration)
+    .collect();
+
+  for import in scan_import_statements(text) {
+    if !import.specifier.preprocess {
+      continue;
+    }
+    let target = if import.specifier.specifier.starts_with('#') {
+      Some(PathBuf::from(&import.specifier.specifier))
+    } else {
+      resolve_target(path, &import)
+    };
+    if let Some(source) = target.and_then(|target| read_module(&target).ok()) {
+      declarations.extend(
+        DeclarationEngine::locate_declarations(&source)
+          .into_iter()
+          .filter_map(|site| site.declaration),
+      );
+    }
+  }
+
+  declarations
+}
+
+fn describe_declaration(declaration: &Declaration) -> String {
+  let kind = if declaration.is_definition {
+    "definition"
+  } else if declaration.is_constructor {
+    "constructor"
+  } else if declaration.is_macro {
+    "macro"
+  } else {
+    "declaration"
+  };
+
+  let mut value = format!(
+    "**#declare** `{}` ({})\n\n```coffee\n{}\n```",
+    declaration.trigger, kind, declaration.replacement
+  );
+  if let Some(prev) = &declaration.condition_prev {
+    value.push_str(&format!("\n\nOnly after `{}`", prev));
+  }
+  if let Some(next) = &declaration.condition_next {
+    value.push_str(&format!("\n\nOnly before `{}`", next));
+  }
+  value
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lsp_types::notification::{Exit, Initialized};
+  use lsp_types::request::{Initialize, Shutdown};
+  use serde_json::json;
+  use std::fs;
+  use std::thread::JoinHandle;
+  use std::time::Duration;
+
+  /// Talks to `serve` over an in-memory connection, the way an editor does.
+  struct Client {
+    connection: Connection,
+    server: JoinHandle<Result<()>>,
+    next_id: i32,
+  }
+
+  impl Client {
+    fn start() -> Self {
+      let (server, connection) = Connection::memory();
+      let server = std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+          .enable_all()
+          .build()?
+          .block_on(serve(server))
+      });
+      let mut client = Self {
+        connection,
+        server,
+        next_id: 0,
+      };
+      let result = client.request::<Initialize>(json!({ "capabilities": {} }));
+      assert!(result["capabilities"]["hoverProvider"].as_bool().unwrap());
+      client.notify::<Initialized>(json!({}));
+      client
+    }
+
+    fn recv(&self) -> Message {
+      self
+        .connection
+        .receiver
+        .recv_timeout(Duration::from_secs(60))
+        .expect("the server stopped answering")
+    }
+
+    fn request<R: LspRequest>(&mut self, params: serde_json::Value) -> serde_json::Value {
+      self.next_id += 1;
+      let id = RequestId::from(self.next_id);
+      let request = Request::new(id.clone(), R::METHOD.to_string(), params);
+      self.connection.sender.send(request.into()).unwrap();
+      loop {
+        match self.recv() {
+          Message::Response(response) if response.id == id => {
+            assert!(response.error.is_none(), "{:?}", response.error);
+            return response.result.unwrap_or_default();
+          }
+          _ => {}
+        }
+      }
+    }
+
+    fn notify<N: LspNotification>(&self, params: serde_json::Value) {
+      let notification = Notification::new(N::METHOD.to_string(), params);
+      self.connection.sender.send(notification.into()).unwrap();
+    }
+
+    /// Waits for the diagnostics the server publishes for `uri`.
+    fn diagnostics(&self, uri: &Url) -> Vec<Diagnostic> {
+      loop {
+        match self.recv() {
+          Message::Notification(notification)
+            if notification.method == PublishDiagnostics::METHOD =>
+          {
+            let params: PublishDiagnosticsParams =
+              serde_json::from_value(notification.params).unwrap();
+            if &params.uri == uri {
+              return params.diagnostics;
+            }
+          }
+          _ => {}
+        }
+      }
+    }
+
+    fn finish(mut self) {
+      self.request::<Shutdown>(serde_json::Value::Null);
+      self.notify::<Exit>(serde_json::Value::Null);
+      self.server.join().unwrap().unwrap();
+    }
+  }
+
+  fn position(uri: &Url, line: u32, character: u32) -> serde_json::Value {
+    json!({
+      "textDocument": { "uri": uri },
+      "position": { "line": line, "character": character },
+    })
+  }
+
+  #[test]
+  fn editor_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    let lib = dir.join("lib.coffee");
+    fs::write(&lib, "export greet = (name) -> \"hi \" + name\n").unwrap();
+    let uri = Url::from_file_path(dir.join("main.coffee")).unwrap();
+    let text = [
+      "#declare \"shout\" = print;",
+      "import { greet } from \"./lib.coffee\"",
+      "import \"./missing.coffee\"",
+      "shout greet \"rew\"",
+      "rew::i",
+      "",
+    ]
+    .join("\n");
+
+    let mut client = Client::start();
+    client.notify::<DidOpenTextDocument>(json!({
+      "textDocument": { "uri": uri, "languageId": "coffeescript", "version": 1, "text": text },
+    }));
+    let diagnostics = client.diagnostics(&uri);
+    let unresolved = diagnostics
+      .iter()
+      .find(|d| d.code == Some(NumberOrString::String("unresolved-import".to_string())))
+      .expect("no diagnostic for the missing import");
+    assert_eq!(unresolved.range.start.line, 2);
+    assert_eq!(unresolved.severity, Some(DiagnosticSeverity::ERROR));
+
+    let completion: CompletionResponse =
+      serde_json::from_value(client.request::<Completion>(position(&uri, 4, 6))).unwrap();
+    let CompletionResponse::Array(items) = completion else {
+      panic!("expected a list of completions");
+    };
+    let labels: Vec<&str> = items.iter().map(|item| item.label.as_str()).collect();
+    assert!(labels.contains(&"io"), "{:?}", labels);
+    assert!(labels.iter().all(|label| label.starts_with('i')));
+
+    let definition: GotoDefinitionResponse =
+      serde_json::from_value(client.request::<GotoDefinition>(position(&uri, 3, 8))).unwrap();
+    let GotoDefinitionResponse::Scalar(location) = definition else {
+      panic!("expected one location");
+    };
+    assert_eq!(location.uri, Url::from_file_path(&lib).unwrap());
+    assert_eq!(location.range.start, Position::new(0, 7));
+
+    let hover: Hover =
+      serde_json::from_value(client.request::<HoverRequest>(position(&uri, 3, 2))).unwrap();
+    let HoverContents::Markup(markup) = hover.contents else {
+      panic!("expected markdown");
+    };
+    assert!(markup.value.starts_with("**#declare** `shout`"), "{}", markup.value);
+    assert_eq!(hover.range.unwrap().start, Position::new(3, 0));
+
+    client.notify::<DidCloseTextDocument>(json!({ "textDocument": { "uri": uri } }));
+    assert!(client.diagnostics(&uri).is_empty());
+    client.finish();
+  }
+
+  #[test]
+  fn unknown_requests_are_answered_with_an_error() {
+    let client = Client::start();
+    let request = Request::new(RequestId::from(99), "rew/unknown".to_string(), json!({}));
+    client.connection.sender.send(request.into()).unwrap();
+    let Message::Response(response) = client.recv() else {
+      panic!("expected a response");
+    };
+    assert_eq!(response.error.unwrap().code, METHOD_NOT_FOUND);
+    client.finish();
+  }
+}
@@ -1,21 +1,38 @@
 use clap::{Parser, Subcommand};
 use colored::*;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use tokio::task::LocalSet;
 
+mod brew;
 pub mod builtins;
 mod civet;
 mod compiler;
 pub mod data_manager;
 mod declarations;
+mod diagnostics;
 pub mod ext;
+mod formatter;
+mod fs_watch;
 pub mod runtime;
 mod runtime_script;
+mod source_maps;
+mod test_runner;
+mod trust;
 // mod shell;
 mod jsx;
+mod linter;
+mod lsp;
+mod module_graph;
+mod permission_prompt;
+mod permissions;
+mod repl;
 mod utils;
+pub mod vfs;
+mod watch;
 mod workers;
+use permissions::{PermissionFlags, RewPermissions};
 use runtime::RewRuntime;
 
 /// Ensures that necessary directories for the Rew runtime exist.
@@ -47,13 +64,36 @@ fn ensure_rew_dirs() -> anyhow::Result<()> {
   Ok(())
 }
 
+/// Runs a file once, or keeps restarting it on changes when `watch` is set.
+async fn run_file(
+  file: &Path,
+  args: &[String],
+  watch: bool,
+  flags: &PermissionFlags,
+) -> anyhow::Result<()> {
+  if watch {
+    return watch::run_watched(file, args, flags).await;
+  }
+
+  let permissions = RewPermissions::for_entry(flags, file)?;
+  let mut runtime = RewRuntime::with_permissions(Some(args.to_vec()), permissions)?;
+  runtime.run_file(file).await
+}
+
 #[derive(Parser)]
 #[command(name = "rew")]
 #[command(version = env!("CARGO_PKG_VERSION"),)]
 #[command(about = "A Rust-based Rew runtime using deno_core")]
 struct Cli {
   #[command(subcommand)]
-  command: Commands,
+  command: Option<Commands>,
+
+  #[arg(
+    long,
+    global = true,
+    help = "Print diagnostics as JSON lines on stderr"
+  )]
+  json: bool,
 }
 
 #[derive(Subcommand)]
@@ -62,12 +102,15 @@ enum Commands {
     #[arg(name = "FILE")]
     file: PathBuf,
 
-    #[arg(short, long)]
+    #[arg(short, long, help = "Restart when a file in the import graph changes")]
     watch: bool,
 
     #[arg(short, long, help = "Specify an entry point for app packages")]
     entry: Option<String>,
 
+    #[command(flatten)]
+    permissions: Box<PermissionFlags>,
+
     #[arg(trailing_var_arg = true)]
     args: Vec<String>,
   },
@@ -75,9 +118,14 @@ enum Commands {
     #[arg(name = "FILE")]
     file: PathBuf,
   },
+  #[command(about = "Start an interactive session (the default without a subcommand)")]
+  Repl,
   Exec {
-    #[arg(name = "CODE")]
+    #[arg(name = "CODE", help = "Code to run, or `-` to read it from stdin")]
     code: String,
+
+    #[arg(trailing_var_arg = true)]
+    args: Vec<String>,
   },
   Brew {
     #[arg(name = "FILE")]
@@ -95,6 +143,105 @@ enum Commands {
       help = "Specify an entry file different from the main file"
     )]
     entry: Option<PathBuf>,
+
+    #[arg(short, long, help = "Compress the modules stored in the brew")]
+    compress: bool,
+
+    #[arg(
+      long,
+      value_name = "KEY",
+      help = "Sign the brew with a key file or a key name"
+    )]
+    sign: Option<String>,
+
+    #[arg(
+      long,
+      value_name = "OUTPUT",
+      help = "Build a standalone executable instead of a .brew file"
+    )]
+    exe: Option<PathBuf>,
+
+    #[arg(
+      long,
+      requires = "exe",
+      help = "Executable to embed the brew into, rew itself by default"
+    )]
+    stub: Option<PathBuf>,
+  },
+  #[command(about = "Manage the keys brews are signed with")]
+  Key {
+    #[command(subcommand)]
+    action: KeyCommand,
+  },
+  #[command(about = "Manage the keys and policy signed brews are checked against")]
+  Trust {
+    #[command(subcommand)]
+    action: TrustCommand,
+  },
+  #[command(about = "Format .coffee, .civet and .rew files in place")]
+  Fmt {
+    #[arg(name = "PATHS")]
+    paths: Vec<PathBuf>,
+
+    #[arg(long, help = "Only report files that aren't formatted, exit 1 if there are any")]
+    check: bool,
+  },
+  #[command(about = "Start the language server on stdin and stdout")]
+  Lsp,
+  #[command(about = "Check Rew files for directive, import and compiler option mistakes")]
+  Lint {
+    #[arg(name = "PATHS")]
+    paths: Vec<PathBuf>,
+
+    #[arg(long, help = "List the available rules and their default levels")]
+    rules: bool,
+  },
+  #[command(about = "Run *.test.coffee and *_test.rew files, or the app's test entry")]
+  Test {
+    #[arg(name = "PATHS")]
+    paths: Vec<PathBuf>,
+
+    #[arg(long, help = "Only run tests whose name contains this, or matches /regex/")]
+    filter: Option<String>,
+
+    #[arg(long, help = "Stop after the first failing test")]
+    bail: bool,
+
+    #[arg(long, help = "Write a JUnit XML report to this path")]
+    junit: Option<PathBuf>,
+  },
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+  #[command(about = "Create a signing key under the rew config directory")]
+  Generate {
+    #[arg(name = "NAME")]
+    name: String,
+  },
+}
+
+#[derive(Subcommand)]
+enum TrustCommand {
+  #[command(about = "Trust brews signed by a public key")]
+  Add {
+    #[arg(name = "NAME")]
+    name: String,
+
+    #[arg(name = "PUBLIC_KEY")]
+    key: String,
+  },
+  #[command(about = "Stop trusting a key")]
+  Remove {
+    #[arg(name = "NAME")]
+    name: String,
+  },
+  #[command(about = "Show the trust policy and trusted keys")]
+  List,
+  #[command(about = "Choose what happens to brews not signed by a trusted key")]
+  Policy {
+    #[arg(value_enum)]
+    policy: trust::TrustPolicy,
   },
 }
 
@@ -104,131 +251,280 @@ fn main() -> anyhow::Result<()> {
     .enable_all()
     .build()?
     .block_on(local.run_until(async {
+      // An executable built with `rew brew --exe` runs its brew instead of
+      // the CLI. Only one whose trailer says so is read any further.
+      let exe = std::env::current_exe()
+        .ok()
+        .filter(|exe| brew::has_exe_payload(exe));
+      if let Some(exe) = exe {
+        let payload = brew::read_exe_payload(&exe)?
+          .ok_or_else(|| anyhow::anyhow!("The embedded brew of {:?} is missing", exe))?;
+        ensure_rew_dirs()?;
+        let args = runtime::RuntimeArgs::from_env();
+        if let Err(error) = brew::run_exe_payload(&exe, &payload, args).await {
+          diagnostics::report_error(&error);
+          std::process::exit(1);
+        }
+        return Ok(());
+      }
+
       // let cli = Cli::parse_from(["rew", "run", "./test/imp.coffee"]);
       let cli = Cli::parse();
 
       // Ensure Rew directories exist
       ensure_rew_dirs()?;
+      diagnostics::set_json_output(cli.json);
 
-      match &cli.command {
-        Commands::Run {
-          file,
-          watch: _,
-          entry,
-          args,
-        } => {
-          if file.is_dir() {
-            let app_yaml = file.join("app.yaml");
-            if app_yaml.exists() {
-              let entry_point = if let Some(entry_name) = entry {
-                entry_name
-              } else {
-                &"main".to_string()
-              };
-
-              // Read app.yaml to find the entry file
-              if let Ok(config_str) = fs::read_to_string(&app_yaml) {
-                if let Ok(config) = serde_yaml::from_str::<utils::AppConfig>(&config_str) {
-                  if let Some(entries) = config.entries {
-                    if let Some(entry_file) = entries.get(&entry_point.clone()) {
-                      let full_path = file.join(entry_file);
-
-                      let mut runtime = RewRuntime::new(Some(args.clone()), None)?;
-                      runtime.run_file(&full_path).await?;
-                      return Ok(());
-                    }
-                  }
+      if let Err(error) = run_command(&cli).await {
+        diagnostics::report_error(&error);
+        std::process::exit(1);
+      }
+      Ok(())
+    }))
+}
+
+/// Runs the command given on the command line.
+async fn run_command(cli: &Cli) -> anyhow::Result<()> {
+  match &cli.command {
+    None | Some(Commands::Repl) => {
+      repl::Repl::new(None).await?.run().await?;
+    }
+    Some(Commands::Run {
+      file,
+      watch,
+      entry,
+      permissions,
+      args,
+    }) => {
+      if file.is_dir() {
+        let app_yaml = file.join("app.yaml");
+        if app_yaml.exists() {
+          let entry_point = if let Some(entry_name) = entry {
+            entry_name
+          } else {
+            &"main".to_string()
+          };
+
+          // Read app.yaml to find the entry file
+          if let Ok(config_str) = fs::read_to_string(&app_yaml) {
+            if let Ok(config) = serde_yaml::from_str::<utils::AppConfig>(&config_str) {
+              if let Some(entries) = config.entries {
+                if let Some(entry_file) = entries.get(&entry_point.clone()) {
+                  let full_path = file.join(entry_file);
+
+                  run_file(&full_path, args, *watch, permissions).await?;
+                  return Ok(());
                 }
               }
-              println!("Failed to find entry point in app.yaml");
-            } else {
-              println!("No app.yaml found in directory");
-            }
-          } else if !file.exists()
-            && !file.to_string_lossy().contains('/')
-            && !file.to_string_lossy().contains('\\')
-          {
-            let package_name = file.to_string_lossy().to_string();
-
-            let entry_name = entry.as_deref().unwrap_or("main");
-
-            if let Some(app_entry) = utils::resolve_app_entry(&package_name, Some(entry_name)) {
-              if utils::is_valid_utf8(app_entry.clone())? {
-                let mut runtime = RewRuntime::new(Some(args.clone()), None)?;
-                runtime.run_file(&app_entry).await?;
-                return Ok(());
-              } else {
-                println!("App running binary");
-                std::process::Command::new(app_entry.to_string_lossy().to_string())
-                  .args(args.clone())
-                  .stdout(std::process::Stdio::inherit())
-                  .stderr(std::process::Stdio::inherit())
-                  .stdin(std::process::Stdio::inherit())
-                  .spawn()
-                  .expect("Failed to start process")
-                  .wait()
-                  .expect("Failed to wait on child");
-              }
-            } else {
-              println!("App package not found: {}", package_name.red());
             }
-          } else {
-            let mut runtime = RewRuntime::new(Some(args.clone()), None)?;
-            runtime.run_file(file).await?;
           }
+          println!("Failed to find entry point in app.yaml");
+        } else {
+          println!("No app.yaml found in directory");
         }
-        Commands::Compile { file } => {
-          let mut runtime = RewRuntime::new(None, None)?;
-          let content = fs::read_to_string(file)?;
-          let f = runtime.compile_and_run(&content, file, true).await?;
-          println!("{}", f);
+      } else if !file.exists()
+        && !file.to_string_lossy().contains('/')
+        && !file.to_string_lossy().contains('\\')
+      {
+        let package_name = file.to_string_lossy().to_string();
+
+        let entry_name = entry.as_deref().unwrap_or("main");
+
+        if let Some(app_entry) = utils::resolve_app_entry(&package_name, Some(entry_name)) {
+          let is_brew = fs::read(&app_entry).is_ok_and(|bytes| brew::is_brew_container(&bytes));
+          if is_brew || utils::is_valid_utf8(app_entry.clone())? {
+            run_file(&app_entry, args, *watch, permissions).await?;
+            return Ok(());
+          } else {
+            println!("App running binary");
+            std::process::Command::new(app_entry.to_string_lossy().to_string())
+              .args(args.clone())
+              .stdout(std::process::Stdio::inherit())
+              .stderr(std::process::Stdio::inherit())
+              .stdin(std::process::Stdio::inherit())
+              .spawn()
+              .expect("Failed to start process")
+              .wait()
+              .expect("Failed to wait on child");
+          }
+        } else {
+          println!("App package not found: {}", package_name.red());
         }
-        Commands::Exec { code } => {
-          println!("Executing code: {}", code.blue());
-          // TODO: Implement code execution
-          // let mut runtime = RewRuntime::new()?;
-          // TODO: Add a method to execute code directly
+      } else {
+        run_file(file, args, *watch, permissions).await?;
+      }
+    }
+    Some(Commands::Compile { file }) => {
+      let mut runtime = RewRuntime::new(None, None)?;
+      let content = fs::read_to_string(file)?;
+      let f = runtime.compile_and_run(&content, file, true).await?;
+      println!("{}", f);
+    }
+    Some(Commands::Exec { code, args }) => {
+      let code = if code == "-" {
+        let mut buffer = String::new();
+        std::io::stdin().read_to_string(&mut buffer)?;
+        buffer
+      } else {
+        code.clone()
+      };
+
+      let mut runtime = RewRuntime::new(Some(args.clone()), None)?;
+      runtime.run_code(&code).await?;
+    }
+    Some(Commands::Brew {
+      file,
+      output,
+      bundle_all,
+      entry,
+      compress,
+      sign,
+      exe,
+      stub,
+    }) => {
+      if let Some(file_path) = file {
+        println!(
+          "Building file: {} to {}",
+          file_path.display().to_string().green(),
+          exe.as_ref().unwrap_or(output).display().to_string().green()
+        );
+
+        if *bundle_all {
+          println!("Including all apps in build");
+        } else {
+          println!("Including only the main app in build");
         }
-        Commands::Brew {
-          file,
-          output,
-          bundle_all,
-          entry,
-        } => {
-          if let Some(file_path) = file {
-            println!(
-              "Building file: {} to {}",
-              file_path.display().to_string().green(),
-              output.display().to_string().green()
-            );
-
-            if *bundle_all {
-              println!("Including all apps in build");
-            } else {
-              println!("Including only the main app in build");
-            }
 
-            if let Some(entry_path) = entry {
-              println!(
-                "Using custom entry: {}",
-                entry_path.display().to_string().yellow()
-              );
-            }
+        if let Some(entry_path) = entry {
+          println!(
+            "Using custom entry: {}",
+            entry_path.display().to_string().yellow()
+          );
+        }
 
-            let mut runtime = RewRuntime::new(None, None)?;
+        let mut runtime = RewRuntime::new(None, None)?;
 
-            let options = runtime::BuildOptions {
-              bundle_all: *bundle_all,
-              entry_file: entry.clone(),
-            };
+        let options = runtime::BuildOptions {
+          bundle_all: *bundle_all,
+          entry_file: entry.clone(),
+          compress: *compress,
+          sign: sign.clone(),
+        };
 
-            let output_string = runtime.build_file(file_path, options).await?;
+        let output_bytes = runtime.build_file(file_path, options).await?;
 
-            fs::write(output, output_string.clone())?;
+        if let Some(exe_path) = exe {
+          let stub_path = match stub {
+            Some(stub) => stub.clone(),
+            None => std::env::current_exe()?,
+          };
+          let stub_bytes = fs::read(&stub_path)
+            .map_err(|e| anyhow::anyhow!("Failed to read stub {:?}: {}", stub_path, e))?;
+          brew::write_executable(exe_path, &stub_bytes, &output_bytes)?;
+        } else {
+          fs::write(output, output_bytes)?;
+        }
+      }
+      println!("Building complete");
+    }
+    Some(Commands::Key {
+      action: KeyCommand::Generate { name },
+    }) => {
+      let (path, public_key) = trust::generate_key(name)?;
+      println!("Created signing key {}", path.display().to_string().green());
+      println!("Public key: {}", public_key);
+      println!(
+        "Trust it elsewhere with: {}",
+        format!("rew trust add {} {}", name, public_key).cyan()
+      );
+    }
+    Some(Commands::Trust { action }) => {
+      let mut store = trust::TrustStore::load()?;
+      match action {
+        TrustCommand::Add { name, key } => {
+          store.add(name, key)?;
+          store.save()?;
+          println!("Trusting brews signed by {}", name.green());
+        }
+        TrustCommand::Remove { name } => {
+          if !store.remove(name) {
+            anyhow::bail!("No trusted key named {}", name);
+          }
+          store.save()?;
+          println!("Removed {}", name);
+        }
+        TrustCommand::List => {
+          println!(
+            "policy: {}",
+            format!("{:?}", store.policy).to_lowercase().cyan()
+          );
+          for trusted in &store.keys {
+            println!("{:<20} {}", trusted.name.green(), trusted.key);
           }
-          println!("Building complete");
+        }
+        TrustCommand::Policy { policy } => {
+          store.policy = *policy;
+          store.save()?;
+          println!(
+            "Trust policy set to {}",
+            format!("{:?}", policy).to_lowercase().cyan()
+          );
         }
       }
-      Ok(())
-    }))
+    }
+    Some(Commands::Lsp) => lsp::run_stdio().await?,
+    Some(Commands::Fmt { paths, check }) => {
+      if !formatter::format_paths(paths, *check).await? {
+        std::process::exit(1);
+      }
+    }
+    Some(Commands::Lint { rules: true, .. }) => {
+      for rule in linter::RULES {
+        println!(
+          "{:<26} {:<6} {}",
+          rule.name.cyan(),
+          format!("{:?}", rule.default).to_lowercase(),
+          rule.description
+        );
+      }
+    }
+    Some(Commands::Lint { paths, .. }) => {
+      let found = linter::lint_paths(paths)?;
+      found.iter().for_each(diagnostics::emit);
+
+      let errors = found.iter().filter(|d| d.is_error()).count();
+      if !diagnostics::json_output() {
+        let warnings = found.len() - errors;
+        if found.is_empty() {
+          println!("{}", "No problems found".green());
+        } else {
+          eprintln!(
+            "{} error(s), {} warning(s)",
+            errors.to_string().red().bold(),
+            warnings.to_string().yellow().bold()
+          );
+        }
+      }
+      if errors > 0 {
+        std::process::exit(1);
+      }
+    }
+    Some(Commands::Test {
+      paths,
+      filter,
+      bail,
+      junit,
+    }) => {
+      let options = test_runner::TestOptions {
+        filter: filter.clone(),
+        bail: *bail,
+        junit: junit.clone(),
+      };
+      let summary = test_runner::run_tests(paths, &options).await?;
+      if summary.failed() > 0 {
+        std::process::exit(1);
+      }
+    }
+  }
+  Ok(())
 }
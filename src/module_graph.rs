@@ -0,0 +1,615 @@
+use crate::brew::read_brew;
+use crate::builtins::BUILTIN_MODULES;
+use crate::compiler::{Token, token_positions, tokenize_coffee_script};
+use crate::runtime::{get_storage_path, get_virtual_file, is_virtual_file};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// How a module ended up in the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleKind {
+  /// A file reached through a relative or absolute path, including the entry.
+  Relative,
+  /// A `#std` builtin bundled into the binary.
+  Builtin,
+  /// An entry of an installed app package.
+  App,
+  /// A prebuilt `.brew`/`.qrew` bundle.
+  Brew,
+}
+
+/// An import as written in the source, with the `!` suffix stripped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportSpecifier {
+  pub specifier: String,
+  /// The import ended with `!`, so its declarations become global.
+  pub preprocess: bool,
+  /// The module is only loaded at runtime through `imp`.
+  pub dynamic: bool,
+}
+
+impl ImportSpecifier {
+  fn new(raw: &str, dynamic: bool) -> Self {
+    let (specifier, preprocess) = match raw.strip_suffix('!') {
+      Some(stripped) => (stripped, true),
+      None => (raw, false),
+    };
+    Self {
+      specifier: specifier.to_string(),
+      preprocess,
+      dynamic,
+    }
+  }
+}
+
+/// An edge of the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dependency {
+  /// Index of the imported module in [`ModuleGraph::nodes`].
+  pub index: usize,
+  pub dynamic: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleNode {
+  /// The resolved path, or the module id for builtins.
+  pub path: PathBuf,
+  pub source: String,
+  pub kind: ModuleKind,
+  /// Declarations in this module are promoted to global declarations.
+  pub preprocess: bool,
+  /// The module is only reachable through `imp` calls.
+  pub dynamic: bool,
+  pub dependencies: Vec<Dependency>,
+}
+
+/// The modules an entry file depends on, in the order they were discovered.
+///
+/// Imports are found on the token stream from `tokenize_coffee_script`, so
+/// imports spanning several lines or nested in blocks are picked up while
+/// anything inside strings and comments is ignored. Brews and `"no-compile"`
+/// files only contribute their `// external` references.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleGraph {
+  nodes: Vec<ModuleNode>,
+  index: HashMap<PathBuf, usize>,
+}
+
+fn string_value(token: &Token) -> Option<&str> {
+  if token.token_type != "STRING" || token.value.len() < 2 || token.value.contains("#{") {
+    return None;
+  }
+  Some(&token.value[1..token.value.len() - 1])
+}
+
+/// An `import` statement or `imp` call, and where it was written.
+#[derive(Debug, Clone)]
+pub struct ImportStatement {
+  pub specifier: ImportSpecifier,
+  /// Names the statement binds, with their 1-based line and column.
+  pub bindings: Vec<(String, usize, usize)>,
+  /// 1-based line of the path string.
+  pub line: usize,
+  /// 1-based column of the path string.
+  pub column: usize,
+  /// Length of the path string, quotes included.
+  pub length: usize,
+  /// Indices of the tokens the statement spans, in `tokenize_coffee_script`
+  /// output.
+  pub tokens: Range<usize>,
+}
+
+/// Opens or closes a `###` block comment. `####` starts a line comment, and
+/// a `### ... ###` on one line closes itself.
+fn is_block_comment_fence(comment: &str) -> bool {
+  comment.starts_with("###") && !comment.starts_with("####") && !comment[3..].contains("###")
+}
+
+/// The tokens that are code, with their index in `tokens`.
+///
+/// The tokenizer only knows line comments, so the lines inside a `###`
+/// block comment are dropped here.
+fn code_tokens(tokens: &[Token]) -> Vec<(usize, &Token)> {
+  let mut in_block_comment = false;
+  tokens
+    .iter()
+    .enumerate()
+    .filter(|(_, t)| {
+      if t.token_type == "COMMENT" {
+        if is_block_comment_fence(&t.value) {
+          in_block_comment = !in_block_comment;
+        }
+        return false;
+      }
+      !in_block_comment && t.token_type != "WHITESPACE"
+    })
+    .collect()
+}
+
+/// Finds `import` statements and `imp` calls with a literal path.
+pub fn scan_imports(source: &str) -> Vec<ImportSpecifier> {
+  scan_import_statements(source)
+    .into_iter()
+    .map(|statement| statement.specifier)
+    .collect()
+}
+
+/// Like [`scan_imports`], but keeps the bindings and positions of every
+/// import.
+pub fn scan_import_statements(source: &str) -> Vec<ImportStatement> {
+  let all_tokens = tokenize_coffee_script(source);
+  let positions = token_positions(&all_tokens);
+  let tokens = code_tokens(&all_tokens);
+  let mut imports = Vec::new();
+
+  let statement = |start: usize, path_idx: usize, bindings, dynamic| {
+    let (path_orig, path_token) = tokens[path_idx];
+    let (line, column) = positions[path_orig];
+    ImportStatement {
+      specifier: ImportSpecifier::new(string_value(path_token).unwrap_or_default(), dynamic),
+      bindings,
+      line,
+      column,
+      length: path_token.value.chars().count(),
+      tokens: tokens[start].0..path_orig + 1,
+    }
+  };
+  let is_string = |idx: usize| {
+    tokens
+      .get(idx)
+      .is_some_and(|(_, t)| string_value(t).is_some())
+  };
+
+  for (i, (_, token)) in tokens.iter().enumerate() {
+    if token.token_type != "IDENTIFIER" || (i > 0 && tokens[i - 1].1.value == ".") {
+      continue;
+    }
+
+    match token.value.as_str() {
+      "import" => {
+        let mut bindings = Vec::new();
+        let mut j = i + 1;
+        while let Some((orig, next)) = tokens.get(j) {
+          if is_string(j) {
+            // Only `import "x"` may have the path straight after `import`.
+            if j == i + 1 {
+              imports.push(statement(i, j, bindings, false));
+            }
+            break;
+          }
+          if next.token_type == "IDENTIFIER" && next.value == "from" {
+            if is_string(j + 1) {
+              imports.push(statement(i, j + 1, bindings, false));
+            }
+            break;
+          }
+          let is_binding =
+            next.token_type == "IDENTIFIER" || matches!(next.value.as_str(), "," | "{" | "}" | "*");
+          if !is_binding {
+            break;
+          }
+          if next.token_type == "IDENTIFIER" {
+            if next.value == "as" {
+              // `a as b` binds `b`, `* as ns` binds `ns`.
+              if tokens[j - 1].1.value != "*" {
+                bindings.pop();
+              }
+            } else {
+              let (line, column) = positions[*orig];
+              bindings.push((next.value.clone(), line, column));
+            }
+          }
+          j += 1;
+        }
+      }
+      "imp" => {
+        let path_idx = match tokens.get(i + 1) {
+          Some((_, next)) if next.value == "(" => Some(i + 2),
+          Some(_) => Some(i + 1),
+          None => None,
+        };
+        if let Some(path_idx) = path_idx.filter(|idx| is_string(*idx)) {
+          imports.push(statement(i, path_idx, Vec::new(), true));
+        }
+      }
+      _ => {}
+    }
+  }
+
+  imports
+}
+
+/// Finds the `// external "..."` references at the top of a `"no-compile"`
+/// script.
+pub fn scan_externals(source: &str) -> Vec<ImportSpecifier> {
+  let tokens = tokenize_coffee_script(source);
+  let mut externals = Vec::new();
+
+  for (i, token) in tokens.iter().enumerate() {
+    if token.value != "/" || tokens.get(i + 1).is_none_or(|t| t.value != "/") {
+      continue;
+    }
+    let rest: Vec<&Token> = tokens[i + 2..]
+      .iter()
+      .filter(|t| t.token_type != "WHITESPACE")
+      .take(2)
+      .collect();
+    let path = match rest.as_slice() {
+      [keyword, path] if keyword.value == "external" => string_value(path),
+      _ => None,
+    };
+    if let Some(path) = path {
+      externals.push(ImportSpecifier::new(path, false));
+    }
+  }
+
+  externals
+}
+
+fn is_brew(path: &Path) -> bool {
+  path
+    .extension()
+    .is_some_and(|ext| ext == "brew" || ext == "qrew")
+}
+
+/// Reads a module from the virtual files, the builtins or disk, decoding
+/// brews.
+pub(crate) fn read_module(path: &Path) -> Result<String> {
+  if is_brew(path) {
+    return Ok(read_brew(path)?.script());
+  }
+  let path_str = path.to_string_lossy();
+
+  if let Some(contents) = get_virtual_file(path) {
+    return Ok(contents);
+  }
+
+  if path_str.starts_with('#') {
+    return BUILTIN_MODULES
+      .get(path_str.as_ref())
+      .map(|content| content.to_string())
+      .ok_or_else(|| anyhow::anyhow!("Builtin module not found: {}", path_str));
+  }
+
+  fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))
+}
+
+impl ModuleGraph {
+  /// Builds the graph of everything `entry` imports, directly or not.
+  pub fn build<P: AsRef<Path>>(entry: P) -> Result<Self> {
+    let entry = if is_virtual_file(entry.as_ref()) {
+      entry.as_ref().to_path_buf()
+    } else {
+      entry
+        .as_ref()
+        .canonicalize()
+        .with_context(|| "Failed to resolve import".to_string())?
+    };
+
+    let mut graph = Self::default();
+    let kind = if is_brew(&entry) {
+      ModuleKind::Brew
+    } else {
+      ModuleKind::Relative
+    };
+    graph.visit(&entry, kind, false)?;
+    graph.mark_dynamic();
+
+    Ok(graph)
+  }
+
+  /// All modules, starting with the entry.
+  pub fn nodes(&self) -> &[ModuleNode] {
+    &self.nodes
+  }
+
+  /// Files on disk that are part of the graph.
+  pub fn files(&self) -> impl Iterator<Item = &Path> {
+    self
+      .nodes()
+      .iter()
+      .filter(|node| node.kind != ModuleKind::Builtin && node.path.exists())
+      .map(|node| node.path.as_path())
+  }
+
+  /// Converts the statically imported modules into `(storage path, source,
+  /// preprocess)` triples, the shape `prepare` and `build_file` work with.
+  pub fn into_includes(self) -> Vec<(PathBuf, String, bool)> {
+    self
+      .nodes
+      .into_iter()
+      .filter(|node| !node.dynamic)
+      .map(|node| {
+        (
+          get_storage_path(&node.path.to_string_lossy()),
+          node.source,
+          node.preprocess,
+        )
+      })
+      .collect()
+  }
+
+  fn visit(&mut self, path: &Path, kind: ModuleKind, preprocess: bool) -> Result<usize> {
+    if let Some(&idx) = self.index.get(path) {
+      self.nodes[idx].preprocess |= preprocess;
+      return Ok(idx);
+    }
+
+    let (source, specifiers) = if is_brew(path) {
+      // Whether the brew may run is checked by the runtime before it runs
+      // anything, building the graph only reads it.
+      let brew = read_brew(path)?;
+      let externals = brew
+        .manifest
+        .externals
+        .iter()
+        .map(|external| ImportSpecifier::new(external, false))
+        .collect();
+      (brew.script(), externals)
+    } else {
+      let source = read_module(path)?;
+      let specifiers = if source.starts_with("\"no-compile\"") {
+        scan_externals(&source)
+      } else {
+        scan_imports(&source)
+      };
+      (source, specifiers)
+    };
+    let external = kind == ModuleKind::Brew || source.starts_with("\"no-compile\"");
+
+    let idx = self.nodes.len();
+    self.index.insert(path.to_path_buf(), idx);
+    self.nodes.push(ModuleNode {
+      path: path.to_path_buf(),
+      source,
+      kind,
+      preprocess,
+      dynamic: false,
+      dependencies: Vec::new(),
+    });
+
+    let parent = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    for specifier in specifiers {
+      let resolved = if external {
+        resolve_external(&specifier)
+      } else {
+        resolve_import(&parent, &specifier)?
+      };
+
+      if let Some((dep_path, dep_kind)) = resolved {
+        let dep_kind = if is_brew(&dep_path) {
+          ModuleKind::Brew
+        } else {
+          dep_kind
+        };
+        let dep_idx = self.visit(&dep_path, dep_kind, specifier.preprocess)?;
+        let dependency = Dependency {
+          index: dep_idx,
+          dynamic: specifier.dynamic,
+        };
+        if !self.nodes[idx].dependencies.contains(&dependency) {
+          self.nodes[idx].dependencies.push(dependency);
+        }
+      }
+    }
+
+    Ok(idx)
+  }
+
+  /// Marks modules that can't be reached from the entry without `imp`.
+  fn mark_dynamic(&mut self) {
+    let mut reachable = vec![false; self.nodes.len()];
+    let mut stack = vec![0];
+
+    while let Some(idx) = stack.pop() {
+      if idx >= reachable.len() || reachable[idx] {
+        continue;
+      }
+      reachable[idx] = true;
+      stack.extend(
+        self.nodes[idx]
+          .dependencies
+          .iter()
+          .filter(|dep| !dep.dynamic)
+          .map(|dep| dep.index),
+      );
+    }
+
+    for (node, reachable) in self.nodes.iter_mut().zip(reachable) {
+      node.dynamic = !reachable;
+    }
+  }
+}
+
+/// Resolves an `import` or `imp` specifier found in a file under `parent`.
+pub(crate) fn resolve_import(
+  parent: &Path,
+  specifier: &ImportSpecifier,
+) -> Result<Option<(PathBuf, ModuleKind)>> {
+  let name = specifier.specifier.as_str();
+
+  if name.starts_with('#') {
+    return Ok(Some((PathBuf::from(name), ModuleKind::Builtin)));
+  }
+
+  // `imp` only ever loads files relative to the importing module.
+  if specifier.dynamic {
+    return Ok(
+      parent
+        .join(name)
+        .canonicalize()
+        .ok()
+        .map(|path| (path, ModuleKind::Relative)),
+    );
+  }
+
+  if !name.contains('/') && !name.contains('\\') && !name.starts_with('.') {
+    return match crate::utils::resolve_app_entry(name, None) {
+      Some(app_entry) => Ok(Some((app_entry, ModuleKind::App))),
+      None => Err(anyhow::anyhow!("App not found: {}", name)),
+    };
+  }
+
+  if name.contains('/') && !name.starts_with('.') && !name.starts_with('/') {
+    let (package_name, entry_name) = name.split_once('/').unwrap_or((name, "main"));
+    return match crate::utils::resolve_app_entry(package_name, Some(entry_name)) {
+      Some(app_entry) => Ok(Some((app_entry, ModuleKind::App))),
+      None => Err(anyhow::anyhow!(
+        "App entry not found: {}/{}",
+        package_name,
+        entry_name
+      )),
+    };
+  }
+
+  let path = parent
+    .join(name)
+    .canonicalize()
+    .with_context(|| format!("Failed to resolve import: {}", name))?;
+  Ok(Some((path, ModuleKind::Relative)))
+}
+
+/// Resolves a `// external` reference of a brew. References that can't be
+/// resolved are skipped, the brew may not need them on this machine.
+fn resolve_external(specifier: &ImportSpecifier) -> Option<(PathBuf, ModuleKind)> {
+  let name = specifier.specifier.as_str();
+
+  if name.contains('/') {
+    let parts: Vec<&str> = name.split('/').collect();
+    if let [package_name, entry_name] = parts.as_slice() {
+      return crate::utils::resolve_app_entry(package_name, Some(entry_name))
+        .map(|app_entry| (app_entry, ModuleKind::App));
+    }
+    return None;
+  }
+
+  let kind = if name.starts_with('#') {
+    ModuleKind::Builtin
+  } else {
+    ModuleKind::Relative
+  };
+  Some((PathBuf::from(name), kind))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn specifiers(source: &str) -> Vec<String> {
+    scan_imports(source)
+      .into_iter()
+      .map(|import| import.specifier)
+      .collect()
+  }
+
+  #[test]
+  fn imports_may_span_several_lines() {
+    let source = "import {\n  a,\n  b as c\n} from \"./lib.coffee\"\nimport \"#std.fs\"\n";
+    let statements = scan_import_statements(source);
+    assert_eq!(statements.len(), 2);
+
+    let names: Vec<&str> = statements[0]
+      .bindings
+      .iter()
+      .map(|(name, _, _)| name.as_str())
+      .collect();
+    assert_eq!(names, ["a", "c"]);
+    assert_eq!(statements[0].specifier.specifier, "./lib.coffee");
+    assert_eq!((statements[0].line, statements[0].column), (4, 8));
+    assert_eq!(statements[0].length, "\"./lib.coffee\"".len());
+    assert_eq!(statements[1].specifier.specifier, "#std.fs");
+  }
+
+  #[test]
+  fn imports_in_strings_and_comments_are_ignored() {
+    let source = r#"
+text = "import x from './string.coffee'"
+# import "./line-comment.coffee"
+###
+import "./block-comment.coffee"
+###
+path = "./#{name}.coffee"
+import dynamic from path
+import "./real.coffee"
+"#;
+    assert_eq!(specifiers(source), ["./real.coffee"]);
+  }
+
+  #[test]
+  fn imp_calls_are_dynamic_imports() {
+    let source = concat!(
+      "a = imp \"./a.coffee\"\n",
+      "b = imp(\"./b.coffee\")\n",
+      "c = imp name\n",
+      "obj.imp \"./c.coffee\"\n",
+    );
+    let imports = scan_imports(source);
+    let found: Vec<(&str, bool)> = imports
+      .iter()
+      .map(|import| (import.specifier.as_str(), import.dynamic))
+      .collect();
+    assert_eq!(found, [("./a.coffee", true), ("./b.coffee", true)]);
+  }
+
+  #[test]
+  fn a_trailing_bang_marks_a_preprocess_import() {
+    let imports = scan_imports("import \"./macros.coffee!\"\nimport \"./plain.coffee\"\n");
+    assert_eq!(imports[0].specifier, "./macros.coffee");
+    assert!(imports[0].preprocess);
+    assert_eq!(imports[1].specifier, "./plain.coffee");
+    assert!(!imports[1].preprocess);
+  }
+
+  #[test]
+  fn externals_are_read_from_comments() {
+    let source = concat!(
+      "\"no-compile\"\n",
+      "// external \"#std.fs\"\n",
+      "// external \"pkg/main\"\n",
+      "import \"./ignored.js\"\n",
+    );
+    let externals: Vec<String> = scan_externals(source)
+      .into_iter()
+      .map(|external| external.specifier)
+      .collect();
+    assert_eq!(externals, ["#std.fs", "pkg/main"]);
+  }
+
+  #[test]
+  fn graph_follows_static_and_dynamic_imports() {
+    let dir = tempfile::tempdir().unwrap();
+    let write = |name: &str, source: &str| {
+      let path = dir.path().join(name);
+      fs::write(&path, source).unwrap();
+      path
+    };
+    let main = write(
+      "main.coffee",
+      "import {\n  shout\n} from \"./lib.coffee!\"\nlater = imp \"./later.coffee\"\n",
+    );
+    write("lib.coffee", "import \"./main.coffee\"\nexport shout = ->\n");
+    write("later.coffee", "import \"./lib.coffee\"\n");
+
+    let graph = ModuleGraph::build(&main).unwrap();
+    let nodes: Vec<(String, bool, bool)> = graph
+      .nodes()
+      .iter()
+      .map(|node| {
+        let name = node.path.file_name().unwrap().to_string_lossy().into_owned();
+        (name, node.preprocess, node.dynamic)
+      })
+      .collect();
+    assert_eq!(
+      nodes,
+      [
+        ("main.coffee".to_string(), false, false),
+        ("lib.coffee".to_string(), true, false),
+        ("later.coffee".to_string(), false, true),
+      ]
+    );
+    assert_eq!(graph.into_includes().len(), 2);
+  }
+}
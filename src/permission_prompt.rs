@@ -0,0 +1,756 @@
+use crate::ext::web::{
+  AllowlistWebPermissions, PermissionDeniedError, SystemsPermissionKind, WebPermissions,
+  resolve_path,
+};
+use crate::permissions::{AppPermissions, PermissionValue};
+use crate::utils::{check_package_name, get_rew_root};
+use anyhow::{Context, Result};
+use colored::*;
+use deno_core::error::JsStackFrame;
+use deno_permissions::PromptResponse;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The kind of access a prompt asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PermissionKind {
+  Read,
+  Write,
+  Net,
+  Env,
+  Sys,
+  Ffi,
+}
+
+impl PermissionKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      PermissionKind::Read => "read",
+      PermissionKind::Write => "write",
+      PermissionKind::Net => "net",
+      PermissionKind::Env => "env",
+      PermissionKind::Sys => "sys",
+      PermissionKind::Ffi => "ffi",
+    }
+  }
+
+  /// The kind a deno permission flag name stands for. Subprocesses and
+  /// imports have no kind, they are never asked about.
+  pub fn from_flag(name: &str) -> Option<Self> {
+    match name {
+      "read" => Some(PermissionKind::Read),
+      "write" => Some(PermissionKind::Write),
+      "net" => Some(PermissionKind::Net),
+      "env" => Some(PermissionKind::Env),
+      "sys" => Some(PermissionKind::Sys),
+      "ffi" => Some(PermissionKind::Ffi),
+      _ => None,
+    }
+  }
+}
+
+/// The answer to a permission prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptAnswer {
+  /// Allow it for the rest of the run.
+  Allow,
+  Deny,
+  /// Allow it now and in every later run of the same app.
+  AllowAlways,
+}
+
+/// Asks whether an access that wasn't granted up front may happen.
+pub trait PermissionPrompter: std::fmt::Debug + Send + Sync {
+  /// # Arguments
+  /// * `kind` - The kind of access.
+  /// * `target` - The path, host, variable or API accessed, or `None` when
+  ///   the access isn't about one in particular.
+  fn prompt(&self, kind: PermissionKind, target: Option<&str>) -> PromptAnswer;
+
+  /// Asks whether the app in `app_dir` may have the permissions its
+  /// `app.yaml` asks for.
+  fn confirm_app(&self, app_dir: &Path, permissions: &AppPermissions) -> bool;
+}
+
+/// Asks on the terminal, and denies everything when stdin isn't one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TtyPrompter;
+
+impl TtyPrompter {
+  /// Whether there is a terminal to ask on.
+  pub fn is_available() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+  }
+}
+
+impl PermissionPrompter for TtyPrompter {
+  fn prompt(&self, kind: PermissionKind, target: Option<&str>) -> PromptAnswer {
+    if !Self::is_available() {
+      return PromptAnswer::Deny;
+    }
+
+    let access = match target {
+      Some(target) => format!("{} access to {}", kind.as_str(), target.bold()),
+      None => format!("{} access", kind.as_str()),
+    };
+    let mut stderr = std::io::stderr().lock();
+    let _ = write!(
+      stderr,
+      "{} Allow {}? {} ",
+      "⚠".yellow(),
+      access,
+      "[y/n/A]".dimmed()
+    );
+    let _ = stderr.flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+      return PromptAnswer::Deny;
+    }
+    match answer.trim() {
+      "y" | "Y" | "yes" => PromptAnswer::Allow,
+      "A" | "always" => PromptAnswer::AllowAlways,
+      _ => PromptAnswer::Deny,
+    }
+  }
+
+  fn confirm_app(&self, app_dir: &Path, permissions: &AppPermissions) -> bool {
+    if !Self::is_available() {
+      return false;
+    }
+
+    let listed = serde_yaml::to_string(permissions).unwrap_or_default();
+    let mut stderr = std::io::stderr().lock();
+    let _ = writeln!(
+      stderr,
+      "{} The app in {} asks for these permissions:",
+      "⚠".yellow(),
+      app_dir.display().to_string().bold()
+    );
+    for line in listed.lines() {
+      let _ = writeln!(stderr, "    {}", line);
+    }
+    let _ = write!(stderr, "  Grant them? {} ", "[y/N]".dimmed());
+    let _ = stderr.flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+      return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+  }
+}
+
+/// Where the "always" answers of `package` are kept, in the same format as
+/// the `permissions:` of `app.yaml`.
+///
+/// Fails for package names that would put the file anywhere else.
+fn saved_answers_path(package: &str) -> Result<PathBuf> {
+  check_package_name(package)?;
+  let dir = get_rew_root().join("config").join("permissions");
+  let path = dir.join(format!("{}.yaml", package));
+  if path.parent() != Some(dir.as_path()) {
+    anyhow::bail!("The answers of {:?} would be kept outside {:?}", package, dir);
+  }
+  Ok(path)
+}
+
+/// Loads the "always" answers given for `package` in earlier runs.
+pub fn load_saved_answers(package: &str) -> Result<Option<AppPermissions>> {
+  let path = saved_answers_path(package)?;
+  if !path.exists() {
+    return Ok(None);
+  }
+  let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+  serde_yaml::from_str(&content)
+    .map(Some)
+    .with_context(|| format!("Failed to parse {:?}", path))
+}
+
+/// Where the `permissions:` the user granted to apps are kept, by app
+/// directory. Not among the saved answers, where a package could be named
+/// after it.
+fn confirmed_apps_path(root: &Path) -> PathBuf {
+  root.join("config").join("confirmed-apps.yaml")
+}
+
+fn load_confirmed_apps(root: &Path) -> Result<BTreeMap<String, AppPermissions>> {
+  let path = confirmed_apps_path(root);
+  if !path.exists() {
+    return Ok(BTreeMap::new());
+  }
+  let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+  serde_yaml::from_str(&content).with_context(|| format!("Failed to parse {:?}", path))
+}
+
+/// Whether the `permissions:` of the app in `app_dir` may be granted.
+///
+/// An app gets them once the user confirmed exactly these permissions for
+/// it, so editing `app.yaml` asks again. Until then `prompter` is asked,
+/// and a yes is remembered.
+///
+/// # Arguments
+/// * `trusted` - Grant them without asking, from `--trust-app`.
+/// * `prompter` - Asks the user, or `None` when there is no one to ask.
+pub fn confirm_app_permissions(
+  app_dir: &Path,
+  permissions: &AppPermissions,
+  trusted: bool,
+  prompter: Option<&dyn PermissionPrompter>,
+) -> Result<bool> {
+  confirm_app_permissions_in(&get_rew_root(), app_dir, permissions, trusted, prompter)
+}
+
+fn confirm_app_permissions_in(
+  root: &Path,
+  app_dir: &Path,
+  permissions: &AppPermissions,
+  trusted: bool,
+  prompter: Option<&dyn PermissionPrompter>,
+) -> Result<bool> {
+  if trusted {
+    return Ok(true);
+  }
+  let key = app_dir.to_string_lossy().into_owned();
+  let mut confirmed = load_confirmed_apps(root)?;
+  if confirmed.get(&key) == Some(permissions) {
+    return Ok(true);
+  }
+  if !prompter.is_some_and(|prompter| prompter.confirm_app(app_dir, permissions)) {
+    return Ok(false);
+  }
+
+  confirmed.insert(key, permissions.clone());
+  let path = confirmed_apps_path(root);
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  fs::write(&path, serde_yaml::to_string(&confirmed)?)
+    .with_context(|| format!("Failed to write {:?}", path))?;
+  Ok(true)
+}
+
+/// Adds `target` to a saved permission, or allows everything of its kind
+/// when there is no target.
+fn add_target(value: &mut Option<PermissionValue>, target: Option<&str>) {
+  match (value.as_mut(), target) {
+    (Some(PermissionValue::All(true)), _) => {}
+    (Some(PermissionValue::List(list)), Some(target)) => {
+      if !list.iter().any(|item| item == target) {
+        list.push(target.to_string());
+      }
+    }
+    (_, Some(target)) => *value = Some(PermissionValue::List(vec![target.to_string()])),
+    (_, None) => *value = Some(PermissionValue::All(true)),
+  }
+}
+
+/// Remembers an "always" answer for later runs of `package`.
+fn save_answer(package: &str, kind: PermissionKind, target: Option<&str>) -> Result<()> {
+  let mut saved = load_saved_answers(package)?.unwrap_or_default();
+  let allow = &mut saved.allow;
+  let value = match kind {
+    PermissionKind::Read => &mut allow.read,
+    PermissionKind::Write => &mut allow.write,
+    PermissionKind::Net => &mut allow.net,
+    PermissionKind::Env => &mut allow.env,
+    PermissionKind::Sys => &mut allow.sys,
+    PermissionKind::Ffi => &mut allow.ffi,
+  };
+  add_target(value, target);
+
+  let path = saved_answers_path(package)?;
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  fs::write(&path, serde_yaml::to_string(&saved)?)
+    .with_context(|| format!("Failed to write {:?}", path))
+}
+
+/// Permissions that ask before denying an access that wasn't granted up
+/// front, and remember the answers in an `AllowlistWebPermissions`.
+///
+/// Accesses that were explicitly denied are never asked about.
+#[derive(Debug)]
+pub struct PromptingWebPermissions {
+  inner: AllowlistWebPermissions,
+  prompter: Arc<dyn PermissionPrompter>,
+  package: Option<String>,
+  refused: Mutex<HashSet<(PermissionKind, Option<String>)>>,
+}
+
+impl PromptingWebPermissions {
+  /// # Arguments
+  /// * `inner` - The permissions granted up front, where answers are added.
+  /// * `prompter` - Asks about everything else.
+  /// * `package` - The app whose "always" answers are saved, if any.
+  pub fn new(
+    inner: AllowlistWebPermissions,
+    prompter: Arc<dyn PermissionPrompter>,
+    package: Option<String>,
+  ) -> Self {
+    Self {
+      inner,
+      prompter,
+      package,
+      refused: Mutex::new(HashSet::new()),
+    }
+  }
+
+  /// Asks about an access and records the answer. Only one prompt is shown
+  /// at a time, and a denied access isn't asked about again.
+  ///
+  /// # Returns
+  /// * Whether the access was allowed.
+  fn ask(&self, kind: PermissionKind, target: Option<&str>) -> bool {
+    let mut refused = self.refused.lock().unwrap();
+    let request = (kind, target.map(str::to_string));
+    if refused.contains(&request) {
+      return false;
+    }
+
+    let answer = self.prompter.prompt(kind, target);
+    if answer == PromptAnswer::Deny {
+      refused.insert(request);
+      return false;
+    }
+
+    self.grant(kind, target);
+    let saved = match (answer, &self.package) {
+      (PromptAnswer::AllowAlways, Some(package)) => save_answer(package, kind, target),
+      _ => Ok(()),
+    };
+    if let Err(e) = saved {
+      eprintln!("{} {:#}", "Failed to save the permission:".yellow(), e);
+    }
+    true
+  }
+
+  /// Asks about an access unless it was already granted, by a flag or an
+  /// earlier answer.
+  ///
+  /// # Returns
+  /// * Whether the access is allowed.
+  pub fn request(&self, kind: PermissionKind, target: Option<&str>) -> bool {
+    self.granted(kind, target) || self.ask(kind, target)
+  }
+
+  fn granted(&self, kind: PermissionKind, target: Option<&str>) -> bool {
+    let inner = &self.inner;
+    match (kind, target) {
+      (PermissionKind::Read, Some(path)) => inner.check_read(Path::new(path), None).is_ok(),
+      (PermissionKind::Read, None) => inner.check_read_all(None).is_ok(),
+      (PermissionKind::Write, Some(path)) => inner.check_write(Path::new(path), None).is_ok(),
+      (PermissionKind::Write, None) => inner.check_write_all("").is_ok(),
+      (PermissionKind::Net, Some(target)) => {
+        let (host, port) = match target.rsplit_once(':') {
+          Some((host, port)) if port.parse::<u16>().is_ok() => (host, port.parse().ok()),
+          _ => (target, None),
+        };
+        inner.check_host(host, port, "").is_ok()
+      }
+      (PermissionKind::Env, Some(var)) => inner.check_env(var).is_ok(),
+      (PermissionKind::Sys, Some(kind)) => {
+        inner.check_sys(SystemsPermissionKind::new(kind), "").is_ok()
+      }
+      (PermissionKind::Ffi, _) => inner.check_exec().is_ok(),
+      (PermissionKind::Net | PermissionKind::Env | PermissionKind::Sys, None) => false,
+    }
+  }
+
+  fn grant(&self, kind: PermissionKind, target: Option<&str>) {
+    match (kind, target) {
+      (PermissionKind::Read, Some(path)) => self.inner.allow_read(path),
+      (PermissionKind::Read, None) => self.inner.set_read_all(true),
+      (PermissionKind::Write, Some(path)) => self.inner.allow_write(path),
+      (PermissionKind::Write, None) => self.inner.set_write_all(true),
+      (PermissionKind::Net, Some(host)) => self.inner.allow_host(host),
+      (PermissionKind::Net, None) => self.inner.set_net_all(true),
+      (PermissionKind::Env, Some(var)) => self.inner.allow_env(var),
+      (PermissionKind::Env, None) => self.inner.set_env_all(true),
+      (PermissionKind::Sys, Some(kind)) => self.inner.allow_sys(SystemsPermissionKind::new(kind)),
+      (PermissionKind::Sys, None) => self.inner.set_sys_all(true),
+      (PermissionKind::Ffi, _) => self.inner.set_exec(true),
+    }
+  }
+
+  /// Asks about an access the inner permissions denied but could grant,
+  /// and checks again when it was allowed.
+  fn retry<T>(
+    &self,
+    result: Result<T, PermissionDeniedError>,
+    kind: PermissionKind,
+    target: Option<&str>,
+    check: impl FnOnce() -> Result<T, PermissionDeniedError>,
+  ) -> Result<T, PermissionDeniedError> {
+    match result {
+      Err(PermissionDeniedError::Retryable { .. }) if self.ask(kind, target) => check(),
+      result => result,
+    }
+  }
+}
+
+fn path_target(path: &Path) -> String {
+  resolve_path(path).to_string_lossy().into_owned()
+}
+
+impl WebPermissions for PromptingWebPermissions {
+  fn allow_hrtime(&self) -> bool {
+    self.inner.allow_hrtime()
+  }
+
+  fn check_url(
+    &self,
+    url: &deno_core::url::Url,
+    api_name: &str,
+  ) -> Result<(), PermissionDeniedError> {
+    match url.host_str() {
+      Some(host) => self.check_host(host, url.port_or_known_default(), api_name),
+      None => self.inner.check_url(url, api_name),
+    }
+  }
+
+  fn check_open<'a>(
+    &self,
+    resolved: bool,
+    read: bool,
+    write: bool,
+    path: &'a Path,
+    api_name: &str,
+  ) -> Option<Cow<'a, Path>> {
+    self
+      .inner
+      .check_open(resolved, read, write, path, api_name)
+      .or_else(|| {
+        let readable = !read || self.check_read(path, Some(api_name)).is_ok();
+        let writable = !write || self.check_write(path, Some(api_name)).is_ok();
+        (readable && writable).then_some(Cow::Borrowed(path))
+      })
+  }
+
+  fn check_read<'a>(
+    &self,
+    p: &'a Path,
+    api_name: Option<&str>,
+  ) -> Result<Cow<'a, Path>, PermissionDeniedError> {
+    let target = path_target(p);
+    self.retry(
+      self.inner.check_read(p, api_name),
+      PermissionKind::Read,
+      Some(&target),
+      || self.inner.check_read(p, api_name),
+    )
+  }
+
+  fn check_read_all(&self, api_name: Option<&str>) -> Result<(), PermissionDeniedError> {
+    self.retry(
+      self.inner.check_read_all(api_name),
+      PermissionKind::Read,
+      None,
+      || self.inner.check_read_all(api_name),
+    )
+  }
+
+  fn check_read_blind(
+    &self,
+    p: &Path,
+    display: &str,
+    api_name: &str,
+  ) -> Result<(), PermissionDeniedError> {
+    self.inner.check_read_blind(p, display, api_name)
+  }
+
+  fn check_write<'a>(
+    &self,
+    p: &'a Path,
+    api_name: Option<&str>,
+  ) -> Result<Cow<'a, Path>, PermissionDeniedError> {
+    let target = path_target(p);
+    self.retry(
+      self.inner.check_write(p, api_name),
+      PermissionKind::Write,
+      Some(&target),
+      || self.inner.check_write(p, api_name),
+    )
+  }
+
+  fn check_write_all(&self, api_name: &str) -> Result<(), PermissionDeniedError> {
+    self.retry(
+      self.inner.check_write_all(api_name),
+      PermissionKind::Write,
+      None,
+      || self.inner.check_write_all(api_name),
+    )
+  }
+
+  fn check_write_blind(
+    &self,
+    p: &Path,
+    display: &str,
+    api_name: &str,
+  ) -> Result<(), PermissionDeniedError> {
+    self.check_write(p, Some(api_name))?;
+    self.inner.check_write_blind(p, display, api_name)
+  }
+
+  fn check_write_partial(
+    &self,
+    path: &str,
+    api_name: &str,
+  ) -> Result<PathBuf, PermissionDeniedError> {
+    let p = self.check_write(Path::new(path), Some(api_name))?;
+    Ok(p.into_owned())
+  }
+
+  fn check_host(
+    &self,
+    host: &str,
+    port: Option<u16>,
+    api_name: &str,
+  ) -> Result<(), PermissionDeniedError> {
+    let target = match port {
+      Some(port) => format!("{}:{}", host, port),
+      None => host.to_string(),
+    };
+    self.retry(
+      self.inner.check_host(host, port, api_name),
+      PermissionKind::Net,
+      Some(&target),
+      || self.inner.check_host(host, port, api_name),
+    )
+  }
+
+  fn check_sys(
+    &self,
+    kind: SystemsPermissionKind,
+    api_name: &str,
+  ) -> Result<(), PermissionDeniedError> {
+    let target = kind.as_str().to_string();
+    self.retry(
+      self.inner.check_sys(kind.clone(), api_name),
+      PermissionKind::Sys,
+      Some(&target),
+      || self.inner.check_sys(kind, api_name),
+    )
+  }
+
+  fn check_env(&self, var: &str) -> Result<(), PermissionDeniedError> {
+    self.retry(
+      self.inner.check_env(var),
+      PermissionKind::Env,
+      Some(var),
+      || self.inner.check_env(var),
+    )
+  }
+
+  fn check_exec(&self) -> Result<(), PermissionDeniedError> {
+    self.retry(self.inner.check_exec(), PermissionKind::Ffi, None, || {
+      self.inner.check_exec()
+    })
+  }
+}
+
+/// Asks about the accesses the deno extensions check, through the
+/// `PermissionPrompter` and with the answers of a `PromptingWebPermissions`,
+/// so an access is asked about once whichever API makes it.
+///
+/// deno keeps one prompter for the whole process, installed with
+/// `deno_permissions::set_prompter`.
+#[derive(Debug)]
+pub struct DenoPrompter(pub Arc<PromptingWebPermissions>);
+
+impl deno_permissions::PermissionPrompter for DenoPrompter {
+  fn prompt(
+    &mut self,
+    message: &str,
+    name: &str,
+    _api_name: Option<&str>,
+    _is_unary: bool,
+    _stack: Option<Vec<JsStackFrame>>,
+  ) -> PromptResponse {
+    let Some(kind) = PermissionKind::from_flag(name) else {
+      return PromptResponse::Deny;
+    };
+    match self.0.request(kind, prompt_target(message, name).as_deref()) {
+      true => PromptResponse::Allow,
+      false => PromptResponse::Deny,
+    }
+  }
+}
+
+/// The target of a deno prompt message like `read access to "/etc/hosts"`.
+fn prompt_target(message: &str, name: &str) -> Option<String> {
+  let target = message.strip_prefix(name)?.strip_prefix(" access to ")?;
+  let unquoted = target
+    .strip_prefix('"')
+    .and_then(|target| target.strip_suffix('"'));
+  Some(unquoted.unwrap_or(target).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use deno_permissions::PermissionPrompter as _;
+  use std::collections::VecDeque;
+
+  /// Gives the answers it was made with, in order, and records what it was
+  /// asked.
+  #[derive(Debug, Default)]
+  struct ScriptedPrompter {
+    answers: Mutex<VecDeque<PromptAnswer>>,
+    asked: Mutex<Vec<(PermissionKind, Option<String>)>>,
+  }
+
+  impl ScriptedPrompter {
+    fn new(answers: &[PromptAnswer]) -> Arc<Self> {
+      Arc::new(Self {
+        answers: Mutex::new(answers.iter().copied().collect()),
+        asked: Mutex::default(),
+      })
+    }
+
+    fn asked(&self) -> Vec<(PermissionKind, Option<String>)> {
+      self.asked.lock().unwrap().clone()
+    }
+  }
+
+  impl PermissionPrompter for ScriptedPrompter {
+    fn prompt(&self, kind: PermissionKind, target: Option<&str>) -> PromptAnswer {
+      self.asked.lock().unwrap().push((kind, target.map(str::to_string)));
+      self.answers.lock().unwrap().pop_front().expect("unexpected prompt")
+    }
+
+    fn confirm_app(&self, app_dir: &Path, _permissions: &AppPermissions) -> bool {
+      self.prompt(PermissionKind::Read, Some(&app_dir.to_string_lossy())) != PromptAnswer::Deny
+    }
+  }
+
+  fn prompting(
+    prompter: &Arc<ScriptedPrompter>,
+    package: Option<&str>,
+  ) -> (AllowlistWebPermissions, PromptingWebPermissions) {
+    let inner = AllowlistWebPermissions::new();
+    let permissions = PromptingWebPermissions::new(
+      inner.clone(),
+      prompter.clone(),
+      package.map(str::to_string),
+    );
+    (inner, permissions)
+  }
+
+  #[test]
+  fn allow_once_grants_for_the_rest_of_the_run() {
+    let prompter = ScriptedPrompter::new(&[PromptAnswer::Allow]);
+    let (_, permissions) = prompting(&prompter, None);
+
+    assert!(permissions.check_env("HOME").is_ok());
+    assert!(permissions.check_env("HOME").is_ok());
+    assert_eq!(prompter.asked(), [(PermissionKind::Env, Some("HOME".to_string()))]);
+  }
+
+  #[test]
+  fn deny_is_not_asked_again() {
+    let prompter = ScriptedPrompter::new(&[PromptAnswer::Deny]);
+    let (_, permissions) = prompting(&prompter, None);
+
+    assert!(permissions.check_host("example.com", Some(443), "fetch").is_err());
+    assert!(permissions.check_host("example.com", Some(443), "fetch").is_err());
+    assert_eq!(prompter.asked().len(), 1);
+  }
+
+  #[test]
+  fn explicit_denials_are_never_asked_about() {
+    let prompter = ScriptedPrompter::new(&[]);
+    let (inner, permissions) = prompting(&prompter, None);
+    inner.deny_env("SECRET");
+
+    assert!(permissions.check_env("SECRET").is_err());
+    assert!(prompter.asked().is_empty());
+  }
+
+  #[test]
+  fn allow_always_is_saved_for_the_app() {
+    crate::utils::test_rew_root();
+    let prompter = ScriptedPrompter::new(&[PromptAnswer::AllowAlways]);
+    let (_, permissions) = prompting(&prompter, Some("com.example.app"));
+
+    assert!(permissions.check_env("API_KEY").is_ok());
+    let saved = load_saved_answers("com.example.app").unwrap().unwrap();
+    let flags = saved.to_flags(Path::new("/app"));
+    assert_eq!(flags.allow_env, Some(vec!["API_KEY".to_string()]));
+  }
+
+  #[test]
+  fn answers_of_a_traversal_package_are_neither_loaded_nor_saved() {
+    let package = "../../apps/notes/grants";
+    assert!(load_saved_answers(package).is_err());
+    assert!(save_answer(package, PermissionKind::Env, Some("HOME")).is_err());
+    assert!(load_saved_answers("/etc/grants").is_err());
+  }
+
+  #[test]
+  fn deno_prompts_share_the_answers() {
+    let prompter = ScriptedPrompter::new(&[PromptAnswer::Allow]);
+    let (_, permissions) = prompting(&prompter, None);
+    let mut deno = DenoPrompter(Arc::new(permissions));
+
+    let answer = deno.prompt("env access to \"HOME\"", "env", None, true, None);
+    assert_eq!(answer, PromptResponse::Allow);
+    assert!(deno.0.check_env("HOME").is_ok());
+    assert_eq!(prompter.asked(), [(PermissionKind::Env, Some("HOME".to_string()))]);
+
+    let answer = deno.prompt("run access to \"ls\"", "run", None, true, None);
+    assert_eq!(answer, PromptResponse::Deny);
+    assert_eq!(prompter.asked().len(), 1);
+  }
+
+  #[test]
+  fn prompt_targets_are_read_from_deno_messages() {
+    let target = |message| prompt_target(message, "read");
+    assert_eq!(target("read access to \"/etc/hosts\"").as_deref(), Some("/etc/hosts"));
+    assert_eq!(target("read access to <CWD>").as_deref(), Some("<CWD>"));
+    assert_eq!(target("read access"), None);
+  }
+
+  fn app_permissions(yaml: &str) -> AppPermissions {
+    serde_yaml::from_str(yaml).unwrap()
+  }
+
+  #[test]
+  fn app_permissions_are_confirmed_once() {
+    let root = tempfile::tempdir().unwrap();
+    let app = Path::new("/apps/notes");
+    let permissions = app_permissions("net: true\nread: [\"./assets\"]\n");
+    let confirm = |prompter: &Arc<ScriptedPrompter>| {
+      let prompter = prompter.as_ref() as &dyn PermissionPrompter;
+      confirm_app_permissions_in(root.path(), app, &permissions, false, Some(prompter)).unwrap()
+    };
+
+    let prompter = ScriptedPrompter::new(&[PromptAnswer::Allow]);
+    assert!(confirm(&prompter));
+    assert!(confirm(&prompter));
+    assert_eq!(prompter.asked().len(), 1);
+  }
+
+  #[test]
+  fn changed_or_refused_app_permissions_are_not_granted() {
+    let root = tempfile::tempdir().unwrap();
+    let app = Path::new("/apps/notes");
+    let before = app_permissions("net: true\n");
+    let after = app_permissions("net: true\nwrite: true\n");
+    let prompter = ScriptedPrompter::new(&[PromptAnswer::Allow, PromptAnswer::Deny]);
+    let prompter = Some(prompter.as_ref() as &dyn PermissionPrompter);
+
+    assert!(confirm_app_permissions_in(root.path(), app, &before, false, prompter).unwrap());
+    assert!(!confirm_app_permissions_in(root.path(), app, &after, false, prompter).unwrap());
+    // Without anyone to ask, only `--trust-app` grants them.
+    assert!(!confirm_app_permissions_in(root.path(), app, &after, false, None).unwrap());
+    assert!(confirm_app_permissions_in(root.path(), app, &after, true, None).unwrap());
+    // What was confirmed before is still granted.
+    assert!(confirm_app_permissions_in(root.path(), app, &before, false, None).unwrap());
+  }
+}
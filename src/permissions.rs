@@ -0,0 +1,545 @@
+use crate::data_manager::DataManager;
+use crate::ext::web::{
+  AllowlistWebPermissions, DefaultWebPermissions, SystemsPermissionKind, WebPermissions,
+  resolve_path,
+};
+use crate::permission_prompt::{
+  DenoPrompter, PermissionPrompter, PromptingWebPermissions, TtyPrompter,
+  confirm_app_permissions, load_saved_answers,
+};
+use crate::utils::find_app_info;
+use anyhow::Result;
+use colored::*;
+use deno_permissions::{
+  AllowRunDescriptor, AllowRunDescriptorParseResult, DenyRunDescriptor, EnvDescriptor,
+  EnvDescriptorParseError, FfiDescriptor, ImportDescriptor, NetDescriptor, NetDescriptorParseError,
+  PathQueryDescriptor, PathResolveError, PermissionDescriptorParser, Permissions,
+  PermissionsContainer, PermissionsOptions, ReadDescriptor, RunDescriptorParseError,
+  RunQueryDescriptor, SysDescriptor, SysDescriptorParseError, WriteDescriptor,
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Resolves the paths in permission flags and queries against the current
+/// directory.
+#[derive(Debug, Clone)]
+pub struct RewDescriptorParser;
+
+impl PermissionDescriptorParser for RewDescriptorParser {
+  fn parse_read_descriptor(&self, text: &str) -> Result<ReadDescriptor, PathResolveError> {
+    Ok(ReadDescriptor(resolve_path(Path::new(text))))
+  }
+
+  fn parse_write_descriptor(&self, text: &str) -> Result<WriteDescriptor, PathResolveError> {
+    Ok(WriteDescriptor(resolve_path(Path::new(text))))
+  }
+
+  fn parse_net_descriptor(&self, text: &str) -> Result<NetDescriptor, NetDescriptorParseError> {
+    NetDescriptor::parse(text)
+  }
+
+  fn parse_import_descriptor(
+    &self,
+    text: &str,
+  ) -> Result<ImportDescriptor, NetDescriptorParseError> {
+    ImportDescriptor::parse(text)
+  }
+
+  fn parse_env_descriptor(&self, text: &str) -> Result<EnvDescriptor, EnvDescriptorParseError> {
+    Ok(EnvDescriptor::new(text))
+  }
+
+  fn parse_sys_descriptor(&self, text: &str) -> Result<SysDescriptor, SysDescriptorParseError> {
+    SysDescriptor::parse(text.to_string())
+  }
+
+  fn parse_allow_run_descriptor(
+    &self,
+    text: &str,
+  ) -> Result<AllowRunDescriptorParseResult, RunDescriptorParseError> {
+    Ok(AllowRunDescriptorParseResult::Descriptor(
+      AllowRunDescriptor(resolve_path(Path::new(text))),
+    ))
+  }
+
+  fn parse_deny_run_descriptor(&self, text: &str) -> Result<DenyRunDescriptor, PathResolveError> {
+    if text.contains('/') || text.contains('\\') {
+      Ok(DenyRunDescriptor::Path(resolve_path(Path::new(text))))
+    } else {
+      Ok(DenyRunDescriptor::Name(text.to_string()))
+    }
+  }
+
+  fn parse_ffi_descriptor(&self, text: &str) -> Result<FfiDescriptor, PathResolveError> {
+    Ok(FfiDescriptor(resolve_path(Path::new(text))))
+  }
+
+  fn parse_path_query(&self, path: &str) -> Result<PathQueryDescriptor, PathResolveError> {
+    Ok(PathQueryDescriptor {
+      resolved: resolve_path(Path::new(path)),
+      requested: path.to_string(),
+    })
+  }
+
+  fn parse_run_query(
+    &self,
+    requested: &str,
+  ) -> Result<RunQueryDescriptor, RunDescriptorParseError> {
+    RunQueryDescriptor::parse(requested).map_err(Into::into)
+  }
+}
+
+/// The Deno style permission flags of `rew run`.
+///
+/// A flag given without a value allows or denies everything of its kind,
+/// `--allow-read=./data,./config` only the listed entries.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct PermissionFlags {
+  #[arg(short = 'A', long, help = "Allow everything")]
+  pub allow_all: bool,
+
+  #[arg(long, help = "Deny what wasn't allowed instead of asking. Subprocesses are never asked about")]
+  pub no_prompt: bool,
+
+  #[arg(long, help = "Grant the permissions in app.yaml without confirming them")]
+  pub trust_app: bool,
+
+  #[arg(long, value_name = "PATH", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Allow reading files, or only these paths")]
+  pub allow_read: Option<Vec<String>>,
+
+  #[arg(long, value_name = "PATH", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Deny reading files, or only these paths")]
+  pub deny_read: Option<Vec<String>>,
+
+  #[arg(long, value_name = "PATH", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Allow writing files, or only these paths")]
+  pub allow_write: Option<Vec<String>>,
+
+  #[arg(long, value_name = "PATH", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Deny writing files, or only these paths")]
+  pub deny_write: Option<Vec<String>>,
+
+  #[arg(long, value_name = "HOST", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Allow network access, or only to these hosts")]
+  pub allow_net: Option<Vec<String>>,
+
+  #[arg(long, value_name = "HOST", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Deny network access, or only to these hosts")]
+  pub deny_net: Option<Vec<String>>,
+
+  #[arg(long, value_name = "VAR", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Allow environment access, or only to these variables")]
+  pub allow_env: Option<Vec<String>>,
+
+  #[arg(long, value_name = "VAR", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Deny environment access, or only to these variables")]
+  pub deny_env: Option<Vec<String>>,
+
+  #[arg(long, value_name = "PROGRAM", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Allow running subprocesses, or only these programs")]
+  pub allow_run: Option<Vec<String>>,
+
+  #[arg(long, value_name = "PROGRAM", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Deny running subprocesses, or only these programs")]
+  pub deny_run: Option<Vec<String>>,
+
+  #[arg(long, value_name = "PATH", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Allow loading native libraries, or only these paths")]
+  pub allow_ffi: Option<Vec<String>>,
+
+  #[arg(long, value_name = "PATH", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Deny loading native libraries, or only these paths")]
+  pub deny_ffi: Option<Vec<String>>,
+
+  #[arg(long, value_name = "API", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Allow system information APIs, or only these")]
+  pub allow_sys: Option<Vec<String>>,
+
+  #[arg(long, value_name = "API", num_args = 0.., value_delimiter = ',', require_equals = true, help = "Deny system information APIs, or only these")]
+  pub deny_sys: Option<Vec<String>>,
+}
+
+/// Adds the entries of `other` to `flag`. An empty list stands for
+/// everything, so it wins over any list.
+fn merge_flag(flag: &mut Option<Vec<String>>, other: Option<Vec<String>>) {
+  match (flag.as_mut(), other) {
+    (_, None) => {}
+    (None, other) => *flag = other,
+    (Some(list), Some(other)) => {
+      if list.is_empty() || other.is_empty() {
+        list.clear();
+      } else {
+        list.extend(other);
+      }
+    }
+  }
+}
+
+impl PermissionFlags {
+  /// Whether no permission was given at all, in which case everything is
+  /// allowed as before permissions existed.
+  pub fn is_unset(&self) -> bool {
+    !self.allow_all
+      && [
+        &self.allow_read,
+        &self.deny_read,
+        &self.allow_write,
+        &self.deny_write,
+        &self.allow_net,
+        &self.deny_net,
+        &self.allow_env,
+        &self.deny_env,
+        &self.allow_run,
+        &self.deny_run,
+        &self.allow_ffi,
+        &self.deny_ffi,
+        &self.allow_sys,
+        &self.deny_sys,
+      ]
+      .iter()
+      .all(|flag| flag.is_none())
+  }
+
+  /// Combines two sets of flags, allowing and denying what either does.
+  pub fn merge(&mut self, other: PermissionFlags) {
+    self.allow_all |= other.allow_all;
+    self.no_prompt |= other.no_prompt;
+    self.trust_app |= other.trust_app;
+    merge_flag(&mut self.allow_read, other.allow_read);
+    merge_flag(&mut self.deny_read, other.deny_read);
+    merge_flag(&mut self.allow_write, other.allow_write);
+    merge_flag(&mut self.deny_write, other.deny_write);
+    merge_flag(&mut self.allow_net, other.allow_net);
+    merge_flag(&mut self.deny_net, other.deny_net);
+    merge_flag(&mut self.allow_env, other.allow_env);
+    merge_flag(&mut self.deny_env, other.deny_env);
+    merge_flag(&mut self.allow_run, other.allow_run);
+    merge_flag(&mut self.deny_run, other.deny_run);
+    merge_flag(&mut self.allow_ffi, other.allow_ffi);
+    merge_flag(&mut self.deny_ffi, other.deny_ffi);
+    merge_flag(&mut self.allow_sys, other.allow_sys);
+    merge_flag(&mut self.deny_sys, other.deny_sys);
+  }
+}
+
+/// One permission in `app.yaml`: `true` for everything, or a list.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PermissionValue {
+  All(bool),
+  List(Vec<String>),
+}
+
+impl PermissionValue {
+  fn to_flag(&self, resolve: impl Fn(&str) -> String) -> Option<Vec<String>> {
+    match self {
+      PermissionValue::All(true) => Some(vec![]),
+      PermissionValue::All(false) => None,
+      PermissionValue::List(list) => Some(list.iter().map(|item| resolve(item)).collect()),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PermissionList {
+  pub read: Option<PermissionValue>,
+  pub write: Option<PermissionValue>,
+  pub net: Option<PermissionValue>,
+  pub env: Option<PermissionValue>,
+  pub run: Option<PermissionValue>,
+  pub ffi: Option<PermissionValue>,
+  pub sys: Option<PermissionValue>,
+}
+
+/// The `permissions:` section of `app.yaml`.
+///
+/// ```yaml
+/// permissions:
+///   read: ["./assets"]
+///   net: true
+///   deny:
+///     read: ["./secrets"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppPermissions {
+  pub all: bool,
+  #[serde(flatten)]
+  pub allow: PermissionList,
+  pub deny: PermissionList,
+}
+
+impl AppPermissions {
+  /// Turns the section into flags, resolving relative paths against the
+  /// app directory.
+  pub fn to_flags(&self, app_dir: &Path) -> PermissionFlags {
+    let path = |p: &str| app_dir.join(p).to_string_lossy().into_owned();
+    let name = |n: &str| n.to_string();
+    let flag = |value: &Option<PermissionValue>, resolve: &dyn Fn(&str) -> String| {
+      value.as_ref().and_then(|value| value.to_flag(resolve))
+    };
+
+    PermissionFlags {
+      allow_all: self.all,
+      no_prompt: false,
+      trust_app: false,
+      allow_read: flag(&self.allow.read, &path),
+      deny_read: flag(&self.deny.read, &path),
+      allow_write: flag(&self.allow.write, &path),
+      deny_write: flag(&self.deny.write, &path),
+      allow_net: flag(&self.allow.net, &name),
+      deny_net: flag(&self.deny.net, &name),
+      allow_env: flag(&self.allow.env, &name),
+      deny_env: flag(&self.deny.env, &name),
+      allow_run: flag(&self.allow.run, &name),
+      deny_run: flag(&self.deny.run, &name),
+      allow_ffi: flag(&self.allow.ffi, &path),
+      deny_ffi: flag(&self.deny.ffi, &path),
+      allow_sys: flag(&self.allow.sys, &name),
+      deny_sys: flag(&self.deny.sys, &name),
+    }
+  }
+}
+
+/// The flags the `permissions:` of the app in `app_dir` add.
+///
+/// What the app allows itself is only granted once the user confirmed it,
+/// its denials always apply. Until then the app may read its own files and
+/// everything else is asked about, or denied, as for any script.
+fn app_flags(
+  app_dir: &Path,
+  permissions: &AppPermissions,
+  trusted: bool,
+  prompter: Option<&dyn PermissionPrompter>,
+) -> Result<PermissionFlags> {
+  if confirm_app_permissions(app_dir, permissions, trusted, prompter)? {
+    return Ok(permissions.to_flags(app_dir));
+  }
+  if prompter.is_none() {
+    eprintln!(
+      "{} The permissions in {:?} were not granted, confirm them in a terminal or pass --trust-app",
+      "Warning:".yellow(),
+      app_dir.join("app.yaml")
+    );
+  }
+
+  let denials = AppPermissions {
+    deny: permissions.deny.clone(),
+    ..Default::default()
+  };
+  let mut flags = denials.to_flags(app_dir);
+  flags.allow_read = Some(vec![app_dir.to_string_lossy().into_owned()]);
+  Ok(flags)
+}
+
+/// The permissions of a runtime, checked by the deno extensions through
+/// `container` and by the web, FFI and Rew ops through `web`.
+#[derive(Debug, Clone)]
+pub struct RewPermissions {
+  pub container: PermissionsContainer,
+  pub web: Arc<dyn WebPermissions>,
+}
+
+impl Default for RewPermissions {
+  fn default() -> Self {
+    Self::allow_all()
+  }
+}
+
+/// Applies one allow or deny flag through `all` when it covers everything
+/// and `each` for every listed entry otherwise.
+fn apply_flag(flag: &Option<Vec<String>>, all: impl FnOnce(), each: impl Fn(&str)) {
+  match flag {
+    Some(list) if list.is_empty() => all(),
+    Some(list) => list.iter().for_each(|item| each(item)),
+    None => {}
+  }
+}
+
+/// The entries of an allow flag, or none when the matching deny flag denies
+/// everything.
+fn unless_denied(allow: &Option<Vec<String>>, deny: &Option<Vec<String>>) -> Option<Vec<String>> {
+  match deny {
+    Some(deny) if deny.is_empty() => None,
+    _ => allow.clone(),
+  }
+}
+
+impl RewPermissions {
+  pub fn allow_all() -> Self {
+    Self {
+      container: PermissionsContainer::allow_all(Arc::new(RewDescriptorParser)),
+      web: Arc::new(DefaultWebPermissions),
+    }
+  }
+
+  /// Builds the permissions the flags describe. Without any flag everything
+  /// is allowed.
+  ///
+  /// # Arguments
+  /// * `flags` - What is allowed and denied up front.
+  /// * `prompter` - Asks about other accesses, instead of denying them. It
+  ///   becomes the prompter of the deno extensions too, for the whole
+  ///   process.
+  /// * `package` - The app "always" answers are saved for.
+  pub fn from_flags(
+    flags: &PermissionFlags,
+    prompter: Option<Arc<dyn PermissionPrompter>>,
+    package: Option<&str>,
+  ) -> Result<Self> {
+    if flags.is_unset() {
+      return Ok(Self::allow_all());
+    }
+
+    let parser = Arc::new(RewDescriptorParser);
+    let options = PermissionsOptions {
+      allow_all: flags.allow_all,
+      allow_env: flags.allow_env.clone(),
+      deny_env: flags.deny_env.clone(),
+      allow_net: flags.allow_net.clone(),
+      deny_net: flags.deny_net.clone(),
+      allow_ffi: flags.allow_ffi.clone(),
+      deny_ffi: flags.deny_ffi.clone(),
+      allow_read: flags.allow_read.clone(),
+      deny_read: flags.deny_read.clone(),
+      allow_run: flags.allow_run.clone(),
+      deny_run: flags.deny_run.clone(),
+      allow_sys: flags.allow_sys.clone(),
+      deny_sys: flags.deny_sys.clone(),
+      allow_write: flags.allow_write.clone(),
+      deny_write: flags.deny_write.clone(),
+      // Modules are loaded by rew itself, not through deno's import checks.
+      allow_import: Some(vec![]),
+      prompt: prompter.is_some(),
+    };
+    let container = PermissionsContainer::new(
+      parser.clone(),
+      Permissions::from_options(parser.as_ref(), &options)?,
+    );
+
+    let web = AllowlistWebPermissions::new();
+    web.set_hrtime(true);
+    if flags.allow_all {
+      web.set_read_all(true);
+      web.set_write_all(true);
+      web.set_net_all(true);
+      web.set_env_all(true);
+      web.set_sys_all(true);
+      web.set_exec(true);
+    }
+    apply_flag(
+      &unless_denied(&flags.allow_read, &flags.deny_read),
+      || web.set_read_all(true),
+      |p| web.allow_read(p),
+    );
+    apply_flag(
+      &unless_denied(&flags.allow_write, &flags.deny_write),
+      || web.set_write_all(true),
+      |p| web.allow_write(p),
+    );
+    apply_flag(
+      &unless_denied(&flags.allow_net, &flags.deny_net),
+      || web.set_net_all(true),
+      |h| web.allow_host(h),
+    );
+    apply_flag(
+      &unless_denied(&flags.allow_env, &flags.deny_env),
+      || web.set_env_all(true),
+      |v| web.allow_env(v),
+    );
+    apply_flag(
+      &unless_denied(&flags.allow_sys, &flags.deny_sys),
+      || web.set_sys_all(true),
+      |k| web.allow_sys(SystemsPermissionKind::new(k)),
+    );
+    // Loading a library reads it, so the listed libraries are readable too.
+    apply_flag(
+      &unless_denied(&flags.allow_ffi, &flags.deny_ffi),
+      || web.set_exec(true),
+      |p| {
+        web.set_exec(true);
+        web.allow_read(p);
+      },
+    );
+    apply_flag(
+      &flags.deny_read,
+      || web.set_read_all(false),
+      |p| web.deny_read(p),
+    );
+    apply_flag(
+      &flags.deny_write,
+      || web.set_write_all(false),
+      |p| web.deny_write(p),
+    );
+    apply_flag(
+      &flags.deny_net,
+      || web.set_net_all(false),
+      |h| web.deny_host(h),
+    );
+    apply_flag(
+      &flags.deny_env,
+      || web.set_env_all(false),
+      |v| web.deny_env(v),
+    );
+    apply_flag(
+      &flags.deny_sys,
+      || web.set_sys_all(false),
+      |k| web.deny_sys(SystemsPermissionKind::new(k)),
+    );
+    // Libraries are checked as reads, so a denied library can't be read either.
+    apply_flag(
+      &flags.deny_ffi,
+      || web.set_exec(false),
+      |p| web.deny_read(p),
+    );
+
+    let web: Arc<dyn WebPermissions> = match prompter {
+      Some(prompter) => {
+        let web = Arc::new(PromptingWebPermissions::new(
+          web,
+          prompter,
+          package.map(str::to_string),
+        ));
+        deno_permissions::set_prompter(Box::new(DenoPrompter(web.clone())));
+        web
+      }
+      None => Arc::new(web),
+    };
+
+    Ok(Self { container, web })
+  }
+
+  /// Builds the permissions for running `entry`: the flags, plus the
+  /// `permissions:` of the app the entry belongs to and the "always"
+  /// answers given to it before.
+  ///
+  /// Once an app asks for permissions, it can still use its own data
+  /// directory without asking for it. Accesses that weren't granted are
+  /// asked about when rew runs in a terminal, unless `--no-prompt` is set.
+  ///
+  /// # Arguments
+  /// * `flags` - The flags given on the command line.
+  /// * `entry` - The file being run.
+  pub fn for_entry(flags: &PermissionFlags, entry: &Path) -> Result<Self> {
+    let mut flags = flags.clone();
+    let mut package = None;
+    let prompter = (!flags.no_prompt && TtyPrompter::is_available())
+      .then(|| Arc::new(TtyPrompter) as Arc<dyn PermissionPrompter>);
+
+    if let Some(app_info) = find_app_info(entry) {
+      if let Some(permissions) = &app_info.config.permissions {
+        let trusted = flags.trust_app;
+        flags.merge(app_flags(&app_info.path, permissions, trusted, prompter.as_deref())?);
+      }
+      package = app_info
+        .config
+        .manifest
+        .as_ref()
+        .and_then(|manifest| manifest.package.clone());
+      if let Some(package) = package.as_deref().filter(|_| !flags.is_unset()) {
+        if let Some(saved) = load_saved_answers(package)? {
+          flags.merge(saved.to_flags(&app_info.path));
+        }
+        let data_dir = DataManager::data_dir("default", package)?;
+        let data_dir = data_dir.to_string_lossy().into_owned();
+        flags.merge(PermissionFlags {
+          allow_read: Some(vec![data_dir.clone()]),
+          allow_write: Some(vec![data_dir]),
+          ..Default::default()
+        });
+      }
+    }
+
+    Self::from_flags(&flags, prompter, package.as_deref())
+  }
+}
@@ -0,0 +1,497 @@
+use crate::compiler::tokenize_coffee_script;
+use crate::runtime::{EXEC_PRELUDE, RewRuntime, add_virtual_file};
+use crate::utils::get_rew_root;
+use anyhow::Result;
+use colored::*;
+use deno_core::PollEventLoopOptions;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{
+  Cmd, ConditionalEventHandler, Context, Editor, Event, EventContext, EventHandler, Helper,
+  KeyCode, KeyEvent, Modifiers, RepeatCount,
+};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+const PROMPT: &str = "rew> ";
+const INDENT: &str = "  ";
+
+/// Keywords that open an indented block when they start a line.
+const BLOCK_KEYWORDS: &[&str] = &[
+  "if", "else", "unless", "for", "while", "until", "loop", "switch", "when", "try", "catch",
+  "finally", "class", "function",
+];
+
+/// Creates the persistent execution context and the helpers the REPL calls
+/// into. `with` needs sloppy mode, which plain scripts run in.
+const REPL_SETUP: &str = r#"
+(function (id) {
+  let context;
+  const define = () => {
+    rew.prototype.mod.prototype.defineNew(id, { [id](context) { return { context }; } }, []);
+    return rew.prototype.mod.prototype.get(id).context;
+  };
+  const show = (result) => {
+    if (result !== undefined) context.rew.prototype.io.prototype.out.print(result);
+  };
+  context = define();
+  globalThis.__rew_repl_context__ = context;
+  globalThis.__rew_repl__ = {
+    eval(code) {
+      const result = globalThis.__rew_repl_eval__(code);
+      if (result instanceof Promise) return result.then(show);
+      show(result);
+    },
+    refresh() {
+      const previous = context;
+      context = define();
+      for (const key of Object.keys(previous)) {
+        if (!(key in context)) context[key] = previous[key];
+      }
+      globalThis.__rew_repl_context__ = context;
+    },
+    completions() {
+      const names = new Set(Object.getOwnPropertyNames(globalThis));
+      for (const key in context) names.add(key);
+      for (const key of Object.keys(context.rew.prototype)) {
+        names.add(`rew::${key}`);
+        const ns = context.rew.prototype[key]?.prototype;
+        if (ns && typeof ns === "object") {
+          for (const sub of Object.keys(ns)) {
+            if (!sub.startsWith("_")) names.add(`rew::${key}::${sub}`);
+          }
+        }
+      }
+      return JSON.stringify([...names].filter((name) => !name.startsWith("__")));
+    },
+  };
+})
+"#;
+
+const REPL_EVAL: &str = r#"
+globalThis.__rew_repl_eval__ = function (__rew_repl_code__) {
+  with (globalThis.__rew_repl_context__) {
+    return eval(__rew_repl_code__);
+  }
+};
+"#;
+
+enum ReplInput {
+  Line(String),
+  Interrupted,
+  Eof,
+}
+
+/// Returns `true` if `line` opens an indented block.
+fn opens_block(line: &str) -> bool {
+  let trimmed = line.trim_end();
+  if ["->", "=>", "(", "[", "{"]
+    .iter()
+    .any(|end| trimmed.ends_with(end))
+  {
+    return true;
+  }
+
+  let first_word = trimmed
+    .trim_start()
+    .split(|c: char| !c.is_alphanumeric() && c != '_')
+    .next()
+    .unwrap_or("");
+  BLOCK_KEYWORDS.contains(&first_word) && !trimmed.contains(" then ")
+}
+
+/// Decides whether the buffer needs more lines before it can be evaluated.
+///
+/// Input continues while brackets or `"""` strings are open, while a
+/// `#declare` has not reached its `;`, after a line that opens a block, and
+/// for any multi-line buffer until an empty line is entered.
+fn needs_more_input(buffer: &str) -> bool {
+  let mut depth = 0;
+  for token in tokenize_coffee_script(buffer) {
+    if token.token_type == "OTHER" {
+      match token.value.as_str() {
+        "(" | "[" | "{" => depth += 1,
+        ")" | "]" | "}" => depth -= 1,
+        _ => {}
+      }
+    }
+  }
+  if depth > 0 || buffer.matches("\"\"\"").count() % 2 == 1 {
+    return true;
+  }
+
+  let mut in_declare = false;
+  for line in buffer.lines() {
+    let trimmed = line.trim();
+    if trimmed.starts_with("#declare") || trimmed.starts_with("//declare") {
+      in_declare = true;
+    }
+    if in_declare && trimmed.ends_with(';') {
+      in_declare = false;
+    }
+  }
+  if in_declare {
+    return true;
+  }
+
+  let last_line = buffer.rsplit('\n').next().unwrap_or("");
+  if last_line.trim().is_empty() {
+    return false;
+  }
+
+  opens_block(last_line) || last_line.trim_end().ends_with(['\\', ',']) || buffer.contains('\n')
+}
+
+/// Indentation for the line that follows `buffer`.
+fn next_indent(buffer: &str) -> String {
+  let last_line = buffer.rsplit('\n').next().unwrap_or("");
+  let mut indent: String = last_line
+    .chars()
+    .take_while(|c| c.is_whitespace())
+    .collect();
+  if opens_block(last_line) {
+    indent.push_str(INDENT);
+  }
+  indent
+}
+
+/// Inserts a newline plus indentation instead of accepting incomplete input.
+struct IndentHandler;
+
+impl ConditionalEventHandler for IndentHandler {
+  fn handle(
+    &self,
+    _evt: &Event,
+    _n: RepeatCount,
+    _positive: bool,
+    ctx: &EventContext,
+  ) -> Option<Cmd> {
+    let line = ctx.line();
+    if ctx.pos() == line.len() && needs_more_input(line) {
+      Some(Cmd::Insert(1, format!("\n{}", next_indent(line))))
+    } else {
+      None
+    }
+  }
+}
+
+struct ReplHelper {
+  completions: Arc<Mutex<Vec<String>>>,
+}
+
+impl Completer for ReplHelper {
+  type Candidate = Pair;
+
+  fn complete(
+    &self,
+    line: &str,
+    pos: usize,
+    _ctx: &Context<'_>,
+  ) -> rustyline::Result<(usize, Vec<Pair>)> {
+    let start = line[..pos]
+      .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$' || c == '@' || c == ':'))
+      .map(|idx| idx + 1)
+      .unwrap_or(0);
+    let word = &line[start..pos];
+    if word.is_empty() {
+      return Ok((start, vec![]));
+    }
+
+    let mut candidates: Vec<Pair> = self
+      .completions
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|name| name.starts_with(word))
+      .map(|name| Pair {
+        display: name.clone(),
+        replacement: name.clone(),
+      })
+      .collect();
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+
+    Ok((start, candidates))
+  }
+}
+
+impl Hinter for ReplHelper {
+  type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {
+  fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+    if needs_more_input(ctx.input()) {
+      Ok(ValidationResult::Incomplete)
+    } else {
+      Ok(ValidationResult::Valid(None))
+    }
+  }
+}
+
+impl Helper for ReplHelper {}
+
+/// An interactive session running every input in one long-lived runtime.
+///
+/// Inputs are compiled like any other Rew file, so `#declare` rules,
+/// `using namespace` and variables stay in effect between lines.
+pub struct Repl {
+  runtime: RewRuntime,
+  entry: PathBuf,
+  loaded: HashSet<PathBuf>,
+  completions: Arc<Mutex<Vec<String>>>,
+}
+
+impl Repl {
+  pub async fn new(args: Option<Vec<String>>) -> Result<Self> {
+    let mut runtime = RewRuntime::new(args, None)?;
+    // Assignments without `let` land on the global object and survive
+    // between inputs.
+    runtime.add_compile_option("autoLet.off");
+    runtime.runtime.execute_script("<repl>", REPL_EVAL)?;
+
+    let entry = std::env::current_dir()?.join("<repl>.coffee");
+    let mut repl = Self {
+      runtime,
+      entry,
+      loaded: HashSet::new(),
+      completions: Arc::new(Mutex::new(vec![])),
+    };
+
+    // `#std` has to be loaded before the context is created so the context
+    // sees every builtin namespace.
+    repl.load_dependencies(EXEC_PRELUDE).await?;
+    let entry_id = serde_json::to_string(&repl.entry.to_string_lossy())?;
+    repl
+      .runtime
+      .runtime
+      .execute_script("<repl>", format!("{}({});", REPL_SETUP, entry_id))?;
+    repl.eval(EXEC_PRELUDE).await?;
+
+    Ok(repl)
+  }
+
+  /// Compiles and runs every not yet loaded module `source` imports.
+  ///
+  /// # Returns
+  /// * `true` if a builtin module was loaded.
+  async fn load_dependencies(&mut self, source: &str) -> Result<bool> {
+    add_virtual_file(&self.entry.to_string_lossy(), source);
+    let files_with_flags = RewRuntime::resolve_runnable_includes(&self.entry)?;
+
+    let mut files = Vec::new();
+    for (path, content, preprocess) in files_with_flags.into_iter().skip(1) {
+      if preprocess {
+        self.runtime.promote_declarations(&content);
+      }
+      if self.loaded.insert(path.clone()) {
+        files.push((path, content));
+      }
+    }
+
+    if files.is_empty() {
+      return Ok(false);
+    }
+
+    let has_builtins = files
+      .iter()
+      .any(|(path, _)| path.to_string_lossy().starts_with('#'));
+    let script = self.runtime.prepare(files, None).await?;
+    self.runtime.runtime.execute_script("<repl>", script)?;
+
+    Ok(has_builtins)
+  }
+
+  async fn eval(&mut self, source: &str) -> Result<()> {
+    if self.load_dependencies(source).await? {
+      self
+        .runtime
+        .runtime
+        .execute_script("<repl>", "__rew_repl__.refresh();")?;
+    }
+    self.runtime.promote_declarations(source);
+
+    let entry = self.entry.clone();
+    let compiled = self.runtime.compile_and_run(source, &entry, false).await?;
+
+    let result = self.runtime.runtime.execute_script(
+      "<repl>",
+      format!("__rew_repl__.eval({});", serde_json::to_string(&compiled)?),
+    )?;
+    let resolved = self.runtime.runtime.resolve(result);
+    self
+      .runtime
+      .runtime
+      .with_event_loop_promise(Box::pin(resolved), PollEventLoopOptions::default())
+      .await?;
+
+    Ok(())
+  }
+
+  fn refresh_completions(&mut self) -> Result<()> {
+    let result = self
+      .runtime
+      .runtime
+      .execute_script("<repl>", "__rew_repl__.completions();")?;
+    let names = {
+      let scope = &mut self.runtime.runtime.handle_scope();
+      result.open(scope).to_rust_string_lossy(scope)
+    };
+    *self.completions.lock().unwrap() = serde_json::from_str(&names)?;
+    Ok(())
+  }
+
+  /// Waits for the next input while keeping timers and other pending work
+  /// of the runtime going.
+  async fn next_input(&mut self, inputs: &mut UnboundedReceiver<ReplInput>) -> ReplInput {
+    let mut idle = false;
+    loop {
+      tokio::select! {
+        input = inputs.recv() => return input.unwrap_or(ReplInput::Eof),
+        result = self.runtime.runtime.run_event_loop(PollEventLoopOptions::default()), if !idle => {
+          if let Err(e) = result {
+            eprintln!("{}", e.to_string().red());
+          }
+          idle = true;
+        }
+      }
+    }
+  }
+
+  pub async fn run(mut self) -> Result<()> {
+    let history_path = get_rew_root().join("data").join("repl_history");
+    let completions = Arc::clone(&self.completions);
+    let (input_tx, mut input_rx) = unbounded_channel();
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel::<()>();
+
+    // rustyline blocks, so it gets its own thread and hands lines over.
+    std::thread::spawn(move || {
+      let mut editor = match Editor::<ReplHelper, FileHistory>::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+          eprintln!("Failed to start line editor: {}", e);
+          let _ = input_tx.send(ReplInput::Eof);
+          return;
+        }
+      };
+      editor.set_helper(Some(ReplHelper { completions }));
+      editor.bind_sequence(
+        KeyEvent(KeyCode::Enter, Modifiers::NONE),
+        EventHandler::Conditional(Box::new(IndentHandler)),
+      );
+      let _ = editor.load_history(&history_path);
+
+      while ready_rx.recv().is_ok() {
+        let input = match editor.readline(PROMPT) {
+          Ok(line) => {
+            if !line.trim().is_empty() {
+              let _ = editor.add_history_entry(line.as_str());
+              let _ = editor.save_history(&history_path);
+            }
+            ReplInput::Line(line)
+          }
+          Err(ReadlineError::Interrupted) => ReplInput::Interrupted,
+          Err(ReadlineError::Eof) => ReplInput::Eof,
+          Err(e) => {
+            eprintln!("{}", e);
+            ReplInput::Eof
+          }
+        };
+        let eof = matches!(input, ReplInput::Eof);
+        if input_tx.send(input).is_err() || eof {
+          break;
+        }
+      }
+    });
+
+    println!(
+      "Rew {} (type {} or press Ctrl+D to exit)",
+      env!("CARGO_PKG_VERSION"),
+      ".exit".yellow()
+    );
+
+    loop {
+      if let Err(e) = self.refresh_completions() {
+        eprintln!("{}", e.to_string().red());
+      }
+      if ready_tx.send(()).is_err() {
+        break;
+      }
+
+      match self.next_input(&mut input_rx).await {
+        ReplInput::Line(line) => {
+          let source = line.trim_end();
+          if source.trim().is_empty() {
+            continue;
+          }
+          if source.trim() == ".exit" {
+            break;
+          }
+          if let Err(e) = self.eval(source).await {
+            eprintln!("{}", e.to_string().red());
+          }
+        }
+        ReplInput::Interrupted => {
+          println!("(To exit, press Ctrl+D or type {})", ".exit".yellow());
+        }
+        ReplInput::Eof => break,
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn single_lines_run_right_away() {
+    assert!(!needs_more_input("x = 1"));
+    assert!(!needs_more_input("print \"(\""));
+    assert!(!needs_more_input("if ready then go()"));
+    assert!(!needs_more_input("#declare* \"say\" = rew::io::out.print;"));
+  }
+
+  #[test]
+  fn open_blocks_and_brackets_ask_for_more() {
+    assert!(needs_more_input("f = ->"));
+    assert!(needs_more_input("if ready"));
+    assert!(needs_more_input("class Point"));
+    assert!(needs_more_input("point = {"));
+    assert!(needs_more_input("print 1,"));
+    assert!(needs_more_input("print 1 + \\"));
+    assert!(needs_more_input("#declare* \"say\" ="));
+  }
+
+  #[test]
+  fn multi_line_input_ends_with_an_empty_line() {
+    assert!(needs_more_input("f = ->\n  1"));
+    assert!(needs_more_input("point = {\n  x: 1\n}"));
+    assert!(!needs_more_input("f = ->\n  1\n"));
+    assert!(!needs_more_input("point = {\n  x: 1\n}\n"));
+  }
+
+  #[test]
+  fn open_brackets_and_strings_outlast_empty_lines() {
+    assert!(needs_more_input("point = {\n  x: 1\n"));
+    assert!(needs_more_input("text = \"\"\"\nhello\n"));
+    assert!(!needs_more_input("text = \"\"\"\nhello\n\"\"\"\n"));
+  }
+
+  #[test]
+  fn next_line_is_indented_after_a_block_opener() {
+    assert_eq!(next_indent("f = ->"), INDENT);
+    assert_eq!(next_indent("f = ->\n  if ready"), "    ");
+    assert_eq!(next_indent("f = ->\n  x = 1"), "  ");
+    assert_eq!(next_indent("x = 1"), "");
+  }
+}
@@ -1,31 +1,33 @@
 use super::civet::get_civet_script;
 use super::compiler::{CompilerOptions, compile_rew_stuff};
-use crate::builtins::BUILTIN_MODULES;
+use crate::brew::{Brew, read_brew};
 use crate::compiler::CompilerResults;
-use crate::data_manager::{DataFormat, DataManager};
+use crate::data_manager::{DataFormat, DataManager, ScanOptions};
 use crate::declarations::{Declaration, DeclarationEngine};
+use crate::diagnostics::{Diagnostics, RewDiagnostic, Span};
 use crate::ext::{console, ffi, process, url, web, webidl};
+use crate::fs_watch::{op_fs_watch, op_fs_watch_next};
 use crate::jsx::compile_jsx;
+use crate::module_graph::{ModuleGraph, ModuleKind};
+use crate::permissions::RewPermissions;
 use crate::runtime_script::get_runtime_script;
+use crate::source_maps::{ModuleSourceMap, RewModuleLoader, ScriptSourceMap, SourceMapStore};
+use crate::trust::check_brew;
 use crate::utils::find_app_path;
+use crate::vfs::{MemoryFs, OverlayFs, RealFs};
 use crate::workers::{
   op_thread_message, op_thread_post_message, op_thread_receive, op_thread_spawn,
-  op_thread_terminate,
+  op_thread_take_message, op_thread_terminate,
 };
 use anyhow::{Context, Result};
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use deno_core::OpState;
 use deno_core::PollEventLoopOptions;
 use deno_core::error::CoreError;
-use deno_core::{JsRuntime, RuntimeOptions, extension, op2};
-use deno_fs::{FileSystem, RealFs};
-use deno_permissions::{
-  AllowRunDescriptor, AllowRunDescriptorParseResult, DenyRunDescriptor, EnvDescriptor,
-  EnvDescriptorParseError, FfiDescriptor, ImportDescriptor, NetDescriptor, NetDescriptorParseError,
-  PathQueryDescriptor, PathResolveError, PermissionDescriptorParser, PermissionsContainer,
-  ReadDescriptor, RunDescriptorParseError, RunQueryDescriptor, SysDescriptor,
-  SysDescriptorParseError, WriteDescriptor,
-};
+use deno_core::{JsRuntime, RuntimeOptions, SharedArrayBufferStore, extension, op2};
+use deno_error::JsErrorBox;
+use deno_fs::{FileSystem, FileSystemRc, OpenOptions};
+use deno_io::fs::{FsError, FsResult};
 use futures::stream::{self, StreamExt};
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -33,55 +35,62 @@ use serde::{Deserialize, Serialize};
 use serde_yaml;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::collections::HashSet;
-use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::Mutex;
+use std::sync::Arc;
 
 // use crate::shell::{op_shell_close, op_shell_kill, op_shell_read, op_shell_spawn, op_shell_write};
 
 #[derive(Default)]
 pub struct RuntimeArgs(pub Vec<String>);
 
-/// Encodes the provided string content into Base64 format.
-/// 
-/// # Arguments
-/// * `content` - The string to encode.
-/// 
-/// # Returns
-/// * A Base64 encoded string.
-fn encode_brew_file(content: &str) -> String {
-  BASE64.encode(content.as_bytes())
+impl RuntimeArgs {
+  /// The arguments the process was started with, without the program name.
+  pub fn from_env() -> Self {
+    Self(std::env::args().skip(1).collect())
+  }
 }
 
-/// Decodes a Base64 encoded string back into its original form.
-/// 
-/// # Arguments
-/// * `encoded` - The Base64 encoded string to decode.
-/// 
-/// # Returns
-/// * A `Result` containing the decoded string or an error message.
-fn decode_brew_file(encoded: &str) -> Result<String> {
-  let decoded = BASE64
-    .decode(encoded.trim())
-    .map_err(|e| anyhow::anyhow!("Failed to decode brew file: {}", e))?;
-
-  String::from_utf8(decoded)
-    .map_err(|e| anyhow::anyhow!("Failed to convert decoded bytes to string: {}", e))
-}
+/// Files registered at runtime, served as the top layer of every runtime's file system.
+pub static VIRTUAL_FILES: Lazy<MemoryFs> = Lazy::new(MemoryFs::default);
 
-pub static VIRTUAL_FILES: Lazy<Mutex<Vec<(String, String)>>> = Lazy::new(|| Mutex::new(vec![]));
+/// Shared by every runtime in the process, so `SharedArrayBuffer`s and
+/// transferred `ArrayBuffer`s reach worker threads without being copied.
+static SHARED_ARRAY_BUFFERS: Lazy<SharedArrayBufferStore> =
+  Lazy::new(SharedArrayBufferStore::default);
 
 /// Adds a virtual file to the runtime's virtual file storage.
 /// 
+/// Adding a path that is already registered replaces its contents.
+/// 
 /// # Arguments
 /// * `path` - The path of the virtual file.
 /// * `contents` - The contents of the virtual file.
 pub fn add_virtual_file(path: &str, contents: &str) {
-  let mut files = VIRTUAL_FILES.lock().unwrap();
-  files.push((path.to_string(), contents.to_string()));
+  VIRTUAL_FILES.insert(Path::new(path), contents);
+}
+
+/// Returns `true` if a virtual file has been registered under `path`.
+pub fn is_virtual_file(path: &Path) -> bool {
+  VIRTUAL_FILES.contains(path)
+}
+
+/// Returns the contents of the virtual file registered under `path`, if any.
+pub fn get_virtual_file(path: &Path) -> Option<String> {
+  VIRTUAL_FILES
+    .get(path)
+    .map(|data| String::from_utf8_lossy(&data).into_owned())
+}
+
+/// The file system runtimes use unless told otherwise: the virtual files
+/// layered read-only over the real disk.
+pub fn default_fs() -> FileSystemRc {
+  Arc::new(OverlayFs::new(
+    Arc::new(VIRTUAL_FILES.clone()),
+    Arc::new(RealFs),
+  ))
 }
 
 pub fn is_js_executable(mod_id: &str) -> bool {
@@ -91,92 +100,127 @@ pub fn is_js_executable(mod_id: &str) -> bool {
   )
 }
 
+/// Prepended to every snippet run through [`RewRuntime::run_code`].
+pub(crate) const EXEC_PRELUDE: &str = "import \"#std!\"\nusing namespace rew::ns\n";
+
 #[derive(Default)]
 struct RuntimeState {
   current_dir: PathBuf,
   args: Vec<String>,
+  /// The configs of the apps whose entries this runtime ran, by package.
+  apps: HashMap<String, crate::utils::AppConfig>,
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct BuildOptions {
-  pub bundle_all: bool,
-  pub entry_file: Option<PathBuf>,
+/// What the Civet compile step hands back.
+#[derive(Deserialize)]
+struct CivetOutput {
+  #[serde(default)]
+  code: String,
+  map: Option<serde_json::Value>,
+  #[serde(default)]
+  errors: Vec<CivetError>,
 }
 
-#[derive(Debug, Clone)]
-struct TestPermissionDescriptorParser;
+#[derive(Deserialize)]
+struct CivetError {
+  message: String,
+  body: Option<String>,
+  line: Option<serde_json::Value>,
+  column: Option<serde_json::Value>,
+}
 
-impl TestPermissionDescriptorParser {
-  fn join_path_with_root(&self, path: &str) -> PathBuf {
-    if path.starts_with("C:\\") {
-      PathBuf::from(path)
-    } else {
-      PathBuf::from("/").join(path)
+impl CivetError {
+  /// Converts the error into a diagnostic pointing at the original source.
+  ///
+  /// # Arguments
+  /// * `line_map` - The original line of every line Civet was given.
+  fn into_diagnostic(self, line_map: &[usize]) -> RewDiagnostic {
+    let mut message = self.message;
+    let mut position = self
+      .line
+      .and_then(|line| line.as_u64())
+      .zip(self.column.and_then(|column| column.as_u64()));
+
+    // Some errors only carry their position in a `file:line:column` prefix.
+    let location = Regex::new(r"^.*?:(\d+):(\d+) ").unwrap();
+    if let Some(caps) = location.captures(&message.clone()) {
+      position = position.or(caps[1].parse().ok().zip(caps[2].parse().ok()));
+      message = message[caps[0].len()..].to_string();
     }
-  }
-}
 
-impl PermissionDescriptorParser for TestPermissionDescriptorParser {
-  fn parse_read_descriptor(&self, text: &str) -> Result<ReadDescriptor, PathResolveError> {
-    Ok(ReadDescriptor(self.join_path_with_root(text)))
-  }
+    // Anything after the first line is a dump of the remaining input.
+    let mut diagnostic = RewDiagnostic::error(
+      message
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("Failed to compile"),
+    );
 
-  fn parse_write_descriptor(&self, text: &str) -> Result<WriteDescriptor, PathResolveError> {
-    Ok(WriteDescriptor(self.join_path_with_root(text)))
-  }
+    if let Some((line, column)) = position {
+      let line = line_map
+        .get((line as usize).saturating_sub(1))
+        .map_or(line as usize, |line| line + 1);
+      diagnostic = diagnostic.with_span(Span::new(line, column as usize, 1));
+    }
 
-  fn parse_net_descriptor(&self, text: &str) -> Result<NetDescriptor, NetDescriptorParseError> {
-    NetDescriptor::parse(text)
-  }
+    if let Some(body) = self.body {
+      let expected: Vec<&str> = body
+        .lines()
+        .filter(|line| line.starts_with('\t'))
+        .map(str::trim)
+        .collect();
+      if !expected.is_empty() {
+        let shown = expected.iter().take(5).copied().collect::<Vec<_>>().join(", ");
+        let more = if expected.len() > 5 { ", ..." } else { "" };
+        diagnostic = diagnostic.with_hint(format!("expected one of {}{}", shown, more));
+      }
+      if let Some(found) = body.lines().find_map(|line| line.strip_prefix("Found: ")) {
+        diagnostic = diagnostic.with_hint(format!("found {}", found));
+      }
+    }
 
-  fn parse_import_descriptor(
-    &self,
-    text: &str,
-  ) -> Result<ImportDescriptor, NetDescriptorParseError> {
-    ImportDescriptor::parse(text)
+    diagnostic
   }
+}
 
-  fn parse_env_descriptor(&self, text: &str) -> Result<EnvDescriptor, EnvDescriptorParseError> {
-    Ok(EnvDescriptor::new(text))
-  }
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+  pub bundle_all: bool,
+  pub entry_file: Option<PathBuf>,
+  /// Deflate the module bodies stored in the brew.
+  pub compress: bool,
+  /// Signing key to sign the brew with, a file or a key name.
+  pub sign: Option<String>,
+}
 
-  fn parse_sys_descriptor(&self, text: &str) -> Result<SysDescriptor, SysDescriptorParseError> {
-    SysDescriptor::parse(text.to_string())
-  }
+/// A compiled module wrapped into its definition.
+pub(crate) struct PreparedModule {
+  pub id: String,
+  /// The `app://package/entry` name the module is also defined under.
+  pub alias: Option<String>,
+  pub code: String,
+}
 
-  fn parse_allow_run_descriptor(
-    &self,
-    text: &str,
-  ) -> Result<AllowRunDescriptorParseResult, RunDescriptorParseError> {
-    Ok(AllowRunDescriptorParseResult::Descriptor(
-      AllowRunDescriptor(self.join_path_with_root(text)),
-    ))
-  }
+/// The modules of a script, before they are joined together.
+pub(crate) struct PreparedScript {
+  pub modules: Vec<PreparedModule>,
+  /// The modules to run once everything is defined.
+  pub entries: Vec<String>,
+  pub map: ScriptSourceMap,
+}
 
-  fn parse_deny_run_descriptor(&self, text: &str) -> Result<DenyRunDescriptor, PathResolveError> {
-    if text.contains("/") {
-      Ok(DenyRunDescriptor::Path(self.join_path_with_root(text)))
-    } else {
-      Ok(DenyRunDescriptor::Name(text.to_string()))
+impl PreparedScript {
+  /// Joins the modules and entry calls into the script the runtime runs.
+  pub fn script(&self) -> String {
+    let mut script: String = self.modules.iter().map(|m| m.code.as_str()).collect();
+    for entry in &self.entries {
+      script.push_str(&format!(
+        "\nrew.prototype.mod.prototype.get('{}');",
+        entry.replace('\\', "\\\\")
+      ));
     }
-  }
-
-  fn parse_ffi_descriptor(&self, text: &str) -> Result<FfiDescriptor, PathResolveError> {
-    Ok(FfiDescriptor(self.join_path_with_root(text)))
-  }
-
-  fn parse_path_query(&self, path: &str) -> Result<PathQueryDescriptor, PathResolveError> {
-    Ok(PathQueryDescriptor {
-      resolved: self.join_path_with_root(path),
-      requested: path.to_string(),
-    })
-  }
-
-  fn parse_run_query(
-    &self,
-    requested: &str,
-  ) -> Result<RunQueryDescriptor, RunDescriptorParseError> {
-    RunQueryDescriptor::parse(requested).map_err(Into::into)
+    script
   }
 }
 
@@ -193,6 +237,8 @@ extension!(
     op_fs_copy,
     op_fs_rename,
     op_fs_cwdir,
+    op_fs_watch,
+    op_fs_watch_next,
     op_to_base64,
     op_from_base64,
     op_find_app,
@@ -202,6 +248,12 @@ extension!(
     op_data_read,
     op_data_write,
     op_data_delete,
+    op_data_transaction,
+    op_kv_get,
+    op_kv_set,
+    op_kv_delete,
+    op_kv_scan,
+    op_kv_increment,
     op_data_exists,
     op_data_list,
     op_data_read_binary,
@@ -215,6 +267,7 @@ extension!(
     op_thread_post_message,
     op_thread_terminate,
     op_thread_receive,
+    op_thread_take_message,
     op_fetch_env,
     op_get_args,
     op_os_info_os,
@@ -228,25 +281,47 @@ extension!(
     op_vfile_get,
     op_terminal_size
   ],
-  state = |state| {
-    let permissions =
-      PermissionsContainer::allow_all(std::sync::Arc::new(TestPermissionDescriptorParser));
-
-    state.put::<PermissionsContainer>(permissions.clone());
+  options = {
+    permissions: RewPermissions
+  },
+  state = |state, config| {
+    state.put(config.permissions.container.clone());
+    state.put(config.permissions);
   }
 );
 
+/// Emits the warnings among `diagnostics` and fails with the errors, if any.
+fn check_diagnostics(filepath: &Path, source: &str, diagnostics: Vec<RewDiagnostic>) -> Result<()> {
+  let (errors, warnings): (Vec<_>, Vec<_>) = diagnostics
+    .into_iter()
+    .map(|diagnostic| diagnostic.in_file(filepath, source))
+    .partition(RewDiagnostic::is_error);
+
+  warnings.iter().for_each(crate::diagnostics::emit);
+  if errors.is_empty() {
+    Ok(())
+  } else {
+    Err(Diagnostics(errors).into())
+  }
+}
+
 pub fn get_rew_runtime(
   is_compiler: bool,
   is_main: bool,
   args: Option<Vec<String>>,
+  permissions: RewPermissions,
+  fs: FileSystemRc,
 ) -> Result<JsRuntime> {
-  let mut extensions = vec![rewextension::init()];
+  let web_options = web::WebOptions {
+    permissions: permissions.web.clone(),
+    ..Default::default()
+  };
+  let mut extensions = vec![rewextension::init(permissions)];
 
   extensions.extend(webidl::extensions(false));
   extensions.extend(console::extensions(false));
   extensions.extend(url::extensions(false));
-  extensions.extend(web::extensions(web::WebOptions::default(), false));
+  extensions.extend(web::extensions(web_options, false));
   extensions.extend(ffi::extensions(false));
   extensions.extend(crate::ext::telemetry::extensions(false));
   extensions.extend(crate::ext::networking::extensions(false));
@@ -259,16 +334,17 @@ pub fn get_rew_runtime(
     }),
     false,
   ));
-  extensions.extend(crate::ext::fs::extensions(
-    std::rc::Rc::new(RealFs) as std::rc::Rc<dyn FileSystem>,
-    false,
-  ));
+  extensions.extend(crate::ext::fs::extensions(fs, false));
   extensions.extend(crate::ext::os::extensions(false));
   extensions.extend(process::extensions(false));
 
+  let source_maps = SourceMapStore::default();
+
   let mut runtime = JsRuntime::new(RuntimeOptions {
     extensions,
     // module_loader: Some(Rc::new(deno_core::FsModuleLoader)),
+    module_loader: Some(Rc::new(RewModuleLoader::new(source_maps.clone()))),
+    shared_array_buffer_store: Some(SHARED_ARRAY_BUFFERS.clone()),
     is_main,
     ..Default::default()
   });
@@ -278,9 +354,12 @@ pub fn get_rew_runtime(
   let state = RuntimeState {
     current_dir: current_dir.clone(),
     args: args.unwrap_or_default(),
+    apps: HashMap::new(),
   };
 
   runtime.op_state().borrow_mut().put(state);
+  runtime.op_state().borrow_mut().put(source_maps);
+  runtime.op_state().borrow_mut().put(DataManagers::default());
   runtime.execute_script(
     "<setup>",
     r#"
@@ -301,7 +380,7 @@ globalThis._evalVM = (string) => eval(string);
   Ok(runtime)
 }
 
-fn get_storage_path(file_path_str: &str) -> PathBuf {
+pub(crate) fn get_storage_path(file_path_str: &str) -> PathBuf {
   if !file_path_str.starts_with("./") {
     return PathBuf::from(file_path_str);
   }
@@ -332,11 +411,18 @@ pub struct RewRuntime {
   inlinemap: bool,
 
   compile_options: Vec<String>,
+  module_maps: HashMap<PathBuf, ModuleSourceMap>,
+  /// How many lines at the top of a file were put there by rew, by file.
+  /// Errors count lines from after them.
+  preludes: HashMap<PathBuf, usize>,
 }
 
 impl RewRuntime {
   pub fn new(args: Option<Vec<String>>, jruntime: Option<JsRuntime>) -> Result<Self> {
-    let runtime = jruntime.unwrap_or_else(|| get_rew_runtime(true, true, args).unwrap());
+    let runtime = match jruntime {
+      Some(runtime) => runtime,
+      None => get_rew_runtime(true, true, args, RewPermissions::allow_all(), default_fs())?,
+    };
     // let mut compiler_runtime = get_compiler_runtime();
 
     let declaration_engine = DeclarationEngine {
@@ -346,242 +432,86 @@ impl RewRuntime {
     Ok(Self {
       runtime,
       inlinemap: false,
-      sourcemap: false,
+      sourcemap: true,
       compile_options: vec![],
+      module_maps: HashMap::new(),
+      preludes: HashMap::new(),
       // compiler_runtime,
       declaration_engine,
     })
   }
 
-  pub fn resolve_includes_recursive_from<P: AsRef<Path>>(
-    filepath: P,
-  ) -> Result<Vec<(PathBuf, String, bool)>> {
-    let filepath = filepath
-      .as_ref()
-      .canonicalize()
-      .with_context(|| "Failed to resolve import".to_string())?;
-
-    let import_re = Regex::new(r#"(?m)^\s*import\s+(?:[^;]*?\s+from\s+)?["']([^"']+)["']"#)
-      .context("Invalid regex pattern")?;
-    let external_re =
-      Regex::new(r#"(?m)^\s*// external\s+['"]([^'"]+)['"]"#).context("Invalid regex pattern")?;
-
-    let mut visited = HashSet::new();
-    let mut result = Vec::new();
-
-    fn visit_file(
-      file_path: &Path,
-      visited: &mut HashSet<PathBuf>,
-      result: &mut Vec<(PathBuf, String, bool)>,
-      preprocess_import: bool,
-      import_re: &Regex,
-      external_re: &Regex,
-    ) -> Result<()> {
-      if visited.contains(file_path) {
-        return Ok(());
-      }
-
-      let mut should_preprocess = preprocess_import;
-      let file_path_unf = file_path.to_str().unwrap_or("");
-      let file_path_str = if file_path_unf.ends_with('!') {
-        should_preprocess = true;
-        &file_path_unf[0..file_path_unf.len() - 1]
-      } else {
-        file_path_unf
-      };
-
-      let is_brew_file = file_path
-        .extension()
-        .is_some_and(|ext| ext == "brew" || ext == "qrew");
-
-      let real_content = if let Some(v) = VIRTUAL_FILES
-        .lock()
-        .unwrap()
-        .iter()
-        .find(|(p, _)| p == file_path_str)
-      {
-        v.1.clone()
-      } else if file_path_str.starts_with("#") {
-        "".to_string()
-      } else {
-        fs::read_to_string(file_path).with_context(|| format!("Failed to read {:?}", file_path))?
-      };
+  /// Creates a runtime whose ops are limited to `permissions`.
+  pub fn with_permissions(args: Option<Vec<String>>, permissions: RewPermissions) -> Result<Self> {
+    Self::with_options(args, permissions, default_fs())
+  }
 
-      let content = if file_path_str.starts_with("#") {
-        if let Some(builtin_content) = BUILTIN_MODULES.get(file_path_str) {
-          builtin_content.to_string()
-        } else {
-          return Err(anyhow::anyhow!(
-            "Builtin module not found: {}",
-            file_path_str
-          ));
-        }
-      } else if is_brew_file {
-        if let Ok(decoded) = decode_brew_file(&real_content) {
-          decoded
-        } else {
-          real_content
-        }
-      } else {
-        real_content
-      };
+  /// Creates a runtime whose ops are limited to `permissions` and go through `fs`.
+  ///
+  /// # Arguments
+  /// * `args` - The arguments passed to the program.
+  /// * `permissions` - What the program may access.
+  /// * `fs` - The file system every `rew::fs` and `Deno` fs op uses, e.g. a
+  ///   `MemoryFs` in tests or a `RootedFs` to sandbox an app.
+  pub fn with_options(
+    args: Option<Vec<String>>,
+    permissions: RewPermissions,
+    fs: FileSystemRc,
+  ) -> Result<Self> {
+    Self::new(None, Some(get_rew_runtime(true, true, args, permissions, fs)?))
+  }
 
-      visited.insert(PathBuf::from(file_path_str));
+  /// Adds a Civet option to every compilation done by this runtime.
+  ///
+  /// Uses the same syntax as `using compiler`, e.g. `autoLet.off`.
+  pub fn add_compile_option(&mut self, option: &str) {
+    self.compile_options.push(option.to_string());
+  }
 
-      // Create a normalized path for storage
-      let storage_path = get_storage_path(file_path_str);
+  /// Forgets the global declarations and `using pub compiler` options earlier
+  /// compilations left behind, so the next file compiles on its own.
+  pub(crate) fn reset_compile_state(&mut self) {
+    self.declaration_engine.global_declarations.clear();
+    self.compile_options.clear();
+  }
 
-      result.push((storage_path, content.clone(), should_preprocess));
+  /// Registers the `#declare` rules found in `source` as global declarations.
+  pub(crate) fn promote_declarations(&mut self, source: &str) {
+    let local_declarations = self.declaration_engine.process_script(source);
 
-      let parent = file_path.parent().unwrap_or(Path::new("."));
+    for (name, decl) in local_declarations {
+      self
+        .declaration_engine
+        .global_declarations
+        .insert(name, decl);
+    }
+  }
 
-      if is_brew_file || content.starts_with("\"no-compile\"") {
-        for cap in external_re.captures_iter(&content) {
-          let external_app_path = cap[1].to_string();
-          let mut should_preprocess_import = false;
-          let external_app = if external_app_path.ends_with('!') {
-            should_preprocess_import = true;
-            &external_app_path[0..external_app_path.len() - 1]
-          } else {
-            &external_app_path
-          };
-
-          if external_app.contains('/') {
-            let parts: Vec<&str> = external_app.split('/').collect();
-            if parts.len() == 2 {
-              let package_name = parts[0];
-              let entry_name = parts[1];
-
-              if let Some(app_entry) =
-                crate::utils::resolve_app_entry(package_name, Some(entry_name))
-              {
-                visit_file(
-                  &app_entry,
-                  visited,
-                  result,
-                  should_preprocess_import,
-                  import_re,
-                  external_re,
-                )?;
-              }
-            }
-          } else {
-            visit_file(
-              &PathBuf::from(external_app),
-              visited,
-              result,
-              should_preprocess_import,
-              import_re,
-              external_re,
-            )?;
-          }
-        }
-      } else {
-        for cap in import_re.captures_iter(&content) {
-          let relative_path_raw = cap[1].to_string();
-          let mut should_preprocess_import = false;
-          let relative_path = if relative_path_raw.ends_with('!') {
-            should_preprocess_import = true;
-            &relative_path_raw[0..relative_path_raw.len() - 1]
-          } else {
-            &relative_path_raw
-          };
-
-          if relative_path.starts_with("#") {
-            let builtin_path = PathBuf::from(&relative_path);
-            visit_file(
-              &builtin_path,
-              visited,
-              result,
-              should_preprocess_import,
-              import_re,
-              external_re,
-            )?;
-          } else if !relative_path.contains("/")
-            && !relative_path.contains("\\")
-            && !relative_path.starts_with(".")
-          {
-            if let Some(app_entry) = crate::utils::resolve_app_entry(relative_path, None) {
-              visit_file(
-                &app_entry,
-                visited,
-                result,
-                should_preprocess_import,
-                import_re,
-                external_re,
-              )?;
-            } else {
-              return Err(anyhow::anyhow!("App not found: {}", relative_path));
-            }
-          } else if relative_path.contains("/") && !relative_path.starts_with(".") {
-            let parts: Vec<&str> = relative_path.splitn(2, "/").collect();
-            if parts.len() == 2 {
-              let package_name = parts[0];
-              let entry_name = parts[1];
-
-              if let Some(app_entry) =
-                crate::utils::resolve_app_entry(package_name, Some(entry_name))
-              {
-                visit_file(
-                  &app_entry,
-                  visited,
-                  result,
-                  should_preprocess_import,
-                  import_re,
-                  external_re,
-                )?;
-              } else {
-                return Err(anyhow::anyhow!(
-                  "App entry not found: {}/{}",
-                  package_name,
-                  entry_name
-                ));
-              }
-            } else {
-              let included_path = parent
-                .join(relative_path)
-                .canonicalize()
-                .with_context(|| "Failed to resolve import".to_string())?;
-
-              visit_file(
-                &included_path,
-                visited,
-                result,
-                should_preprocess_import,
-                import_re,
-                external_re,
-              )?;
-            }
-          } else {
-            let included_path = parent
-              .join(relative_path)
-              .canonicalize()
-              .with_context(|| "Failed to resolve import".to_string())?;
-
-            visit_file(
-              &included_path,
-              visited,
-              result,
-              should_preprocess_import,
-              import_re,
-              external_re,
-            )?;
-          }
-        }
-      }
+  /// Resolves every module `filepath` statically depends on.
+  ///
+  /// # Returns
+  /// * `(storage path, source, preprocess)` for each module, entry first.
+  pub fn resolve_includes_recursive_from<P: AsRef<Path>>(
+    filepath: P,
+  ) -> Result<Vec<(PathBuf, String, bool)>> {
+    Ok(ModuleGraph::build(filepath)?.into_includes())
+  }
 
-      Ok(())
+  /// Like `resolve_includes_recursive_from`, for a file that is about to
+  /// run: every brew among the includes is checked against the trust policy
+  /// first.
+  pub(crate) fn resolve_runnable_includes<P: AsRef<Path>>(
+    filepath: P,
+  ) -> Result<Vec<(PathBuf, String, bool)>> {
+    let graph = ModuleGraph::build(filepath)?;
+    let brews = graph
+      .nodes()
+      .iter()
+      .filter(|node| node.kind == ModuleKind::Brew && !node.dynamic);
+    for node in brews {
+      check_brew(&node.path, &read_brew(&node.path)?)?;
     }
-
-    visit_file(
-      &filepath,
-      &mut visited,
-      &mut result,
-      false,
-      &import_re,
-      &external_re,
-    )?;
-    Ok(result)
+    Ok(graph.into_includes())
   }
 
   pub async fn prepare(
@@ -589,8 +519,30 @@ impl RewRuntime {
     files: Vec<(PathBuf, String)>,
     entry: Option<&Path>,
   ) -> Result<String> {
-    let mut module_wrappers = String::new();
-    let mut entry_calls = Vec::new();
+    Ok(self.prepare_mapped(files, entry).await?.0)
+  }
+
+  /// Same as `prepare`, but also returns the source map of the script.
+  pub(crate) async fn prepare_mapped(
+    &mut self,
+    files: Vec<(PathBuf, String)>,
+    entry: Option<&Path>,
+  ) -> Result<(String, ScriptSourceMap)> {
+    let prepared = self.prepare_modules(files, entry).await?;
+    Ok((prepared.script(), prepared.map))
+  }
+
+  /// Compiles every file and wraps it into a module definition, keeping the
+  /// modules apart so brews can store them separately.
+  pub(crate) async fn prepare_modules(
+    &mut self,
+    files: Vec<(PathBuf, String)>,
+    entry: Option<&Path>,
+  ) -> Result<PreparedScript> {
+    let mut modules: Vec<PreparedModule> = Vec::new();
+    let mut line_count = 0;
+    let mut entries = Vec::new();
+    let mut script_map = ScriptSourceMap::default();
 
     let shared = std::rc::Rc::new(tokio::sync::Mutex::new(self));
     
@@ -605,7 +557,13 @@ impl RewRuntime {
           runtime
             .compile_and_run(&source, &path, false)
             .await
-            .map_err(|e| anyhow::anyhow!("Runtime error: {}", e))
+            .map_err(|e| {
+              if e.is::<Diagnostics>() {
+                e
+              } else {
+                anyhow::anyhow!("Runtime error: {}", e)
+              }
+            })
         };
 
         res.map(|out| (path_original.clone(), out))
@@ -615,6 +573,11 @@ impl RewRuntime {
     .collect::<Vec<_>>()
     .await;
 
+    let this = match Rc::try_unwrap(shared) {
+      Ok(mutex) => mutex.into_inner(),
+      Err(_) => unreachable!("every compile task has finished"),
+    };
+
     let entry_regex = Regex::new(r#"//\s*entry\s*"([^"]+)""#).unwrap();
     for result in results {
       let (path, compiled) = result?;
@@ -624,7 +587,7 @@ impl RewRuntime {
         .replace('\\', "\\\\")
         .replace('\'', "\\'")
         .replace('"', "\\\"");
-      let mut mod_alias = String::new();
+      let mut alias = None;
 
       if let Some(app_info) = crate::utils::find_app_info(&path) {
         if let Some(manifest) = &app_info.config.manifest {
@@ -635,7 +598,7 @@ impl RewRuntime {
               if let Some(entries) = &app_info.config.entries {
                 for (key, value) in entries {
                   if value == rel_path_str {
-                    mod_alias.push_str(&format!("[\"app://{}/{}\"]", package, key));
+                    alias = Some(format!("app://{}/{}", package, key));
                     break;
                   }
                 }
@@ -645,9 +608,9 @@ impl RewRuntime {
                 .with_extension("")
                 .to_string_lossy()
                 .into_owned();
-              if mod_alias.is_empty() {
-                mod_alias.push_str(&format!(
-                  "[\"app://{}/{}\"]",
+              if alias.is_none() {
+                alias = Some(format!(
+                  "app://{}/{}",
                   package,
                   base_name.replace('\\', "\\\\")
                 ))
@@ -656,28 +619,30 @@ impl RewRuntime {
           }
         }
       }
+      let mod_alias = alias
+        .as_ref()
+        .map(|alias| format!("[\"{}\"]", alias))
+        .unwrap_or_default();
 
-      if mod_id.starts_with('#') {
-        module_wrappers.push_str(&format!(
+      let code = if mod_id.starts_with('#') {
+        format!(
           "(function(module){{\n{compiled}\n}})({{filename: \"{id}\"}});",
           id = mod_id,
           compiled = compiled
-        ));
+        )
       } else if mod_id.ends_with(".brew") || mod_id.ends_with(".qrew") {
-        // if mod_id.ends_with(".brew") {
-        //   module_wrappers.push_str(format!("globalThis.__filename__ = \"{}\";", mod_id).as_str());
-        // }
-        module_wrappers.push_str(compiled.as_str());
-
         for cap in entry_regex.captures_iter(&compiled) {
-          let entry_file = cap[1].to_string();
-          entry_calls.push(format!(
-            "rew.prototype.mod.prototype.get('{}');",
-            entry_file.replace('\\', "\\\\")
-          ));
+          entries.push(cap[1].to_string());
         }
+
+        compiled
       } else if is_js_executable(&mod_id) {
-        module_wrappers.push_str(&format!(
+        if let Some(map) = this.module_maps.remove(&path) {
+          // The compiled code starts three lines into the wrapper, indented
+          // by two spaces.
+          script_map.add_module(&path, map, line_count + 3, 2);
+        }
+        format!(
           r#"rew.prototype.mod.prototype.defineNew("{id}", {{
 "{id}"(globalThis){{
 with (globalThis) {{
@@ -689,17 +654,24 @@ return globalThis.module.exports;
           id = mod_id,
           mod_alias = mod_alias,
           compiled = compiled
-        ));
+        )
       } else {
-        module_wrappers.push_str(&format!(
+        format!(
           r#"rew.prototype.mod.prototype.defineNew("{id}", function(globalThis){{
   return rew.prototype.mod.prototype.preprocess("{id}", `{compiled}`);
 }}, {mod_alias});"#,
           id = mod_id,
           mod_alias = mod_alias,
           compiled = compiled.replace("`", "\\`").replace("\\", "\\\\")
-        ));
-      }
+        )
+      };
+
+      line_count += code.matches('\n').count();
+      modules.push(PreparedModule {
+        id: path.to_string_lossy().into_owned(),
+        alias,
+        code,
+      });
     }
 
     if let Some(entry) = entry {
@@ -725,38 +697,36 @@ return globalThis.module.exports;
         }
       }
 
-      let final_entry_id = entry_app_id
-        .unwrap_or_else(|| entry_mod_id.to_string())
-        .replace('\\', "\\\\");
-      if entry_calls.is_empty()
+      let final_entry_id = entry_app_id.unwrap_or_else(|| entry_mod_id.to_string());
+      if entries.is_empty()
         && !final_entry_id.ends_with(".brew")
         && !final_entry_id.ends_with(".qrew")
       {
-        entry_calls.push(format!(
-          "rew.prototype.mod.prototype.get('{}');",
-          final_entry_id
-        ));
+        entries.push(final_entry_id);
       }
     }
 
-    for entry_call in entry_calls {
-      module_wrappers.push_str(&format!("\n{}", entry_call));
-    }
-
-    // fs::write("out.js", module_wrappers.clone())?;
-
-    Ok(module_wrappers.to_string())
+    Ok(PreparedScript {
+      modules,
+      entries,
+      map: script_map,
+    })
   }
 
   pub async fn build_file<P: AsRef<Path>>(
     &mut self,
     filepath: P,
     options: BuildOptions,
-  ) -> Result<String> {
+  ) -> Result<Vec<u8>> {
     let filepath = filepath
       .as_ref()
       .canonicalize()
       .with_context(|| format!("Failed to resolve file path: {:?}", filepath.as_ref()))?;
+    let signing_key = options
+      .sign
+      .as_deref()
+      .map(crate::trust::load_signing_key)
+      .transpose()?;
 
     let files_with_flags = RewRuntime::resolve_includes_recursive_from(&filepath)?;
     let mut excluded: Vec<String> = Vec::new();
@@ -817,28 +787,16 @@ return globalThis.module.exports;
     };
     let entry = get_storage_path(entry_path.to_str().unwrap());
 
-    let mut string = self.prepare(files, None).await?;
-    string.insert_str(
-      0,
-      format!(
-        "{}\n",
-        excluded
-          .into_iter()
-          .map(|item| format!("// external \"{}\"", item))
-          .collect::<Vec<String>>()
-          .join("\n")
-      )
-      .as_str(),
-    );
-    string.insert_str(
-      0,
-      format!("\n// entry \"{}\" \n", entry.to_str().unwrap_or("unknown")).as_str(),
-    );
-    string.push('\n');
-
-    string = encode_brew_file(&string);
+    let prepared = self.prepare_modules(files, None).await?;
+    let mut entries = prepared.entries;
+    entries.push(entry.to_string_lossy().into_owned());
+    let modules = prepared
+      .modules
+      .into_iter()
+      .map(|module| (module.id, module.alias, module.code))
+      .collect();
 
-    Ok(string)
+    Brew::new(modules, entries, excluded).encode(options.compress, signing_key.as_ref())
   }
 
   pub async fn include_and_run(
@@ -846,16 +804,54 @@ return globalThis.module.exports;
     files: Vec<(PathBuf, String)>,
     entry: &Path,
   ) -> Result<()> {
-    let final_script = self.prepare(files, Some(entry)).await?;
-
-    self.runtime.execute_script("<main>", final_script)?;
+    self.include(files, entry).await?;
     self
       .runtime
       .run_event_loop(PollEventLoopOptions::default())
-      .await?;
+      .await
+      .map_err(|e| self.js_error(e))?;
+    Ok(())
+  }
+
+  /// Compiles `files` and runs the top level of `entry`, leaving the timers
+  /// and ops it started to whoever drives the event loop.
+  pub(crate) async fn include(
+    &mut self,
+    files: Vec<(PathBuf, String)>,
+    entry: &Path,
+  ) -> Result<()> {
+    let (final_script, script_map) = self.prepare_mapped(files, Some(entry)).await?;
+    if let Some(source_maps) = self.source_maps() {
+      source_maps.register("<main>", script_map)?;
+    }
+
+    self
+      .runtime
+      .execute_script("<main>", final_script)
+      .map_err(|e| self.js_error(e))?;
     Ok(())
   }
 
+  fn source_maps(&self) -> Option<SourceMapStore> {
+    self
+      .runtime
+      .op_state()
+      .borrow()
+      .try_borrow::<SourceMapStore>()
+      .cloned()
+  }
+
+  /// Turns an uncaught exception into an error pointing at the original
+  /// source, with a code frame.
+  fn js_error(&self, error: CoreError) -> anyhow::Error {
+    match (error, self.source_maps()) {
+      (CoreError::Js(js_error), Some(source_maps)) => {
+        anyhow::anyhow!(source_maps.format_js_error(&js_error))
+      }
+      (error, _) => error.into(),
+    }
+  }
+
   pub async fn compile_and_run(
     &mut self,
     source: &str,
@@ -880,12 +876,30 @@ return globalThis.module.exports;
       .unwrap_or("unknown")
       .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
 
-    let processed = self.preprocess_rew(
+    let mut diagnostics = DeclarationEngine::check_script(source);
+    let mut processed = self.preprocess_rew(
       source,
       local_declarations,
       global_declarations,
       keep_imports,
     )?;
+    diagnostics.append(&mut processed.options.diagnostics);
+
+    // What rew put in front of the source is left out of errors.
+    let prelude = self.preludes.get(filepath).copied().unwrap_or(0);
+    let source = &source
+      .split_inclusive('\n')
+      .skip(prelude)
+      .collect::<String>();
+    let line_map: Vec<usize> = processed
+      .line_map
+      .iter()
+      .map(|line| line.saturating_sub(prelude))
+      .collect();
+    for span in diagnostics.iter_mut().filter_map(|d| d.span.as_mut()) {
+      span.line = span.line.saturating_sub(prelude);
+      span.end_line = span.end_line.saturating_sub(prelude);
+    }
 
     let mut civet_options: Vec<String> = vec![];
     civet_options.extend(processed.options.civet_options.clone());
@@ -911,19 +925,37 @@ return globalThis.module.exports;
           else options[i] = true; 
         }}
       }});
-      let _compiled = compile({file_id}, {{
-        parseOptions: options,
-        sync: true,
-        filename: '{file}.civet',
-        bare: true,
-        js: true,
-        inlineMap: {inp},
-        sourceMap: {smp},
-      }});
-
-      delete globalThis.{file_id};
-
-      return _compiled;
+      let _compiled;
+      try {{
+        _compiled = compile({file_id}, {{
+          parseOptions: options,
+          sync: true,
+          filename: '{file}.civet',
+          bare: true,
+          js: true,
+          inlineMap: {inp},
+          sourceMap: {smp},
+        }});
+      }} catch (e) {{
+        return JSON.stringify({{
+          errors: (e.errors || [e]).map(err => ({{
+            message: err.header || err.message || String(err),
+            body: err.body,
+            line: err.line,
+            column: err.column
+          }}))
+        }});
+      }} finally {{
+        delete globalThis.{file_id};
+      }}
+
+      if (_compiled.sourceMap) {{
+        return JSON.stringify({{
+          code: _compiled.code,
+          map: _compiled.sourceMap.json('{file}', '{file}.js')
+        }});
+      }}
+      return JSON.stringify({{ code: _compiled }});
     }})()
     "#,
       file_id = file_id,
@@ -952,8 +984,24 @@ return globalThis.module.exports;
 
     let result = self.runtime.execute_script("<rew>", code.clone())?;
     // let compiled = self.runtime.resolve(result).await?;
-    let scope = &mut self.runtime.handle_scope();
-    let mut result_code = result.open(scope).to_rust_string_lossy(scope);
+    let output: CivetOutput = {
+      let scope = &mut self.runtime.handle_scope();
+      serde_json::from_str(&result.open(scope).to_rust_string_lossy(scope))?
+    };
+
+    diagnostics.extend(
+      output
+        .errors
+        .into_iter()
+        .map(|error| error.into_diagnostic(&line_map)),
+    );
+    check_diagnostics(filepath, source, diagnostics)?;
+
+    if let Some(map) = output.map {
+      let map = ModuleSourceMap::compose(map.to_string().as_bytes(), &line_map, source)?;
+      self.module_maps.insert(filepath.to_path_buf(), map);
+    }
+    let mut result_code = output.code;
 
     if processed.options.jsx || civet_options.contains(&"JSX".to_string()) {
       result_code = compile_jsx(result_code, Some("__jsx__prefix".to_string()));
@@ -984,35 +1032,83 @@ return globalThis.module.exports;
       included: false,
       local_declarations,
       global_declarations,
+      diagnostics: vec![],
     };
 
     compile_rew_stuff(source, &mut options)
   }
 
   pub async fn run_file<P: AsRef<Path>>(&mut self, filepath: P) -> Result<()> {
-    let filepath = filepath
-      .as_ref()
+    let filepath = Self::entry_path(filepath.as_ref())?;
+    self.run_entry(filepath).await
+  }
+
+  /// Like `run_file`, but returns once the top level of the file has run,
+  /// without driving the event loop.
+  pub(crate) async fn load_file<P: AsRef<Path>>(&mut self, filepath: P) -> Result<()> {
+    let filepath = Self::entry_path(filepath.as_ref())?;
+    self.load_entry(&filepath).await
+  }
+
+  fn entry_path(filepath: &Path) -> Result<PathBuf> {
+    if is_virtual_file(filepath) {
+      return Ok(filepath.to_path_buf());
+    }
+    filepath
       .canonicalize()
-      .with_context(|| format!("Failed to resolve file path: {:?}", filepath.as_ref()))?;
+      .with_context(|| format!("Failed to resolve file path: {:?}", filepath))
+  }
 
-    let files_with_flags = RewRuntime::resolve_includes_recursive_from(&filepath)?;
+  /// Compiles and runs an inline snippet of Rew code.
+  ///
+  /// The snippet is registered as a virtual `.coffee` file in the current
+  /// directory and then goes through the same pipeline as `run_file`.
+  ///
+  /// # Arguments
+  /// * `code` - The Rew source to run.
+  pub async fn run_code(&mut self, code: &str) -> Result<()> {
+    let filepath = std::env::current_dir()?.join("<exec>.coffee");
+
+    self
+      .preludes
+      .insert(filepath.clone(), EXEC_PRELUDE.lines().count());
+    add_virtual_file(
+      filepath.to_str().unwrap_or("<exec>.coffee"),
+      &format!("{}{}", EXEC_PRELUDE, code),
+    );
+
+    self.run_entry(filepath).await
+  }
+
+  async fn run_entry(&mut self, filepath: PathBuf) -> Result<()> {
+    self.load_entry(&filepath).await?;
+    self
+      .runtime
+      .run_event_loop(PollEventLoopOptions::default())
+      .await
+      .map_err(|e| self.js_error(e))?;
+    Ok(())
+  }
+
+  async fn load_entry(&mut self, filepath: &Path) -> Result<()> {
+    let files_with_flags = RewRuntime::resolve_runnable_includes(filepath)?;
 
     for (_, content, preprocess) in &files_with_flags {
       if *preprocess {
-        let local_declarations = self.declaration_engine.process_script(content);
-
-        for (name, decl) in local_declarations {
-          self
-            .declaration_engine
-            .global_declarations
-            .insert(name, decl);
-        }
+        self.promote_declarations(content);
       }
     }
 
-    if let Some(app_info) = crate::utils::find_app_info(&filepath) {
+    if let Some(app_info) = crate::utils::find_app_info(filepath) {
       if let Some(manifest) = &app_info.config.manifest {
         if let Some(package) = &manifest.package {
+          self
+            .runtime
+            .op_state()
+            .borrow_mut()
+            .borrow_mut::<RuntimeState>()
+            .apps
+            .insert(package.clone(), app_info.config.clone());
           self.runtime.execute_script(
             "<app-recognition>",
             format!(
@@ -1031,10 +1127,8 @@ return globalThis.module.exports;
       .collect();
 
     self
-      .include_and_run(files, &get_storage_path(filepath.to_str().unwrap()))
-      .await?;
-
-    Ok(())
+      .include(files, &get_storage_path(filepath.to_str().unwrap()))
+      .await
   }
 }
 
@@ -1050,25 +1144,60 @@ fn op_get_args(state: Rc<RefCell<OpState>>) -> Result<serde_json::Value, CoreErr
   Ok(serde_json::json!(runtime_args.args.clone()))
 }
 
+/// Fails unless the runtime's permissions allow reading `path`.
+pub(crate) fn check_read_permission(state: &Rc<RefCell<OpState>>, path: &Path) -> Result<(), JsErrorBox> {
+  let state = state.borrow();
+  let permissions = &state.borrow::<RewPermissions>().web;
+  permissions
+    .check_read(path, None)
+    .map(|_| ())
+    .map_err(|e| JsErrorBox::new("NotCapable", e.to_string()))
+}
+
+/// Fails unless the runtime's permissions allow writing `path`.
+fn check_write_permission(state: &Rc<RefCell<OpState>>, path: &Path) -> Result<(), JsErrorBox> {
+  let state = state.borrow();
+  let permissions = &state.borrow::<RewPermissions>().web;
+  permissions
+    .check_write(path, None)
+    .map(|_| ())
+    .map_err(|e| JsErrorBox::new("NotCapable", e.to_string()))
+}
+
+/// The file system the runtime's fs ops go through.
+fn get_fs(state: &Rc<RefCell<OpState>>) -> FileSystemRc {
+  state.borrow().borrow::<FileSystemRc>().clone()
+}
+
+fn fs_error(error: FsError) -> CoreError {
+  CoreError::Io(error.into_io_error())
+}
+
+/// Seconds since the epoch from a `FsStat` time, which is in milliseconds.
+fn stat_secs(time: Option<u64>) -> Option<u64> {
+  time.map(|ms| ms / 1000)
+}
+
 #[op2]
 #[serde]
 fn op_fs_read(
   #[string] current_file: String,
   #[string] filepath: String,
   #[serde] options: Option<ReadOptions>,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<serde_json::Value, CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
   let full_path = base_dir.join(filepath);
+  check_read_permission(&state, &full_path)?;
 
   let options = options.unwrap_or_default();
+  let buffer = get_fs(&state)
+    .read_file_sync(&full_path, None)
+    .map_err(fs_error)?
+    .into_owned();
 
   if options.binary {
-    let mut file = File::open(&full_path).map_err(CoreError::Io)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).map_err(CoreError::Io)?;
-
     Ok(serde_json::Value::Array(
       buffer
         .into_iter()
@@ -1076,7 +1205,8 @@ fn op_fs_read(
         .collect(),
     ))
   } else {
-    let content = fs::read_to_string(&full_path).map_err(CoreError::Io)?;
+    let content = String::from_utf8(buffer)
+      .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
     Ok(serde_json::Value::String(content))
   }
 }
@@ -1092,21 +1222,24 @@ async fn op_fs_write(
   #[string] filepath: String,
   #[serde] content: serde_json::Value,
   #[serde] options: Option<WriteOptions>,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let full_path = base_dir.join(filepath);
+  check_write_permission(&state, &full_path)?;
 
   let options = options.unwrap_or_default();
+  let fs = get_fs(&state);
 
   if let Some(parent) = full_path.parent() {
     if options.create_dirs {
-      fs::create_dir_all(parent).map_err(CoreError::Io)?;
+      fs.mkdir_sync(parent, true, None).map_err(fs_error)?;
     }
   }
 
+  let write_options = OpenOptions::write(true, false, false, None);
   if options.binary {
     if let serde_json::Value::Array(bytes) = content {
       let buffer: Result<Vec<u8>, _> = bytes
@@ -1128,7 +1261,9 @@ async fn op_fs_write(
         })
         .collect();
 
-      fs::write(&full_path, buffer?).map_err(CoreError::Io)?;
+      fs
+        .write_file_sync(&full_path, write_options, None, &buffer?)
+        .map_err(fs_error)?;
     } else {
       return Err(CoreError::Io(io::Error::new(
         io::ErrorKind::InvalidData,
@@ -1136,8 +1271,9 @@ async fn op_fs_write(
       )));
     }
   } else if let serde_json::Value::String(text) = content {
-    let mut file = File::create(&full_path).map_err(CoreError::Io)?;
-    file.write_all(text.as_bytes()).map_err(CoreError::Io)?;
+    fs
+      .write_file_sync(&full_path, write_options, None, text.as_bytes())
+      .map_err(fs_error)?;
   } else {
     return Err(CoreError::Io(io::Error::new(
       io::ErrorKind::InvalidData,
@@ -1160,14 +1296,17 @@ use sha2::{Digest, Sha256};
 fn op_fs_sha(
   #[string] current_file: String,
   #[string] filepath: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let full_path = base_dir.join(filepath);
+  check_read_permission(&state, &full_path)?;
 
-  let file_bytes = fs::read(&full_path)?;
+  let file_bytes = get_fs(&state)
+    .read_file_sync(&full_path, None)
+    .map_err(fs_error)?;
   let mut hasher = Sha256::new();
   hasher.update(file_bytes);
   let hash = hasher.finalize();
@@ -1179,14 +1318,15 @@ fn op_fs_sha(
 fn op_fs_exists(
   #[string] current_file: String,
   #[string] filepath: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<bool, CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let full_path = base_dir.join(filepath);
+  check_read_permission(&state, &full_path)?;
 
-  Ok(full_path.exists())
+  Ok(get_fs(&state).exists_sync(&full_path))
 }
 
 #[op2(async)]
@@ -1194,24 +1334,19 @@ async fn op_fs_rm(
   #[string] current_file: String,
   #[string] filepath: String,
   #[serde] options: Option<RemoveOptions>,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let full_path = base_dir.join(filepath);
+  check_write_permission(&state, &full_path)?;
 
   let options = options.unwrap_or_default();
 
-  if full_path.is_dir() {
-    if options.recursive {
-      fs::remove_dir_all(&full_path).map_err(CoreError::Io)?;
-    } else {
-      fs::remove_dir(&full_path).map_err(CoreError::Io)?;
-    }
-  } else {
-    fs::remove_file(&full_path).map_err(CoreError::Io)?;
-  }
+  get_fs(&state)
+    .remove_sync(&full_path, options.recursive)
+    .map_err(fs_error)?;
 
   Ok(())
 }
@@ -1226,20 +1361,19 @@ async fn op_fs_mkdir(
   #[string] current_file: String,
   #[string] dirpath: String,
   #[serde] options: Option<MkdirOptions>,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let full_path = base_dir.join(dirpath);
+  check_write_permission(&state, &full_path)?;
 
   let options = options.unwrap_or_default();
 
-  if options.recursive {
-    fs::create_dir_all(&full_path).map_err(CoreError::Io)?;
-  } else {
-    fs::create_dir(&full_path).map_err(CoreError::Io)?;
-  }
+  get_fs(&state)
+    .mkdir_sync(&full_path, options.recursive, None)
+    .map_err(fs_error)?;
 
   Ok(())
 }
@@ -1255,47 +1389,40 @@ fn op_fs_readdir(
   #[string] current_file: String,
   #[string] dirpath: String,
   #[serde] options: Option<ReaddirOptions>,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let full_path = base_dir.join(dirpath);
+  check_read_permission(&state, &full_path)?;
 
   let options = options.unwrap_or_default();
 
-  let entries = fs::read_dir(&full_path).map_err(CoreError::Io)?;
+  let fs = get_fs(&state);
+  let entries = fs.read_dir_sync(&full_path).map_err(fs_error)?;
 
   let mut result = Vec::new();
 
   for entry in entries {
-    let entry = entry.map_err(CoreError::Io)?;
-    let file_type = entry.file_type().map_err(CoreError::Io)?;
-
-    if !options.include_hidden {
-      if let Some(file_name) = entry.path().file_name() {
-        if let Some(name_str) = file_name.to_str() {
-          if name_str.starts_with(".") {
-            continue;
-          }
-        }
-      }
+    if !options.include_hidden && entry.name.starts_with(".") {
+      continue;
     }
 
     if let Some(filter_type) = &options.filter_type {
       match filter_type.as_str() {
         "file" => {
-          if !file_type.is_file() {
+          if !entry.is_file {
             continue;
           }
         }
         "directory" => {
-          if !file_type.is_dir() {
+          if !entry.is_directory {
             continue;
           }
         }
         "symlink" => {
-          if !file_type.is_symlink() {
+          if !entry.is_symlink {
             continue;
           }
         }
@@ -1303,25 +1430,18 @@ fn op_fs_readdir(
       }
     }
 
-    let metadata = entry.metadata().map_err(CoreError::Io)?;
+    let entry_path = full_path.join(&entry.name);
+    let metadata = fs.stat_sync(&entry_path).map_err(fs_error)?;
 
     let entry_info = DirEntryInfo {
-      name: entry.file_name().to_string_lossy().to_string(),
-      path: entry.path().to_string_lossy().to_string(),
-      is_file: file_type.is_file(),
-      is_directory: file_type.is_dir(),
-      is_symlink: file_type.is_symlink(),
-      size: metadata.len(),
-      modified: metadata
-        .modified()
-        .ok()
-        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-        .map(|d| d.as_secs()),
-      created: metadata
-        .created()
-        .ok()
-        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-        .map(|d| d.as_secs()),
+      name: entry.name,
+      path: entry_path.to_string_lossy().to_string(),
+      is_file: entry.is_file,
+      is_directory: entry.is_directory,
+      is_symlink: entry.is_symlink,
+      size: metadata.size,
+      modified: stat_secs(metadata.mtime),
+      created: stat_secs(metadata.birthtime),
     };
 
     result.push(entry_info);
@@ -1364,25 +1484,31 @@ struct DirEntryInfo {
 fn op_fs_stats(
   #[string] current_file: String,
   #[string] filepath: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let full_path = base_dir.join(filepath);
+  check_read_permission(&state, &full_path)?;
 
-  let metadata = fs::metadata(&full_path).map_err(CoreError::Io)?;
+  let fs = get_fs(&state);
+  let metadata = fs.stat_sync(&full_path).map_err(fs_error)?;
+  let is_symlink = fs
+    .lstat_sync(&full_path)
+    .map(|stat| stat.is_symlink)
+    .unwrap_or(false);
 
   let stats = serde_json::json!({
-      "isFile": metadata.is_file(),
-      "isDirectory": metadata.is_dir(),
-      "isSymlink": metadata.file_type().is_symlink(),
-      "size": metadata.len(),
-      "modified": metadata.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()).map(|d| d.as_secs()),
-      "created": metadata.created().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()).map(|d| d.as_secs()),
-      "accessed": metadata.accessed().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+      "isFile": metadata.is_file,
+      "isDirectory": metadata.is_directory,
+      "isSymlink": is_symlink,
+      "size": metadata.size,
+      "modified": stat_secs(metadata.mtime),
+      "created": stat_secs(metadata.birthtime),
+      "accessed": stat_secs(metadata.atime),
       "permissions": {
-          "readonly": metadata.permissions().readonly(),
+          "readonly": metadata.mode != 0 && metadata.mode & 0o222 == 0,
           // "mode": metadata.permissions().mode(),
       }
   });
@@ -1396,19 +1522,22 @@ async fn op_fs_copy(
   #[string] src: String,
   #[string] dest: String,
   #[serde] options: Option<CopyOptions>,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let src_path = base_dir.join(src);
   let dest_path = base_dir.join(dest);
+  check_read_permission(&state, &src_path)?;
+  check_write_permission(&state, &dest_path)?;
 
   let options = options.unwrap_or_default();
+  let fs = get_fs(&state);
 
-  if src_path.is_dir() {
+  if fs.is_dir_sync(&src_path) {
     if options.recursive {
-      copy_dir_recursive(&src_path, &dest_path, &options).map_err(CoreError::Io)?;
+      copy_dir_recursive(fs.as_ref(), &src_path, &dest_path, &options).map_err(fs_error)?;
     } else {
       return Err(CoreError::Io(io::Error::new(
         io::ErrorKind::InvalidInput,
@@ -1418,11 +1547,11 @@ async fn op_fs_copy(
   } else {
     if let Some(parent) = dest_path.parent() {
       if options.create_dirs {
-        fs::create_dir_all(parent).map_err(CoreError::Io)?;
+        fs.mkdir_sync(parent, true, None).map_err(fs_error)?;
       }
     }
 
-    fs::copy(&src_path, &dest_path).map_err(CoreError::Io)?;
+    fs.copy_file_sync(&src_path, &dest_path).map_err(fs_error)?;
   }
 
   Ok(())
@@ -1435,23 +1564,27 @@ struct CopyOptions {
   overwrite: bool,
 }
 
-fn copy_dir_recursive(src: &Path, dest: &Path, options: &CopyOptions) -> io::Result<()> {
-  if !dest.exists() {
-    fs::create_dir_all(dest)?;
+fn copy_dir_recursive(
+  fs: &dyn FileSystem,
+  src: &Path,
+  dest: &Path,
+  options: &CopyOptions,
+) -> FsResult<()> {
+  if !fs.exists_sync(dest) {
+    fs.mkdir_sync(dest, true, None)?;
   }
 
-  for entry in fs::read_dir(src)? {
-    let entry = entry?;
-    let src_path = entry.path();
-    let dest_path = dest.join(entry.file_name());
+  for entry in fs.read_dir_sync(src)? {
+    let src_path = src.join(&entry.name);
+    let dest_path = dest.join(&entry.name);
 
-    if src_path.is_dir() {
-      copy_dir_recursive(&src_path, &dest_path, options)?;
+    if entry.is_directory {
+      copy_dir_recursive(fs, &src_path, &dest_path, options)?;
     } else {
-      if dest_path.exists() && !options.overwrite {
+      if fs.exists_sync(&dest_path) && !options.overwrite {
         continue;
       }
-      fs::copy(&src_path, &dest_path)?;
+      fs.copy_file_sync(&src_path, &dest_path)?;
     }
   }
 
@@ -1463,15 +1596,19 @@ async fn op_fs_rename(
   #[string] current_file: String,
   #[string] src: String,
   #[string] dest: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
   let current_file_path = Path::new(&current_file);
   let base_dir = current_file_path.parent().unwrap_or(Path::new("."));
 
   let src_path = base_dir.join(src);
   let dest_path = base_dir.join(dest);
+  check_write_permission(&state, &src_path)?;
+  check_write_permission(&state, &dest_path)?;
 
-  fs::rename(&src_path, &dest_path).map_err(CoreError::Io)?;
+  get_fs(&state)
+    .rename_sync(&src_path, &dest_path)
+    .map_err(fs_error)?;
 
   Ok(())
 }
@@ -1624,23 +1761,59 @@ fn op_app_loadconfig(
 }
 
 // Helper function to get DataManager for a specific app package
-fn get_data_manager_for_package(app_package: &str) -> Result<DataManager, CoreError> {
+/// Opens the data of `app_package`, after `check` allowed access to `key`
+/// in it.
+fn get_data_manager_for_package(
+  state: &Rc<RefCell<OpState>>,
+  app_package: &str,
+  key: &str,
+  check: fn(&Rc<RefCell<OpState>>, &Path) -> Result<(), JsErrorBox>,
+) -> Result<Rc<DataManager>, JsErrorBox> {
   // For now, use "default" as the user ID
   // In a real implementation, you'd get this from user authentication
   let user_id = "default";
 
-  DataManager::new(user_id, app_package)
-    .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))
+  let data_dir = DataManager::data_dir(user_id, app_package)
+    .map_err(|e| JsErrorBox::generic(e.to_string()))?;
+  check(state, &data_dir.join(key))?;
+
+  let mut state = state.borrow_mut();
+  if let Some(manager) = state.borrow::<DataManagers>().0.get(app_package) {
+    return Ok(manager.clone());
+  }
+
+  let config = state
+    .borrow::<RuntimeState>()
+    .apps
+    .get(app_package)
+    .cloned()
+    .or_else(|| crate::utils::find_app_by_package(app_package).map(|app| app.config))
+    .and_then(|config| config.data)
+    .unwrap_or_default();
+  let manager = DataManager::new(user_id, app_package, config.backend)
+    .map_err(|e| JsErrorBox::generic(format!("{:#}", e)))?;
+  let manager = Rc::new(manager);
+  state
+    .borrow_mut::<DataManagers>()
+    .0
+    .insert(app_package.to_string(), manager.clone());
+  Ok(manager)
 }
 
+/// The data managers ops opened so far, by package, so the store is opened
+/// and migrated once per runtime.
+#[derive(Default)]
+struct DataManagers(HashMap<String, Rc<DataManager>>);
+
 #[op2]
 #[string]
 fn op_data_read(
   #[string] app_package: String,
   #[string] key: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_read_permission)?;
   data_manager
     .read(&key)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))
@@ -1651,9 +1824,10 @@ async fn op_data_write(
   #[string] app_package: String,
   #[string] key: String,
   #[string] content: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_write_permission)?;
   data_manager
     .write(&key, &content)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))
@@ -1663,21 +1837,74 @@ async fn op_data_write(
 async fn op_data_delete(
   #[string] app_package: String,
   #[string] key: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_write_permission)?;
   data_manager
     .delete(&key)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))
 }
 
+/// One change of a `conf.transaction`, as staged by the JS side.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum DataChange {
+  Write { key: String, content: String },
+  Binary { key: String, data: Vec<u8> },
+  Yaml { key: String, value: serde_json::Value },
+  Delete { key: String },
+}
+
+impl DataChange {
+  fn key(&self) -> &str {
+    match self {
+      DataChange::Write { key, .. }
+      | DataChange::Binary { key, .. }
+      | DataChange::Yaml { key, .. }
+      | DataChange::Delete { key } => key,
+    }
+  }
+}
+
+#[op2(async)]
+async fn op_data_transaction(
+  #[string] app_package: String,
+  #[serde] changes: Vec<DataChange>,
+  state: Rc<RefCell<OpState>>,
+) -> Result<(), CoreError> {
+  let Some(first) = changes.first() else {
+    return Ok(());
+  };
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, first.key(), check_write_permission)?;
+  for change in &changes[1..] {
+    check_write_permission(&state, &data_manager.get_path(change.key()))?;
+  }
+
+  data_manager
+    .transaction(|tx| {
+      for change in changes {
+        match change {
+          DataChange::Write { key, content } => tx.write(&key, &content),
+          DataChange::Binary { key, data } => tx.write_binary(&key, &data),
+          DataChange::Yaml { key, value } => tx.write_yaml(&key, &value)?,
+          DataChange::Delete { key } => tx.delete(&key),
+        }
+      }
+      Ok(())
+    })
+    .map_err(|e| CoreError::Io(io::Error::other(e)))
+}
+
 #[op2(fast)]
 fn op_data_exists(
   #[string] app_package: String,
   #[string] key: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<bool, CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_read_permission)?;
   Ok(data_manager.exists(&key))
 }
 
@@ -1686,9 +1913,10 @@ fn op_data_exists(
 fn op_data_list(
   #[string] app_package: String,
   #[string] prefix: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &prefix, check_read_permission)?;
   let files = data_manager
     .list(&prefix)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))?;
@@ -1701,9 +1929,10 @@ fn op_data_list(
 fn op_data_read_binary(
   #[string] app_package: String,
   #[string] key: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<Vec<u8>, CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_read_permission)?;
   data_manager
     .read_binary(&key)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))
@@ -1739,9 +1968,10 @@ async fn op_data_write_binary(
   #[string] app_package: String,
   #[string] key: String,
   #[serde] data: Vec<u8>,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_write_permission)?;
   data_manager
     .write_binary(&key, &data)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))
@@ -1752,9 +1982,10 @@ async fn op_data_write_binary(
 fn op_data_read_yaml(
   #[string] app_package: String,
   #[string] key: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<serde_json::Value, CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_read_permission)?;
   data_manager
     .read_yaml(&key)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))
@@ -1765,22 +1996,111 @@ async fn op_data_write_yaml(
   #[string] app_package: String,
   #[string] key: String,
   #[serde] data: serde_json::Value,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(), CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_write_permission)?;
   data_manager
     .write_yaml(&key, &data)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))
 }
 
+#[op2]
+#[serde]
+fn op_kv_get(
+  #[string] app_package: String,
+  #[string] key: String,
+  state: Rc<RefCell<OpState>>,
+) -> Result<Option<serde_json::Value>, JsErrorBox> {
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_read_permission)?;
+  data_manager
+    .kv()
+    .get(&key)
+    .map_err(|e| JsErrorBox::generic(format!("{:#}", e)))
+}
+
+#[op2(async)]
+async fn op_kv_set(
+  #[string] app_package: String,
+  #[string] key: String,
+  #[serde] value: serde_json::Value,
+  #[serde] ttl_ms: Option<u64>,
+  state: Rc<RefCell<OpState>>,
+) -> Result<(), JsErrorBox> {
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_write_permission)?;
+  data_manager
+    .kv()
+    .set(&key, value, ttl_ms.map(std::time::Duration::from_millis))
+    .map_err(|e| JsErrorBox::generic(format!("{:#}", e)))
+}
+
+#[op2(async)]
+async fn op_kv_delete(
+  #[string] app_package: String,
+  #[string] key: String,
+  state: Rc<RefCell<OpState>>,
+) -> Result<(), JsErrorBox> {
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_write_permission)?;
+  data_manager
+    .kv()
+    .delete(&key)
+    .map_err(|e| JsErrorBox::generic(format!("{:#}", e)))
+}
+
+#[derive(Serialize)]
+struct KvItem {
+  key: String,
+  value: serde_json::Value,
+}
+
+#[op2]
+#[serde]
+fn op_kv_scan(
+  #[string] app_package: String,
+  #[serde] options: ScanOptions,
+  state: Rc<RefCell<OpState>>,
+) -> Result<Vec<KvItem>, JsErrorBox> {
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &options.prefix, check_read_permission)?;
+  let entries = data_manager
+    .kv()
+    .scan(&options)
+    .map_err(|e| JsErrorBox::generic(format!("{:#}", e)))?;
+  Ok(
+    entries
+      .into_iter()
+      .map(|(key, value)| KvItem { key, value })
+      .collect(),
+  )
+}
+
+#[op2(async)]
+async fn op_kv_increment(
+  #[string] app_package: String,
+  #[string] key: String,
+  by: f64,
+  state: Rc<RefCell<OpState>>,
+) -> Result<f64, JsErrorBox> {
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_write_permission)?;
+  data_manager
+    .kv()
+    .increment(&key, by)
+    .map_err(|e| JsErrorBox::generic(format!("{:#}", e)))
+}
+
 #[op2]
 #[serde]
 fn op_data_get_info(
   #[string] app_package: String,
   #[string] key: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<(bool, String), CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, &key, check_read_permission)?;
   let (exists, format) = data_manager
     .get_file_info(&key)
     .map_err(|e| CoreError::Io(io::Error::new(io::ErrorKind::Other, e)))?;
@@ -1799,9 +2119,10 @@ fn op_data_get_info(
 #[string]
 fn op_data_get_path(
   #[string] app_package: String,
-  _: Rc<RefCell<OpState>>,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
-  let data_manager = get_data_manager_for_package(&app_package)?;
+  let data_manager =
+    get_data_manager_for_package(&state, &app_package, "", check_read_permission)?;
 
   Ok(data_manager.get_path("").to_string_lossy().to_string())
 }
@@ -1842,18 +2163,11 @@ async fn op_dyn_imp(
   let mut runtime = RewRuntime::new(None, None)
     .map_err(|_| CoreError::Io(io::Error::new(io::ErrorKind::NotFound, "")))?;
 
-  let files_with_flags = RewRuntime::resolve_includes_recursive_from(file_path.clone())
-    .map_err(|_| CoreError::Io(io::Error::new(io::ErrorKind::NotFound, "")))?;
+  let files_with_flags = RewRuntime::resolve_runnable_includes(file_path.clone())
+    .map_err(|e| JsErrorBox::generic(e.to_string()))?;
   for (_, content, preprocess) in &files_with_flags {
     if *preprocess {
-      let local_declarations = runtime.declaration_engine.process_script(content);
-
-      for (name, decl) in local_declarations {
-        runtime
-          .declaration_engine
-          .global_declarations
-          .insert(name, decl);
-      }
+      runtime.promote_declarations(content);
     }
   }
 
@@ -1916,15 +2230,7 @@ fn op_vfile_set(#[string] full_path: String, #[string] content: String) -> Strin
 #[op2]
 #[string]
 fn op_vfile_get(#[string] full_path: String) -> String {
-  if let Some(v) = VIRTUAL_FILES
-    .lock()
-    .unwrap()
-    .iter()
-    .find(|(p, _)| *p == full_path)
-  {
-    return v.1.clone();
-  }
-  "".to_string()
+  get_virtual_file(Path::new(&full_path)).unwrap_or_default()
 }
 
 #[op2]
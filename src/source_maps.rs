@@ -0,0 +1,245 @@
+use crate::diagnostics::format_code_frame;
+use anyhow::Result;
+use colored::*;
+use deno_core::error::JsError;
+use deno_core::url::Url;
+use deno_core::{
+  ModuleLoadResponse, ModuleLoader, ModuleSpecifier, NoopModuleLoader, RequestedModuleType,
+  ResolutionKind,
+};
+use sourcemap::{SourceMap, SourceMapBuilder};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Maps a compiled module back to the file it was compiled from.
+#[derive(Debug, Clone)]
+pub struct ModuleSourceMap {
+  source: String,
+  /// `(compiled line, compiled column, source line, source column)`, 0-based.
+  tokens: Vec<(u32, u32, u32, u32)>,
+}
+
+impl ModuleSourceMap {
+  /// Composes the map Civet produced with the line map of `compile_rew_stuff`.
+  ///
+  /// # Arguments
+  /// * `civet_map` - The source map JSON returned by Civet.
+  /// * `line_map` - The original line of every line Civet was given.
+  /// * `source` - The original source of the module.
+  pub fn compose(civet_map: &[u8], line_map: &[usize], source: &str) -> Result<Self> {
+    let civet_map = SourceMap::from_slice(civet_map)?;
+    let tokens = civet_map
+      .tokens()
+      .filter(|token| token.has_source())
+      .map(|token| {
+        let src_line = line_map
+          .get(token.get_src_line() as usize)
+          .map_or(token.get_src_line(), |line| *line as u32);
+        (
+          token.get_dst_line(),
+          token.get_dst_col(),
+          src_line,
+          token.get_src_col(),
+        )
+      })
+      .collect();
+
+    Ok(Self {
+      source: source.to_string(),
+      tokens,
+    })
+  }
+}
+
+/// Builds the source map of a script made of several wrapped modules.
+#[derive(Default)]
+pub struct ScriptSourceMap {
+  tokens: Vec<(u32, u32, u32, u32, String)>,
+  sources: HashMap<String, String>,
+}
+
+impl ScriptSourceMap {
+  /// Adds a module whose compiled code starts at `line_offset` in the script.
+  ///
+  /// # Arguments
+  /// * `path` - The file the module was compiled from.
+  /// * `map` - The map of the compiled module.
+  /// * `line_offset` - The script line the compiled code starts on.
+  /// * `first_line_indent` - Columns added in front of the first compiled line.
+  pub fn add_module(
+    &mut self,
+    path: &Path,
+    map: ModuleSourceMap,
+    line_offset: usize,
+    first_line_indent: usize,
+  ) {
+    let name = source_name(path);
+
+    for (dst_line, dst_col, src_line, src_col) in map.tokens {
+      let dst_col = if dst_line == 0 {
+        dst_col + first_line_indent as u32
+      } else {
+        dst_col
+      };
+      self.tokens.push((
+        dst_line + line_offset as u32,
+        dst_col,
+        src_line,
+        src_col,
+        name.clone(),
+      ));
+    }
+    self.sources.insert(name, map.source);
+  }
+
+  fn is_empty(&self) -> bool {
+    self.tokens.is_empty()
+  }
+
+  fn into_json(mut self, script_name: &str) -> Result<Vec<u8>> {
+    self.tokens.sort_by_key(|(line, col, ..)| (*line, *col));
+
+    let mut builder = SourceMapBuilder::new(Some(script_name));
+    for (dst_line, dst_col, src_line, src_col, source) in &self.tokens {
+      builder.add(
+        *dst_line,
+        *dst_col,
+        *src_line,
+        *src_col,
+        Some(source),
+        None,
+        false,
+      );
+    }
+
+    let mut json = Vec::new();
+    builder.into_sourcemap().to_writer(&mut json)?;
+    Ok(json)
+  }
+}
+
+/// The name a file goes by in stack traces once it has been source mapped.
+fn source_name(path: &Path) -> String {
+  Url::from_file_path(path)
+    .map(|url| url.to_string())
+    .unwrap_or_else(|_| path.to_string_lossy().to_string())
+}
+
+#[derive(Default)]
+struct SourceMapStoreInner {
+  scripts: HashMap<String, Vec<u8>>,
+  sources: HashMap<String, String>,
+}
+
+/// Source maps of the scripts a runtime has executed, shared between the
+/// module loader V8 asks for maps and the `RewRuntime` that registers them.
+#[derive(Clone, Default)]
+pub struct SourceMapStore {
+  inner: Rc<RefCell<SourceMapStoreInner>>,
+}
+
+impl SourceMapStore {
+  /// Registers the map of a script before it is executed as `script_name`.
+  pub fn register(&self, script_name: &str, map: ScriptSourceMap) -> Result<()> {
+    if map.is_empty() {
+      return Ok(());
+    }
+
+    let mut inner = self.inner.borrow_mut();
+    inner.sources.extend(map.sources.clone());
+    let json = map.into_json(script_name)?;
+    inner.scripts.insert(script_name.to_string(), json);
+    Ok(())
+  }
+
+  fn source(&self, name: &str) -> Option<String> {
+    self.inner.borrow().sources.get(name).cloned()
+  }
+
+  /// Formats an uncaught error with its source mapped stack and a code frame
+  /// of the first frame that points into a Rew file.
+  pub fn format_js_error(&self, error: &JsError) -> String {
+    let mut output = error.to_string();
+
+    let frame = error.frames.iter().find_map(|frame| {
+      let file_name = frame.file_name.as_ref()?;
+      let source = self.source(file_name)?;
+      Some((
+        file_name,
+        source,
+        frame.line_number? as usize,
+        frame.column_number.unwrap_or(1) as usize,
+      ))
+    });
+
+    if let Some((file_name, source, line, column)) = frame {
+      let display_name = Url::parse(file_name)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .map_or(file_name.clone(), |path| path.display().to_string());
+      output.push_str(&format!(
+        "\n\n{}:{}:{}\n{}",
+        display_name.cyan(),
+        line.to_string().yellow(),
+        column.to_string().yellow(),
+        format_code_frame(&source, line, column, 1)
+      ));
+    }
+
+    output
+  }
+}
+
+/// A module loader that only serves source maps, Rew does not load ES
+/// modules through deno_core.
+pub struct RewModuleLoader {
+  store: SourceMapStore,
+}
+
+impl RewModuleLoader {
+  pub fn new(store: SourceMapStore) -> Self {
+    Self { store }
+  }
+}
+
+impl ModuleLoader for RewModuleLoader {
+  fn resolve(
+    &self,
+    specifier: &str,
+    referrer: &str,
+    kind: ResolutionKind,
+  ) -> Result<ModuleSpecifier, deno_core::error::ModuleLoaderError> {
+    NoopModuleLoader.resolve(specifier, referrer, kind)
+  }
+
+  fn load(
+    &self,
+    module_specifier: &ModuleSpecifier,
+    maybe_referrer: Option<&ModuleSpecifier>,
+    is_dyn_import: bool,
+    requested_module_type: RequestedModuleType,
+  ) -> ModuleLoadResponse {
+    NoopModuleLoader.load(
+      module_specifier,
+      maybe_referrer,
+      is_dyn_import,
+      requested_module_type,
+    )
+  }
+
+  fn get_source_map(&self, file_name: &str) -> Option<Cow<'_, [u8]>> {
+    let inner = self.store.inner.borrow();
+    inner
+      .scripts
+      .get(file_name)
+      .map(|json| Cow::Owned(json.clone()))
+  }
+
+  fn get_source_mapped_source_line(&self, file_name: &str, line_number: usize) -> Option<String> {
+    let source = self.store.source(file_name)?;
+    source.lines().nth(line_number).map(|line| line.to_string())
+  }
+}
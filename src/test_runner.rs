@@ -0,0 +1,446 @@
+use crate::runtime::RewRuntime;
+use crate::utils::{AppConfig, collect_files};
+use anyhow::{Context, Result};
+use colored::*;
+use deno_core::PollEventLoopOptions;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Default)]
+pub struct TestOptions {
+  /// Only run tests whose full name contains this, or matches it when
+  /// written as `/regex/`.
+  pub filter: Option<String>,
+  /// Stop after the first failing test.
+  pub bail: bool,
+  /// Where to write a JUnit XML report.
+  pub junit: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestStatus {
+  Passed,
+  Failed,
+  Skipped,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TestError {
+  pub message: String,
+  pub stack: String,
+}
+
+/// The outcome of a single `it` block.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TestResult {
+  pub name: String,
+  pub suite: Vec<String>,
+  pub status: TestStatus,
+  /// Milliseconds.
+  pub duration: u64,
+  pub error: Option<TestError>,
+}
+
+impl TestResult {
+  pub fn full_name(&self) -> String {
+    let mut parts = self.suite.clone();
+    parts.push(self.name.clone());
+    parts.join(" > ")
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileReport {
+  pub path: PathBuf,
+  pub results: Vec<TestResult>,
+  pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TestSummary {
+  pub files: Vec<FileReport>,
+  pub duration: Duration,
+}
+
+impl TestSummary {
+  fn count(&self, status: TestStatus) -> usize {
+    self
+      .files
+      .iter()
+      .flat_map(|file| &file.results)
+      .filter(|result| result.status == status)
+      .count()
+  }
+
+  pub fn passed(&self) -> usize {
+    self.count(TestStatus::Passed)
+  }
+
+  pub fn failed(&self) -> usize {
+    self.count(TestStatus::Failed)
+  }
+
+  pub fn skipped(&self) -> usize {
+    self.count(TestStatus::Skipped)
+  }
+}
+
+fn is_test_file(path: &Path) -> bool {
+  let name = path
+    .file_name()
+    .map(|name| name.to_string_lossy())
+    .unwrap_or_default();
+  name.ends_with(".test.coffee") || name.ends_with("_test.rew")
+}
+
+/// The `test` entry of the app in `dir`, if it has one.
+fn app_test_entry(dir: &Path) -> Option<PathBuf> {
+  let config = fs::read_to_string(dir.join("app.yaml")).ok()?;
+  let config: AppConfig = serde_yaml::from_str(&config).ok()?;
+  let entry = config.entries?.get("test")?.clone();
+  Some(dir.join(entry))
+}
+
+/// Finds the test files to run.
+///
+/// Files are taken as they are. Directories use the `test` entry of their
+/// `app.yaml` when there is one, and are searched for `*.test.coffee` and
+/// `*_test.rew` files otherwise.
+///
+/// # Arguments
+/// * `paths` - Files and directories to look in, the current directory when empty.
+pub fn discover_tests(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+  let paths = if paths.is_empty() {
+    vec![std::env::current_dir()?]
+  } else {
+    paths.to_vec()
+  };
+
+  let mut files = Vec::new();
+  for path in paths {
+    if path.is_file() {
+      files.push(path);
+    } else if let Some(entry) = app_test_entry(&path) {
+      files.push(entry);
+    } else if path.is_dir() {
+      collect_files(&path, &is_test_file, &mut files)
+        .with_context(|| format!("Failed to read directory {:?}", path))?;
+    } else {
+      anyhow::bail!("Test path not found: {}", path.display());
+    }
+  }
+
+  // A file can be named directly and also be found in a directory.
+  let mut seen = HashSet::new();
+  files.retain(|file| seen.insert(file.canonicalize().unwrap_or_else(|_| file.clone())));
+  Ok(files)
+}
+
+/// Runs the tests a file registers, in a fresh runtime.
+async fn run_test_file(path: &Path, options: &TestOptions) -> Result<Vec<TestResult>> {
+  let mut runtime = RewRuntime::new(Some(vec![]), None)?;
+  runtime.run_file(path).await?;
+
+  let run_options = serde_json::json!({
+    "filter": options.filter,
+    "bail": options.bail,
+  });
+  let script = format!(
+    "globalThis.__rew_testing__ ? globalThis.__rew_testing__.run({}) : '[]'",
+    run_options
+  );
+
+  let promise = runtime.runtime.execute_script("<test>", script)?;
+  let resolved = runtime.runtime.resolve(promise);
+  let value = runtime
+    .runtime
+    .with_event_loop_promise(Box::pin(resolved), PollEventLoopOptions::default())
+    .await?;
+
+  let json = {
+    let scope = &mut runtime.runtime.handle_scope();
+    value.open(scope).to_rust_string_lossy(scope)
+  };
+  Ok(serde_json::from_str(&json)?)
+}
+
+fn print_result(result: &TestResult) {
+  let status = match result.status {
+    TestStatus::Passed => "ok".green(),
+    TestStatus::Failed => "FAILED".red().bold(),
+    TestStatus::Skipped => "skipped".yellow(),
+  };
+  println!(
+    "  {} ... {} {}",
+    result.full_name(),
+    status,
+    format!("({}ms)", result.duration).dimmed()
+  );
+}
+
+/// Runs every test file and prints the results as they come in.
+pub async fn run_tests(paths: &[PathBuf], options: &TestOptions) -> Result<TestSummary> {
+  let files = discover_tests(paths)?;
+  if files.is_empty() {
+    anyhow::bail!("No test files found");
+  }
+
+  let started = Instant::now();
+  let mut summary = TestSummary::default();
+
+  for path in files {
+    println!("{} {}", "running".dimmed(), path.display());
+    let file_started = Instant::now();
+
+    let results = match run_test_file(&path, options).await {
+      Ok(results) => results,
+      Err(e) => {
+        // A file that fails to load counts as one failing test.
+        vec![TestResult {
+          name: "<load>".to_string(),
+          suite: vec![],
+          status: TestStatus::Failed,
+          duration: 0,
+          error: Some(TestError {
+            message: e.to_string(),
+            stack: format!("{:?}", e),
+          }),
+        }]
+      }
+    };
+    results.iter().for_each(print_result);
+
+    let failed = results.iter().any(|r| r.status == TestStatus::Failed);
+    summary.files.push(FileReport {
+      path,
+      results,
+      duration: file_started.elapsed(),
+    });
+    if failed && options.bail {
+      break;
+    }
+  }
+
+  summary.duration = started.elapsed();
+  print_summary(&summary);
+
+  if let Some(junit) = &options.junit {
+    fs::write(junit, to_junit_xml(&summary))
+      .with_context(|| format!("Failed to write JUnit report to {:?}", junit))?;
+  }
+
+  Ok(summary)
+}
+
+fn print_summary(summary: &TestSummary) {
+  let failures: Vec<&TestResult> = summary
+    .files
+    .iter()
+    .flat_map(|file| &file.results)
+    .filter(|result| result.status == TestStatus::Failed)
+    .collect();
+
+  if !failures.is_empty() {
+    println!("\n{}\n", "failures:".red().bold());
+    for failure in &failures {
+      println!("{}", failure.full_name().bold());
+      if let Some(error) = &failure.error {
+        for line in error.stack.lines() {
+          println!("  {}", line);
+        }
+      }
+      println!();
+    }
+  }
+
+  let verdict = if failures.is_empty() {
+    "ok".green().bold()
+  } else {
+    "FAILED".red().bold()
+  };
+  println!(
+    "\n{} | {} passed | {} failed | {} skipped {}",
+    verdict,
+    summary.passed(),
+    summary.failed(),
+    summary.skipped(),
+    format!("({}ms)", summary.duration.as_millis()).dimmed()
+  );
+}
+
+fn escape_xml(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&apos;")
+}
+
+/// Renders the results in the JUnit XML format CI servers understand.
+pub fn to_junit_xml(summary: &TestSummary) -> String {
+  let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  xml.push_str(&format!(
+    "<testsuites name=\"rew test\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+    summary.passed() + summary.failed() + summary.skipped(),
+    summary.failed(),
+    summary.skipped(),
+    summary.duration.as_secs_f64()
+  ));
+
+  for file in &summary.files {
+    let count = |status| file.results.iter().filter(|r| r.status == status).count();
+    xml.push_str(&format!(
+      "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+      escape_xml(&file.path.display().to_string()),
+      file.results.len(),
+      count(TestStatus::Failed),
+      count(TestStatus::Skipped),
+      file.duration.as_secs_f64()
+    ));
+
+    for result in &file.results {
+      let open = format!(
+        "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+        escape_xml(&result.name),
+        escape_xml(&result.suite.join(".")),
+        result.duration as f64 / 1000.0
+      );
+      match result.status {
+        TestStatus::Failed => {
+          let (message, stack) = match &result.error {
+            Some(error) => (error.message.as_str(), error.stack.as_str()),
+            None => ("Test failed", ""),
+          };
+          xml.push_str(&format!(
+            "{}>\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+            open,
+            escape_xml(message),
+            escape_xml(stack)
+          ));
+        }
+        TestStatus::Skipped => {
+          xml.push_str(&format!("{}>\n      <skipped/>\n    </testcase>\n", open));
+        }
+        TestStatus::Passed => xml.push_str(&format!("{}/>\n", open)),
+      }
+    }
+
+    xml.push_str("  </testsuite>\n");
+  }
+
+  xml.push_str("</testsuites>\n");
+  xml
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn result(name: &str, status: TestStatus, error: Option<TestError>) -> TestResult {
+    TestResult {
+      name: name.to_string(),
+      suite: vec!["math".to_string(), "add".to_string()],
+      status,
+      duration: 1500,
+      error,
+    }
+  }
+
+  #[test]
+  fn xml_special_characters_are_escaped() {
+    assert_eq!(
+      escape_xml(r#"<a href="x">Tom & 'Jerry'</a>"#),
+      "&lt;a href=&quot;x&quot;&gt;Tom &amp; &apos;Jerry&apos;&lt;/a&gt;"
+    );
+  }
+
+  #[test]
+  fn junit_report_has_a_test_case_per_result() {
+    let summary = TestSummary {
+      files: vec![FileReport {
+        path: PathBuf::from("math.test.coffee"),
+        results: vec![
+          result("adds", TestStatus::Passed, None),
+          result(
+            "carries <1>",
+            TestStatus::Failed,
+            Some(TestError {
+              message: "expected 2 & got 3".to_string(),
+              stack: "Error: expected 2\n    at adds".to_string(),
+            }),
+          ),
+          result("overflows", TestStatus::Skipped, None),
+        ],
+        duration: Duration::from_millis(4500),
+      }],
+      duration: Duration::from_secs(5),
+    };
+
+    let xml = to_junit_xml(&summary);
+    assert!(xml.contains(
+      "<testsuites name=\"rew test\" tests=\"3\" failures=\"1\" skipped=\"1\" time=\"5.000\">"
+    ));
+    assert!(xml.contains(
+      "<testsuite name=\"math.test.coffee\" tests=\"3\" failures=\"1\" skipped=\"1\" \
+       time=\"4.500\">"
+    ));
+    assert!(xml.contains("<testcase name=\"adds\" classname=\"math.add\" time=\"1.500\"/>"));
+    assert!(xml.contains(
+      "<testcase name=\"carries &lt;1&gt;\" classname=\"math.add\" time=\"1.500\">\n      \
+       <failure message=\"expected 2 &amp; got 3\">Error: expected 2\n    at adds</failure>"
+    ));
+    assert!(xml.contains(
+      "<testcase name=\"overflows\" classname=\"math.add\" time=\"1.500\">\n      <skipped/>"
+    ));
+  }
+
+  #[test]
+  fn failures_without_an_error_are_still_failures() {
+    let summary = TestSummary {
+      files: vec![FileReport {
+        path: PathBuf::from("a.test.coffee"),
+        results: vec![result("breaks", TestStatus::Failed, None)],
+        duration: Duration::ZERO,
+      }],
+      duration: Duration::ZERO,
+    };
+
+    let xml = to_junit_xml(&summary);
+    assert!(xml.contains(
+      "<testcase name=\"breaks\" classname=\"math.add\" time=\"1.500\">\n      \
+       <failure message=\"Test failed\"></failure>"
+    ));
+  }
+
+  #[test]
+  fn test_files_are_found_once_in_the_order_given() {
+    let dir = tempfile::tempdir().unwrap();
+    let tests = dir.path().join("tests");
+    fs::create_dir_all(tests.join("node_modules")).unwrap();
+    for name in ["b.test.coffee", "a_test.rew", "helper.coffee"] {
+      fs::write(tests.join(name), "").unwrap();
+    }
+    fs::write(tests.join("node_modules").join("dep.test.coffee"), "").unwrap();
+    let single = dir.path().join("z.test.coffee");
+    fs::write(&single, "").unwrap();
+
+    let files = discover_tests(&[single.clone(), tests.clone(), tests.join("a_test.rew")]).unwrap();
+    assert_eq!(files, [single, tests.join("a_test.rew"), tests.join("b.test.coffee")]);
+  }
+
+  #[test]
+  fn apps_run_their_test_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("app.yaml"), "entries:\n  test: spec/all.coffee\n").unwrap();
+    fs::write(dir.path().join("other.test.coffee"), "").unwrap();
+
+    let files = discover_tests(&[dir.path().to_path_buf()]).unwrap();
+    assert_eq!(files, [dir.path().join("spec/all.coffee")]);
+    assert!(discover_tests(&[dir.path().join("missing")]).is_err());
+  }
+}
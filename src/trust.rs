@@ -0,0 +1,312 @@
+use crate::brew::{Brew, SignatureStatus};
+use crate::diagnostics::{self, RewDiagnostic};
+use crate::utils::get_rew_root;
+use anyhow::{Context, Result};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What to do with a brew that isn't signed by a trusted key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustPolicy {
+  /// Don't run it.
+  Refuse,
+  /// Run it after printing a warning.
+  #[default]
+  Warn,
+  /// Run it without saying anything.
+  Allow,
+}
+
+/// A public key whose brews are trusted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedKey {
+  pub name: String,
+  /// Base64 ed25519 public key.
+  pub key: String,
+}
+
+/// The keys brews are checked against, kept in
+/// `get_rew_root()/config/trust.yaml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrustStore {
+  #[serde(default)]
+  pub policy: TrustPolicy,
+  #[serde(default)]
+  pub keys: Vec<TrustedKey>,
+}
+
+fn config_dir() -> PathBuf {
+  get_rew_root().join("config")
+}
+
+fn store_path() -> PathBuf {
+  config_dir().join("trust.yaml")
+}
+
+/// Where `rew key generate` puts signing keys.
+pub fn keys_dir() -> PathBuf {
+  config_dir().join("keys")
+}
+
+/// Checks that `key` is a base64 ed25519 public key.
+fn parse_public_key(key: &str) -> Result<VerifyingKey> {
+  let bytes = BASE64
+    .decode(key.trim())
+    .context("Public key is not valid base64")?;
+  let bytes: [u8; 32] = bytes
+    .try_into()
+    .map_err(|_| anyhow::anyhow!("Public key must be 32 bytes"))?;
+  VerifyingKey::from_bytes(&bytes).context("Public key is not a valid ed25519 key")
+}
+
+impl TrustStore {
+  /// Loads the trust store, or an empty one with the default policy when
+  /// there is none yet.
+  pub fn load() -> Result<Self> {
+    let path = store_path();
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+    let content =
+      fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    serde_yaml::from_str(&content).with_context(|| format!("Failed to parse {:?}", path))
+  }
+
+  pub fn save(&self) -> Result<()> {
+    let path = store_path();
+    fs::create_dir_all(config_dir())?;
+    fs::write(&path, serde_yaml::to_string(self)?)
+      .with_context(|| format!("Failed to write {:?}", path))
+  }
+
+  /// Trusts `key` under `name`, replacing a key that had the same name.
+  ///
+  /// # Arguments
+  /// * `name` - A name to refer to the key by.
+  /// * `key` - The base64 public key, as `rew key generate` prints it.
+  pub fn add(&mut self, name: &str, key: &str) -> Result<()> {
+    parse_public_key(key)?;
+    self.keys.retain(|trusted| trusted.name != name);
+    self.keys.push(TrustedKey {
+      name: name.to_string(),
+      key: key.trim().to_string(),
+    });
+    Ok(())
+  }
+
+  /// Stops trusting the key called `name`. Returns `false` if there was none.
+  pub fn remove(&mut self, name: &str) -> bool {
+    let before = self.keys.len();
+    self.keys.retain(|trusted| trusted.name != name);
+    self.keys.len() != before
+  }
+
+  pub fn find(&self, key: &str) -> Option<&TrustedKey> {
+    self.keys.iter().find(|trusted| trusted.key == key)
+  }
+
+  /// Decides whether a brew may run, according to its signature and the
+  /// policy.
+  ///
+  /// A signature that doesn't match the brew is always refused. Unsigned
+  /// brews and brews signed by unknown keys are refused, warned about or
+  /// allowed depending on the policy.
+  pub fn check(&self, path: &Path, brew: &Brew) -> Result<()> {
+    let mut diagnostic = match brew.verify_signature() {
+      SignatureStatus::Valid(key) if self.find(&key).is_some() => return Ok(()),
+      SignatureStatus::Invalid => {
+        let mut diagnostic =
+          RewDiagnostic::error("The brew was changed after it was signed, refusing to run it");
+        diagnostic.file = Some(path.to_path_buf());
+        return Err(diagnostic.into());
+      }
+      SignatureStatus::Valid(key) => {
+        RewDiagnostic::warning(format!("The brew is signed by an untrusted key {}", key))
+          .with_hint(format!("trust it with `rew trust add <name> {}`", key))
+      }
+      SignatureStatus::Unsigned => RewDiagnostic::warning("The brew is not signed")
+        .with_hint("sign it with `rew brew --sign <key>`"),
+    };
+    diagnostic.file = Some(path.to_path_buf());
+
+    match self.policy {
+      TrustPolicy::Allow => Ok(()),
+      TrustPolicy::Warn => {
+        diagnostics::emit(&diagnostic.with_hint("set `rew trust policy allow` to run it quietly"));
+        Ok(())
+      }
+      TrustPolicy::Refuse => {
+        diagnostic.severity = diagnostics::Severity::Error;
+        diagnostic.message.push_str(", refusing to run it");
+        Err(diagnostic.into())
+      }
+    }
+  }
+}
+
+/// Decides whether a brew may run, according to its signature and the
+/// trust store under `get_rew_root()`. See [`TrustStore::check`].
+///
+/// # Arguments
+/// * `path` - Where the brew was loaded from, for messages.
+/// * `brew` - The decoded brew.
+pub fn check_brew(path: &Path, brew: &Brew) -> Result<()> {
+  TrustStore::load()?.check(path, brew)
+}
+
+fn key_path(name: &str) -> Result<PathBuf> {
+  if name.is_empty() || name.contains(['/', '\\', '.']) {
+    anyhow::bail!("Invalid key name: {:?}", name);
+  }
+  Ok(keys_dir().join(format!("{}.key", name)))
+}
+
+/// Encodes the public half of a signing key the way the trust store keeps
+/// it.
+pub fn public_key(key: &SigningKey) -> String {
+  BASE64.encode(key.verifying_key().as_bytes())
+}
+
+/// Creates a new signing key under `keys_dir()`.
+///
+/// # Arguments
+/// * `name` - The name of the key, also its file name.
+///
+/// # Returns
+/// * Where the key was written and its base64 public key.
+pub fn generate_key(name: &str) -> Result<(PathBuf, String)> {
+  let path = key_path(name)?;
+  if path.exists() {
+    anyhow::bail!("A key named {} already exists at {:?}", name, path);
+  }
+
+  let key = SigningKey::generate(&mut rand::rngs::OsRng);
+  fs::create_dir_all(keys_dir())?;
+  fs::write(&path, BASE64.encode(key.to_bytes()))
+    .with_context(|| format!("Failed to write {:?}", path))?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+  }
+
+  Ok((path, public_key(&key)))
+}
+
+/// Loads a signing key, either from a file or by its name under
+/// `keys_dir()`.
+pub fn load_signing_key(key: &str) -> Result<SigningKey> {
+  let path = if Path::new(key).is_file() {
+    PathBuf::from(key)
+  } else {
+    key_path(key)?
+  };
+  let content =
+    fs::read_to_string(&path).with_context(|| format!("Failed to read key {:?}", path))?;
+  let bytes: [u8; 32] = BASE64
+    .decode(content.trim())
+    .ok()
+    .and_then(|bytes| bytes.try_into().ok())
+    .ok_or_else(|| anyhow::anyhow!("{:?} is not a signing key", path))?;
+  Ok(SigningKey::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32])
+  }
+
+  fn brew(key: Option<&SigningKey>) -> Brew {
+    let modules = vec![("main".to_string(), None, "print 'hi'".to_string())];
+    let bytes = Brew::new(modules, vec!["main".to_string()], vec![])
+      .encode(false, key)
+      .unwrap();
+    Brew::decode(&bytes).unwrap()
+  }
+
+  fn store(policy: TrustPolicy, trusted: &[&SigningKey]) -> TrustStore {
+    let mut store = TrustStore {
+      policy,
+      keys: vec![],
+    };
+    for (i, key) in trusted.iter().enumerate() {
+      store.add(&format!("key{}", i), &public_key(key)).unwrap();
+    }
+    store
+  }
+
+  #[test]
+  fn trusted_signature_runs_under_any_policy() {
+    let signer = key(1);
+    let path = Path::new("app.brew");
+    for policy in [TrustPolicy::Refuse, TrustPolicy::Warn, TrustPolicy::Allow] {
+      store(policy, &[&signer]).check(path, &brew(Some(&signer))).unwrap();
+    }
+  }
+
+  #[test]
+  fn tampered_brew_is_refused_under_any_policy() {
+    let signer = key(1);
+    let mut bytes = Brew::new(
+      vec![("main".to_string(), None, "print 'hi'".to_string())],
+      vec!["main".to_string()],
+      vec!["#std.fs".to_string()],
+    )
+    .encode(false, Some(&signer))
+    .unwrap();
+    let at = bytes.windows(6).position(|w| w == b"std.fs").unwrap();
+    bytes[at..at + 6].copy_from_slice(b"std.os");
+    let tampered = Brew::decode(&bytes).unwrap();
+
+    for policy in [TrustPolicy::Refuse, TrustPolicy::Warn, TrustPolicy::Allow] {
+      let err = store(policy, &[&signer])
+        .check(Path::new("app.brew"), &tampered)
+        .unwrap_err();
+      assert!(err.to_string().contains("changed after it was signed"));
+    }
+  }
+
+  #[test]
+  fn untrusted_key_follows_the_policy() {
+    let signer = key(1);
+    let other = key(2);
+    let path = Path::new("app.brew");
+    let signed = brew(Some(&signer));
+
+    store(TrustPolicy::Allow, &[&other]).check(path, &signed).unwrap();
+    store(TrustPolicy::Warn, &[&other]).check(path, &signed).unwrap();
+    let err = store(TrustPolicy::Refuse, &[&other])
+      .check(path, &signed)
+      .unwrap_err();
+    assert!(err.to_string().contains("untrusted key"));
+  }
+
+  #[test]
+  fn unsigned_brew_follows_the_policy() {
+    let path = Path::new("app.brew");
+    store(TrustPolicy::Allow, &[]).check(path, &brew(None)).unwrap();
+    store(TrustPolicy::Warn, &[]).check(path, &brew(None)).unwrap();
+    assert!(store(TrustPolicy::Refuse, &[]).check(path, &brew(None)).is_err());
+  }
+
+  #[test]
+  fn only_valid_public_keys_are_trusted() {
+    let mut store = TrustStore::default();
+    assert!(store.add("bad", "not a key").is_err());
+    assert!(store.add("short", &BASE64.encode([1u8; 16])).is_err());
+
+    store.add("me", &public_key(&key(1))).unwrap();
+    store.add("me", &public_key(&key(2))).unwrap();
+    assert_eq!(store.keys.len(), 1);
+    assert!(store.find(&public_key(&key(2))).is_some());
+    assert!(store.remove("me"));
+    assert!(!store.remove("me"));
+  }
+}
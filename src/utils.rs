@@ -16,6 +16,12 @@ pub struct AppManifest {
 pub struct AppConfig {
   pub manifest: Option<AppManifest>,
   pub entries: Option<std::collections::HashMap<String, String>>,
+  /// Settings for `rew lint`.
+  pub lint: Option<crate::linter::LintConfig>,
+  /// What the app may access when it is run.
+  pub permissions: Option<crate::permissions::AppPermissions>,
+  /// Where `conf` keeps the app's data.
+  pub data: Option<crate::data_manager::DataConfig>,
 }
 
 #[derive(Debug, Clone)]
@@ -24,6 +30,20 @@ pub struct AppInfo {
   pub config: AppConfig,
 }
 
+/// Checks that a package name from `app.yaml` can name a directory of its
+/// own, so paths built from it stay where they are meant to be.
+pub fn check_package_name(name: &str) -> anyhow::Result<()> {
+  let invalid = name.is_empty()
+    || name == "."
+    || name.contains("..")
+    || name.contains(['/', '\\', ':', '\0'])
+    || Path::new(name).is_absolute();
+  if invalid {
+    anyhow::bail!("Invalid package name {:?}", name);
+  }
+  Ok(())
+}
+
 // Get the Rew root directory
 pub fn get_rew_root() -> PathBuf {
   // First check for REW_ROOT environment variable
@@ -52,51 +72,53 @@ pub fn get_rew_root() -> PathBuf {
   }
 }
 
-// Find an app by package name
-pub fn find_app_by_package(package_name: &str) -> Option<AppInfo> {
-  let rew_root = get_rew_root();
-  let apps_dir = rew_root.join("apps");
-
-  if !apps_dir.exists() {
-    return None;
-  }
-
-  // let app_dir = apps_dir.join(package_name);
-  // let config_path = app_dir.join("app.yaml");
-
-  // println!("{}", config_path.display());
-
-  let app_dirs = fs::read_dir(&apps_dir).ok()?;
-
-  for dir_entry in app_dirs.flatten() {
-    let app_dir = dir_entry.path();
-    if !app_dir.is_dir() {
-      continue;
-    }
-
-    let config_path = app_dir.join("app.yaml");
-    if !config_path.exists() {
-      continue;
-    }
+/// A rew root for the tests, so they never touch the real one. Tests that
+/// need one share it, as `REW_ROOT` is the same for the whole process.
+#[cfg(test)]
+pub fn test_rew_root() -> &'static Path {
+  static ROOT: std::sync::OnceLock<tempfile::TempDir> = std::sync::OnceLock::new();
+  ROOT
+    .get_or_init(|| {
+      let root = tempfile::tempdir().unwrap();
+      // SAFETY: this is the only place tests write REW_ROOT, and it runs once.
+      unsafe { env::set_var("REW_ROOT", root.path()) };
+      root
+    })
+    .path()
+}
 
-    // Read and parse the app.yaml file
-    let config_str = fs::read_to_string(&config_path).ok()?;
-    let config: AppConfig = serde_yaml::from_str(&config_str).ok()?;
-
-    // Check if this app has the requested package name
-    if let Some(manifest) = &config.manifest {
-      if let Some(pkg) = &manifest.package {
-        if pkg == package_name {
-          return Some(AppInfo {
-            path: app_dir,
-            config,
-          });
-        }
-      }
-    }
-  }
+/// Every app installed under `get_rew_root()/apps` with a readable `app.yaml`.
+pub fn installed_apps() -> Vec<AppInfo> {
+  let apps_dir = get_rew_root().join("apps");
+  let Ok(app_dirs) = fs::read_dir(&apps_dir) else {
+    return Vec::new();
+  };
+
+  app_dirs
+    .flatten()
+    .map(|dir_entry| dir_entry.path())
+    .filter(|app_dir| app_dir.is_dir())
+    .filter_map(|app_dir| {
+      let config_str = fs::read_to_string(app_dir.join("app.yaml")).ok()?;
+      let config: AppConfig = serde_yaml::from_str(&config_str).ok()?;
+      Some(AppInfo {
+        path: app_dir,
+        config,
+      })
+    })
+    .collect()
+}
 
-  None
+// Find an app by package name
+pub fn find_app_by_package(package_name: &str) -> Option<AppInfo> {
+  installed_apps().into_iter().find(|app| {
+    app
+      .config
+      .manifest
+      .as_ref()
+      .and_then(|manifest| manifest.package.as_deref())
+      == Some(package_name)
+  })
 }
 
 // Find app info for a file path
@@ -154,6 +176,38 @@ pub fn find_app_path(dir_path: &Path) -> Option<PathBuf> {
   None
 }
 
+/// Directories never searched when collecting source files.
+const IGNORED_DIRS: &[&str] = &["node_modules", "target"];
+
+/// Recursively collects the files under `dir` that `predicate` accepts,
+/// skipping hidden directories, `node_modules` and `target`.
+pub fn collect_files(
+  dir: &Path,
+  predicate: &dyn Fn(&Path) -> bool,
+  files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+  let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .collect();
+  entries.sort();
+
+  for path in entries {
+    let name = path
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+      .unwrap_or_default();
+    if path.is_dir() {
+      if !name.starts_with('.') && !IGNORED_DIRS.contains(&name.as_str()) {
+        collect_files(&path, predicate, files)?;
+      }
+    } else if predicate(&path) {
+      files.push(path);
+    }
+  }
+
+  Ok(())
+}
+
 #[allow(unused)]
 pub fn is_valid_utf8<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
   let bytes = std::fs::read(path)?;
@@ -0,0 +1,1612 @@
+use crate::ext::web::normalize_path;
+use deno_core::futures::future::LocalBoxFuture;
+use deno_core::{BufMutView, BufView, ResourceHandleFd, WriteOutcome};
+use deno_fs::{AccessCheckCb, FileSystem, FileSystemRc, FsDirEntry, FsFileType, OpenOptions};
+use deno_io::fs::{File, FsError, FsResult, FsStat};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use deno_fs::RealFs;
+
+fn fs_error(kind: io::ErrorKind, message: String) -> FsError {
+  io::Error::new(kind, message).into()
+}
+
+fn not_found(path: &Path) -> FsError {
+  fs_error(
+    io::ErrorKind::NotFound,
+    format!("No such file or directory: {}", path.display()),
+  )
+}
+
+/// Implements `FileSystem` for a type from the sync methods given in the
+/// block. The async methods run their sync counterpart, so this is only for
+/// file systems that never block, like `MemoryFs`. Layers over another file
+/// system implement the async methods themselves and await the inner layer.
+macro_rules! file_system {
+  ($ty:ty { $($sync:tt)* }) => {
+    #[async_trait::async_trait(?Send)]
+    impl FileSystem for $ty {
+      $($sync)*
+
+      async fn open_async<'a>(
+        &'a self,
+        path: PathBuf,
+        options: OpenOptions,
+        access_check: Option<AccessCheckCb<'a>>,
+      ) -> FsResult<Rc<dyn File>> {
+        self.open_sync(&path, options, access_check)
+      }
+
+      async fn mkdir_async(&self, path: PathBuf, recursive: bool, mode: Option<u32>) -> FsResult<()> {
+        self.mkdir_sync(&path, recursive, mode)
+      }
+
+      async fn chmod_async(&self, path: PathBuf, mode: u32) -> FsResult<()> {
+        self.chmod_sync(&path, mode)
+      }
+
+      async fn chown_async(&self, path: PathBuf, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+        self.chown_sync(&path, uid, gid)
+      }
+
+      async fn lchown_async(&self, path: PathBuf, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+        self.lchown_sync(&path, uid, gid)
+      }
+
+      async fn remove_async(&self, path: PathBuf, recursive: bool) -> FsResult<()> {
+        self.remove_sync(&path, recursive)
+      }
+
+      async fn copy_file_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+        self.copy_file_sync(&oldpath, &newpath)
+      }
+
+      async fn cp_async(&self, path: PathBuf, new_path: PathBuf) -> FsResult<()> {
+        self.cp_sync(&path, &new_path)
+      }
+
+      async fn stat_async(&self, path: PathBuf) -> FsResult<FsStat> {
+        self.stat_sync(&path)
+      }
+
+      async fn lstat_async(&self, path: PathBuf) -> FsResult<FsStat> {
+        self.lstat_sync(&path)
+      }
+
+      async fn realpath_async(&self, path: PathBuf) -> FsResult<PathBuf> {
+        self.realpath_sync(&path)
+      }
+
+      async fn read_dir_async(&self, path: PathBuf) -> FsResult<Vec<FsDirEntry>> {
+        self.read_dir_sync(&path)
+      }
+
+      async fn rename_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+        self.rename_sync(&oldpath, &newpath)
+      }
+
+      async fn link_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+        self.link_sync(&oldpath, &newpath)
+      }
+
+      async fn symlink_async(
+        &self,
+        oldpath: PathBuf,
+        newpath: PathBuf,
+        file_type: Option<FsFileType>,
+      ) -> FsResult<()> {
+        self.symlink_sync(&oldpath, &newpath, file_type)
+      }
+
+      async fn read_link_async(&self, path: PathBuf) -> FsResult<PathBuf> {
+        self.read_link_sync(&path)
+      }
+
+      async fn truncate_async(&self, path: PathBuf, len: u64) -> FsResult<()> {
+        self.truncate_sync(&path, len)
+      }
+
+      async fn utime_async(
+        &self,
+        path: PathBuf,
+        atime_secs: i64,
+        atime_nanos: u32,
+        mtime_secs: i64,
+        mtime_nanos: u32,
+      ) -> FsResult<()> {
+        self.utime_sync(&path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+      }
+
+      async fn lutime_async(
+        &self,
+        path: PathBuf,
+        atime_secs: i64,
+        atime_nanos: u32,
+        mtime_secs: i64,
+        mtime_nanos: u32,
+      ) -> FsResult<()> {
+        self.lutime_sync(&path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+      }
+    }
+  };
+}
+
+/// Copies a file, or a directory and everything in it, from one file system
+/// to another.
+pub fn copy_tree(
+  from: &dyn FileSystem,
+  to: &dyn FileSystem,
+  src: &Path,
+  dest: &Path,
+) -> FsResult<()> {
+  if from.stat_sync(src)?.is_directory {
+    to.mkdir_sync(dest, true, None)?;
+    for entry in from.read_dir_sync(src)? {
+      copy_tree(from, to, &src.join(&entry.name), &dest.join(&entry.name))?;
+    }
+    Ok(())
+  } else {
+    let data = from.read_file_sync(src, None)?;
+    let options = OpenOptions::write(true, false, false, None);
+    to.write_file_sync(dest, options, None, &data)
+  }
+}
+
+/// `copy_tree` with the async methods of both file systems.
+fn copy_tree_async<'a>(
+  from: &'a dyn FileSystem,
+  to: &'a dyn FileSystem,
+  src: PathBuf,
+  dest: PathBuf,
+) -> LocalBoxFuture<'a, FsResult<()>> {
+  Box::pin(async move {
+    if from.stat_async(src.clone()).await?.is_directory {
+      to.mkdir_async(dest.clone(), true, None).await?;
+      for entry in from.read_dir_async(src.clone()).await? {
+        copy_tree_async(from, to, src.join(&entry.name), dest.join(&entry.name)).await?;
+      }
+      Ok(())
+    } else {
+      let data = from.read_file_async(src, None).await?;
+      let options = OpenOptions::write(true, false, false, None);
+      to.write_file_async(dest, options, None, data.into_owned()).await
+    }
+  })
+}
+
+#[derive(Debug)]
+struct MemoryFileData {
+  data: Vec<u8>,
+  modified: SystemTime,
+}
+
+#[derive(Debug, Default)]
+struct MemoryTree {
+  files: BTreeMap<PathBuf, MemoryFileData>,
+  dirs: BTreeMap<PathBuf, SystemTime>,
+}
+
+impl MemoryTree {
+  fn is_dir(&self, path: &Path) -> bool {
+    path.parent().is_none() || self.dirs.contains_key(path)
+  }
+
+  /// Creates `path` and every directory above it.
+  fn add_dir_all(&mut self, path: &Path) {
+    let now = SystemTime::now();
+    for dir in path.ancestors().filter(|dir| dir.parent().is_some()) {
+      self.dirs.entry(dir.to_path_buf()).or_insert(now);
+    }
+  }
+
+  fn check_parent(&self, path: &Path) -> FsResult<()> {
+    match path.parent() {
+      Some(parent) if !self.is_dir(parent) => Err(not_found(parent)),
+      _ => Ok(()),
+    }
+  }
+
+  fn stat(&self, path: &Path) -> FsResult<FsStat> {
+    if let Some(file) = self.files.get(path) {
+      Ok(memory_stat(true, file.data.len() as u64, file.modified))
+    } else if self.is_dir(path) {
+      let modified = self.dirs.get(path).copied().unwrap_or(UNIX_EPOCH);
+      Ok(memory_stat(false, 0, modified))
+    } else {
+      Err(not_found(path))
+    }
+  }
+}
+
+fn memory_stat(is_file: bool, size: u64, modified: SystemTime) -> FsStat {
+  let time = modified
+    .duration_since(UNIX_EPOCH)
+    .ok()
+    .map(|time| time.as_millis() as u64);
+  FsStat {
+    is_file,
+    is_directory: !is_file,
+    is_symlink: false,
+    size,
+    mtime: time,
+    atime: time,
+    birthtime: time,
+    ctime: time,
+    dev: 0,
+    ino: 0,
+    mode: if is_file { 0o100644 } else { 0o040755 },
+    nlink: 1,
+    uid: 0,
+    gid: 0,
+    rdev: 0,
+    blksize: 0,
+    blocks: 0,
+    is_block_device: false,
+    is_char_device: false,
+    is_fifo: false,
+    is_socket: false,
+  }
+}
+
+/// A file system kept in memory, for tests and for files that only exist
+/// while rew runs.
+///
+/// Paths are made absolute against the current directory. Permissions,
+/// owners and access times aren't kept, and there are no links.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs(Arc<RwLock<MemoryTree>>);
+
+impl MemoryFs {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  fn read(&self) -> RwLockReadGuard<'_, MemoryTree> {
+    self.0.read().unwrap()
+  }
+
+  fn write(&self) -> RwLockWriteGuard<'_, MemoryTree> {
+    self.0.write().unwrap()
+  }
+
+  /// Adds or replaces a file, creating the directories above it.
+  pub fn insert(&self, path: &Path, data: impl Into<Vec<u8>>) {
+    let path = normalize_path(path);
+    let mut tree = self.write();
+    if let Some(parent) = path.parent() {
+      tree.add_dir_all(parent);
+    }
+    tree.files.insert(
+      path,
+      MemoryFileData {
+        data: data.into(),
+        modified: SystemTime::now(),
+      },
+    );
+  }
+
+  /// The contents of the file at `path`, if there is one.
+  pub fn get(&self, path: &Path) -> Option<Vec<u8>> {
+    let path = normalize_path(path);
+    self.read().files.get(&path).map(|file| file.data.clone())
+  }
+
+  /// Whether there is a file at `path`.
+  pub fn contains(&self, path: &Path) -> bool {
+    self.read().files.contains_key(&normalize_path(path))
+  }
+
+  fn check_exists(&self, path: &Path) -> FsResult<()> {
+    self.read().stat(&normalize_path(path)).map(|_| ())
+  }
+}
+
+file_system!(MemoryFs {
+  fn cwd(&self) -> FsResult<PathBuf> {
+    Ok(std::env::current_dir()?)
+  }
+
+  fn tmp_dir(&self) -> FsResult<PathBuf> {
+    Ok(std::env::temp_dir())
+  }
+
+  fn chdir(&self, _path: &Path) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  fn umask(&self, _mask: Option<u32>) -> FsResult<u32> {
+    Err(FsError::NotSupported)
+  }
+
+  fn open_sync(
+    &self,
+    path: &Path,
+    options: OpenOptions,
+    _access_check: Option<AccessCheckCb>,
+  ) -> FsResult<Rc<dyn File>> {
+    let path = normalize_path(path);
+    let mut tree = self.write();
+    if tree.is_dir(&path) {
+      return Err(fs_error(
+        io::ErrorKind::IsADirectory,
+        format!("Is a directory: {}", path.display()),
+      ));
+    }
+
+    match tree.files.get_mut(&path) {
+      Some(_) if options.create_new => {
+        return Err(fs_error(
+          io::ErrorKind::AlreadyExists,
+          format!("File exists: {}", path.display()),
+        ));
+      }
+      Some(file) => {
+        if options.write && options.truncate {
+          file.data.clear();
+          file.modified = SystemTime::now();
+        }
+      }
+      None if options.create || options.create_new => {
+        tree.check_parent(&path)?;
+        tree.files.insert(
+          path.clone(),
+          MemoryFileData {
+            data: Vec::new(),
+            modified: SystemTime::now(),
+          },
+        );
+      }
+      None => return Err(not_found(&path)),
+    }
+
+    Ok(Rc::new(MemoryFile {
+      fs: self.clone(),
+      path,
+      position: Cell::new(0),
+      append: options.append,
+    }))
+  }
+
+  fn mkdir_sync(&self, path: &Path, recursive: bool, _mode: Option<u32>) -> FsResult<()> {
+    let path = normalize_path(path);
+    let mut tree = self.write();
+    let exists = tree.files.contains_key(&path) || (tree.is_dir(&path) && !recursive);
+    if exists {
+      return Err(fs_error(
+        io::ErrorKind::AlreadyExists,
+        format!("File exists: {}", path.display()),
+      ));
+    }
+    if recursive {
+      tree.add_dir_all(&path);
+    } else {
+      tree.check_parent(&path)?;
+      tree.dirs.insert(path, SystemTime::now());
+    }
+    Ok(())
+  }
+
+  fn chmod_sync(&self, path: &Path, _mode: u32) -> FsResult<()> {
+    self.check_exists(path)
+  }
+
+  fn chown_sync(&self, path: &Path, _uid: Option<u32>, _gid: Option<u32>) -> FsResult<()> {
+    self.check_exists(path)
+  }
+
+  fn lchown_sync(&self, path: &Path, _uid: Option<u32>, _gid: Option<u32>) -> FsResult<()> {
+    self.check_exists(path)
+  }
+
+  fn remove_sync(&self, path: &Path, recursive: bool) -> FsResult<()> {
+    let path = normalize_path(path);
+    let mut tree = self.write();
+    if tree.files.remove(&path).is_some() {
+      return Ok(());
+    }
+    if path.parent().is_none() || !tree.dirs.contains_key(&path) {
+      return Err(not_found(&path));
+    }
+
+    let inside = |entry: &PathBuf| entry != &path && entry.starts_with(&path);
+    let has_entries = tree.files.keys().any(inside) || tree.dirs.keys().any(inside);
+    if has_entries && !recursive {
+      return Err(fs_error(
+        io::ErrorKind::DirectoryNotEmpty,
+        format!("Directory not empty: {}", path.display()),
+      ));
+    }
+    tree.files.retain(|entry, _| !entry.starts_with(&path));
+    tree.dirs.retain(|entry, _| !entry.starts_with(&path));
+    Ok(())
+  }
+
+  fn copy_file_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+    let data = self.get(oldpath).ok_or_else(|| not_found(oldpath))?;
+    let newpath = normalize_path(newpath);
+    let mut tree = self.write();
+    tree.check_parent(&newpath)?;
+    if tree.is_dir(&newpath) {
+      return Err(fs_error(
+        io::ErrorKind::IsADirectory,
+        format!("Is a directory: {}", newpath.display()),
+      ));
+    }
+    tree.files.insert(
+      newpath,
+      MemoryFileData {
+        data,
+        modified: SystemTime::now(),
+      },
+    );
+    Ok(())
+  }
+
+  fn cp_sync(&self, path: &Path, new_path: &Path) -> FsResult<()> {
+    copy_tree(self, self, path, new_path)
+  }
+
+  fn stat_sync(&self, path: &Path) -> FsResult<FsStat> {
+    self.read().stat(&normalize_path(path))
+  }
+
+  fn lstat_sync(&self, path: &Path) -> FsResult<FsStat> {
+    self.stat_sync(path)
+  }
+
+  fn realpath_sync(&self, path: &Path) -> FsResult<PathBuf> {
+    let path = normalize_path(path);
+    self.read().stat(&path)?;
+    Ok(path)
+  }
+
+  fn read_dir_sync(&self, path: &Path) -> FsResult<Vec<FsDirEntry>> {
+    let path = normalize_path(path);
+    let tree = self.read();
+    if !tree.is_dir(&path) {
+      return Err(match tree.files.contains_key(&path) {
+        true => fs_error(
+          io::ErrorKind::NotADirectory,
+          format!("Not a directory: {}", path.display()),
+        ),
+        false => not_found(&path),
+      });
+    }
+
+    let entry = |entry: &PathBuf, is_file: bool| {
+      (entry.parent() == Some(path.as_path())).then(|| FsDirEntry {
+        name: entry
+          .file_name()
+          .map(|name| name.to_string_lossy().into_owned())
+          .unwrap_or_default(),
+        is_file,
+        is_directory: !is_file,
+        is_symlink: false,
+      })
+    };
+    let dirs = tree.dirs.keys().filter_map(|dir| entry(dir, false));
+    let files = tree.files.keys().filter_map(|file| entry(file, true));
+    Ok(dirs.chain(files).collect())
+  }
+
+  fn rename_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+    let oldpath = normalize_path(oldpath);
+    let newpath = normalize_path(newpath);
+    let mut tree = self.write();
+    tree.check_parent(&newpath)?;
+
+    if let Some(file) = tree.files.remove(&oldpath) {
+      tree.files.insert(newpath, file);
+      return Ok(());
+    }
+    if !tree.dirs.contains_key(&oldpath) {
+      return Err(not_found(&oldpath));
+    }
+    if newpath.starts_with(&oldpath) {
+      return Err(fs_error(
+        io::ErrorKind::InvalidInput,
+        format!("Cannot move {} into itself", oldpath.display()),
+      ));
+    }
+
+    let moved = |entry: &PathBuf| newpath.join(entry.strip_prefix(&oldpath).unwrap());
+    let (files, rest): (BTreeMap<_, _>, _) = std::mem::take(&mut tree.files)
+      .into_iter()
+      .partition(|(entry, _)| entry.starts_with(&oldpath));
+    tree.files = rest;
+    tree.files.extend(files.into_iter().map(|(entry, file)| (moved(&entry), file)));
+    let (dirs, rest): (BTreeMap<_, _>, _) = std::mem::take(&mut tree.dirs)
+      .into_iter()
+      .partition(|(entry, _)| entry.starts_with(&oldpath));
+    tree.dirs = rest;
+    tree.dirs.extend(dirs.into_iter().map(|(entry, time)| (moved(&entry), time)));
+    Ok(())
+  }
+
+  fn link_sync(&self, _oldpath: &Path, _newpath: &Path) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  fn symlink_sync(
+    &self,
+    _oldpath: &Path,
+    _newpath: &Path,
+    _file_type: Option<FsFileType>,
+  ) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  fn read_link_sync(&self, _path: &Path) -> FsResult<PathBuf> {
+    Err(FsError::NotSupported)
+  }
+
+  fn truncate_sync(&self, path: &Path, len: u64) -> FsResult<()> {
+    let path = normalize_path(path);
+    let mut tree = self.write();
+    let file = tree.files.get_mut(&path).ok_or_else(|| not_found(&path))?;
+    file.data.resize(len as usize, 0);
+    file.modified = SystemTime::now();
+    Ok(())
+  }
+
+  fn utime_sync(
+    &self,
+    path: &Path,
+    _atime_secs: i64,
+    _atime_nanos: u32,
+    _mtime_secs: i64,
+    _mtime_nanos: u32,
+  ) -> FsResult<()> {
+    self.check_exists(path)
+  }
+
+  fn lutime_sync(
+    &self,
+    path: &Path,
+    _atime_secs: i64,
+    _atime_nanos: u32,
+    _mtime_secs: i64,
+    _mtime_nanos: u32,
+  ) -> FsResult<()> {
+    self.check_exists(path)
+  }
+});
+
+/// An open file of a `MemoryFs`. Reads and writes go straight to the file
+/// system, so every handle sees the same contents.
+#[derive(Debug)]
+struct MemoryFile {
+  fs: MemoryFs,
+  path: PathBuf,
+  position: Cell<u64>,
+  append: bool,
+}
+
+impl MemoryFile {
+  fn with_data<T>(&self, f: impl FnOnce(&mut MemoryFileData) -> T) -> FsResult<T> {
+    let mut tree = self.fs.write();
+    let file = tree
+      .files
+      .get_mut(&self.path)
+      .ok_or_else(|| not_found(&self.path))?;
+    Ok(f(file))
+  }
+}
+
+#[async_trait::async_trait(?Send)]
+impl File for MemoryFile {
+  fn read_sync(self: Rc<Self>, buf: &mut [u8]) -> FsResult<usize> {
+    let start = self.position.get() as usize;
+    let read = self.with_data(|file| {
+      let available = file.data.get(start..).unwrap_or_default();
+      let read = available.len().min(buf.len());
+      buf[..read].copy_from_slice(&available[..read]);
+      read
+    })?;
+    self.position.set((start + read) as u64);
+    Ok(read)
+  }
+
+  async fn read_byob(self: Rc<Self>, mut buf: BufMutView) -> FsResult<(usize, BufMutView)> {
+    let read = self.read_sync(&mut buf)?;
+    Ok((read, buf))
+  }
+
+  fn write_sync(self: Rc<Self>, buf: &[u8]) -> FsResult<usize> {
+    let position = self.position.get() as usize;
+    let end = self.with_data(|file| {
+      let start = if self.append {
+        file.data.len()
+      } else {
+        position
+      };
+      let end = start + buf.len();
+      if file.data.len() < end {
+        file.data.resize(end, 0);
+      }
+      file.data[start..end].copy_from_slice(buf);
+      file.modified = SystemTime::now();
+      end
+    })?;
+    self.position.set(end as u64);
+    Ok(buf.len())
+  }
+
+  async fn write(self: Rc<Self>, buf: BufView) -> FsResult<WriteOutcome> {
+    let nwritten = self.write_sync(&buf)?;
+    Ok(WriteOutcome::Full { nwritten })
+  }
+
+  fn write_all_sync(self: Rc<Self>, buf: &[u8]) -> FsResult<()> {
+    self.write_sync(buf).map(|_| ())
+  }
+
+  async fn write_all(self: Rc<Self>, buf: BufView) -> FsResult<()> {
+    self.write_all_sync(&buf)
+  }
+
+  fn read_all_sync(self: Rc<Self>) -> FsResult<Cow<'static, [u8]>> {
+    let start = self.position.get() as usize;
+    let data = self.with_data(|file| file.data.get(start..).unwrap_or_default().to_vec())?;
+    self.position.set((start + data.len()) as u64);
+    Ok(Cow::Owned(data))
+  }
+
+  async fn read_all_async(self: Rc<Self>) -> FsResult<Cow<'static, [u8]>> {
+    self.read_all_sync()
+  }
+
+  fn chmod_sync(self: Rc<Self>, _mode: u32) -> FsResult<()> {
+    Ok(())
+  }
+
+  async fn chmod_async(self: Rc<Self>, _mode: u32) -> FsResult<()> {
+    Ok(())
+  }
+
+  fn seek_sync(self: Rc<Self>, pos: io::SeekFrom) -> FsResult<u64> {
+    let len = self.with_data(|file| file.data.len() as i64)?;
+    let position = match pos {
+      io::SeekFrom::Start(offset) => offset as i64,
+      io::SeekFrom::End(offset) => len + offset,
+      io::SeekFrom::Current(offset) => self.position.get() as i64 + offset,
+    };
+    if position < 0 {
+      return Err(fs_error(
+        io::ErrorKind::InvalidInput,
+        "Cannot seek before the start of the file".to_string(),
+      ));
+    }
+    self.position.set(position as u64);
+    Ok(position as u64)
+  }
+
+  async fn seek_async(self: Rc<Self>, pos: io::SeekFrom) -> FsResult<u64> {
+    self.seek_sync(pos)
+  }
+
+  fn datasync_sync(self: Rc<Self>) -> FsResult<()> {
+    Ok(())
+  }
+
+  async fn datasync_async(self: Rc<Self>) -> FsResult<()> {
+    Ok(())
+  }
+
+  fn sync_sync(self: Rc<Self>) -> FsResult<()> {
+    Ok(())
+  }
+
+  async fn sync_async(self: Rc<Self>) -> FsResult<()> {
+    Ok(())
+  }
+
+  fn stat_sync(self: Rc<Self>) -> FsResult<FsStat> {
+    self.fs.stat_sync(&self.path)
+  }
+
+  async fn stat_async(self: Rc<Self>) -> FsResult<FsStat> {
+    self.fs.stat_sync(&self.path)
+  }
+
+  fn lock_sync(self: Rc<Self>, _exclusive: bool) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  async fn lock_async(self: Rc<Self>, _exclusive: bool) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  fn unlock_sync(self: Rc<Self>) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  async fn unlock_async(self: Rc<Self>) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  fn truncate_sync(self: Rc<Self>, len: u64) -> FsResult<()> {
+    self.fs.truncate_sync(&self.path, len)
+  }
+
+  async fn truncate_async(self: Rc<Self>, len: u64) -> FsResult<()> {
+    self.fs.truncate_sync(&self.path, len)
+  }
+
+  fn utime_sync(
+    self: Rc<Self>,
+    _atime_secs: i64,
+    _atime_nanos: u32,
+    _mtime_secs: i64,
+    _mtime_nanos: u32,
+  ) -> FsResult<()> {
+    Ok(())
+  }
+
+  async fn utime_async(
+    self: Rc<Self>,
+    _atime_secs: i64,
+    _atime_nanos: u32,
+    _mtime_secs: i64,
+    _mtime_nanos: u32,
+  ) -> FsResult<()> {
+    Ok(())
+  }
+
+  fn as_stdio(self: Rc<Self>) -> FsResult<std::process::Stdio> {
+    Err(FsError::NotSupported)
+  }
+
+  fn backing_fd(self: Rc<Self>) -> Option<ResourceHandleFd> {
+    None
+  }
+
+  fn try_clone_inner(self: Rc<Self>) -> FsResult<Rc<dyn File>> {
+    Ok(Rc::new(MemoryFile {
+      fs: self.fs.clone(),
+      path: self.path.clone(),
+      position: Cell::new(self.position.get()),
+      append: self.append,
+    }))
+  }
+}
+
+/// Keeps another file system inside a directory, like a chroot.
+///
+/// Paths already inside the root are used as they are, every other path is
+/// resolved under the root, relative ones from the root itself, and `..`
+/// can't climb out of it. New symlinks
+/// can't be created, but symlinks that already exist in the root are
+/// followed.
+#[derive(Debug)]
+pub struct RootedFs {
+  root: PathBuf,
+  inner: FileSystemRc,
+}
+
+impl RootedFs {
+  pub fn new(root: &Path, inner: FileSystemRc) -> Self {
+    Self {
+      root: normalize_path(root),
+      inner,
+    }
+  }
+
+  fn resolve(&self, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+      let path = normalize_path(path);
+      if path.starts_with(&self.root) {
+        return path;
+      }
+    }
+
+    // Relative paths start at the root, which is also the cwd, and `..`
+    // stops there.
+    let mut resolved = self.root.clone();
+    for component in path.components() {
+      match component {
+        Component::Normal(part) => resolved.push(part),
+        Component::ParentDir if resolved != self.root => {
+          resolved.pop();
+        }
+        _ => {}
+      }
+    }
+    resolved
+  }
+}
+
+#[async_trait::async_trait(?Send)]
+impl FileSystem for RootedFs {
+  fn cwd(&self) -> FsResult<PathBuf> {
+    Ok(self.root.clone())
+  }
+
+  fn tmp_dir(&self) -> FsResult<PathBuf> {
+    Ok(self.resolve(&std::env::temp_dir()))
+  }
+
+  fn chdir(&self, _path: &Path) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  fn umask(&self, mask: Option<u32>) -> FsResult<u32> {
+    self.inner.umask(mask)
+  }
+
+  fn open_sync(
+    &self,
+    path: &Path,
+    options: OpenOptions,
+    access_check: Option<AccessCheckCb>,
+  ) -> FsResult<Rc<dyn File>> {
+    self.inner.open_sync(&self.resolve(path), options, access_check)
+  }
+
+  fn mkdir_sync(&self, path: &Path, recursive: bool, mode: Option<u32>) -> FsResult<()> {
+    self.inner.mkdir_sync(&self.resolve(path), recursive, mode)
+  }
+
+  fn chmod_sync(&self, path: &Path, mode: u32) -> FsResult<()> {
+    self.inner.chmod_sync(&self.resolve(path), mode)
+  }
+
+  fn chown_sync(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+    self.inner.chown_sync(&self.resolve(path), uid, gid)
+  }
+
+  fn lchown_sync(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+    self.inner.lchown_sync(&self.resolve(path), uid, gid)
+  }
+
+  fn remove_sync(&self, path: &Path, recursive: bool) -> FsResult<()> {
+    self.inner.remove_sync(&self.resolve(path), recursive)
+  }
+
+  fn copy_file_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+    self.inner.copy_file_sync(&self.resolve(oldpath), &self.resolve(newpath))
+  }
+
+  fn cp_sync(&self, path: &Path, new_path: &Path) -> FsResult<()> {
+    self.inner.cp_sync(&self.resolve(path), &self.resolve(new_path))
+  }
+
+  fn stat_sync(&self, path: &Path) -> FsResult<FsStat> {
+    self.inner.stat_sync(&self.resolve(path))
+  }
+
+  fn lstat_sync(&self, path: &Path) -> FsResult<FsStat> {
+    self.inner.lstat_sync(&self.resolve(path))
+  }
+
+  fn realpath_sync(&self, path: &Path) -> FsResult<PathBuf> {
+    self.inner.realpath_sync(&self.resolve(path))
+  }
+
+  fn read_dir_sync(&self, path: &Path) -> FsResult<Vec<FsDirEntry>> {
+    self.inner.read_dir_sync(&self.resolve(path))
+  }
+
+  fn rename_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+    self.inner.rename_sync(&self.resolve(oldpath), &self.resolve(newpath))
+  }
+
+  fn link_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+    self.inner.link_sync(&self.resolve(oldpath), &self.resolve(newpath))
+  }
+
+  fn symlink_sync(
+    &self,
+    _oldpath: &Path,
+    _newpath: &Path,
+    _file_type: Option<FsFileType>,
+  ) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  fn read_link_sync(&self, path: &Path) -> FsResult<PathBuf> {
+    self.inner.read_link_sync(&self.resolve(path))
+  }
+
+  fn truncate_sync(&self, path: &Path, len: u64) -> FsResult<()> {
+    self.inner.truncate_sync(&self.resolve(path), len)
+  }
+
+  fn utime_sync(
+    &self,
+    path: &Path,
+    atime_secs: i64,
+    atime_nanos: u32,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+  ) -> FsResult<()> {
+    let path = self.resolve(path);
+    self.inner.utime_sync(&path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+  }
+
+  fn lutime_sync(
+    &self,
+    path: &Path,
+    atime_secs: i64,
+    atime_nanos: u32,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+  ) -> FsResult<()> {
+    let path = self.resolve(path);
+    self.inner.lutime_sync(&path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+  }
+
+  async fn open_async<'a>(
+    &'a self,
+    path: PathBuf,
+    options: OpenOptions,
+    access_check: Option<AccessCheckCb<'a>>,
+  ) -> FsResult<Rc<dyn File>> {
+    self.inner.open_async(self.resolve(&path), options, access_check).await
+  }
+
+  async fn mkdir_async(&self, path: PathBuf, recursive: bool, mode: Option<u32>) -> FsResult<()> {
+    self.inner.mkdir_async(self.resolve(&path), recursive, mode).await
+  }
+
+  async fn chmod_async(&self, path: PathBuf, mode: u32) -> FsResult<()> {
+    self.inner.chmod_async(self.resolve(&path), mode).await
+  }
+
+  async fn chown_async(&self, path: PathBuf, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+    self.inner.chown_async(self.resolve(&path), uid, gid).await
+  }
+
+  async fn lchown_async(&self, path: PathBuf, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+    self.inner.lchown_async(self.resolve(&path), uid, gid).await
+  }
+
+  async fn remove_async(&self, path: PathBuf, recursive: bool) -> FsResult<()> {
+    self.inner.remove_async(self.resolve(&path), recursive).await
+  }
+
+  async fn copy_file_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+    let (oldpath, newpath) = (self.resolve(&oldpath), self.resolve(&newpath));
+    self.inner.copy_file_async(oldpath, newpath).await
+  }
+
+  async fn cp_async(&self, path: PathBuf, new_path: PathBuf) -> FsResult<()> {
+    let (path, new_path) = (self.resolve(&path), self.resolve(&new_path));
+    self.inner.cp_async(path, new_path).await
+  }
+
+  async fn stat_async(&self, path: PathBuf) -> FsResult<FsStat> {
+    self.inner.stat_async(self.resolve(&path)).await
+  }
+
+  async fn lstat_async(&self, path: PathBuf) -> FsResult<FsStat> {
+    self.inner.lstat_async(self.resolve(&path)).await
+  }
+
+  async fn realpath_async(&self, path: PathBuf) -> FsResult<PathBuf> {
+    self.inner.realpath_async(self.resolve(&path)).await
+  }
+
+  async fn read_dir_async(&self, path: PathBuf) -> FsResult<Vec<FsDirEntry>> {
+    self.inner.read_dir_async(self.resolve(&path)).await
+  }
+
+  async fn rename_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+    let (oldpath, newpath) = (self.resolve(&oldpath), self.resolve(&newpath));
+    self.inner.rename_async(oldpath, newpath).await
+  }
+
+  async fn link_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+    let (oldpath, newpath) = (self.resolve(&oldpath), self.resolve(&newpath));
+    self.inner.link_async(oldpath, newpath).await
+  }
+
+  async fn symlink_async(
+    &self,
+    _oldpath: PathBuf,
+    _newpath: PathBuf,
+    _file_type: Option<FsFileType>,
+  ) -> FsResult<()> {
+    Err(FsError::NotSupported)
+  }
+
+  async fn read_link_async(&self, path: PathBuf) -> FsResult<PathBuf> {
+    self.inner.read_link_async(self.resolve(&path)).await
+  }
+
+  async fn truncate_async(&self, path: PathBuf, len: u64) -> FsResult<()> {
+    self.inner.truncate_async(self.resolve(&path), len).await
+  }
+
+  async fn utime_async(
+    &self,
+    path: PathBuf,
+    atime_secs: i64,
+    atime_nanos: u32,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+  ) -> FsResult<()> {
+    let path = self.resolve(&path);
+    self
+      .inner
+      .utime_async(path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+      .await
+  }
+
+  async fn lutime_async(
+    &self,
+    path: PathBuf,
+    atime_secs: i64,
+    atime_nanos: u32,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+  ) -> FsResult<()> {
+    let path = self.resolve(&path);
+    self
+      .inner
+      .lutime_async(path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+      .await
+  }
+}
+
+
+/// Lays a read-only file system over another one.
+///
+/// Files in the overlay hide the ones at the same path in the base, and
+/// can't be changed or removed. Everything else, including every write, goes
+/// to the base.
+#[derive(Debug)]
+pub struct OverlayFs {
+  overlay: FileSystemRc,
+  base: FileSystemRc,
+}
+
+impl OverlayFs {
+  pub fn new(overlay: FileSystemRc, base: FileSystemRc) -> Self {
+    Self { overlay, base }
+  }
+
+  fn in_overlay(&self, path: &Path) -> bool {
+    self.overlay.stat_sync(path).is_ok_and(|stat| stat.is_file)
+  }
+
+  async fn in_overlay_async(&self, path: &Path) -> bool {
+    let stat = self.overlay.stat_async(path.to_path_buf()).await;
+    stat.is_ok_and(|stat| stat.is_file)
+  }
+
+  /// The layer reads of `path` go to. Directories only come from the
+  /// overlay when the base doesn't have them.
+  fn layer(&self, path: &Path) -> &dyn FileSystem {
+    match self.overlay.stat_sync(path) {
+      Ok(stat) if stat.is_file || !self.base.exists_sync(path) => &*self.overlay,
+      _ => &*self.base,
+    }
+  }
+
+  async fn layer_async(&self, path: &Path) -> &dyn FileSystem {
+    let in_overlay = match self.overlay.stat_async(path.to_path_buf()).await {
+      Ok(stat) if stat.is_file => true,
+      Ok(_) => !self.base.exists_async(path.to_path_buf()).await.unwrap_or(false),
+      Err(_) => false,
+    };
+    if in_overlay {
+      &*self.overlay
+    } else {
+      &*self.base
+    }
+  }
+
+  /// The base, unless `path` is a file of the overlay.
+  fn writable(&self, path: &Path) -> FsResult<&dyn FileSystem> {
+    if self.in_overlay(path) {
+      Err(read_only(path))
+    } else {
+      Ok(&*self.base)
+    }
+  }
+
+  async fn writable_async(&self, path: &Path) -> FsResult<&dyn FileSystem> {
+    if self.in_overlay_async(path).await {
+      Err(read_only(path))
+    } else {
+      Ok(&*self.base)
+    }
+  }
+}
+
+fn read_only(path: &Path) -> FsError {
+  fs_error(
+    io::ErrorKind::PermissionDenied,
+    format!("Read-only file: {}", path.display()),
+  )
+}
+
+/// The entries of a directory in both layers, the overlay's files replacing
+/// the base's.
+fn merge_dir_entries(
+  base: FsResult<Vec<FsDirEntry>>,
+  overlay: FsResult<Vec<FsDirEntry>>,
+) -> FsResult<Vec<FsDirEntry>> {
+  let mut entries = match (base, &overlay) {
+    (Ok(entries), _) => entries,
+    (Err(_), Ok(_)) => Vec::new(),
+    (Err(e), Err(_)) => return Err(e),
+  };
+  for entry in overlay.unwrap_or_default() {
+    match entries.iter_mut().find(|existing| existing.name == entry.name) {
+      Some(existing) if entry.is_file => *existing = entry,
+      Some(_) => {}
+      None => entries.push(entry),
+    }
+  }
+  Ok(entries)
+}
+
+#[async_trait::async_trait(?Send)]
+impl FileSystem for OverlayFs {
+  fn cwd(&self) -> FsResult<PathBuf> {
+    self.base.cwd()
+  }
+
+  fn tmp_dir(&self) -> FsResult<PathBuf> {
+    self.base.tmp_dir()
+  }
+
+  fn chdir(&self, path: &Path) -> FsResult<()> {
+    self.base.chdir(path)
+  }
+
+  fn umask(&self, mask: Option<u32>) -> FsResult<u32> {
+    self.base.umask(mask)
+  }
+
+  fn open_sync(
+    &self,
+    path: &Path,
+    options: OpenOptions,
+    access_check: Option<AccessCheckCb>,
+  ) -> FsResult<Rc<dyn File>> {
+    let writes = options.write || options.append || options.create || options.create_new;
+    let layer = if writes {
+      self.writable(path)?
+    } else {
+      self.layer(path)
+    };
+    layer.open_sync(path, options, access_check)
+  }
+
+  fn mkdir_sync(&self, path: &Path, recursive: bool, mode: Option<u32>) -> FsResult<()> {
+    self.writable(path)?.mkdir_sync(path, recursive, mode)
+  }
+
+  fn chmod_sync(&self, path: &Path, mode: u32) -> FsResult<()> {
+    self.writable(path)?.chmod_sync(path, mode)
+  }
+
+  fn chown_sync(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+    self.writable(path)?.chown_sync(path, uid, gid)
+  }
+
+  fn lchown_sync(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+    self.writable(path)?.lchown_sync(path, uid, gid)
+  }
+
+  fn remove_sync(&self, path: &Path, recursive: bool) -> FsResult<()> {
+    self.writable(path)?.remove_sync(path, recursive)
+  }
+
+  fn copy_file_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+    let base = self.writable(newpath)?;
+    if self.in_overlay(oldpath) {
+      copy_tree(&*self.overlay, base, oldpath, newpath)
+    } else {
+      base.copy_file_sync(oldpath, newpath)
+    }
+  }
+
+  fn cp_sync(&self, path: &Path, new_path: &Path) -> FsResult<()> {
+    let base = self.writable(new_path)?;
+    if self.overlay.exists_sync(path) {
+      copy_tree(self, base, path, new_path)
+    } else {
+      base.cp_sync(path, new_path)
+    }
+  }
+
+  fn stat_sync(&self, path: &Path) -> FsResult<FsStat> {
+    self.layer(path).stat_sync(path)
+  }
+
+  fn lstat_sync(&self, path: &Path) -> FsResult<FsStat> {
+    self.layer(path).lstat_sync(path)
+  }
+
+  fn realpath_sync(&self, path: &Path) -> FsResult<PathBuf> {
+    self.layer(path).realpath_sync(path)
+  }
+
+  fn read_dir_sync(&self, path: &Path) -> FsResult<Vec<FsDirEntry>> {
+    merge_dir_entries(self.base.read_dir_sync(path), self.overlay.read_dir_sync(path))
+  }
+
+  fn rename_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+    self.writable(oldpath)?;
+    self.writable(newpath)?.rename_sync(oldpath, newpath)
+  }
+
+  fn link_sync(&self, oldpath: &Path, newpath: &Path) -> FsResult<()> {
+    self.writable(newpath)?.link_sync(oldpath, newpath)
+  }
+
+  fn symlink_sync(
+    &self,
+    oldpath: &Path,
+    newpath: &Path,
+    file_type: Option<FsFileType>,
+  ) -> FsResult<()> {
+    self.writable(newpath)?.symlink_sync(oldpath, newpath, file_type)
+  }
+
+  fn read_link_sync(&self, path: &Path) -> FsResult<PathBuf> {
+    self.layer(path).read_link_sync(path)
+  }
+
+  fn truncate_sync(&self, path: &Path, len: u64) -> FsResult<()> {
+    self.writable(path)?.truncate_sync(path, len)
+  }
+
+  fn utime_sync(
+    &self,
+    path: &Path,
+    atime_secs: i64,
+    atime_nanos: u32,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+  ) -> FsResult<()> {
+    self
+      .writable(path)?
+      .utime_sync(path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+  }
+
+  fn lutime_sync(
+    &self,
+    path: &Path,
+    atime_secs: i64,
+    atime_nanos: u32,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+  ) -> FsResult<()> {
+    self
+      .writable(path)?
+      .lutime_sync(path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+  }
+
+  async fn open_async<'a>(
+    &'a self,
+    path: PathBuf,
+    options: OpenOptions,
+    access_check: Option<AccessCheckCb<'a>>,
+  ) -> FsResult<Rc<dyn File>> {
+    let writes = options.write || options.append || options.create || options.create_new;
+    let layer = if writes {
+      self.writable_async(&path).await?
+    } else {
+      self.layer_async(&path).await
+    };
+    layer.open_async(path, options, access_check).await
+  }
+
+  async fn mkdir_async(&self, path: PathBuf, recursive: bool, mode: Option<u32>) -> FsResult<()> {
+    let base = self.writable_async(&path).await?;
+    base.mkdir_async(path, recursive, mode).await
+  }
+
+  async fn chmod_async(&self, path: PathBuf, mode: u32) -> FsResult<()> {
+    self.writable_async(&path).await?.chmod_async(path, mode).await
+  }
+
+  async fn chown_async(&self, path: PathBuf, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+    self.writable_async(&path).await?.chown_async(path, uid, gid).await
+  }
+
+  async fn lchown_async(&self, path: PathBuf, uid: Option<u32>, gid: Option<u32>) -> FsResult<()> {
+    self.writable_async(&path).await?.lchown_async(path, uid, gid).await
+  }
+
+  async fn remove_async(&self, path: PathBuf, recursive: bool) -> FsResult<()> {
+    self.writable_async(&path).await?.remove_async(path, recursive).await
+  }
+
+  async fn copy_file_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+    let base = self.writable_async(&newpath).await?;
+    if self.in_overlay_async(&oldpath).await {
+      copy_tree_async(&*self.overlay, base, oldpath, newpath).await
+    } else {
+      base.copy_file_async(oldpath, newpath).await
+    }
+  }
+
+  async fn cp_async(&self, path: PathBuf, new_path: PathBuf) -> FsResult<()> {
+    let base = self.writable_async(&new_path).await?;
+    if self.overlay.exists_async(path.clone()).await? {
+      copy_tree_async(self, base, path, new_path).await
+    } else {
+      base.cp_async(path, new_path).await
+    }
+  }
+
+  async fn stat_async(&self, path: PathBuf) -> FsResult<FsStat> {
+    self.layer_async(&path).await.stat_async(path).await
+  }
+
+  async fn lstat_async(&self, path: PathBuf) -> FsResult<FsStat> {
+    self.layer_async(&path).await.lstat_async(path).await
+  }
+
+  async fn realpath_async(&self, path: PathBuf) -> FsResult<PathBuf> {
+    self.layer_async(&path).await.realpath_async(path).await
+  }
+
+  async fn read_dir_async(&self, path: PathBuf) -> FsResult<Vec<FsDirEntry>> {
+    let overlay = self.overlay.read_dir_async(path.clone()).await;
+    let base = self.base.read_dir_async(path).await;
+    merge_dir_entries(base, overlay)
+  }
+
+  async fn rename_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+    self.writable_async(&oldpath).await?;
+    let base = self.writable_async(&newpath).await?;
+    base.rename_async(oldpath, newpath).await
+  }
+
+  async fn link_async(&self, oldpath: PathBuf, newpath: PathBuf) -> FsResult<()> {
+    let base = self.writable_async(&newpath).await?;
+    base.link_async(oldpath, newpath).await
+  }
+
+  async fn symlink_async(
+    &self,
+    oldpath: PathBuf,
+    newpath: PathBuf,
+    file_type: Option<FsFileType>,
+  ) -> FsResult<()> {
+    let base = self.writable_async(&newpath).await?;
+    base.symlink_async(oldpath, newpath, file_type).await
+  }
+
+  async fn read_link_async(&self, path: PathBuf) -> FsResult<PathBuf> {
+    self.layer_async(&path).await.read_link_async(path).await
+  }
+
+  async fn truncate_async(&self, path: PathBuf, len: u64) -> FsResult<()> {
+    self.writable_async(&path).await?.truncate_async(path, len).await
+  }
+
+  async fn utime_async(
+    &self,
+    path: PathBuf,
+    atime_secs: i64,
+    atime_nanos: u32,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+  ) -> FsResult<()> {
+    let base = self.writable_async(&path).await?;
+    base
+      .utime_async(path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+      .await
+  }
+
+  async fn lutime_async(
+    &self,
+    path: PathBuf,
+    atime_secs: i64,
+    atime_nanos: u32,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+  ) -> FsResult<()> {
+    let base = self.writable_async(&path).await?;
+    base
+      .lutime_async(path, atime_secs, atime_nanos, mtime_secs, mtime_nanos)
+      .await
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn read(fs: &dyn FileSystem, path: &str) -> FsResult<String> {
+    let data = fs.read_file_sync(Path::new(path), None)?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+  }
+
+  fn write(fs: &dyn FileSystem, path: &str, data: &str) -> FsResult<()> {
+    let options = OpenOptions::write(true, false, false, None);
+    fs.write_file_sync(Path::new(path), options, None, data.as_bytes())
+  }
+
+  fn names(mut entries: Vec<FsDirEntry>) -> Vec<String> {
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries.into_iter().map(|entry| entry.name).collect()
+  }
+
+  #[test]
+  fn memory_fs_reads_back_what_it_writes() {
+    let fs = MemoryFs::new();
+    fs.mkdir_sync(Path::new("/app"), false, None).unwrap();
+    write(&fs, "/app/a.txt", "hello").unwrap();
+    assert_eq!(read(&fs, "/app/a.txt").unwrap(), "hello");
+    assert_eq!(fs.get(Path::new("/app/../app/a.txt")), Some(b"hello".to_vec()));
+
+    let file = fs
+      .open_sync(Path::new("/app/a.txt"), OpenOptions::write(false, true, false, None), None)
+      .unwrap();
+    file.write_all_sync(b", world").unwrap();
+    assert_eq!(read(&fs, "/app/a.txt").unwrap(), "hello, world");
+  }
+
+  #[test]
+  fn memory_fs_needs_parent_directories() {
+    let fs = MemoryFs::new();
+    let err = write(&fs, "/missing/a.txt", "x").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(fs.mkdir_sync(Path::new("/a/b"), false, None).is_err());
+
+    fs.mkdir_sync(Path::new("/a/b"), true, None).unwrap();
+    assert!(fs.stat_sync(Path::new("/a")).unwrap().is_directory);
+    write(&fs, "/a/b/c.txt", "x").unwrap();
+  }
+
+  #[test]
+  fn memory_fs_removes_and_renames_directories() {
+    let fs = MemoryFs::new();
+    fs.insert(Path::new("/src/lib/a.txt"), "a");
+    fs.insert(Path::new("/src/b.txt"), "b");
+    assert_eq!(names(fs.read_dir_sync(Path::new("/src")).unwrap()), ["b.txt", "lib"]);
+
+    let err = fs.remove_sync(Path::new("/src"), false).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::DirectoryNotEmpty);
+
+    fs.rename_sync(Path::new("/src"), Path::new("/dest")).unwrap();
+    assert!(!fs.exists_sync(Path::new("/src")));
+    assert_eq!(read(&fs, "/dest/lib/a.txt").unwrap(), "a");
+    assert!(fs.rename_sync(Path::new("/dest"), Path::new("/dest/inner")).is_err());
+
+    fs.remove_sync(Path::new("/dest"), true).unwrap();
+    assert!(!fs.contains(Path::new("/dest/b.txt")));
+  }
+
+  #[test]
+  fn rooted_fs_keeps_paths_inside_the_root() {
+    let inner = MemoryFs::new();
+    let fs = RootedFs::new(Path::new("/sandbox"), Arc::new(inner.clone()));
+
+    write(&fs, "/etc/passwd", "root").unwrap_err();
+    fs.mkdir_sync(Path::new("/etc"), true, None).unwrap();
+    write(&fs, "/etc/passwd", "root").unwrap();
+    assert!(inner.contains(Path::new("/sandbox/etc/passwd")));
+    assert!(!inner.contains(Path::new("/etc/passwd")));
+
+    assert_eq!(read(&fs, "/sandbox/etc/passwd").unwrap(), "root");
+    assert_eq!(read(&fs, "/../../etc/passwd").unwrap(), "root");
+    assert_eq!(fs.cwd().unwrap(), PathBuf::from("/sandbox"));
+  }
+
+  #[test]
+  fn rooted_fs_resolves_relative_paths_from_the_root() {
+    let inner = MemoryFs::new();
+    inner.mkdir_sync(Path::new("/sandbox"), false, None).unwrap();
+    let fs = RootedFs::new(Path::new("/sandbox"), Arc::new(inner.clone()));
+
+    write(&fs, "a.txt", "relative").unwrap();
+    assert!(inner.contains(Path::new("/sandbox/a.txt")));
+    assert_eq!(read(&fs, "./a.txt").unwrap(), "relative");
+    assert_eq!(read(&fs, "../../a.txt").unwrap(), "relative");
+    assert_eq!(read(&fs, "/a.txt").unwrap(), "relative");
+  }
+
+  #[test]
+  fn rooted_fs_refuses_new_symlinks() {
+    let dir = tempfile::tempdir().unwrap();
+    let fs = RootedFs::new(dir.path(), Arc::new(RealFs));
+    let err = fs
+      .symlink_sync(Path::new("/etc/passwd"), Path::new("/link"), None)
+      .unwrap_err();
+    assert!(matches!(err, FsError::NotSupported));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn rooted_fs_follows_symlinks_already_in_the_root() {
+    let outside = tempfile::tempdir().unwrap();
+    std::fs::write(outside.path().join("secret"), "outside").unwrap();
+    let root = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+
+    // Documented: links that exist before the root is used can leave it.
+    let fs = RootedFs::new(root.path(), Arc::new(RealFs));
+    assert_eq!(read(&fs, "/escape/secret").unwrap(), "outside");
+  }
+
+  fn overlay() -> (MemoryFs, MemoryFs, OverlayFs) {
+    let top = MemoryFs::new();
+    top.insert(Path::new("/app/virtual.js"), "overlay");
+    top.insert(Path::new("/app/shared.js"), "overlay");
+    let base = MemoryFs::new();
+    base.insert(Path::new("/app/shared.js"), "base");
+    base.insert(Path::new("/app/real.js"), "base");
+    let fs = OverlayFs::new(Arc::new(top.clone()), Arc::new(base.clone()));
+    (top, base, fs)
+  }
+
+  #[test]
+  fn overlay_fs_reads_through_to_the_base() {
+    let (_, _, fs) = overlay();
+    assert_eq!(read(&fs, "/app/virtual.js").unwrap(), "overlay");
+    assert_eq!(read(&fs, "/app/shared.js").unwrap(), "overlay");
+    assert_eq!(read(&fs, "/app/real.js").unwrap(), "base");
+    assert!(read(&fs, "/app/none.js").is_err());
+    assert_eq!(
+      names(fs.read_dir_sync(Path::new("/app")).unwrap()),
+      ["real.js", "shared.js", "virtual.js"]
+    );
+  }
+
+  #[test]
+  fn overlay_fs_writes_to_the_base_only() {
+    let (top, base, fs) = overlay();
+    let err = write(&fs, "/app/shared.js", "changed").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(fs.remove_sync(Path::new("/app/virtual.js"), false).is_err());
+
+    write(&fs, "/app/new.js", "new").unwrap();
+    assert_eq!(base.get(Path::new("/app/new.js")), Some(b"new".to_vec()));
+    assert!(!top.contains(Path::new("/app/new.js")));
+
+    fs.copy_file_sync(Path::new("/app/virtual.js"), Path::new("/app/copy.js")).unwrap();
+    assert_eq!(base.get(Path::new("/app/copy.js")), Some(b"overlay".to_vec()));
+  }
+
+  #[tokio::test]
+  async fn overlay_fs_async_methods_use_the_layers() {
+    let (_, base, fs) = overlay();
+    let data = fs.read_file_async(PathBuf::from("/app/virtual.js"), None).await.unwrap();
+    assert_eq!(&*data, b"overlay");
+
+    let options = OpenOptions::write(true, false, false, None);
+    let shared = PathBuf::from("/app/shared.js");
+    let err = fs.write_file_async(shared, options, None, b"x".to_vec()).await;
+    assert!(err.is_err());
+
+    let path = PathBuf::from("/app/async.js");
+    fs.write_file_async(path, options, None, b"async".to_vec()).await.unwrap();
+    assert_eq!(base.get(Path::new("/app/async.js")), Some(b"async".to_vec()));
+
+    let copy = (PathBuf::from("/app"), PathBuf::from("/copy"));
+    fs.cp_async(copy.0, copy.1).await.unwrap();
+    assert_eq!(base.get(Path::new("/copy/virtual.js")), Some(b"overlay".to_vec()));
+    assert_eq!(base.get(Path::new("/copy/real.js")), Some(b"base".to_vec()));
+  }
+
+  #[tokio::test]
+  async fn rooted_fs_async_methods_resolve_under_the_root() {
+    let dir = tempfile::tempdir().unwrap();
+    let fs = RootedFs::new(dir.path(), Arc::new(RealFs));
+    let options = OpenOptions::write(true, false, false, None);
+    let path = PathBuf::from("/../note.txt");
+    fs.write_file_async(path, options, None, b"note".to_vec()).await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join("note.txt")).unwrap(), b"note");
+
+    let stat = fs.stat_async(PathBuf::from("/note.txt")).await.unwrap();
+    assert!(stat.is_file);
+  }
+}
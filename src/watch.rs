@@ -0,0 +1,183 @@
+use crate::module_graph::ModuleGraph;
+use crate::permissions::{PermissionFlags, RewPermissions};
+use crate::runtime::RewRuntime;
+use anyhow::Result;
+use colored::*;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+/// How long the file system has to stay quiet before a restart happens.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Collects every file on disk that `entry` depends on.
+///
+/// This is every file in the module graph, which already follows app
+/// entries and `// external` brew references, plus the `app.yaml` of every
+/// app involved.
+pub fn collect_watch_paths(entry: &Path) -> Result<HashSet<PathBuf>> {
+  let mut paths = HashSet::new();
+  paths.insert(entry.to_path_buf());
+
+  for path in ModuleGraph::build(entry)?.files() {
+    if let Some(app_path) = crate::utils::find_app_path(path) {
+      paths.insert(app_path.join("app.yaml"));
+    }
+    paths.insert(path.to_path_buf());
+  }
+
+  Ok(paths)
+}
+
+/// Watches the parent directories of `paths`.
+///
+/// Directories are watched instead of the files themselves because editors
+/// often save by replacing the file, which drops a watch on the old inode.
+fn watch_paths(
+  paths: &HashSet<PathBuf>,
+) -> Result<(RecommendedWatcher, UnboundedReceiver<PathBuf>)> {
+  let (tx, rx) = unbounded_channel();
+  let watched_files = paths.clone();
+
+  let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+    let Ok(event) = res else {
+      return;
+    };
+    if !matches!(
+      event.kind,
+      EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    ) {
+      return;
+    }
+    for path in event.paths {
+      if watched_files.contains(&path) {
+        let _ = tx.send(path);
+      }
+    }
+  })?;
+
+  let dirs: HashSet<&Path> = paths.iter().filter_map(|path| path.parent()).collect();
+  for dir in dirs {
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+  }
+
+  Ok((watcher, rx))
+}
+
+/// Waits for a change and then for the burst of events it causes to settle.
+///
+/// # Returns
+/// * The first file that changed, or `None` if the watcher went away.
+async fn next_change(rx: &mut UnboundedReceiver<PathBuf>) -> Option<PathBuf> {
+  let changed = rx.recv().await?;
+  while let Ok(Some(_)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {}
+  Some(changed)
+}
+
+/// Runs `entry` and restarts it with a fresh runtime whenever a file in its
+/// import graph changes.
+pub async fn run_watched(entry: &Path, args: &[String], flags: &PermissionFlags) -> Result<()> {
+  let entry = entry.canonicalize()?;
+  let mut paths = HashSet::from([entry.clone()]);
+
+  loop {
+    match collect_watch_paths(&entry) {
+      Ok(collected) => paths = collected,
+      Err(e) => crate::diagnostics::report_error(&e),
+    }
+    let (_watcher, mut rx) = watch_paths(&paths)?;
+
+    let changed = {
+      let permissions = RewPermissions::for_entry(flags, &entry)?;
+      let mut runtime = RewRuntime::with_permissions(Some(args.to_vec()), permissions)?;
+      tokio::select! {
+        result = runtime.run_file(&entry) => {
+          if let Err(e) = result {
+            crate::diagnostics::report_error(&e);
+          }
+          println!("{}", "Waiting for changes...".dimmed());
+          next_change(&mut rx).await
+        }
+        changed = next_change(&mut rx) => changed,
+      }
+    };
+
+    let Some(changed) = changed else {
+      return Ok(());
+    };
+
+    print!("\x1B[2J\x1B[1;1H");
+    println!(
+      "{} {} changed",
+      "Restarting:".yellow(),
+      changed.display().to_string().green()
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::brew::Brew;
+  use std::fs;
+  use std::time::Instant;
+
+  #[test]
+  fn watch_paths_include_app_configs_and_brew_externals() {
+    let lib = crate::utils::test_rew_root().join("apps").join("watch-lib");
+    fs::create_dir_all(&lib).unwrap();
+    fs::write(
+      lib.join("app.yaml"),
+      "manifest:\n  package: watch.lib\nentries:\n  main: main.coffee\n",
+    )
+    .unwrap();
+    fs::write(lib.join("main.coffee"), "export x = 1\n").unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let app = dir.path().canonicalize().unwrap();
+    fs::write(app.join("app.yaml"), "manifest:\n  package: watch.app\n").unwrap();
+    fs::write(app.join("main.coffee"), "import \"./bundle.brew\"\n").unwrap();
+    let brew = Brew::new(
+      vec![("main".to_string(), None, "print 1\n".to_string())],
+      vec!["main".to_string()],
+      vec!["#std.fs".to_string(), "watch.lib/main".to_string()],
+    );
+    fs::write(app.join("bundle.brew"), brew.encode(false, None).unwrap()).unwrap();
+
+    let paths = collect_watch_paths(&app.join("main.coffee")).unwrap();
+    let expected = HashSet::from([
+      app.join("main.coffee"),
+      app.join("bundle.brew"),
+      app.join("app.yaml"),
+      lib.join("main.coffee"),
+      lib.join("app.yaml"),
+    ]);
+    assert_eq!(paths, expected);
+  }
+
+  #[tokio::test]
+  async fn changes_are_reported_once_the_burst_settles() {
+    let (tx, mut rx) = unbounded_channel();
+    let started = Instant::now();
+    tx.send(PathBuf::from("a.coffee")).unwrap();
+    let burst = tokio::spawn({
+      let tx = tx.clone();
+      async move {
+        for name in ["b.coffee", "c.coffee"] {
+          tokio::time::sleep(DEBOUNCE / 2).await;
+          tx.send(PathBuf::from(name)).unwrap();
+        }
+      }
+    });
+
+    assert_eq!(next_change(&mut rx).await, Some(PathBuf::from("a.coffee")));
+    assert!(started.elapsed() >= DEBOUNCE * 2);
+    assert!(rx.try_recv().is_err());
+    burst.await.unwrap();
+
+    drop(tx);
+    assert_eq!(next_change(&mut rx).await, None);
+  }
+}
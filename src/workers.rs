@@ -1,22 +1,74 @@
-use crate::runtime::get_rew_runtime;
+use crate::permissions::RewPermissions;
+use crate::runtime::{RewRuntime, check_read_permission, get_rew_runtime};
 use anyhow::Result;
 use deno_core::error::CoreError;
-use deno_core::{OpState, op2};
-use serde_json::Value;
+use deno_core::{JsRuntime, OpState, PollEventLoopOptions, ToJsBuffer, op2, v8};
+use deno_fs::FileSystemRc;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::future::poll_fn;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::{
-  Arc, Mutex,
-  mpsc::{Receiver, Sender, channel},
-};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::Poll;
+use std::time::Duration;
 use tokio::runtime::Builder;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::sync::watch;
 use uuid::Uuid;
 
+/// A value passed to `postMessage`, as written by the V8 serializer.
+#[derive(Serialize, Debug)]
+pub struct WorkerMessage {
+  data: ToJsBuffer,
+  /// Where the transferred `ArrayBuffer`s wait in the shared store.
+  transferred: Vec<u32>,
+}
+
+impl WorkerMessage {
+  fn new(data: Vec<u8>, transferred: Vec<u32>) -> Self {
+    Self {
+      data: data.into(),
+      transferred,
+    }
+  }
+}
+
+/// What a worker sends to the thread that spawned it.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FromWorker {
+  /// A value the worker passed to `postMessage`.
+  Message(WorkerMessage),
+  /// The worker script threw, or failed to start.
+  Error { message: String },
+}
+
+/// What a worker runs.
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum WorkerSource {
+  /// Code to run as is. `parent` is the file that spawned the worker; the
+  /// `#std` builtins it imports are loaded into the worker first.
+  Inline {
+    code: String,
+    parent: Option<String>,
+  },
+  /// A module to compile and run together with everything it imports.
+  File { path: String },
+}
+
 /// Represents a handle to a worker thread
 struct WorkerHandle {
-  to_worker: Sender<serde_json::Value>,
-  from_worker: Arc<Mutex<Receiver<serde_json::Value>>>,
+  to_worker: UnboundedSender<WorkerMessage>,
+  from_worker: Arc<tokio::sync::Mutex<UnboundedReceiver<FromWorker>>>,
+  /// Never sent on; dropping it with the handle tells pending receives that
+  /// the worker was terminated.
+  terminated: watch::Sender<()>,
+  /// Set once the worker's runtime exists, so terminating can stop the
+  /// script it is running.
+  isolate: Arc<OnceLock<v8::IsolateHandle>>,
 }
 
 lazy_static::lazy_static! {
@@ -26,11 +78,21 @@ lazy_static::lazy_static! {
 #[op2]
 #[string]
 pub fn op_thread_spawn(
-  #[string] source: String,
-  _: Rc<RefCell<OpState>>,
+  #[serde] source: WorkerSource,
+  state: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
-  let (to_worker_tx, to_worker_rx) = channel::<Value>();
-  let (from_worker_tx, from_worker_rx) = channel::<Value>();
+  if let WorkerSource::File { path } = &source {
+    check_read_permission(&state, Path::new(path))?;
+  }
+
+  // Workers get the same permissions as the runtime that spawns them.
+  let permissions = state.borrow().borrow::<RewPermissions>().clone();
+  let fs = state.borrow().borrow::<FileSystemRc>().clone();
+  let (to_worker_tx, to_worker_rx) = unbounded_channel::<WorkerMessage>();
+  let (from_worker_tx, from_worker_rx) = unbounded_channel::<FromWorker>();
+  let error_tx = from_worker_tx.clone();
+  let isolate = Arc::new(OnceLock::new());
+  let worker_isolate = isolate.clone();
 
   let id = Uuid::new_v4().to_string();
   let worker_id = id.clone();
@@ -54,58 +116,84 @@ pub fn op_thread_spawn(
 
     // Create RewRuntime inside the Tokio runtime context
     let runtime_result = rt.block_on(async {
-      // Create RewRuntime
-      let mut runtime = match get_rew_runtime(false, false, None) {
-        Ok(rt) => rt,
-        Err(e) => {
-          eprintln!("Failed to create RewRuntime: {}", e);
-          return Err(e);
-        }
-      };
+      // Workers compile their own modules, so they get the Civet compiler too.
+      let mut runtime = RewRuntime::new(
+        None,
+        Some(get_rew_runtime(true, false, None, permissions, fs)?),
+      )?;
+      let _ = worker_isolate.set(runtime.runtime.v8_isolate().thread_safe_handle());
 
       // Store the worker ID and sender in the runtime state
       {
-        let op_state = runtime.op_state();
+        let op_state = runtime.runtime.op_state();
         let mut op_state = op_state.borrow_mut();
         op_state.put(worker_id.clone());
         op_state.put(from_worker_tx);
       }
 
       // Initialize the worker with postMessage function
-      if let Err(e) = runtime.execute_script(
+      if let Err(e) = runtime.runtime.execute_script(
         "<init>",
         r#"
-              globalThis.onmessage = () => {};
-              globalThis.postMessage = function (msg) {
-                rew.ops.op_thread_post_message(JSON.stringify(msg));
+              let messageHandler = () => {};
+              globalThis.onmessage = (fn) => { messageHandler = fn; };
+              // Tasks from a pool get whatever the handler returns, or throws,
+              // posted back under the same task id.
+              globalThis._dispatchMessage = () => {
+                const { data, transferred } = rew.ops.op_thread_take_message();
+                const msg = rew.ops.op_deserialize(data, undefined, transferred, false);
+                if (msg === null || typeof msg !== 'object' || !('__task' in msg)) {
+                  return messageHandler(msg);
+                }
+                const task = msg.__task;
+                Promise.resolve()
+                  .then(() => messageHandler(msg.payload))
+                  .then(
+                    (result) => postMessage({ __task: task, result }),
+                    (e) => postMessage({ __task: task, error: e instanceof Error ? e.message : String(e) }),
+                  );
+              };
+              globalThis.postMessage = function (msg, transfer = []) {
+                const transferred = transfer.map((b) => ArrayBuffer.isView(b) ? b.buffer : b);
+                const data = rew.ops.op_serialize(msg, undefined, transferred, false, undefined);
+                rew.ops.op_thread_post_message(data, transferred);
               };
           "#,
       ) {
-        eprintln!("Failed to initialize worker: {}", e);
         return Err(anyhow::anyhow!("Failed to initialize worker: {}", e));
       }
 
       // Load worker source
-      if let Err(e) = runtime.execute_script(
-        "<worker>",
-        format!(
-          r#"
+      match source {
+        WorkerSource::File { path } => runtime
+          .load_file(&path)
+          .await
+          .map_err(|e| anyhow::anyhow!("Failed to execute worker script: {}", e))?,
+        WorkerSource::Inline { code, parent } => {
+          if let Some(parent) = parent {
+            load_builtins_of(&mut runtime, Path::new(&parent)).await?;
+          }
+          if let Err(e) = runtime.runtime.execute_script(
+            "<worker>",
+            format!(
+              r#"
             rew.prototype.mod.prototype.defineNew("<worker::{id}>", {{
               "<worker::{id}>"(ctx){{
-                ctx.onmessage = (fn) => globalThis.onmessage = fn;
-                ctx.postMessage = (msg) => globalThis.postMessage(msg);
+                ctx.onmessage = (fn) => globalThis.onmessage(fn);
+                ctx.postMessage = (msg, transfer) => globalThis.postMessage(msg, transfer);
                 with({{...ctx, globalThis: {{}}}}){{  
                   {source}
                 }}
               }}
             }}, ["::pvt"]);
           "#,
-          id = worker_id.clone(),
-          source = source.clone()
-        ),
-      ) {
-        eprintln!("Failed to execute worker script: {}", e);
-        return Err(anyhow::anyhow!("Failed to execute worker script: {}", e));
+              id = worker_id.clone(),
+              source = code
+            ),
+          ) {
+            return Err(anyhow::anyhow!("Failed to execute worker script: {}", e));
+          }
+        }
       }
 
       Ok(runtime)
@@ -114,51 +202,141 @@ pub fn op_thread_spawn(
     let mut runtime = match runtime_result {
       Ok(rt) => rt,
       Err(e) => {
-        eprintln!("Failed to initialize worker runtime: {}", e);
+        let _ = error_tx.send(FromWorker::Error {
+          message: format!("Failed to initialize worker runtime: {}", e),
+        });
         return;
       }
     };
 
-    // Process messages in the Tokio runtime
-    rt.block_on(async {
-      // Loop: receive and inject messages
-      for msg in to_worker_rx {
-        let msg_json = match serde_json::to_string(&msg) {
-          Ok(json) => json,
-          Err(e) => {
-            eprintln!("Failed to serialize message: {}", e);
-            continue;
-          }
-        };
-
-        let js = format!("onmessage({});", msg_json);
-        if let Err(e) = runtime.execute_script("<message>", js.clone()) {
-          eprintln!("Failed to process message: {}", e);
-          // Continue processing other messages even if one fails
-        }
-
-        // Give the event loop a chance to process events
-        tokio::task::yield_now().await;
-      }
-    });
+    // Drive the worker's event loop and its messages in the Tokio runtime
+    rt.block_on(run_worker_loop(&mut runtime.runtime, to_worker_rx));
   });
 
   WORKER_REGISTRY.lock().unwrap().insert(
     id.clone(),
     WorkerHandle {
       to_worker: to_worker_tx,
-      from_worker: Arc::new(Mutex::new(from_worker_rx)),
+      from_worker: Arc::new(tokio::sync::Mutex::new(from_worker_rx)),
+      terminated: watch::channel(()).0,
+      isolate,
     },
   );
 
   Ok(id)
 }
 
+/// Runs the `#std` builtins `parent` imports, directly or not, so the
+/// extensions they add are there for an inline worker as well.
+///
+/// Files that can't be resolved, such as another inline worker, add nothing.
+async fn load_builtins_of(runtime: &mut RewRuntime, parent: &Path) -> Result<()> {
+  let Ok(includes) = RewRuntime::resolve_includes_recursive_from(parent) else {
+    return Ok(());
+  };
+  let builtins: Vec<(PathBuf, String)> = includes
+    .into_iter()
+    .filter(|(path, _, _)| path.to_string_lossy().starts_with('#'))
+    .map(|(path, source, _)| (path, source))
+    .collect();
+  if builtins.is_empty() {
+    return Ok(());
+  }
+
+  let script = runtime.prepare(builtins, None).await?;
+  runtime.runtime.execute_script("<worker-builtins>", script)?;
+  Ok(())
+}
+
+/// What woke a worker up.
+enum WorkerEvent {
+  /// A message from the main thread, or `None` once the worker was terminated.
+  Message(Option<WorkerMessage>),
+  /// The event loop ran out of work, or failed with an uncaught error.
+  Idle(Result<(), CoreError>),
+}
+
+/// Runs the worker's event loop, handing it messages as they arrive, so
+/// timers, promises and async ops started by the worker complete.
+///
+/// Returns once the worker was terminated and has no pending work left.
+async fn run_worker_loop(
+  runtime: &mut JsRuntime,
+  mut to_worker_rx: UnboundedReceiver<WorkerMessage>,
+) {
+  let parent = runtime
+    .op_state()
+    .borrow()
+    .borrow::<UnboundedSender<FromWorker>>()
+    .clone();
+  let report = |message: String| {
+    let _ = parent.send(FromWorker::Error { message });
+  };
+  let mut terminated = false;
+
+  loop {
+    let event = poll_fn(|cx| {
+      let message = if terminated {
+        Poll::Pending
+      } else {
+        to_worker_rx.poll_recv(cx)
+      };
+      if let Poll::Ready(msg) = message {
+        return Poll::Ready(WorkerEvent::Message(msg));
+      }
+      runtime
+        .poll_event_loop(cx, PollEventLoopOptions::default())
+        .map(WorkerEvent::Idle)
+    })
+    .await;
+
+    match event {
+      WorkerEvent::Message(Some(msg)) => {
+        if let Err(e) = dispatch_message(runtime, msg) {
+          report(e);
+        }
+      }
+      WorkerEvent::Message(None) => terminated = true,
+      WorkerEvent::Idle(result) => {
+        if let Err(e) = result {
+          report(e.to_string());
+        }
+        if terminated {
+          break;
+        }
+        // Nothing to do until the next message arrives.
+        match to_worker_rx.recv().await {
+          Some(msg) => {
+            if let Err(e) = dispatch_message(runtime, msg) {
+              report(e);
+            }
+          }
+          None => break,
+        }
+      }
+    }
+  }
+}
+
+/// Calls the worker's `onmessage` handler with `msg`.
+///
+/// # Returns
+/// * The error the handler threw, if any. Later messages are still handled.
+fn dispatch_message(runtime: &mut JsRuntime, msg: WorkerMessage) -> Result<(), String> {
+  // `_dispatchMessage` picks it up with `op_thread_take_message`.
+  runtime.op_state().borrow_mut().put(msg);
+  runtime
+    .execute_script("<message>", "_dispatchMessage();")
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
 #[op2]
 #[string]
 pub fn op_thread_message(
   #[string] thread_id: String,
-  #[string] msg: String,
+  #[buffer(copy)] data: Vec<u8>,
+  #[serde] transferred: Vec<u32>,
   _: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
   let registry = WORKER_REGISTRY.lock().unwrap();
@@ -166,7 +344,7 @@ pub fn op_thread_message(
   if let Some(handle) = registry.get(&thread_id) {
     handle
       .to_worker
-      .send(serde_json::from_str(&msg).unwrap())
+      .send(WorkerMessage::new(data, transferred))
       .map_err(|e| {
         CoreError::Io(std::io::Error::new(
           std::io::ErrorKind::Other,
@@ -182,17 +360,27 @@ pub fn op_thread_message(
   }
 }
 
+/// Terminates a worker.
+///
+/// # Arguments
+/// * `thread_id` - The worker to terminate.
+/// * `force` - Stop the script it is running right away, e.g. a pool task
+///   that timed out, instead of letting its timers and promises finish.
 #[op2]
 #[string]
 pub fn op_thread_terminate(
   #[string] thread_id: String,
+  force: bool,
   _: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
   let mut registry = WORKER_REGISTRY.lock().unwrap();
 
-  if registry.remove(&thread_id).is_some() {
+  if let Some(handle) = registry.remove(&thread_id) {
     // Removing the handle will cause the channel to close,
     // which will terminate the message loop in the worker
+    if let Some(isolate) = handle.isolate.get().filter(|_| force) {
+      isolate.terminate_execution();
+    }
     Ok("".to_string())
   } else {
     println!("Thread termination failed");
@@ -206,25 +394,20 @@ pub fn op_thread_terminate(
 #[op2]
 #[string]
 pub fn op_thread_post_message(
-  #[string] message: String,
+  #[buffer(copy)] data: Vec<u8>,
+  #[serde] transferred: Vec<u32>,
   state: Rc<RefCell<OpState>>,
 ) -> Result<String, CoreError> {
-  // Parse the message from JSON string
-  let msg: Value = serde_json::from_str(&message).map_err(|e| {
-    CoreError::Io(std::io::Error::new(
-      std::io::ErrorKind::InvalidData,
-      e.to_string(),
-    ))
-  })?;
+  let msg = WorkerMessage::new(data, transferred);
 
   // Get the worker ID and sender from the state
   let state = state.borrow();
   #[allow(unused)]
   let worker_id = state.borrow::<String>();
-  let sender = state.borrow::<Sender<Value>>();
+  let sender = state.borrow::<UnboundedSender<FromWorker>>();
 
   // Send the message back to the main thread
-  sender.send(msg).map_err(|e| {
+  sender.send(FromWorker::Message(msg)).map_err(|e| {
     CoreError::Io(std::io::Error::new(
       std::io::ErrorKind::BrokenPipe,
       e.to_string(),
@@ -234,44 +417,216 @@ pub fn op_thread_post_message(
   Ok("".to_string())
 }
 
+/// Hands the message being dispatched to the worker's `_dispatchMessage`.
 #[op2]
 #[serde]
-pub fn op_thread_receive(
+pub fn op_thread_take_message(state: &mut OpState) -> Option<WorkerMessage> {
+  state.try_take::<WorkerMessage>()
+}
+
+/// Resolves with the next message or error from a worker.
+///
+/// # Arguments
+/// * `thread_id` - The worker to receive from.
+/// * `timeout_ms` - How long to wait, or `None` to wait until something
+///   arrives.
+///
+/// # Returns
+/// * The event, or `None` on timeout or once the worker was terminated.
+#[op2(async)]
+#[serde]
+pub async fn op_thread_receive(
   #[string] thread_id: String,
   #[serde] timeout_ms: Option<u64>,
-  _: Rc<RefCell<OpState>>,
-) -> Result<Option<Value>, CoreError> {
-  let registry = WORKER_REGISTRY.lock().unwrap();
+) -> Result<Option<FromWorker>, CoreError> {
+  let (receiver, mut terminated) = {
+    let registry = WORKER_REGISTRY.lock().unwrap();
+    let Some(handle) = registry.get(&thread_id) else {
+      return Err(CoreError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Thread not found",
+      )));
+    };
+    (handle.from_worker.clone(), handle.terminated.subscribe())
+  };
 
-  if let Some(handle) = registry.get(&thread_id) {
-    let receiver = handle.from_worker.lock().unwrap();
-
-    // If timeout is provided, wait for that duration
-    if let Some(timeout) = timeout_ms {
-      use std::time::Duration;
-      match receiver.recv_timeout(Duration::from_millis(timeout)) {
-        Ok(msg) => Ok(Some(msg)),
-        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Ok(None),
-        Err(e) => Err(CoreError::Io(std::io::Error::new(
-          std::io::ErrorKind::Other,
-          e.to_string(),
-        ))),
-      }
-    } else {
-      // No timeout, try to receive immediately
-      match receiver.try_recv() {
-        Ok(msg) => Ok(Some(msg)),
-        Err(std::sync::mpsc::TryRecvError::Empty) => Ok(None),
-        Err(e) => Err(CoreError::Io(std::io::Error::new(
-          std::io::ErrorKind::Other,
-          e.to_string(),
-        ))),
-      }
+  let mut receiver = receiver.lock().await;
+  let next = async {
+    tokio::select! {
+      event = receiver.recv() => event,
+      _ = terminated.changed() => None,
     }
-  } else {
-    Err(CoreError::Io(std::io::Error::new(
-      std::io::ErrorKind::InvalidData,
-      "Thread not found",
-    )))
+  };
+
+  match timeout_ms {
+    Some(timeout) => Ok(
+      tokio::time::timeout(Duration::from_millis(timeout), next)
+        .await
+        .unwrap_or(None),
+    ),
+    None => Ok(next.await),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Lets a test script throw when something isn't as expected, which fails
+  /// the run.
+  const CHECK: &str = r##"import "#std.threads"
+using namespace rew::ns
+
+check = (ok, message) ->
+  if not ok then throw new Error message
+
+"##;
+
+  /// Runs `script` as a Rew file, driving the event loop until the workers
+  /// it started are done.
+  fn run_script(script: &str) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("main.coffee");
+    std::fs::write(&path, format!("{CHECK}{script}"))?;
+    Builder::new_current_thread()
+      .enable_all()
+      .build()?
+      .block_on(async { RewRuntime::new(None, None)?.run_file(&path).await })
+  }
+
+  #[test]
+  fn pool_makes_callers_wait_while_the_queue_is_full() {
+    run_script(
+      r##"
+pool = threads::pool 1, ((n) -> n * 2), { queueSize: 1 }
+
+main = ->
+  first = pool.run 1
+  second = pool.run 2
+  third = pool.run 3
+  await Promise.resolve()
+  check pool.pending == 1, "#{pool.pending} tasks are queued, not 1"
+  results = await Promise.all [first, second, third]
+  check results.join(",") == "2,4,6", "the tasks returned #{results}"
+  await pool.close()
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn pool_replaces_a_worker_whose_task_timed_out() {
+    run_script(
+      r##"
+pool = threads::pool 1, ((ms) -> new Promise (resolve) -> setTimeout (-> resolve ms), ms), { timeout: 100 }
+
+main = ->
+  error = await pool.run(5000).then (-> null), (e) -> e.message
+  check String(error).includes("timed out"), "the slow task ended with #{error}"
+  check pool.size == 1, "the pool has #{pool.size} workers, not 1"
+  result = await pool.run 10
+  check result == 10, "the new worker returned #{result}"
+  await pool.close()
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn pool_is_broken_when_its_workers_cannot_start() {
+    run_script(
+      r##"
+pool = threads::pool 2, "throw new Error('no start')"
+
+main = ->
+  error = await pool.run(1).then (-> null), (e) -> e.message
+  check String(error).includes("no start"), "the task ended with #{error}"
+  error = await pool.run(2).then (-> null), (e) -> e.message
+  check String(error).includes("no start"), "a later task ended with #{error}"
+  await pool.close()
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn worker_runs_timers_and_promises_between_messages() {
+    run_script(
+      r##"
+worker = threads::create ->
+  setTimeout (-> postMessage "timer"), 10
+  onmessage (n) ->
+    Promise.resolve(n).then (value) ->
+      setTimeout (-> postMessage value * 2), 10
+
+main = ->
+  first = await worker.receiveMessage 5000
+  check first == "timer", "the worker's own timer sent #{first}"
+  worker.postMessage 21
+  reply = await worker.receiveMessage 5000
+  check reply == 42, "the reply to a message was #{reply}"
+  worker.terminate()
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn messages_move_transferred_buffers_and_share_shared_ones() {
+    run_script(
+      r##"
+worker = threads::create ->
+  onmessage ({ buffer, shared }) ->
+    new Int32Array(shared)[0] = 7
+    postMessage new Uint8Array(buffer)[0]
+
+main = ->
+  buffer = new ArrayBuffer 8
+  new Uint8Array(buffer)[0] = 3
+  shared = new SharedArrayBuffer 4
+  worker.postMessage { buffer, shared }, [buffer]
+  check buffer.byteLength == 0, "the sent buffer still has #{buffer.byteLength} bytes"
+  seen = await worker.receiveMessage 5000
+  check seen == 3, "the worker read #{seen} from the transferred buffer"
+  check new Int32Array(shared)[0] == 7, "the worker's write to the shared buffer is not seen"
+  worker.terminate()
+
+main()
+"##,
+    )
+    .unwrap();
+  }
+
+  #[test]
+  fn workers_can_transfer_buffers_back() {
+    run_script(
+      r##"
+worker = threads::create ->
+  onmessage ->
+    buffer = new ArrayBuffer 8
+    new Uint8Array(buffer)[0] = 5
+    postMessage buffer, [buffer]
+    postMessage buffer.byteLength
+
+main = ->
+  worker.postMessage "send"
+  received = await worker.receiveMessage 5000
+  check new Uint8Array(received)[0] == 5, "the main thread got #{received?.byteLength} bytes"
+  left = await worker.receiveMessage 5000
+  check left == 0, "the worker's buffer still has #{left} bytes"
+  worker.terminate()
+
+main()
+"##,
+    )
+    .unwrap();
   }
 }